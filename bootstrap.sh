@@ -6,8 +6,12 @@ TABLE_SCHEMA='{ "column_types": ["STRING", "i64", "f64" ] }'
 # Test Token
 http :6969/auth "$AUTH"
 
-# Create a Table Schema
-echo $TABLE_SCHEMA | http post :6969/table_schemas "$AUTH" | tee target/table_schema.txt | jq '.id' > target/table_schema_id.txt
+# Create a Table Schema, or infer one from a CSV sample given as the second argument
+if [ -n "$2" ]; then
+    http -f post ":6969/table_schemas/infer" "$AUTH" csv@"$2" | tee target/table_schema.txt | jq '.id' > target/table_schema_id.txt
+else
+    echo $TABLE_SCHEMA | http post :6969/table_schemas "$AUTH" | tee target/table_schema.txt | jq '.id' > target/table_schema_id.txt
+fi
 
 # Create a Table
 echo '{ "table_schema_id": '$(cat target/table_schema_id.txt)', "name": "hndefault" }' | http post :6969/tables "$AUTH" | tee target/table.txt | jq '.id' > target/table_id.txt
@@ -0,0 +1 @@
+ALTER TABLE table_schemas DROP COLUMN column_names;
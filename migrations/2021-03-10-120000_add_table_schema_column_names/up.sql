@@ -0,0 +1,9 @@
+ALTER TABLE table_schemas ADD COLUMN column_names TEXT ARRAY NOT NULL DEFAULT '{}';
+
+-- Existing schemas reference their columns by position
+UPDATE table_schemas
+SET column_names = ARRAY(
+    SELECT 'c' || (i - 1)
+    FROM generate_subscripts(column_types, 1) AS i
+    ORDER BY i
+);
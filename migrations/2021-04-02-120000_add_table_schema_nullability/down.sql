@@ -0,0 +1,2 @@
+ALTER TABLE table_schemas DROP COLUMN null_values;
+ALTER TABLE table_schemas DROP COLUMN column_nullable;
@@ -0,0 +1,10 @@
+ALTER TABLE table_schemas ADD COLUMN column_nullable BOOLEAN ARRAY NOT NULL DEFAULT '{}';
+ALTER TABLE table_schemas ADD COLUMN null_values TEXT ARRAY NOT NULL DEFAULT '{}';
+
+-- Existing schemas required a value in every column
+UPDATE table_schemas
+SET column_nullable = ARRAY(
+    SELECT FALSE
+    FROM generate_subscripts(column_types, 1) AS i
+    ORDER BY i
+);
@@ -0,0 +1,2 @@
+-- Types can't be given back the names they were written with, and their canonical names
+-- are still valid
@@ -0,0 +1,15 @@
+-- Schemas are looked up by the canonical names of their types, as they are now stored, so
+-- types written before are lowercased, stripped of whitespace and leading zeros, and lose a
+-- zero decimal scale
+UPDATE table_schemas
+SET column_types = ARRAY(
+    SELECT regexp_replace(
+        regexp_replace(
+            regexp_replace(lower(column_types[i]), '\s', '', 'g'),
+            '([(,])0+([0-9])', '\1\2', 'g'
+        ),
+        '^decimal\(([0-9]+),0\)$', 'decimal(\1)'
+    )
+    FROM generate_subscripts(column_types, 1) AS i
+    ORDER BY i
+);
@@ -186,6 +186,16 @@ impl From<csv::Error> for CustomError {
     }
 }
 
+impl From<sqlparser::parser::ParserError> for CustomError {
+    fn from(error: sqlparser::parser::ParserError) -> CustomError {
+        log::trace!("Encountered ParserError: {:?}", error);
+        CustomError {
+            error_message: format!("Bad request: {}", error),
+            error_status_code: 400,
+        }
+    }
+}
+
 impl From<&str> for CustomError {
     fn from(error: &str) -> CustomError {
         log::trace!("Creating error: {}", error);
@@ -206,6 +216,16 @@ impl From<serde_urlencoded::ser::Error> for CustomError {
     }
 }
 
+impl From<serde_json::Error> for CustomError {
+    fn from(error: serde_json::Error) -> CustomError {
+        log::error!("Internal server serialization error: {:#?}", error);
+        CustomError {
+            error_message: String::from("Internal server error"),
+            error_status_code: 501,
+        }
+    }
+}
+
 impl ResponseError for CustomError {
     fn error_response(&self) -> HttpResponse {
         let status_code = match StatusCode::from_u16(self.error_status_code) {
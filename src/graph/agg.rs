@@ -0,0 +1,275 @@
+use super::AggOpType;
+use crate::{
+    error_handler::CustomError,
+    query::{
+        as_decimal, as_f64, as_i64, as_str, compare, is_decimal, is_null, to_text, Null,
+        QueryRecord, SqlKey, SqlType,
+    },
+};
+use rust_decimal::Decimal;
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+};
+
+// Aggregate functions fold the rows of a relation into a single value
+// NULL inputs are skipped, and only COUNT produces a value for an empty input
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aggregate {
+    pub op: AggOpType,
+    pub column: Option<usize>,
+    pub distinct: bool,
+}
+
+enum AccumulatorState {
+    Count(i64),
+    IntSum(Option<i64>),
+    DecimalSum(Decimal),
+    FloatSum(f64),
+    Average(f64, i64),
+    Extreme(Option<Box<dyn SqlType>>),
+    Concat(Option<String>),
+}
+
+pub struct Accumulator {
+    aggregate: Aggregate,
+    seen: Option<HashSet<SqlKey>>,
+    state: AccumulatorState,
+}
+
+impl Accumulator {
+    pub fn new(aggregate: &Aggregate) -> Accumulator {
+        let state = match aggregate.op {
+            AggOpType::CountStar | AggOpType::Count => AccumulatorState::Count(0),
+            AggOpType::Sum => AccumulatorState::IntSum(None),
+            AggOpType::Average => AccumulatorState::Average(0.0, 0),
+            AggOpType::Maximum | AggOpType::Minimum => AccumulatorState::Extreme(None),
+            AggOpType::GroupConcat(_) => AccumulatorState::Concat(None),
+        };
+        Accumulator {
+            aggregate: aggregate.clone(),
+            seen: match aggregate.distinct {
+                true => Some(HashSet::new()),
+                false => None,
+            },
+            state,
+        }
+    }
+
+    pub fn update(&mut self, record: &QueryRecord) -> Result<(), CustomError> {
+        let column = match self.aggregate.column {
+            Some(column) => column,
+            None => {
+                if let AccumulatorState::Count(ref mut count) = self.state {
+                    *count += 1;
+                }
+                return Ok(());
+            }
+        };
+
+        let value = match record.columns.get(column) {
+            Some(value) if !is_null(value.as_ref()) => value.as_ref(),
+            _ => return Ok(()),
+        };
+        if let Some(ref mut seen) = self.seen {
+            if !seen.insert(SqlKey::from(value)) {
+                return Ok(());
+            }
+        }
+
+        match self.state {
+            AccumulatorState::Count(ref mut count) => *count += 1,
+            AccumulatorState::IntSum(sum) => {
+                self.state = match (sum.unwrap_or(0), as_i64(value)) {
+                    (sum, Some(v)) => match sum.checked_add(v) {
+                        Some(sum) => AccumulatorState::IntSum(Some(sum)),
+                        None => AccumulatorState::FloatSum(sum as f64 + v as f64),
+                    },
+                    (sum, None) if is_decimal(value) => {
+                        Accumulator::add_decimal(Decimal::from(sum), value)
+                    }
+                    (sum, None) => AccumulatorState::FloatSum(
+                        sum as f64 + Accumulator::to_number(&self.aggregate, value)?,
+                    ),
+                }
+            }
+            // Decimals and integers sum exactly until the sum overflows or meets a float
+            AccumulatorState::DecimalSum(sum) => {
+                self.state = match as_decimal(value) {
+                    Some(_) => Accumulator::add_decimal(sum, value),
+                    None => AccumulatorState::FloatSum(
+                        as_f64(&sum).unwrap_or(f64::NAN)
+                            + Accumulator::to_number(&self.aggregate, value)?,
+                    ),
+                }
+            }
+            AccumulatorState::FloatSum(ref mut sum) => {
+                *sum += Accumulator::to_number(&self.aggregate, value)?
+            }
+            AccumulatorState::Average(ref mut sum, ref mut count) => {
+                *sum += Accumulator::to_number(&self.aggregate, value)?;
+                *count += 1;
+            }
+            AccumulatorState::Extreme(ref mut extreme) => {
+                let replace = match extreme {
+                    None => true,
+                    Some(current) => match compare(value, current.as_ref()) {
+                        Some(ordering) => match self.aggregate.op {
+                            AggOpType::Maximum => ordering == Ordering::Greater,
+                            _ => ordering == Ordering::Less,
+                        },
+                        None => {
+                            return Err(CustomError::new(
+                                400,
+                                format!(
+                                    "Bad request: Cannot compare {:?} and {:?} in {:?}",
+                                    value, current, self.aggregate.op
+                                ),
+                            ))
+                        }
+                    },
+                };
+                if replace {
+                    *extreme = Some(dyn_clone::clone_box(value));
+                }
+            }
+            AccumulatorState::Concat(ref mut concat) => {
+                let separator = match self.aggregate.op {
+                    AggOpType::GroupConcat(ref separator) => separator,
+                    _ => unreachable!(),
+                };
+                let text = to_text(value).unwrap_or_default();
+                *concat = match concat.take() {
+                    Some(concat) => Some(format!("{}{}{}", concat, separator, text)),
+                    None => Some(text),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * The aggregate of the rows so far, leaving the accumulator to take more of them
+     */
+    pub fn value(&self) -> Box<dyn SqlType> {
+        match self.state {
+            AccumulatorState::Extreme(Some(ref extreme)) => extreme.clone(),
+            AccumulatorState::Concat(Some(ref concat)) => Box::new(concat.clone()),
+            AccumulatorState::Count(count) => Box::new(count),
+            AccumulatorState::IntSum(Some(sum)) => Box::new(sum),
+            AccumulatorState::DecimalSum(sum) => Box::new(sum),
+            AccumulatorState::FloatSum(sum) => Box::new(sum),
+            AccumulatorState::Average(sum, count) if count > 0 => Box::new(sum / count as f64),
+            _ => Box::new(Null::default()),
+        }
+    }
+
+    pub fn finish(self) -> Box<dyn SqlType> {
+        match self.state {
+            AccumulatorState::Count(count) => Box::new(count),
+            AccumulatorState::IntSum(Some(sum)) => Box::new(sum),
+            AccumulatorState::DecimalSum(sum) => Box::new(sum),
+            AccumulatorState::FloatSum(sum) => Box::new(sum),
+            AccumulatorState::Average(sum, count) if count > 0 => Box::new(sum / count as f64),
+            AccumulatorState::Extreme(Some(extreme)) => extreme,
+            AccumulatorState::Concat(Some(concat)) => Box::new(concat),
+            _ => Box::new(Null::default()),
+        }
+    }
+
+    fn add_decimal(sum: Decimal, value: &dyn SqlType) -> AccumulatorState {
+        let value = as_decimal(value).unwrap_or_default();
+        match sum.checked_add(value) {
+            Some(sum) => AccumulatorState::DecimalSum(sum),
+            None => AccumulatorState::FloatSum(
+                as_f64(&sum).unwrap_or(f64::NAN) + as_f64(&value).unwrap_or(f64::NAN),
+            ),
+        }
+    }
+
+    /*
+     * Strings that hold numbers can be summed, but anything else is an error
+     */
+    fn to_number(aggregate: &Aggregate, value: &dyn SqlType) -> Result<f64, CustomError> {
+        if let Some(v) = as_f64(value) {
+            return Ok(v);
+        }
+        match as_str(value).and_then(|v| v.trim().parse::<f64>().ok()) {
+            Some(v) => Ok(v),
+            None => Err(CustomError::new(
+                400,
+                format!(
+                    "Bad request: Cannot compute {:?} of {:?}",
+                    aggregate.op, value
+                ),
+            )),
+        }
+    }
+}
+
+type Group = (Vec<Box<dyn SqlType>>, Vec<Accumulator>);
+
+/*
+ * Hash aggregation keeps one set of accumulators per distinct group key,
+ * emitting the groups in the order they were first seen
+ */
+pub struct GroupTable {
+    keys: Vec<usize>,
+    aggregates: Vec<Aggregate>,
+    index: HashMap<Vec<SqlKey>, usize>,
+    groups: Vec<Group>,
+}
+
+impl GroupTable {
+    pub fn new(keys: &[usize], aggregates: &[Aggregate]) -> GroupTable {
+        GroupTable {
+            keys: keys.to_vec(),
+            aggregates: aggregates.to_vec(),
+            index: HashMap::new(),
+            groups: vec![],
+        }
+    }
+
+    pub fn update(&mut self, record: &QueryRecord) -> Result<(), CustomError> {
+        let values: Vec<Box<dyn SqlType>> = self
+            .keys
+            .iter()
+            .map(|i| match record.columns.get(*i) {
+                Some(value) => value.clone(),
+                None => Box::new(Null::default()) as Box<dyn SqlType>,
+            })
+            .collect();
+        let key: Vec<SqlKey> = values.iter().map(|v| SqlKey::from(v.as_ref())).collect();
+
+        let group = match self.index.get(&key) {
+            Some(group) => *group,
+            None => {
+                let accumulators = self.aggregates.iter().map(Accumulator::new).collect();
+                self.groups.push((values, accumulators));
+                self.index.insert(key, self.groups.len() - 1);
+                self.groups.len() - 1
+            }
+        };
+        for accumulator in self.groups[group].1.iter_mut() {
+            accumulator.update(record)?;
+        }
+        Ok(())
+    }
+
+    /*
+     * Each group becomes a record of its key values followed by its aggregates
+     */
+    pub fn finish(self) -> Vec<QueryRecord> {
+        self.groups
+            .into_iter()
+            .map(|(mut columns, accumulators)| {
+                columns.extend(accumulators.into_iter().map(Accumulator::finish));
+                QueryRecord {
+                    columns,
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+}
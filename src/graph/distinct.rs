@@ -0,0 +1,217 @@
+use super::{approximate_size, record_key, SortConfig, SpillFile};
+use crate::{
+    error_handler::CustomError,
+    query::{QueryRecord, SqlKey},
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{BufRead, BufReader, BufWriter, Write},
+};
+
+// Deduplication emits each record the first time it is seen, remembering its key until the
+// memory budget is spent. Past that, records that are not already known are spilled to
+// partitions on disk by the hash of their key, so that equal records always share a partition.
+// Each partition is then deduplicated on its own once the input is exhausted, spilling again
+// with a different hash if it is still too large.
+
+const SPILL_PARTITIONS: usize = 16;
+
+pub struct Deduplicator {
+    config: SortConfig,
+    depth: u64,
+    seen: HashSet<Vec<SqlKey>>,
+    seen_size: usize,
+    partitions: Vec<(BufWriter<File>, SpillFile)>,
+}
+
+impl Deduplicator {
+    pub fn new(config: SortConfig) -> Deduplicator {
+        Deduplicator::with_depth(config, 0)
+    }
+
+    fn with_depth(config: SortConfig, depth: u64) -> Deduplicator {
+        Deduplicator {
+            config,
+            depth,
+            seen: HashSet::new(),
+            seen_size: 0,
+            partitions: vec![],
+        }
+    }
+
+    /*
+     * Returns the record if it is the first of its kind to be kept in memory. Duplicates and
+     * records spilled to disk, which come back out of finish(), return None.
+     */
+    pub fn push(&mut self, record: QueryRecord) -> Result<Option<QueryRecord>, CustomError> {
+        let key = record_key(&record);
+        if self.seen.contains(&key) {
+            return Ok(None);
+        }
+        if self.seen_size <= self.config.memory_budget {
+            self.seen_size += approximate_size(&record);
+            self.seen.insert(key);
+            return Ok(Some(record));
+        }
+
+        if self.partitions.is_empty() {
+            log::debug!(
+                "Spilling distinct records past {} keys ({} bytes)",
+                self.seen.len(),
+                self.seen_size
+            );
+            for _ in 0..SPILL_PARTITIONS {
+                let file = SpillFile::new(&self.config, "distinct");
+                self.partitions
+                    .push((BufWriter::new(File::create(file.path())?), file));
+            }
+        }
+        let partition = self.partition(&key);
+        let (writer, _) = &mut self.partitions[partition];
+        serde_json::to_writer(&mut *writer, &record)?;
+        writer.write_all(b"\n")?;
+        Ok(None)
+    }
+
+    /*
+     * Salting the hash with the depth splits a partition that is deduplicated again
+     */
+    fn partition(&self, key: &[SqlKey]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        self.depth.hash(&mut hasher);
+        key.hash(&mut hasher);
+        (hasher.finish() % SPILL_PARTITIONS as u64) as usize
+    }
+
+    pub fn spilled_partitions(&self) -> usize {
+        self.partitions.len()
+    }
+
+    /*
+     * Yield the distinct records that were spilled, one partition at a time
+     */
+    pub fn finish(mut self) -> Result<DistinctRecords, CustomError> {
+        let pending = self.close()?;
+        Ok(DistinctRecords {
+            config: self.config,
+            pending,
+            current: vec![].into_iter(),
+        })
+    }
+
+    fn close(&mut self) -> Result<Vec<(SpillFile, u64)>, CustomError> {
+        let mut pending = vec![];
+        for (mut writer, file) in self.partitions.drain(..) {
+            writer.flush()?;
+            pending.push((file, self.depth + 1));
+        }
+        Ok(pending)
+    }
+}
+
+pub struct DistinctRecords {
+    config: SortConfig,
+    pending: Vec<(SpillFile, u64)>,
+    current: std::vec::IntoIter<QueryRecord>,
+}
+
+impl DistinctRecords {
+    /*
+     * Deduplicate the next spilled partition, queueing up whatever it spills in turn
+     */
+    fn load(&mut self, file: SpillFile, depth: u64) -> Result<(), CustomError> {
+        let mut deduplicator = Deduplicator::with_depth(self.config.clone(), depth);
+        let mut records = vec![];
+        for line in BufReader::new(File::open(file.path())?).lines() {
+            let record: QueryRecord = serde_json::from_str(&line?)?;
+            if let Some(record) = deduplicator.push(record)? {
+                records.push(record);
+            }
+        }
+        drop(file);
+
+        self.pending.extend(deduplicator.close()?);
+        self.current = records.into_iter();
+        Ok(())
+    }
+}
+
+impl Iterator for DistinctRecords {
+    type Item = Result<QueryRecord, CustomError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.current.next() {
+                return Some(Ok(record));
+            }
+            let (file, depth) = self.pending.pop()?;
+            if let Err(err) = self.load(file, depth) {
+                self.pending.clear();
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::{as_i64, SqlType};
+    use std::path::PathBuf;
+
+    fn int_record(value: i64) -> QueryRecord {
+        QueryRecord {
+            columns: vec![Box::new(value) as Box<dyn SqlType>],
+            ..Default::default()
+        }
+    }
+
+    fn deduplicate(config: SortConfig, values: &[i64]) -> (Vec<i64>, usize) {
+        let mut deduplicator = Deduplicator::new(config);
+        let mut distinct: Vec<i64> = values
+            .iter()
+            .filter_map(|v| deduplicator.push(int_record(*v)).unwrap())
+            .map(|r| as_i64(r.columns[0].as_ref()).unwrap())
+            .collect();
+        let spilled = deduplicator.spilled_partitions();
+        for r in deduplicator.finish().unwrap() {
+            distinct.push(as_i64(r.unwrap().columns[0].as_ref()).unwrap());
+        }
+        (distinct, spilled)
+    }
+
+    #[test]
+    fn test_deduplicate_in_memory() {
+        let config = SortConfig {
+            memory_budget: 1 << 20,
+            spill_dir: std::env::temp_dir(),
+        };
+        let (distinct, spilled) = deduplicate(config, &[3, 1, 3, 2, 1, 3]);
+        assert_eq!(distinct, vec![3, 1, 2]);
+        assert_eq!(spilled, 0);
+    }
+
+    #[test]
+    fn test_deduplicate_spills_partitions() {
+        let spill_dir: PathBuf =
+            std::env::temp_dir().join(format!("hetnetdb-distinct-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&spill_dir).unwrap();
+        let config = SortConfig {
+            memory_budget: 1 << 10,
+            spill_dir: spill_dir.clone(),
+        };
+
+        // Far more distinct values than fit in the budget, each repeated a few times
+        let values: Vec<i64> = (0..3).flat_map(|_| (0..2000).rev()).collect();
+        let (mut distinct, spilled) = deduplicate(config, &values);
+        assert_eq!(spilled, SPILL_PARTITIONS);
+        distinct.sort_unstable();
+        assert_eq!(distinct, (0..2000).collect::<Vec<i64>>());
+
+        // Every spilled partition is removed once it has been read
+        assert_eq!(std::fs::read_dir(&spill_dir).unwrap().count(), 0);
+        std::fs::remove_dir(&spill_dir).unwrap();
+    }
+}
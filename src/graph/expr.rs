@@ -0,0 +1,936 @@
+use super::column_index;
+use crate::{
+    error_handler::CustomError,
+    query::{
+        as_decimal, as_exact_decimal, as_f64, as_i64, compare, is_decimal, is_null, to_text,
+        truthy, BinaryOperator, ColumnRef, Expr, Function, Literal, Null, QueryRecord, SqlType,
+        UnaryOperator,
+    },
+};
+use rust_decimal::{Decimal, RoundingStrategy};
+use std::cmp::Ordering;
+
+// Scalar expressions compile to closures over a record, so each row is evaluated without
+// walking the syntax tree again. NULL in gives NULL out, except for AND, OR, COALESCE and CASE.
+// Values of the wrong type evaluate to NULL rather than failing the query.
+
+type Evaluator = Box<dyn Fn(&QueryRecord) -> Box<dyn SqlType>>;
+
+pub struct ScalarEvaluator {
+    evaluator: Evaluator,
+}
+
+impl ScalarEvaluator {
+    pub fn new(expr: &Expr, columns: &[String]) -> Result<ScalarEvaluator, CustomError> {
+        Ok(ScalarEvaluator {
+            evaluator: ScalarEvaluator::compile(expr, columns)?,
+        })
+    }
+
+    pub fn evaluate(&self, record: &QueryRecord) -> Box<dyn SqlType> {
+        (self.evaluator)(record)
+    }
+
+    fn compile(expr: &Expr, columns: &[String]) -> Result<Evaluator, CustomError> {
+        match expr {
+            Expr::Column(column) => ScalarEvaluator::compile_column(column, columns),
+            Expr::Literal(literal) => {
+                let value = ScalarEvaluator::literal(literal)?;
+                Ok(Box::new(move |_record| value.clone()))
+            }
+            Expr::Nested(expr) => ScalarEvaluator::compile(expr, columns),
+            Expr::Unary { op, expr } => {
+                let expr = ScalarEvaluator::compile(expr, columns)?;
+                match op {
+                    UnaryOperator::Not => Ok(Box::new(move |record| {
+                        ScalarEvaluator::from_truth(truthy(expr(record).as_ref()).map(|v| !v))
+                    })),
+                    UnaryOperator::Minus => Ok(Box::new(move |record| {
+                        let value = expr(record);
+                        ScalarEvaluator::arithmetic(
+                            &BinaryOperator::Subtract,
+                            &0_i64,
+                            value.as_ref(),
+                        )
+                    })),
+                    UnaryOperator::Plus => Ok(expr),
+                }
+            }
+            Expr::Binary { left, op, right } => match op {
+                BinaryOperator::And | BinaryOperator::Or => {
+                    ScalarEvaluator::compile_logical(op, left, right, columns)
+                }
+                BinaryOperator::Add
+                | BinaryOperator::Subtract
+                | BinaryOperator::Multiply
+                | BinaryOperator::Divide
+                | BinaryOperator::Modulo => {
+                    ScalarEvaluator::compile_arithmetic(op, left, right, columns)
+                }
+                BinaryOperator::Concat => {
+                    let left = ScalarEvaluator::compile(left, columns)?;
+                    let right = ScalarEvaluator::compile(right, columns)?;
+                    Ok(Box::new(move |record| {
+                        match (
+                            to_text(left(record).as_ref()),
+                            to_text(right(record).as_ref()),
+                        ) {
+                            (Some(l), Some(r)) => Box::new(l + &r),
+                            _ => Box::new(Null::default()),
+                        }
+                    }))
+                }
+                _ => ScalarEvaluator::compile_comparison(op, left, right, columns),
+            },
+            Expr::InList {
+                expr,
+                list,
+                negated,
+            } => ScalarEvaluator::compile_in(expr, list, *negated, columns),
+            Expr::IsNull { expr, negated } => {
+                let expr = ScalarEvaluator::compile(expr, columns)?;
+                let negated = *negated;
+                Ok(Box::new(move |record| {
+                    ScalarEvaluator::from_truth(Some(is_null(expr(record).as_ref()) != negated))
+                }))
+            }
+            Expr::Between {
+                expr,
+                low,
+                high,
+                negated,
+            } => ScalarEvaluator::compile_between(expr, low, high, *negated, columns),
+            Expr::Like {
+                expr,
+                pattern,
+                negated,
+                case_insensitive,
+            } => ScalarEvaluator::compile_like(expr, pattern, *negated, *case_insensitive, columns),
+            Expr::Function(func) if func.is_aggregate() || func.over.is_some() => Err(
+                CustomError::new(400, format!("Bad request: {} is not allowed here", func)),
+            ),
+            Expr::Function(func) => ScalarEvaluator::compile_function(func, columns),
+            Expr::Case {
+                operand,
+                branches,
+                else_result,
+            } => ScalarEvaluator::compile_case(operand, branches, else_result, columns),
+            Expr::InSubquery { .. } | Expr::Exists { .. } => Err(CustomError::from(
+                "IN and EXISTS subqueries are only supported as conditions ANDed into a WHERE clause",
+            )),
+            Expr::Subquery(_) => Err(CustomError::from(
+                "Scalar subqueries are only supported in WHERE, HAVING and the SELECT list",
+            )),
+            _ => Err(CustomError::from("Unsupported Statement")),
+        }
+    }
+
+    fn compile_comparison(
+        op: &BinaryOperator,
+        left: &Expr,
+        right: &Expr,
+        columns: &[String],
+    ) -> Result<Evaluator, CustomError> {
+        let matches: fn(Ordering) -> bool = match op {
+            BinaryOperator::Equal => |o| o == Ordering::Equal,
+            BinaryOperator::NotEqual => |o| o != Ordering::Equal,
+            BinaryOperator::Greater => |o| o == Ordering::Greater,
+            BinaryOperator::GreaterOrEqual => |o| o != Ordering::Less,
+            BinaryOperator::Less => |o| o == Ordering::Less,
+            BinaryOperator::LessOrEqual => |o| o != Ordering::Greater,
+            _ => return Err(CustomError::from("Unsupported Statement")),
+        };
+        let left = ScalarEvaluator::compile(left, columns)?;
+        let right = ScalarEvaluator::compile(right, columns)?;
+        Ok(Box::new(move |record| {
+            let ordering = compare(left(record).as_ref(), right(record).as_ref());
+            ScalarEvaluator::from_truth(ordering.map(matches))
+        }))
+    }
+
+    fn compile_in(
+        expr: &Expr,
+        list: &[Expr],
+        negated: bool,
+        columns: &[String],
+    ) -> Result<Evaluator, CustomError> {
+        let left = ScalarEvaluator::compile(expr, columns)?;
+        let list = ScalarEvaluator::compile_all(list, columns)?;
+        Ok(Box::new(move |record| {
+            let value = left(record);
+            let mut found = Some(false);
+            for item in list.iter() {
+                match compare(value.as_ref(), item(record).as_ref()) {
+                    Some(Ordering::Equal) => {
+                        found = Some(true);
+                        break;
+                    }
+                    Some(_) => (),
+                    None => found = None,
+                }
+            }
+            ScalarEvaluator::from_truth(found.map(|found| found != negated))
+        }))
+    }
+
+    /*
+     * BETWEEN is inclusive at both ends, and is unknown unless one comparison rules it out
+     */
+    fn compile_between(
+        expr: &Expr,
+        low: &Expr,
+        high: &Expr,
+        negated: bool,
+        columns: &[String],
+    ) -> Result<Evaluator, CustomError> {
+        let expr = ScalarEvaluator::compile(expr, columns)?;
+        let low = ScalarEvaluator::compile(low, columns)?;
+        let high = ScalarEvaluator::compile(high, columns)?;
+        Ok(Box::new(move |record| {
+            let value = expr(record);
+            let above = compare(value.as_ref(), low(record).as_ref()).map(|o| o != Ordering::Less);
+            let below =
+                compare(value.as_ref(), high(record).as_ref()).map(|o| o != Ordering::Greater);
+            let truth = match (above, below) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            };
+            ScalarEvaluator::from_truth(truth.map(|truth| truth != negated))
+        }))
+    }
+
+    /*
+     * Literal patterns are parsed once, while patterns read from columns are parsed per row
+     */
+    fn compile_like(
+        expr: &Expr,
+        pattern: &Expr,
+        negated: bool,
+        case_insensitive: bool,
+        columns: &[String],
+    ) -> Result<Evaluator, CustomError> {
+        let expr = ScalarEvaluator::compile(expr, columns)?;
+        if let Expr::Literal(Literal::String(pattern)) = pattern {
+            let pattern = LikePattern::new(pattern, case_insensitive);
+            return Ok(Box::new(move |record| {
+                let truth = to_text(expr(record).as_ref()).map(|value| pattern.matches(&value));
+                ScalarEvaluator::from_truth(truth.map(|truth| truth != negated))
+            }));
+        }
+        let pattern = ScalarEvaluator::compile(pattern, columns)?;
+        Ok(Box::new(move |record| {
+            let truth = match (
+                to_text(expr(record).as_ref()),
+                to_text(pattern(record).as_ref()),
+            ) {
+                (Some(value), Some(pattern)) => {
+                    Some(LikePattern::new(&pattern, case_insensitive).matches(&value))
+                }
+                _ => None,
+            };
+            ScalarEvaluator::from_truth(truth.map(|truth| truth != negated))
+        }))
+    }
+
+    fn compile_logical(
+        op: &BinaryOperator,
+        left: &Expr,
+        right: &Expr,
+        columns: &[String],
+    ) -> Result<Evaluator, CustomError> {
+        let left = ScalarEvaluator::compile(left, columns)?;
+        let right = ScalarEvaluator::compile(right, columns)?;
+        match op {
+            BinaryOperator::And => Ok(Box::new(move |record| {
+                let truth = match truthy(left(record).as_ref()) {
+                    Some(false) => Some(false),
+                    l => match (l, truthy(right(record).as_ref())) {
+                        (_, Some(false)) => Some(false),
+                        (Some(true), Some(true)) => Some(true),
+                        _ => None,
+                    },
+                };
+                ScalarEvaluator::from_truth(truth)
+            })),
+            BinaryOperator::Or => Ok(Box::new(move |record| {
+                let truth = match truthy(left(record).as_ref()) {
+                    Some(true) => Some(true),
+                    l => match (l, truthy(right(record).as_ref())) {
+                        (_, Some(true)) => Some(true),
+                        (Some(false), Some(false)) => Some(false),
+                        _ => None,
+                    },
+                };
+                ScalarEvaluator::from_truth(truth)
+            })),
+            _ => Err(CustomError::from("Unsupported Statement")),
+        }
+    }
+
+    fn compile_arithmetic(
+        op: &BinaryOperator,
+        left: &Expr,
+        right: &Expr,
+        columns: &[String],
+    ) -> Result<Evaluator, CustomError> {
+        let left = ScalarEvaluator::compile(left, columns)?;
+        let right = ScalarEvaluator::compile(right, columns)?;
+        let op = op.clone();
+        Ok(Box::new(move |record| {
+            ScalarEvaluator::arithmetic(&op, left(record).as_ref(), right(record).as_ref())
+        }))
+    }
+
+    fn compile_column(column: &ColumnRef, columns: &[String]) -> Result<Evaluator, CustomError> {
+        let index = column_index(columns, column)?;
+        Ok(Box::new(move |record| match record.columns.get(index) {
+            Some(value) => value.clone(),
+            None => Box::new(Null::default()),
+        }))
+    }
+
+    fn compile_all(exprs: &[Expr], columns: &[String]) -> Result<Vec<Evaluator>, CustomError> {
+        exprs
+            .iter()
+            .map(|expr| ScalarEvaluator::compile(expr, columns))
+            .collect()
+    }
+
+    /*
+     * A CASE with an operand compares it to each WHEN, while one without tests each WHEN
+     */
+    fn compile_case(
+        operand: &Option<Box<Expr>>,
+        branches: &[(Expr, Expr)],
+        else_result: &Option<Box<Expr>>,
+        columns: &[String],
+    ) -> Result<Evaluator, CustomError> {
+        let operand = match operand {
+            Some(operand) => Some(ScalarEvaluator::compile(operand, columns)?),
+            None => None,
+        };
+        let branches = branches
+            .iter()
+            .map(|(when, then)| {
+                Ok((
+                    ScalarEvaluator::compile(when, columns)?,
+                    ScalarEvaluator::compile(then, columns)?,
+                ))
+            })
+            .collect::<Result<Vec<(Evaluator, Evaluator)>, CustomError>>()?;
+        let else_result = match else_result {
+            Some(else_result) => Some(ScalarEvaluator::compile(else_result, columns)?),
+            None => None,
+        };
+        Ok(Box::new(move |record| {
+            let operand = operand.as_ref().map(|operand| operand(record));
+            for (when, then) in branches.iter() {
+                let matched = match operand {
+                    Some(ref operand) => {
+                        compare(operand.as_ref(), when(record).as_ref()) == Some(Ordering::Equal)
+                    }
+                    None => truthy(when(record).as_ref()) == Some(true),
+                };
+                if matched {
+                    return then(record);
+                }
+            }
+            match else_result {
+                Some(ref else_result) => else_result(record),
+                None => Box::new(Null::default()),
+            }
+        }))
+    }
+
+    fn compile_function(func: &Function, columns: &[String]) -> Result<Evaluator, CustomError> {
+        let args = ScalarEvaluator::compile_all(&func.args, columns)?;
+        let unary: fn(&dyn SqlType) -> Box<dyn SqlType> = match func.name.as_str() {
+            "lower" => |v| text(v, |s| s.to_lowercase()),
+            "upper" => |v| text(v, |s| s.to_uppercase()),
+            "trim" => |v| text(v, |s| s.trim().to_string()),
+            "length" => |v| match to_text(v) {
+                Some(s) => Box::new(s.chars().count() as i64),
+                None => Box::new(Null::default()),
+            },
+            "abs" => |v| match (as_i64(v), as_decimal(v), as_f64(v)) {
+                (Some(i), _, _) => match i.checked_abs() {
+                    Some(i) => Box::new(i),
+                    None => Box::new((i as f64).abs()),
+                },
+                (None, Some(d), _) => Box::new(d.abs()),
+                (None, None, Some(f)) => Box::new(f.abs()),
+                _ => Box::new(Null::default()),
+            },
+            "floor" => |v| number(v, f64::floor, Decimal::floor),
+            "ceil" | "ceiling" => |v| number(v, f64::ceil, Decimal::ceil),
+            "sqrt" => |v| match as_f64(v) {
+                Some(f) if f >= 0.0 => Box::new(f.sqrt()),
+                _ => Box::new(Null::default()),
+            },
+            "round" | "substr" | "substring" | "concat" | "coalesce" | "nullif" => {
+                return ScalarEvaluator::compile_variadic(func, args)
+            }
+            _ => {
+                return Err(CustomError::new(
+                    400,
+                    format!("Bad request: Unknown function {}", func.name),
+                ))
+            }
+        };
+        arity(func, &args, 1, 1)?;
+        let arg = args.into_iter().next().unwrap();
+        Ok(Box::new(move |record| unary(arg(record).as_ref())))
+    }
+
+    fn compile_variadic(func: &Function, args: Vec<Evaluator>) -> Result<Evaluator, CustomError> {
+        match func.name.as_str() {
+            // Rounds half away from zero, to a number of decimal places
+            "round" => {
+                arity(func, &args, 1, 2)?;
+                Ok(Box::new(move |record| {
+                    let value = args[0](record);
+                    let places = match args.get(1) {
+                        Some(places) => match as_i64(places(record).as_ref()) {
+                            Some(places) => places,
+                            None => return Box::new(Null::default()),
+                        },
+                        None => 0,
+                    };
+                    if as_i64(value.as_ref()).is_some() && places >= 0 {
+                        return value;
+                    }
+                    match (as_decimal(value.as_ref()), as_f64(value.as_ref())) {
+                        (Some(d), _) if places >= 0 => {
+                            return Box::new(d.round_dp_with_strategy(
+                                places.min(28) as u32,
+                                RoundingStrategy::MidpointAwayFromZero,
+                            ))
+                        }
+                        _ => (),
+                    }
+                    match as_f64(value.as_ref()) {
+                        Some(f) => {
+                            let scale = 10_f64.powi(places.clamp(-308, 308) as i32);
+                            Box::new((f * scale).round() / scale)
+                        }
+                        None => Box::new(Null::default()),
+                    }
+                }))
+            }
+            // Characters are counted from 1, and a start before the string shortens the length
+            "substr" | "substring" => {
+                arity(func, &args, 2, 3)?;
+                Ok(Box::new(move |record| {
+                    let (value, start) = (args[0](record), args[1](record));
+                    let (value, start) = match (to_text(value.as_ref()), as_i64(start.as_ref())) {
+                        (Some(value), Some(start)) => (value, start),
+                        _ => return Box::new(Null::default()),
+                    };
+                    let end = match args.get(2) {
+                        Some(length) => match as_i64(length(record).as_ref()) {
+                            Some(length) if length >= 0 => start.saturating_add(length),
+                            _ => return Box::new(Null::default()),
+                        },
+                        None => i64::MAX,
+                    };
+                    let skip = start.max(1) - 1;
+                    let take = end.saturating_sub(start.max(1)).max(0);
+                    Box::new(
+                        value
+                            .chars()
+                            .skip(skip as usize)
+                            .take(take as usize)
+                            .collect::<String>(),
+                    )
+                }))
+            }
+            // NULL arguments are skipped rather than making the whole result NULL
+            "concat" => Ok(Box::new(move |record| {
+                Box::new(
+                    args.iter()
+                        .filter_map(|arg| to_text(arg(record).as_ref()))
+                        .collect::<String>(),
+                )
+            })),
+            "coalesce" => {
+                arity(func, &args, 1, usize::MAX)?;
+                Ok(Box::new(move |record| {
+                    for arg in args.iter() {
+                        let value = arg(record);
+                        if !is_null(value.as_ref()) {
+                            return value;
+                        }
+                    }
+                    Box::new(Null::default())
+                }))
+            }
+            "nullif" => {
+                arity(func, &args, 2, 2)?;
+                Ok(Box::new(move |record| {
+                    let value = args[0](record);
+                    match compare(value.as_ref(), args[1](record).as_ref()) {
+                        Some(Ordering::Equal) => Box::new(Null::default()),
+                        _ => value,
+                    }
+                }))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn literal(literal: &Literal) -> Result<Box<dyn SqlType>, CustomError> {
+        match literal {
+            Literal::Null => Ok(Box::new(Null::default())),
+            Literal::Boolean(b) => Ok(Box::new(*b)),
+            Literal::Integer(i) => Ok(Box::new(*i)),
+            Literal::Float(v) => Ok(Box::new(*v)),
+            Literal::String(s) => Ok(Box::new(s.clone())),
+        }
+    }
+
+    fn arithmetic(
+        op: &BinaryOperator,
+        left: &dyn SqlType,
+        right: &dyn SqlType,
+    ) -> Box<dyn SqlType> {
+        if let (Some(l), Some(r)) = (as_i64(left), as_i64(right)) {
+            let result = match op {
+                BinaryOperator::Add => l.checked_add(r),
+                BinaryOperator::Subtract => l.checked_sub(r),
+                BinaryOperator::Multiply => l.checked_mul(r),
+                // Integer division truncates toward zero, as in PostgreSQL
+                BinaryOperator::Divide | BinaryOperator::Modulo if r == 0 => {
+                    return Box::new(Null::default())
+                }
+                BinaryOperator::Divide => l.checked_div(r),
+                BinaryOperator::Modulo => l.checked_rem(r),
+                _ => None,
+            };
+            if let Some(result) = result {
+                return Box::new(result);
+            }
+        }
+        // Decimals stay exact with each other, with integers and with floats such as literals,
+        // which are taken as the decimal they are written as, until they overflow
+        if is_decimal(left) || is_decimal(right) {
+            if let (Some(l), Some(r)) = (as_exact_decimal(left), as_exact_decimal(right)) {
+                let result = match op {
+                    BinaryOperator::Add => l.checked_add(r),
+                    BinaryOperator::Subtract => l.checked_sub(r),
+                    BinaryOperator::Multiply => l.checked_mul(r),
+                    BinaryOperator::Divide | BinaryOperator::Modulo if r.is_zero() => {
+                        return Box::new(Null::default())
+                    }
+                    BinaryOperator::Divide => l.checked_div(r),
+                    BinaryOperator::Modulo => l.checked_rem(r),
+                    _ => None,
+                };
+                if let Some(result) = result {
+                    return Box::new(result);
+                }
+            }
+        }
+        match (as_f64(left), as_f64(right)) {
+            (Some(l), Some(r)) => match op {
+                BinaryOperator::Add => Box::new(l + r),
+                BinaryOperator::Subtract => Box::new(l - r),
+                BinaryOperator::Multiply => Box::new(l * r),
+                BinaryOperator::Divide if r != 0.0 => Box::new(l / r),
+                BinaryOperator::Modulo if r != 0.0 => Box::new(l % r),
+                _ => Box::new(Null::default()),
+            },
+            _ => Box::new(Null::default()),
+        }
+    }
+
+    fn from_truth(truth: Option<bool>) -> Box<dyn SqlType> {
+        match truth {
+            Some(truth) => Box::new(truth),
+            None => Box::new(Null::default()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum LikeToken {
+    AnyString,
+    AnyChar,
+    Char(char),
+}
+
+/*
+ * A LIKE pattern, where % matches any run of characters and _ any one character.
+ * A backslash matches the character after it literally, as in PostgreSQL.
+ */
+#[derive(Debug, Clone)]
+pub struct LikePattern {
+    tokens: Vec<LikeToken>,
+    case_insensitive: bool,
+}
+
+impl LikePattern {
+    pub fn new(pattern: &str, case_insensitive: bool) -> LikePattern {
+        let pattern = match case_insensitive {
+            true => pattern.to_lowercase(),
+            false => pattern.to_string(),
+        };
+        let mut tokens = vec![];
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            tokens.push(match c {
+                '%' => LikeToken::AnyString,
+                '_' => LikeToken::AnyChar,
+                '\\' => LikeToken::Char(chars.next().unwrap_or('\\')),
+                c => LikeToken::Char(c),
+            });
+        }
+        LikePattern {
+            tokens,
+            case_insensitive,
+        }
+    }
+
+    /*
+     * Match greedily, backtracking to the most recent % when the rest fails to match
+     */
+    pub fn matches(&self, value: &str) -> bool {
+        let value: Vec<char> = match self.case_insensitive {
+            true => value.to_lowercase().chars().collect(),
+            false => value.chars().collect(),
+        };
+        let (mut v, mut p) = (0, 0);
+        let mut backtrack: Option<(usize, usize)> = None;
+        while v < value.len() {
+            match self.tokens.get(p) {
+                Some(LikeToken::AnyString) => {
+                    backtrack = Some((p + 1, v));
+                    p += 1;
+                    continue;
+                }
+                Some(LikeToken::AnyChar) => {
+                    v += 1;
+                    p += 1;
+                    continue;
+                }
+                Some(LikeToken::Char(c)) if *c == value[v] => {
+                    v += 1;
+                    p += 1;
+                    continue;
+                }
+                _ => (),
+            }
+            match backtrack {
+                Some((after, start)) => {
+                    backtrack = Some((after, start + 1));
+                    p = after;
+                    v = start + 1;
+                }
+                None => return false,
+            }
+        }
+        self.tokens[p..].iter().all(|t| *t == LikeToken::AnyString)
+    }
+}
+
+fn arity(func: &Function, args: &[Evaluator], min: usize, max: usize) -> Result<(), CustomError> {
+    match args.len() >= min && args.len() <= max {
+        true => Ok(()),
+        false => Err(CustomError::new(
+            400,
+            format!("Bad request: Wrong number of arguments to {}", func),
+        )),
+    }
+}
+
+fn text(value: &dyn SqlType, f: fn(&str) -> String) -> Box<dyn SqlType> {
+    match to_text(value) {
+        Some(s) => Box::new(f(&s)),
+        None => Box::new(Null::default()),
+    }
+}
+
+/*
+ * Integers are already whole, so only decimals and floats are rounded
+ */
+fn number(value: &dyn SqlType, f: fn(f64) -> f64, d: fn(&Decimal) -> Decimal) -> Box<dyn SqlType> {
+    match (as_i64(value), as_decimal(value), as_f64(value)) {
+        (Some(i), _, _) => Box::new(i),
+        (None, Some(v), _) => Box::new(d(&v)),
+        (None, None, Some(v)) => Box::new(f(v)),
+        _ => Box::new(Null::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::{as_str, parse_sql, SelectItem, SetExpr, Statement};
+
+    /*
+     * Evaluate the only item of a SELECT list over a record of foo.c0, foo.c1 and foo.c2
+     */
+    fn evaluate(query: &str, values: Vec<Box<dyn SqlType>>) -> Box<dyn SqlType> {
+        let expr = match parse_sql(query).expect("Failed to parse test query") {
+            Statement::Select(query) => match query.body {
+                SetExpr::Select(select) => match select.fields.first() {
+                    Some(SelectItem::Expr { expr, .. }) => expr.clone(),
+                    _ => panic!("Expected an expression"),
+                },
+                _ => panic!("Expected a SELECT"),
+            },
+        };
+        let columns: Vec<String> = (0..3).map(|i| format!("foo.c{}", i)).collect();
+        let evaluator = ScalarEvaluator::new(&expr, &columns).expect("Failed to compile");
+        evaluator.evaluate(&QueryRecord {
+            columns: values,
+            ..Default::default()
+        })
+    }
+
+    fn text_of(query: &str, values: Vec<Box<dyn SqlType>>) -> Option<String> {
+        as_str(evaluate(query, values).as_ref()).map(String::from)
+    }
+
+    fn number_of(query: &str, values: Vec<Box<dyn SqlType>>) -> Option<f64> {
+        as_f64(evaluate(query, values).as_ref())
+    }
+
+    #[test]
+    fn test_string_functions() {
+        let hello = || -> Vec<Box<dyn SqlType>> { vec![Box::new(String::from("  Hello "))] };
+        assert_eq!(text_of("SELECT upper(c0)", hello()).unwrap(), "  HELLO ");
+        assert_eq!(text_of("SELECT lower(trim(c0))", hello()).unwrap(), "hello");
+        assert_eq!(number_of("SELECT length(c0)", hello()), Some(8.0));
+        assert_eq!(text_of("SELECT substr(c0, 3, 4)", hello()).unwrap(), "Hell");
+        assert_eq!(text_of("SELECT substr(c0, 0, 4)", hello()).unwrap(), "  H");
+        assert_eq!(text_of("SELECT substr(c0, 7)", hello()).unwrap(), "o ");
+        assert_eq!(
+            text_of("SELECT SUBSTRING(c0 FROM 3 FOR 2)", hello()).unwrap(),
+            "He"
+        );
+        assert_eq!(
+            text_of("SELECT concat(trim(c0), ', ', c1, 7)", hello()).unwrap(),
+            "Hello, 7"
+        );
+        assert_eq!(
+            text_of(
+                "SELECT 'id-' || c1",
+                vec![Box::new(0_i64), Box::new(42_i64)]
+            )
+            .unwrap(),
+            "id-42"
+        );
+        assert!(is_null(evaluate("SELECT upper(c1)", hello()).as_ref()));
+    }
+
+    #[test]
+    fn test_math_functions() {
+        assert_eq!(
+            number_of("SELECT abs(c0)", vec![Box::new(-3_i64)]),
+            Some(3.0)
+        );
+        assert_eq!(
+            number_of("SELECT abs(c0)", vec![Box::new(-2.5_f64)]),
+            Some(2.5)
+        );
+        assert_eq!(
+            number_of("SELECT round(c0)", vec![Box::new(2.5_f64)]),
+            Some(3.0)
+        );
+        assert_eq!(
+            number_of("SELECT round(c0, 2)", vec![Box::new(1.23456_f64)]),
+            Some(1.23)
+        );
+        assert_eq!(
+            number_of("SELECT round(c0, -1)", vec![Box::new(15_i64)]),
+            Some(20.0)
+        );
+        assert_eq!(
+            number_of("SELECT floor(c0)", vec![Box::new(-1.5_f64)]),
+            Some(-2.0)
+        );
+        assert_eq!(
+            number_of("SELECT CEIL(c0)", vec![Box::new(1.2_f64)]),
+            Some(2.0)
+        );
+        assert_eq!(
+            number_of("SELECT sqrt(c0)", vec![Box::new(16_i64)]),
+            Some(4.0)
+        );
+        assert_eq!(number_of("SELECT sqrt(c0)", vec![Box::new(-1_i64)]), None);
+        assert_eq!(
+            number_of("SELECT (c0 + 1) * 2 % 5", vec![Box::new(3_i64)]),
+            Some(3.0)
+        );
+        assert_eq!(number_of("SELECT c0 / 0", vec![Box::new(3_i64)]), None);
+
+        // Integers divide into integers, truncating toward zero
+        let quotient = evaluate("SELECT c0 / 2", vec![Box::new(7_i64)]);
+        assert_eq!(as_i64(quotient.as_ref()), Some(3));
+        assert_eq!(
+            number_of("SELECT -c0 / 2", vec![Box::new(7_i64)]),
+            Some(-3.0)
+        );
+        assert_eq!(
+            number_of("SELECT c0 / 2.0", vec![Box::new(7_i64)]),
+            Some(3.5)
+        );
+    }
+
+    #[test]
+    fn test_decimal_arithmetic() {
+        let price = || -> Vec<Box<dyn SqlType>> {
+            vec![
+                Box::new(crate::query::parse_decimal("0.10", None, None).unwrap()),
+                Box::new(3_i64),
+            ]
+        };
+        assert_eq!(text_of("SELECT c0 * c1 || ''", price()).unwrap(), "0.30");
+        assert_eq!(text_of("SELECT concat(c0 + 2)", price()).unwrap(), "2.10");
+        assert_eq!(text_of("SELECT concat(c0 + 0.2)", price()).unwrap(), "0.30");
+        assert_eq!(
+            text_of("SELECT concat(0.25 * c0)", price()).unwrap(),
+            "0.0250"
+        );
+        assert_eq!(
+            text_of("SELECT concat(c0 / c1)", price()),
+            Some(String::from("0.0333333333333333333333333333"))
+        );
+        assert_eq!(
+            text_of("SELECT concat(round(c0 / c1, 3))", price()).unwrap(),
+            "0.033"
+        );
+        assert_eq!(
+            text_of("SELECT concat(floor(c0 - c1))", price()).unwrap(),
+            "-3"
+        );
+        assert!(is_null(
+            evaluate("SELECT c1 / (c0 - 0.1)", price()).as_ref()
+        ));
+        assert_eq!(
+            truthy(evaluate("SELECT c0 * c1 = 0.3", price()).as_ref()),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn test_conditional_expressions() {
+        let row = || -> Vec<Box<dyn SqlType>> {
+            vec![
+                Box::new(Null::default()),
+                Box::new(5_i64),
+                Box::new(String::from("b")),
+            ]
+        };
+        assert_eq!(number_of("SELECT coalesce(c0, c1, 1)", row()), Some(5.0));
+        assert_eq!(number_of("SELECT nullif(c1, 5)", row()), None);
+        assert_eq!(number_of("SELECT nullif(c1, 4)", row()), Some(5.0));
+        assert_eq!(
+            text_of(
+                "SELECT CASE WHEN c1 > 10 THEN 'high' WHEN c1 > 3 THEN 'mid' ELSE 'low' END",
+                row()
+            )
+            .unwrap(),
+            "mid"
+        );
+        assert_eq!(
+            text_of(
+                "SELECT CASE c2 WHEN 'a' THEN 'first' WHEN 'b' THEN 'second' END",
+                row()
+            )
+            .unwrap(),
+            "second"
+        );
+        assert!(is_null(
+            evaluate("SELECT CASE WHEN c0 > 1 THEN 1 END", row()).as_ref()
+        ));
+    }
+
+    #[test]
+    fn test_like_patterns() {
+        let cases = [
+            ("abc", "abc", false, true),
+            ("abc", "a%", false, true),
+            ("abc", "%c", false, true),
+            ("abc", "%b%", false, true),
+            ("abc", "a_c", false, true),
+            ("abc", "a_", false, false),
+            ("abc", "%", false, true),
+            ("", "%", false, true),
+            ("", "_", false, false),
+            ("banana", "%an_", false, true),
+            ("banana", "b%n%a", false, true),
+            ("banana", "b%x%", false, false),
+            ("ABC", "a%", false, false),
+            ("ABC", "a%", true, true),
+            ("50%", "50\\%", false, true),
+            ("500", "50\\%", false, false),
+            ("a_b", "a\\_b", false, true),
+            ("axb", "a\\_b", false, false),
+        ];
+        for (value, pattern, case_insensitive, expected) in cases.iter() {
+            assert_eq!(
+                LikePattern::new(pattern, *case_insensitive).matches(value),
+                *expected,
+                "{} LIKE {}",
+                value,
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn test_predicates_with_nulls() {
+        let row = || -> Vec<Box<dyn SqlType>> {
+            vec![
+                Box::new(Null::default()),
+                Box::new(5_i64),
+                Box::new(String::from("Hello")),
+            ]
+        };
+        let truth = |query: &str| -> Option<bool> { truthy(evaluate(query, row()).as_ref()) };
+        assert_eq!(truth("SELECT c0 IS NULL"), Some(true));
+        assert_eq!(truth("SELECT c1 IS NOT NULL"), Some(true));
+        assert_eq!(truth("SELECT c1 BETWEEN 1 AND 5"), Some(true));
+        assert_eq!(truth("SELECT c1 NOT BETWEEN 1 AND 5"), Some(false));
+        assert_eq!(truth("SELECT c1 BETWEEN 6 AND c0"), Some(false));
+        assert_eq!(truth("SELECT c1 BETWEEN 1 AND c0"), None);
+        assert_eq!(truth("SELECT c0 BETWEEN 1 AND 5"), None);
+        assert_eq!(truth("SELECT c2 LIKE 'H%o'"), Some(true));
+        assert_eq!(truth("SELECT c2 NOT LIKE 'h%'"), Some(true));
+        assert_eq!(truth("SELECT c2 ILIKE 'h%'"), Some(true));
+        assert_eq!(truth("SELECT c2 LIKE concat(c1, '%')"), Some(false));
+        assert_eq!(truth("SELECT c0 LIKE '%'"), None);
+        assert_eq!(truth("SELECT c1 IN (1, c0, 5)"), Some(true));
+        assert_eq!(truth("SELECT c1 IN (1, c0)"), None);
+        assert_eq!(truth("SELECT c1 NOT IN (1, c0)"), None);
+        assert_eq!(truth("SELECT c1 NOT IN (1, 2)"), Some(true));
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let columns = vec![String::from("foo.c0")];
+        for query in [
+            "SELECT nosuch(c0) FROM foo",
+            "SELECT nullif(c0) FROM foo",
+            "SELECT upper(c0, c0) FROM foo",
+            "SELECT sum(c0) + 1 FROM foo",
+            "SELECT c9 + 1 FROM foo",
+        ]
+        .iter()
+        {
+            let expr = match parse_sql(query).unwrap() {
+                Statement::Select(query) => match query.body {
+                    SetExpr::Select(select) => match select.fields.first() {
+                        Some(SelectItem::Expr { expr, .. }) => expr.clone(),
+                        _ => unreachable!(),
+                    },
+                    _ => unreachable!(),
+                },
+            };
+            let err = ScalarEvaluator::new(&expr, &columns).err().unwrap();
+            assert_eq!(err.error_status_code, 400, "{}", query);
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use super::{unqualified, JoinOpType, COLUMN_ALIAS_SEPARATOR};
+use crate::query::{is_null, Expr, Null, QueryRecord, SqlKey, SqlType};
+use std::collections::HashMap;
+
+// Joins hash the right relation on its join keys and then stream the left relation past the
+// table, pairing each record with the right records that share its key. Without keys every
+// right record lands in one bucket, so theta joins fall back to a nested loop over the right
+// relation. Any condition beyond the keys is tested on the concatenated pair.
+
+#[derive(Debug, Clone)]
+pub struct Join {
+    pub op: JoinOpType,
+    pub left_keys: Vec<usize>,
+    pub right_keys: Vec<usize>,
+    pub condition: Option<Expr>,
+    pub columns: Vec<String>,
+    pub left_width: usize,
+    pub merge_keys: bool,
+}
+
+impl Join {
+    pub fn new(
+        op: JoinOpType,
+        left: &[String],
+        right: &[String],
+        left_keys: Vec<usize>,
+        right_keys: Vec<usize>,
+        condition: Option<Expr>,
+    ) -> Join {
+        Join {
+            op,
+            left_keys,
+            right_keys,
+            condition,
+            columns: left.iter().chain(right.iter()).cloned().collect(),
+            left_width: left.len(),
+            merge_keys: false,
+        }
+    }
+
+    /*
+     * A join USING named columns keeps one copy of each, as a natural join does
+     */
+    pub fn using(
+        op: JoinOpType,
+        left: &[String],
+        right: &[String],
+        left_keys: Vec<usize>,
+        right_keys: Vec<usize>,
+    ) -> Join {
+        Join {
+            merge_keys: true,
+            ..Join::new(op, left, right, left_keys, right_keys, None)
+        }
+    }
+
+    /*
+     * A natural join is keyed on every column name the two relations share
+     */
+    pub fn natural(left: &[String], right: &[String]) -> Join {
+        let (left_keys, right_keys) = natural_keys(left, right);
+        Join::using(JoinOpType::Natural, left, right, left_keys, right_keys)
+    }
+
+    /*
+     * Semi and anti joins only filter the left relation, and natural and USING joins keep one
+     * copy of each key column, which is known by the names of both sides
+     */
+    pub fn output_columns(&self) -> Vec<String> {
+        match self.op {
+            JoinOpType::Anti | JoinOpType::Semi => self.columns[..self.left_width].to_vec(),
+            _ if self.merge_keys => {
+                let mut columns = self.columns.clone();
+                for (l, r) in self.left_keys.iter().zip(self.right_keys.iter()) {
+                    let alias = &self.columns[self.left_width + r];
+                    columns[*l] = format!("{}{}{}", columns[*l], COLUMN_ALIAS_SEPARATOR, alias);
+                }
+                columns
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(i, _)| !self.is_right_key(*i))
+                    .map(|(_, c)| c)
+                    .collect()
+            }
+            _ => self.columns.clone(),
+        }
+    }
+
+    /*
+     * Concatenate a pair of records, padding a missing side with NULLs for outer joins. A
+     * merged key column takes the right value when the left side has none, e.g. in a right join
+     */
+    pub fn output(&self, left: Option<&QueryRecord>, right: Option<&QueryRecord>) -> QueryRecord {
+        let right_width = self.columns.len() - self.left_width;
+        let mut record = concat_records(
+            &left.cloned().unwrap_or_else(|| nulls(self.left_width)),
+            &right.cloned().unwrap_or_else(|| nulls(right_width)),
+        );
+        if self.merge_keys {
+            for (l, r) in self.left_keys.iter().zip(self.right_keys.iter()) {
+                let r = self.left_width + r;
+                if is_null(record.columns[*l].as_ref()) {
+                    record.columns.swap(*l, r);
+                }
+            }
+            let mut i = 0;
+            record.columns.retain(|_| {
+                i += 1;
+                !self.is_right_key(i - 1)
+            });
+        }
+        record
+    }
+
+    fn is_right_key(&self, i: usize) -> bool {
+        i >= self.left_width && self.right_keys.contains(&(i - self.left_width))
+    }
+}
+
+/*
+ * Pair up the columns of two relations that share a name, ignoring their table qualifiers
+ */
+pub fn natural_keys(left: &[String], right: &[String]) -> (Vec<usize>, Vec<usize>) {
+    left.iter()
+        .enumerate()
+        .filter_map(|(l, lc)| {
+            right
+                .iter()
+                .position(|rc| unqualified(rc) == unqualified(lc))
+                .map(|r| (l, r))
+        })
+        .unzip()
+}
+
+fn nulls(width: usize) -> QueryRecord {
+    QueryRecord {
+        columns: (0..width)
+            .map(|_| Box::new(Null::default()) as Box<dyn SqlType>)
+            .collect(),
+        ..Default::default()
+    }
+}
+
+/*
+ * The hashable key of a record, or None when a key is NULL since NULL never equals anything
+ */
+pub fn join_key(keys: &[usize], record: &QueryRecord) -> Option<Vec<SqlKey>> {
+    keys.iter()
+        .map(|i| match record.columns.get(*i) {
+            Some(value) if !is_null(value.as_ref()) => Some(SqlKey::from(value.as_ref())),
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn concat_records(left: &QueryRecord, right: &QueryRecord) -> QueryRecord {
+    QueryRecord {
+        ready: left.ready.clone(),
+        columns: left
+            .columns
+            .iter()
+            .chain(right.columns.iter())
+            .cloned()
+            .collect(),
+    }
+}
+
+/*
+ * The build side of a join. It remembers which records found a partner so that outer joins
+ * can pad the rest, and keeps records with NULL keys even though they never match.
+ */
+pub struct JoinTable {
+    keys: Vec<usize>,
+    records: Vec<QueryRecord>,
+    matched: Vec<bool>,
+    buckets: HashMap<Vec<SqlKey>, Vec<usize>>,
+    null_keys: bool,
+}
+
+impl JoinTable {
+    pub fn new(keys: &[usize]) -> JoinTable {
+        JoinTable {
+            keys: keys.to_vec(),
+            records: vec![],
+            matched: vec![],
+            buckets: HashMap::new(),
+            null_keys: false,
+        }
+    }
+
+    pub fn insert(&mut self, record: QueryRecord) {
+        match join_key(&self.keys, &record) {
+            Some(key) => self
+                .buckets
+                .entry(key)
+                .or_default()
+                .push(self.records.len()),
+            None => self.null_keys = true,
+        }
+        self.records.push(record);
+        self.matched.push(false);
+    }
+
+    /*
+     * The positions of the records in the table that share a key with a record of the other
+     * relation
+     */
+    pub fn matches(&self, keys: &[usize], record: &QueryRecord) -> Vec<usize> {
+        join_key(keys, record)
+            .and_then(|key| self.buckets.get(&key))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn get(&self, i: usize) -> &QueryRecord {
+        &self.records[i]
+    }
+
+    pub fn mark(&mut self, i: usize) {
+        self.matched[i] = true;
+    }
+
+    pub fn unmatched(&self) -> impl Iterator<Item = &QueryRecord> {
+        self.records
+            .iter()
+            .zip(self.matched.iter())
+            .filter(|(_, matched)| !**matched)
+            .map(|(r, _)| r)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn has_null_keys(&self) -> bool {
+        self.null_keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::as_i64;
+
+    fn record(columns: Vec<Box<dyn SqlType>>) -> QueryRecord {
+        QueryRecord {
+            columns,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_join_table_skips_null_keys() {
+        let mut table = JoinTable::new(&[0]);
+        table.insert(record(vec![Box::new(1i64), Box::new(10i64)]));
+        table.insert(record(vec![Box::new(Null::default()), Box::new(20i64)]));
+        table.insert(record(vec![Box::new(1i64), Box::new(30i64)]));
+
+        let probe = record(vec![Box::new(1i64)]);
+        let matches: Vec<Option<i64>> = table
+            .matches(&[0], &probe)
+            .into_iter()
+            .map(|i| as_i64(table.get(i).columns[1].as_ref()))
+            .collect();
+        assert_eq!(matches, vec![Some(10), Some(30)]);
+
+        // NULL joins with nothing, not even another NULL, but is still kept for outer joins
+        let probe = record(vec![Box::new(Null::default())]);
+        assert!(table.matches(&[0], &probe).is_empty());
+        assert!(table.has_null_keys());
+        table.mark(0);
+        assert_eq!(table.unmatched().count(), 2);
+
+        let joined = concat_records(&probe, table.get(0));
+        assert_eq!(joined.columns.len(), 3);
+    }
+
+    #[test]
+    fn test_natural_join_columns() {
+        let left: Vec<String> = vec![String::from("foo.a"), String::from("foo.b")];
+        let right: Vec<String> = vec![String::from("bar.b"), String::from("bar.c")];
+        let join = Join::natural(&left, &right);
+        assert_eq!((&join.left_keys, &join.right_keys), (&vec![1], &vec![0]));
+        assert_eq!(join.output_columns(), vec!["foo.a", "foo.b|bar.b", "bar.c"]);
+
+        let l = record(vec![Box::new(1i64), Box::new(2i64)]);
+        let r = record(vec![Box::new(2i64), Box::new(3i64)]);
+        let joined: Vec<Option<i64>> = join
+            .output(Some(&l), Some(&r))
+            .columns
+            .iter()
+            .map(|c| as_i64(c.as_ref()))
+            .collect();
+        assert_eq!(joined, vec![Some(1), Some(2), Some(3)]);
+
+        // An outer join pads the missing side to its full width
+        let join = Join::new(JoinOpType::LeftOuter, &left, &right, vec![1], vec![0], None);
+        let padded = join.output(Some(&l), None);
+        assert_eq!(padded.columns.len(), 4);
+        assert!(is_null(padded.columns[3].as_ref()));
+    }
+}
@@ -1,5 +1,19 @@
+mod agg;
+mod distinct;
+mod expr;
+mod join;
 mod node;
 mod routes;
+mod set;
+mod sort;
+mod window;
 
+pub use agg::*;
+pub use distinct::*;
+pub use expr::*;
+pub use join::*;
 pub use node::*;
 pub use routes::init_routes;
+pub use set::*;
+pub use sort::*;
+pub use window::*;
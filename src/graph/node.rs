@@ -1,18 +1,36 @@
 #![allow(unused_variables)]
 #![allow(dead_code)]
 
+use super::{
+    check_frame, concat_records, join_key, Accumulator, Aggregate, Deduplicator, ExternalSorter,
+    GroupTable, Join, JoinTable, RecordSet, ScalarEvaluator, SortConfig, SortKey, TopN, Window,
+    WindowFunction, WindowPartitions,
+};
+use crate::table_schemas::TableSchema;
 use crate::tables;
 use crate::{
     error_handler::CustomError,
-    query::{QueryRecord, SqlType},
+    query::{
+        truthy, BinaryOperator, ColumnRef, Cte, Expr, Function, JoinConstraint, JoinOperator,
+        Literal, Null, OrderBy, QueryRecord, Select, SelectItem, SelectQuery, SetExpr, SetOperator,
+        SqlType, Statement, TableFactor, UnaryOperator, WindowFrame, WindowFrameBound,
+        WindowFrameUnits, WindowSpec,
+    },
     AppData,
 };
+use actix_web::web;
 use async_trait::async_trait;
 use futures::sink::*;
 use futures::stream::*;
-use futures::{channel::mpsc::Receiver, channel::mpsc::Sender, lock::Mutex};
-use nom_sql::{ConditionExpression, FunctionExpression, SelectStatement, SqlQuery};
-use std::{fmt::Debug, sync::Arc};
+use futures::{
+    channel::mpsc::{Receiver, SendError, Sender},
+    lock::Mutex,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    sync::Arc,
+};
 
 // Define nodes in the execution graph with definitions based in relational alebra
 // https://en.wikipedia.org/wiki/Relational_algebra
@@ -21,46 +39,57 @@ use std::{fmt::Debug, sync::Arc};
 pub enum OpType {
     Nop,
     Rename,
-    Reorder,
-    Project,
-    Select,
-    Set,
-    Join,
-    Agg,
+    Reorder(Vec<usize>),
+    Project(Vec<usize>),
+    Select(Expr),
+    Set(SetOpType, bool),
+    Join(Join),
+    Agg(Vec<Aggregate>),
+    GroupBy(Vec<usize>, Vec<Aggregate>),
+    Sort(Vec<SortKey>),
+    Limit(u64, u64),
+    TopN(Vec<SortKey>, u64, u64),
+    Distinct,
+    Scalar,
+    Window(Window),
+    Compute(Vec<Expr>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum SetOpType {
     Union,
     Difference,
     Product,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum JoinOpType {
     Natural,
     Theta,
     Equi,
     Anti,
+    Semi,
     Division,
     LeftOuter,
     RightOuter,
     FullOuter,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum AggOpType {
     Sum,
     Count,
+    CountStar,
     Average,
     Maximum,
     Minimum,
+    GroupConcat(String),
 }
 
 #[derive(Debug, Clone)]
 pub enum IoType {
     Ram(String),
-    Disk,
+    Disk(String),
     Network,
     Generator,
 }
@@ -76,7 +105,7 @@ pub enum NodeInput {
     None,
     Single(Arc<HyperNode>),
     Double(Arc<HyperNode>, Arc<HyperNode>),
-    Leaf(Option<ConditionExpression>),
+    Leaf(Option<Expr>),
 }
 
 #[derive(Clone)]
@@ -125,6 +154,7 @@ pub struct HyperNode {
 pub struct WorkNode {
     ctx: Arc<ExecuteContext>,
     placement: Placement,
+    columns: Option<Vec<String>>,
     info: Arc<NodeInfo>,
 }
 
@@ -145,47 +175,318 @@ pub struct GraphBuilder {
     root: Arc<RootNode>,
 }
 
+/*
+ * A column known by more than one qualified name, like the merged key of a USING join, lists
+ * them all in its name separated by this
+ */
+pub const COLUMN_ALIAS_SEPARATOR: char = '|';
+
+/*
+ * The first of the qualified names a column is known by, which it is selected as
+ */
+pub fn column_name(column: &str) -> &str {
+    column
+        .split(COLUMN_ALIAS_SEPARATOR)
+        .next()
+        .unwrap_or_default()
+}
+
+/*
+ * A column's name without its table qualifier. Only the qualifier is split off, so that
+ * dotted column names such as "location.lat" stay whole
+ */
+pub fn unqualified(column: &str) -> &str {
+    let column = column_name(column);
+    match column.split_once('.') {
+        Some((_, name)) => name,
+        None => column,
+    }
+}
+
+/*
+ * Resolve a column reference to its position among "table.column" qualified names.
+ * Unqualified references match any table as long as the match is unambiguous.
+ */
+pub fn column_index(columns: &[String], column: &ColumnRef) -> Result<usize, CustomError> {
+    let name = column.name.to_lowercase();
+    let qualified = match column.table {
+        Some(ref table) => format!("{}.{}", table.to_lowercase(), name),
+        None => name.clone(),
+    };
+    let matches: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| {
+            **c == qualified
+                || c.split(COLUMN_ALIAS_SEPARATOR).any(|c| {
+                    c == qualified
+                        || (column.table.is_none() && c.contains('.') && unqualified(c) == name)
+                })
+        })
+        .map(|(i, _)| i)
+        .collect();
+
+    match matches.as_slice() {
+        [index] => Ok(*index),
+        [] => Err(CustomError::new(
+            400,
+            format!("Bad request: Unknown column {}", qualified),
+        )),
+        _ => Err(CustomError::new(
+            400,
+            format!("Bad request: Ambiguous column {}", qualified),
+        )),
+    }
+}
+
+/*
+ * Split a condition into the expressions that are ANDed together
+ */
+fn conjuncts(condition: &Expr) -> Vec<Expr> {
+    match condition {
+        Expr::Binary {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => {
+            let mut conditions = conjuncts(left);
+            conditions.extend(conjuncts(right));
+            conditions
+        }
+        Expr::Nested(condition) => conjuncts(condition),
+        condition => vec![condition.clone()],
+    }
+}
+
+fn conjoin(conditions: Vec<Expr>) -> Option<Expr> {
+    conditions.into_iter().fold(None, |left, right| match left {
+        Some(left) => Some(Expr::Binary {
+            left: Box::new(left),
+            op: BinaryOperator::And,
+            right: Box::new(right),
+        }),
+        None => Some(right),
+    })
+}
+
+fn scalar_name(i: usize) -> String {
+    format!("$subquery{}", i)
+}
+
+fn is_scalar(column: &ColumnRef) -> bool {
+    match column.table {
+        Some(ref table) => table.starts_with("$subquery"),
+        None => false,
+    }
+}
+
+/*
+ * Replace the subqueries used as values in a condition by references to the columns that will
+ * hold their results. Repeats of a subquery share a column, so each runs just once.
+ */
+fn bind_scalars(condition: &Expr, scalars: &mut Vec<SelectQuery>) -> Result<Expr, CustomError> {
+    condition.transform(&mut |expr| match expr {
+        Expr::Subquery(subquery) => {
+            let i = match scalars.iter().position(|s| s == subquery.as_ref()) {
+                Some(i) => i,
+                None => {
+                    scalars.push(subquery.as_ref().clone());
+                    scalars.len() - 1
+                }
+            };
+            Ok(Some(Expr::column(Some(&scalar_name(i)), "value")))
+        }
+        _ => Ok(None),
+    })
+}
+
+/*
+ * Take the IN, NOT IN and EXISTS subqueries out of the conditions, as the semi and anti joins
+ * that keep the records whose column is or is not among the subquery results. EXISTS has no
+ * column, so it keeps all or none of the records.
+ */
+fn take_subqueries(
+    conditions: &mut Vec<Expr>,
+) -> Vec<(Option<ColumnRef>, SelectQuery, JoinOpType)> {
+    let mut subqueries = vec![];
+    conditions.retain(|condition| match in_subquery(condition) {
+        Some(subquery) => {
+            subqueries.push(subquery);
+            false
+        }
+        None => true,
+    });
+    subqueries
+}
+
+/*
+ * Match "col [NOT] IN (SELECT ...)" and "[NOT] EXISTS (SELECT ...)" along with their negations.
+ * Within a WHERE clause "NOT col NOT IN (...)" keeps the same records as "col IN (...)", as
+ * both reject NULL, so negations simply swap the semi and anti joins.
+ */
+fn in_subquery(condition: &Expr) -> Option<(Option<ColumnRef>, SelectQuery, JoinOpType)> {
+    let op = |negated: bool| match negated {
+        true => JoinOpType::Anti,
+        false => JoinOpType::Semi,
+    };
+    match condition {
+        Expr::InSubquery {
+            expr,
+            subquery,
+            negated,
+        } => match expr.as_ref() {
+            Expr::Column(col) => Some((Some(col.clone()), *subquery.clone(), op(*negated))),
+            _ => None,
+        },
+        Expr::Exists { subquery, negated } => Some((None, *subquery.clone(), op(*negated))),
+        Expr::Unary {
+            op: UnaryOperator::Not,
+            expr,
+        } => in_subquery(expr)
+            .map(|(col, subquery, join_op)| (col, subquery, op(join_op == JoinOpType::Semi))),
+        Expr::Nested(condition) => in_subquery(condition),
+        _ => None,
+    }
+}
+
+/*
+ * Take the equalities between a column of the left relation and a column of the right one
+ * out of the conditions, returning the key positions on each side
+ */
+fn equi_keys(
+    conditions: &mut Vec<Expr>,
+    left: &[String],
+    right: &[String],
+) -> (Vec<usize>, Vec<usize>) {
+    let side = |expr: &Expr| match expr {
+        Expr::Column(col) => match (column_index(left, col), column_index(right, col)) {
+            (Ok(i), Err(_)) => Some((true, i)),
+            (Err(_), Ok(i)) => Some((false, i)),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    let mut keys = (vec![], vec![]);
+    conditions.retain(|condition| {
+        if let Expr::Binary {
+            left,
+            op: BinaryOperator::Equal,
+            right,
+        } = condition
+        {
+            match (side(left), side(right)) {
+                (Some((true, l)), Some((false, r))) | (Some((false, r)), Some((true, l))) => {
+                    keys.0.push(l);
+                    keys.1.push(r);
+                    return false;
+                }
+                _ => (),
+            }
+        }
+        true
+    });
+    keys
+}
+
 pub struct ConditionPredicate {
-    guard: Box<dyn Fn(QueryRecord) -> bool>,
+    guard: Box<dyn Fn(&QueryRecord) -> bool>,
 }
 
 impl ConditionPredicate {
     pub fn try_from(
-        condition: &Option<ConditionExpression>,
+        condition: &Option<Expr>,
+        columns: &[String],
     ) -> Result<Option<ConditionPredicate>, CustomError> {
         match condition {
-            Some(condition) => match condition {
-                ConditionExpression::ComparisonOp(_) => {
-                    return Err(CustomError::from("Unsupported Statement"))
-                }
-                ConditionExpression::LogicalOp(_) => {
-                    return Err(CustomError::from("Unsupported Statement"))
-                }
-                ConditionExpression::NegationOp(_) => {
-                    return Err(CustomError::from("Unsupported Statement"))
-                }
-                ConditionExpression::Base(_) => {
-                    return Err(CustomError::from("Unsupported Statement"))
-                }
-                ConditionExpression::Arithmetic(_) => {
-                    return Err(CustomError::from("Unsupported Statement"))
-                }
-                ConditionExpression::Bracketed(_) => {
-                    return Err(CustomError::from("Unsupported Statement"))
-                }
-            },
-            None => {
-                return Ok(None);
+            Some(condition) => Ok(Some(ConditionPredicate::new(condition, columns)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn new(condition: &Expr, columns: &[String]) -> Result<ConditionPredicate, CustomError> {
+        let evaluator = ScalarEvaluator::new(condition, columns)?;
+        Ok(ConditionPredicate {
+            guard: Box::new(move |record| {
+                truthy(evaluator.evaluate(record).as_ref()).unwrap_or(false)
+            }),
+        })
+    }
+
+    /*
+     * Rows pass the predicate only when the condition is true, so NULL filters like false
+     */
+    pub fn test(&self, record: &QueryRecord) -> bool {
+        (self.guard)(record)
+    }
+}
+
+/*
+ * Build a record from the given column positions of another, in the order they are listed
+ */
+fn pick_columns(record: QueryRecord, indices: &[usize]) -> QueryRecord {
+    if indices.len() == record.columns.len() && indices.iter().enumerate().all(|(i, j)| i == *j) {
+        return record;
+    }
+
+    let columns = indices
+        .iter()
+        .map(|i| match record.columns.get(*i) {
+            Some(value) => value.clone(),
+            None => Box::new(Null::default()) as Box<dyn SqlType>,
+        })
+        .collect();
+    QueryRecord {
+        ready: record.ready,
+        columns,
+    }
+}
+
+/*
+ * Send the records that pass the predicate, returning false once the receiver is gone
+ */
+async fn send_matching(
+    predicate: &Option<ConditionPredicate>,
+    records: &[QueryRecord],
+    sender: &mut Sender<Result<QueryRecord, CustomError>>,
+    action: &str,
+) -> bool {
+    for r in records.iter() {
+        if let Some(ref predicate) = predicate {
+            if !predicate.test(r) {
+                continue;
             }
         }
+        if let Err(err) = sender.send(Ok(r.clone())).await {
+            log_send_error(action, &err);
+            return false;
+        }
+    }
+    true
+}
+
+/*
+ * A closed receiver downstream has all of the records it wants, e.g. once a limit is met
+ */
+fn log_send_error(action: &str, err: &SendError) {
+    if err.is_disconnected() {
+        log::debug!("Stopped {} after downstream closed", action);
+    } else {
+        log::error!("Send error while {}: {:?}", action, err);
     }
 }
 
 impl WorkNode {
-    fn new(ctx: Arc<ExecuteContext>, placement: Placement, info: Arc<NodeInfo>) -> WorkNode {
+    fn new(
+        ctx: Arc<ExecuteContext>,
+        placement: Placement,
+        columns: Option<Vec<String>>,
+        info: Arc<NodeInfo>,
+    ) -> WorkNode {
         WorkNode {
             ctx,
             placement,
+            columns,
             info,
         }
     }
@@ -210,6 +511,197 @@ impl WorkNode {
         }
     }
 
+    async fn collect_double(
+        self,
+        sender: Sender<Result<QueryRecord, CustomError>>,
+        left: Receiver<Result<QueryRecord, CustomError>>,
+        right: Receiver<Result<QueryRecord, CustomError>>,
+    ) {
+        match &self.info.personality {
+            NodeType::Op(OpType::Join(_)) => self.collect_join(sender, left, right).await,
+            NodeType::Op(OpType::Set(..)) => self.collect_set(sender, left, right).await,
+            _ => panic!("Invalid personality for WorkNode::collect_double()"),
+        }
+    }
+
+    /*
+     * Combine the records of two inputs as sets. A union streams the left input and then the
+     * right one, a difference first gathers the right input to hold the left one up against,
+     * and a product pairs every left record with all of the gathered right records.
+     */
+    async fn collect_set(
+        self,
+        mut sender: Sender<Result<QueryRecord, CustomError>>,
+        left: Receiver<Result<QueryRecord, CustomError>>,
+        mut right: Receiver<Result<QueryRecord, CustomError>>,
+    ) {
+        let (op, distinct) = match &self.info.personality {
+            NodeType::Op(OpType::Set(op, distinct)) => (op, *distinct),
+            _ => panic!("Invalid personality for WorkNode::collect_set()"),
+        };
+        log::trace!("Collecting Set {:?} (distinct: {})", op, distinct);
+
+        let mut seen = RecordSet::new();
+        let mut excluded = RecordSet::new();
+        let mut products: Vec<QueryRecord> = vec![];
+        if *op != SetOpType::Union {
+            loop {
+                match right.next().await {
+                    Some(Ok(r)) if *op == SetOpType::Difference => {
+                        excluded.insert(&r);
+                    }
+                    Some(Ok(r)) => products.push(r),
+                    Some(Err(err)) => {
+                        let _ = sender.send(Err(err)).await;
+                        return;
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        let inputs = match op {
+            SetOpType::Union => vec![left, right],
+            _ => vec![left],
+        };
+        for mut input in inputs.into_iter() {
+            loop {
+                let r = match input.next().await {
+                    Some(Ok(r)) => r,
+                    Some(Err(err)) => {
+                        let _ = sender.send(Err(err)).await;
+                        return;
+                    }
+                    None => break,
+                };
+                if (*op == SetOpType::Difference && excluded.contains(&r))
+                    || (distinct && !seen.insert(&r))
+                {
+                    continue;
+                }
+
+                let output = match op {
+                    SetOpType::Product => products.iter().map(|p| concat_records(&r, p)).collect(),
+                    _ => vec![r],
+                };
+                for r in output.into_iter() {
+                    log::trace!("OpType::Set -> {:?}", r);
+                    if let Err(err) = sender.send(Ok(r)).await {
+                        log_send_error("combining records", &err);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /*
+     * Join the records of two inputs, building a hash table over the right input first
+     */
+    async fn collect_join(
+        self,
+        mut sender: Sender<Result<QueryRecord, CustomError>>,
+        mut left: Receiver<Result<QueryRecord, CustomError>>,
+        mut right: Receiver<Result<QueryRecord, CustomError>>,
+    ) {
+        let join = match &self.info.personality {
+            NodeType::Op(OpType::Join(join)) => join,
+            _ => panic!("Invalid personality for WorkNode::collect_join()"),
+        };
+        log::trace!("Collecting Join {:?}", join);
+        if join.op == JoinOpType::Division {
+            let _ = sender
+                .send(Err(CustomError::from("Unsupported join")))
+                .await;
+            return;
+        }
+        let predicate = match join.condition {
+            Some(ref condition) => match ConditionPredicate::new(condition, &join.columns) {
+                Ok(predicate) => Some(predicate),
+                Err(err) => {
+                    log::error!("Failed to prepare predicate for join: {:#?}", condition);
+                    let _ = sender.send(Err(err)).await;
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        let mut table = JoinTable::new(&join.right_keys);
+        loop {
+            match right.next().await {
+                Some(Ok(r)) => table.insert(r),
+                Some(Err(err)) => {
+                    let _ = sender.send(Err(err)).await;
+                    return;
+                }
+                None => break,
+            }
+        }
+
+        loop {
+            let l = match left.next().await {
+                Some(Ok(l)) => l,
+                Some(Err(err)) => {
+                    let _ = sender.send(Err(err)).await;
+                    return;
+                }
+                None => break,
+            };
+
+            let mut output: Vec<QueryRecord> = vec![];
+            let mut matched = false;
+            for i in table.matches(&join.left_keys, &l) {
+                let r = table.get(i);
+                if let Some(ref predicate) = predicate {
+                    if !predicate.test(&concat_records(&l, r)) {
+                        continue;
+                    }
+                }
+                matched = true;
+                match join.op {
+                    JoinOpType::Anti | JoinOpType::Semi => break,
+                    _ => output.push(join.output(Some(&l), Some(r))),
+                }
+                table.mark(i);
+            }
+            match join.op {
+                JoinOpType::Semi if matched => output.push(l),
+                JoinOpType::Anti if !matched => {
+                    // NOT IN is unknown rather than true once a NULL takes part in the comparison
+                    let unknown = !table.is_empty()
+                        && (table.has_null_keys() || join_key(&join.left_keys, &l).is_none());
+                    if !unknown {
+                        output.push(l);
+                    }
+                }
+                JoinOpType::LeftOuter | JoinOpType::FullOuter if !matched => {
+                    output.push(join.output(Some(&l), None))
+                }
+                _ => (),
+            }
+
+            for r in output.into_iter() {
+                log::trace!("OpType::Join -> {:?}", r);
+                if let Err(err) = sender.send(Ok(r)).await {
+                    log_send_error("joining records", &err);
+                    return;
+                }
+            }
+        }
+
+        if join.op == JoinOpType::RightOuter || join.op == JoinOpType::FullOuter {
+            for r in table.unmatched() {
+                let r = join.output(None, Some(r));
+                log::trace!("OpType::Join -> {:?}", r);
+                if let Err(err) = sender.send(Ok(r)).await {
+                    log_send_error("padding unmatched records", &err);
+                    return;
+                }
+            }
+        }
+    }
+
     async fn collect_op(
         &self,
         op: &OpType,
@@ -219,13 +711,45 @@ impl WorkNode {
         log::trace!("Collecting Op {:?}", op);
         match op {
             OpType::Nop => {}
-            OpType::Rename => {}
-            OpType::Reorder => loop {
+            // Names only live on the graph, so the records pass through as they are
+            OpType::Rename => {
+                while let Some(r) = receiver.next().await {
+                    log::trace!("OpType::Rename -> {:?}", r);
+                    if let Err(err) = sender.send(r).await {
+                        log_send_error("reading data for rename", &err);
+                        return;
+                    }
+                }
+            }
+            OpType::Scalar => {
+                let r = match receiver.next().await {
+                    Some(Ok(r)) => match receiver.next().await {
+                        Some(Ok(_)) => Err(CustomError::from(
+                            "Subquery used as an expression returned more than one row",
+                        )),
+                        Some(Err(err)) => Err(err),
+                        None => Ok(r),
+                    },
+                    Some(Err(err)) => Err(err),
+                    // No rows is a NULL, one column wide like every scalar subquery
+                    None => Ok(QueryRecord {
+                        columns: vec![Box::new(Null::default())],
+                        ..Default::default()
+                    }),
+                };
+                log::trace!("OpType::Scalar -> {:?}", r);
+                if let Err(err) = sender.send(r).await {
+                    log_send_error("reading data for scalar", &err);
+                }
+                receiver.close();
+            }
+            OpType::Reorder(indices) => loop {
                 match receiver.next().await {
                     Some(r) => {
                         log::trace!("OpType::Reorder -> {:?}", r);
+                        let r = r.map(|r| pick_columns(r, indices));
                         if let Err(err) = sender.send(r).await {
-                            log::error!("Send error while reading data for reorder: {:?}", err);
+                            log_send_error("reading data for reorder", &err);
                             let _ = sender.send(Err(CustomError::from("Send Error")));
                             return;
                         }
@@ -233,12 +757,13 @@ impl WorkNode {
                     None => break,
                 }
             },
-            OpType::Project => loop {
+            OpType::Project(indices) => loop {
                 match receiver.next().await {
                     Some(r) => {
                         log::trace!("OpType::Project -> {:?}", r);
+                        let r = r.map(|r| pick_columns(r, indices));
                         if let Err(err) = sender.send(r).await {
-                            log::error!("Send error while reading data for project: {:?}", err);
+                            log_send_error("reading data for project", &err);
                             let _ = sender.send(Err(CustomError::from("Send Error")));
                             return;
                         }
@@ -246,92 +771,443 @@ impl WorkNode {
                     None => break,
                 }
             },
-            OpType::Select => {}
-            OpType::Set => {}
-            OpType::Join => {}
-            OpType::Agg => {}
-        }
-    }
-
-    async fn collect_leaf(
-        &self,
-        leaf: &IoType,
-        mut sender: Sender<Result<QueryRecord, CustomError>>,
-    ) {
-        log::trace!("Collecting Leaf {:?}", leaf);
-        match leaf {
-            IoType::Ram(table_relation) => {
-                let result =
-                    tables::TableRelation::find_by_name(self.ctx.user_id, table_relation.clone());
-                if let Err(err) = result {
-                    log::error!("No table relation found for execution context and graph");
-                    let _ = sender.send(Err(err)).await;
-                    return;
+            OpType::Select(condition) => {
+                let columns = self.columns.clone().unwrap_or_default();
+                let predicate = match ConditionPredicate::new(condition, &columns) {
+                    Ok(predicate) => predicate,
+                    Err(err) => {
+                        log::error!("Failed to prepare predicate for select: {:#?}", condition);
+                        let _ = sender.send(Err(err)).await;
+                        return;
+                    }
+                };
+                loop {
+                    match receiver.next().await {
+                        Some(Ok(r)) => {
+                            if !predicate.test(&r) {
+                                continue;
+                            }
+                            log::trace!("OpType::Select -> {:?}", r);
+                            if let Err(err) = sender.send(Ok(r)).await {
+                                log_send_error("reading data for select", &err);
+                                return;
+                            }
+                        }
+                        Some(Err(err)) => {
+                            let _ = sender.send(Err(err)).await;
+                            return;
+                        }
+                        None => break,
+                    }
                 }
-
-                let condition = match self.info.input {
-                    NodeInput::Leaf(ref condition) => condition,
-                    _ => {
-                        panic!("Invalid input for WorkNode::collect_leaf()")
+            }
+            // Computed columns are appended, so the expressions read the input columns before them
+            OpType::Compute(exprs) => {
+                let columns = self.columns.clone().unwrap_or_default();
+                let inputs = &columns[..columns.len().saturating_sub(exprs.len())];
+                let evaluators = match exprs
+                    .iter()
+                    .map(|expr| ScalarEvaluator::new(expr, inputs))
+                    .collect::<Result<Vec<ScalarEvaluator>, CustomError>>()
+                {
+                    Ok(evaluators) => evaluators,
+                    Err(err) => {
+                        log::error!("Failed to prepare expressions for compute: {:#?}", exprs);
+                        let _ = sender.send(Err(err)).await;
+                        return;
                     }
                 };
-
-                let predicate = ConditionPredicate::try_from(condition);
-                if let Err(err) = result {
-                    log::error!(
-                        "Failed to prepare predicate for condition: {:#?}",
-                        condition
-                    );
-                    let _ = sender.send(Err(err)).await;
-                    return;
+                loop {
+                    match receiver.next().await {
+                        Some(Ok(mut r)) => {
+                            let values: Vec<Box<dyn SqlType>> =
+                                evaluators.iter().map(|e| e.evaluate(&r)).collect();
+                            r.columns.extend(values);
+                            log::trace!("OpType::Compute -> {:?}", r);
+                            if let Err(err) = sender.send(Ok(r)).await {
+                                log_send_error("reading data for compute", &err);
+                                return;
+                            }
+                        }
+                        Some(Err(err)) => {
+                            let _ = sender.send(Err(err)).await;
+                            return;
+                        }
+                        None => break,
+                    }
+                }
+            }
+            OpType::Set(..) => panic!("Invalid input for WorkNode::collect_op()"),
+            OpType::Join(_) => panic!("Invalid input for WorkNode::collect_op()"),
+            OpType::Agg(aggregates) => {
+                let mut accumulators: Vec<Accumulator> =
+                    aggregates.iter().map(Accumulator::new).collect();
+                loop {
+                    match receiver.next().await {
+                        Some(Ok(r)) => {
+                            log::trace!("OpType::Agg <- {:?}", r);
+                            for accumulator in accumulators.iter_mut() {
+                                if let Err(err) = accumulator.update(&r) {
+                                    let _ = sender.send(Err(err)).await;
+                                    return;
+                                }
+                            }
+                        }
+                        Some(Err(err)) => {
+                            let _ = sender.send(Err(err)).await;
+                            return;
+                        }
+                        None => break,
+                    }
                 }
 
-                let table_cache_map = self.ctx.app_data.table_cache.lock().await;
-                let table = result.unwrap();
-                log::trace!("Loading table_data from ram cache");
-                if let Some(table_data) = table_cache_map.get(&table.id) {
-                    log::trace!("Found table_data with {} partitions", table_data.len());
-                    for (i, table_partition) in table_data.iter().enumerate() {
-                        log::trace!(
-                            "IoType::Ram -> Processing {} records for partition {}",
-                            table_partition.len(),
-                            i
-                        );
-                        for r in table_partition {
-                            if let Err(err) = sender.send(Ok(r.clone())).await {
-                                log::error!("Send error while reading data from cache: {:?}", err);
-                                let _ = sender.send(Err(CustomError::from("Send Error")));
+                let r = QueryRecord {
+                    columns: accumulators.into_iter().map(Accumulator::finish).collect(),
+                    ..Default::default()
+                };
+                log::trace!("OpType::Agg -> {:?}", r);
+                if let Err(err) = sender.send(Ok(r)).await {
+                    log_send_error("emitting aggregates", &err);
+                }
+            }
+            OpType::GroupBy(keys, aggregates) => {
+                let mut groups = GroupTable::new(keys, aggregates);
+                loop {
+                    match receiver.next().await {
+                        Some(Ok(r)) => {
+                            log::trace!("OpType::GroupBy <- {:?}", r);
+                            if let Err(err) = groups.update(&r) {
+                                let _ = sender.send(Err(err)).await;
                                 return;
                             }
                         }
+                        Some(Err(err)) => {
+                            let _ = sender.send(Err(err)).await;
+                            return;
+                        }
+                        None => break,
+                    }
+                }
+
+                for r in groups.finish() {
+                    log::trace!("OpType::GroupBy -> {:?}", r);
+                    if let Err(err) = sender.send(Ok(r)).await {
+                        log_send_error("emitting groups", &err);
+                        return;
                     }
-                } else {
-                    log::warn!("No data found for table {:?}", table);
                 }
             }
-            IoType::Disk => {}
-            IoType::Network => {}
-            IoType::Generator => {}
-        }
-    }
-}
+            OpType::Sort(keys) => {
+                let mut sorter = ExternalSorter::new(keys, SortConfig::from_env());
+                loop {
+                    match receiver.next().await {
+                        Some(Ok(r)) => {
+                            log::trace!("OpType::Sort <- {:?}", r);
+                            if let Err(err) = sorter.push(r) {
+                                let _ = sender.send(Err(err)).await;
+                                return;
+                            }
+                        }
+                        Some(Err(err)) => {
+                            let _ = sender.send(Err(err)).await;
+                            return;
+                        }
+                        None => break,
+                    }
+                }
 
-impl HyperNode {
-    fn new(name: String, columns: Option<Vec<String>>, info: NodeInfo) -> HyperNode {
-        HyperNode {
-            name,
-            columns,
-            info: Arc::new(info),
-            execution_info: Arc::new(Mutex::new(None)),
-        }
-    }
-}
+                log::debug!("Merging {} spilled sort runs", sorter.spilled_runs());
+                let sorted = match sorter.finish() {
+                    Ok(sorted) => sorted,
+                    Err(err) => {
+                        let _ = sender.send(Err(err)).await;
+                        return;
+                    }
+                };
+                for r in sorted {
+                    let failed = r.is_err();
+                    if let Err(err) = sender.send(r).await {
+                        log_send_error("emitting sorted records", &err);
+                        return;
+                    }
+                    if failed {
+                        return;
+                    }
+                }
+            }
+            OpType::Distinct => {
+                let mut deduplicator = Deduplicator::new(SortConfig::from_env());
+                loop {
+                    match receiver.next().await {
+                        Some(Ok(r)) => match deduplicator.push(r) {
+                            Ok(Some(r)) => {
+                                log::trace!("OpType::Distinct -> {:?}", r);
+                                if let Err(err) = sender.send(Ok(r)).await {
+                                    log_send_error("emitting distinct records", &err);
+                                    return;
+                                }
+                            }
+                            Ok(None) => (),
+                            Err(err) => {
+                                let _ = sender.send(Err(err)).await;
+                                return;
+                            }
+                        },
+                        Some(Err(err)) => {
+                            let _ = sender.send(Err(err)).await;
+                            return;
+                        }
+                        None => break,
+                    }
+                }
 
-#[async_trait]
-impl Node for HyperNode {
-    fn input(&self) -> NodeInput {
-        self.info.input.clone()
-    }
+                log::debug!(
+                    "Deduplicating {} spilled distinct partitions",
+                    deduplicator.spilled_partitions()
+                );
+                let spilled = match deduplicator.finish() {
+                    Ok(spilled) => spilled,
+                    Err(err) => {
+                        let _ = sender.send(Err(err)).await;
+                        return;
+                    }
+                };
+                for r in spilled {
+                    let failed = r.is_err();
+                    if let Err(err) = sender.send(r).await {
+                        log_send_error("emitting spilled distinct records", &err);
+                        return;
+                    }
+                    if failed {
+                        return;
+                    }
+                }
+            }
+            // The input is sorted on the window keys by a Sort op, so partitions arrive one at a time
+            OpType::Window(window) => {
+                let mut partitions = WindowPartitions::new(window);
+                loop {
+                    match receiver.next().await {
+                        Some(Ok(r)) => {
+                            log::trace!("OpType::Window <- {:?}", r);
+                            let finished = match partitions.push(r) {
+                                Ok(finished) => finished,
+                                Err(err) => {
+                                    let _ = sender.send(Err(err)).await;
+                                    return;
+                                }
+                            };
+                            for r in finished {
+                                log::trace!("OpType::Window -> {:?}", r);
+                                if let Err(err) = sender.send(Ok(r)).await {
+                                    log_send_error("emitting windowed records", &err);
+                                    return;
+                                }
+                            }
+                        }
+                        Some(Err(err)) => {
+                            let _ = sender.send(Err(err)).await;
+                            return;
+                        }
+                        None => break,
+                    }
+                }
+
+                let finished = match partitions.finish() {
+                    Ok(finished) => finished,
+                    Err(err) => {
+                        let _ = sender.send(Err(err)).await;
+                        return;
+                    }
+                };
+                for r in finished {
+                    log::trace!("OpType::Window -> {:?}", r);
+                    if let Err(err) = sender.send(Ok(r)).await {
+                        log_send_error("emitting windowed records", &err);
+                        return;
+                    }
+                }
+            }
+            OpType::Limit(limit, offset) => {
+                let mut skipped = 0;
+                let mut emitted = 0;
+                while emitted < *limit {
+                    match receiver.next().await {
+                        Some(Ok(_)) if skipped < *offset => skipped += 1,
+                        Some(r) => {
+                            log::trace!("OpType::Limit -> {:?}", r);
+                            let failed = r.is_err();
+                            if let Err(err) = sender.send(r).await {
+                                log_send_error("reading data for limit", &err);
+                                break;
+                            }
+                            if failed {
+                                break;
+                            }
+                            emitted += 1;
+                        }
+                        None => break,
+                    }
+                }
+
+                // Stop the upstream nodes from producing records that will never be read
+                receiver.close();
+            }
+            OpType::TopN(keys, limit, offset) => {
+                let mut top = TopN::new(keys, *limit, *offset);
+                if *limit == 0 {
+                    receiver.close();
+                }
+                loop {
+                    match receiver.next().await {
+                        Some(Ok(r)) => {
+                            log::trace!("OpType::TopN <- {:?}", r);
+                            top.push(r);
+                        }
+                        Some(Err(err)) => {
+                            let _ = sender.send(Err(err)).await;
+                            return;
+                        }
+                        None => break,
+                    }
+                }
+
+                for r in top.finish() {
+                    log::trace!("OpType::TopN -> {:?}", r);
+                    if let Err(err) = sender.send(Ok(r)).await {
+                        log_send_error("emitting top records", &err);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn collect_leaf(
+        &self,
+        leaf: &IoType,
+        mut sender: Sender<Result<QueryRecord, CustomError>>,
+    ) {
+        log::trace!("Collecting Leaf {:?}", leaf);
+        let table_relation = match leaf {
+            IoType::Ram(table_relation) | IoType::Disk(table_relation) => table_relation,
+            IoType::Network | IoType::Generator => return,
+        };
+        let result = tables::TableRelation::find_by_name(self.ctx.user_id, table_relation.clone());
+        if let Err(err) = result {
+            log::error!("No table relation found for execution context and graph");
+            let _ = sender.send(Err(err)).await;
+            return;
+        }
+
+        let condition = match self.info.input {
+            NodeInput::Leaf(ref condition) => condition,
+            _ => {
+                panic!("Invalid input for WorkNode::collect_leaf()")
+            }
+        };
+
+        let columns = self.columns.clone().unwrap_or_default();
+        let predicate = match ConditionPredicate::try_from(condition, &columns) {
+            Ok(predicate) => predicate,
+            Err(err) => {
+                log::error!(
+                    "Failed to prepare predicate for condition: {:#?}",
+                    condition
+                );
+                let _ = sender.send(Err(err)).await;
+                return;
+            }
+        };
+
+        let table = result.unwrap();
+        match leaf {
+            IoType::Ram(_) => {
+                // Only hold the cache lock long enough to share the partitions, so scans of other
+                // tables, e.g. for a join, are free to run alongside this one. A table that is not
+                // cached yet, e.g. after a restart, is first loaded from its segments.
+                log::trace!("Loading table_data from ram cache");
+                let table_data = match tables::cached_partitions(&self.ctx.app_data, table.id).await
+                {
+                    Ok(table_data) => table_data,
+                    Err(err) => {
+                        let _ = sender.send(Err(err)).await;
+                        return;
+                    }
+                };
+                if table_data.is_empty() {
+                    log::warn!("No data found for table {:?}", table);
+                }
+                for (i, table_partition) in table_data.iter().enumerate() {
+                    log::trace!(
+                        "IoType::Ram -> Processing {} records for partition {}",
+                        table_partition.len(),
+                        i
+                    );
+                    if !send_matching(
+                        &predicate,
+                        table_partition,
+                        &mut sender,
+                        "reading data from cache",
+                    )
+                    .await
+                    {
+                        return;
+                    }
+                }
+            }
+            IoType::Disk(_) => {
+                // Segments are read one at a time, straight from disk and without being cached
+                let store = tables::SegmentStore::from_env();
+                let table_id = table.id;
+                let paths_store = store.clone();
+                let paths = web::block(move || paths_store.paths(table_id)).await;
+                let paths = match paths.map_err(tables::blocking_error) {
+                    Ok(paths) => paths,
+                    Err(err) => {
+                        let _ = sender.send(Err(err)).await;
+                        return;
+                    }
+                };
+                for path in paths.into_iter() {
+                    let segment_store = store.clone();
+                    let segment = web::block(move || segment_store.read(&path)).await;
+                    let segment = match segment.map_err(tables::blocking_error) {
+                        Ok(segment) => segment,
+                        Err(err) => {
+                            let _ = sender.send(Err(err)).await;
+                            return;
+                        }
+                    };
+                    log::trace!("IoType::Disk -> Processing {} records", segment.len());
+                    if !send_matching(&predicate, &segment, &mut sender, "reading data from disk")
+                        .await
+                    {
+                        return;
+                    }
+                }
+            }
+            IoType::Network | IoType::Generator => {}
+        }
+    }
+}
+
+impl HyperNode {
+    fn new(name: String, columns: Option<Vec<String>>, info: NodeInfo) -> HyperNode {
+        HyperNode {
+            name,
+            columns,
+            info: Arc::new(info),
+            execution_info: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+#[async_trait]
+impl Node for HyperNode {
+    fn input(&self) -> NodeInput {
+        self.info.input.clone()
+    }
 
     fn personality(&self) -> NodeType {
         self.info.personality.clone()
@@ -351,7 +1227,7 @@ impl Node for HyperNode {
                 // Create a work node and spawn the work to be done by this HyperNode
                 let placement = Placement::Server(Partition::Whole); // one shot everything
                 let info = self.info.clone(); // work node knows about inputs and partitioning now
-                let work_node = WorkNode::new(ctx, placement, info);
+                let work_node = WorkNode::new(ctx, placement, self.columns.clone(), info);
 
                 actix_rt::spawn(WorkNode::collect(work_node, sender, None));
             }
@@ -365,7 +1241,7 @@ impl Node for HyperNode {
                 // Create a work node and spawn the work to be done by this HyperNode
                 let placement = Placement::Server(Partition::Whole); // one shot everything
                 let info = self.info.clone(); // work node knows about inputs and partitioning now
-                let work_node = WorkNode::new(ctx.clone(), placement, info);
+                let work_node = WorkNode::new(ctx.clone(), placement, self.columns.clone(), info);
 
                 // Spawn a worker to produce data for the sender
                 actix_rt::spawn(WorkNode::collect(work_node, sender, Some(hyper_receiver)));
@@ -398,7 +1274,7 @@ impl Node for HyperNode {
                 let (hyper_sender, right_receiver) = futures::channel::mpsc::channel::<
                     Result<QueryRecord, CustomError>,
                 >(channel_buf_size);
-                let ctx_clone = ctx;
+                let ctx_clone = ctx.clone();
                 actix_rt::spawn(async move {
                     match right_child.curse(ctx_clone, hyper_sender).await {
                         Ok(()) => (),
@@ -406,7 +1282,16 @@ impl Node for HyperNode {
                     }
                 });
 
-                todo!();
+                // Create a work node and spawn the work to be done over both inputs
+                let placement = Placement::Server(Partition::Whole); // one shot everything
+                let info = self.info.clone();
+                let work_node = WorkNode::new(ctx, placement, self.columns.clone(), info);
+                actix_rt::spawn(WorkNode::collect_double(
+                    work_node,
+                    sender,
+                    left_receiver,
+                    right_receiver,
+                ));
             }
         }
 
@@ -515,340 +1400,3577 @@ impl GraphBuilder {
         }
     }
 
-    fn add_subselect(
-        &mut self,
-        project_columns: Option<Vec<String>>,
-        input_relation: String,
-        condition: Option<ConditionExpression>,
-    ) -> &mut Self {
-        // First: setup where the data comes from
-        let select_node = Arc::new(HyperNode::new(
-            format!("select_{}", input_relation),
-            None,
+    /*
+     * A leaf that reads a table from RAM or disk, keeping the records that pass the condition
+     */
+    fn scan(io: IoType, relation_columns: Vec<String>, condition: Option<Expr>) -> Arc<HyperNode> {
+        let name = match io {
+            IoType::Ram(ref name) | IoType::Disk(ref name) => format!("select_{}", name),
+            _ => String::from("select"),
+        };
+        Arc::new(HyperNode::new(
+            name,
+            Some(relation_columns),
             NodeInfo {
                 input: NodeInput::Leaf(condition),
-                personality: NodeType::Leaf(IoType::Ram(input_relation.to_lowercase())),
-            },
-        ));
-
-        // Second: Project out the data that we want to use
-        let project_node = Arc::new(HyperNode::new(
-            String::from("project"),
-            project_columns.clone(),
-            NodeInfo {
-                input: NodeInput::Single(select_node),
-                personality: NodeType::Op(OpType::Project),
-            },
-        ));
-
-        // Third: Reorder the data that we want output by the subselect
-        let reorder_node = Arc::new(HyperNode::new(
-            String::from("reorder"),
-            project_columns,
-            NodeInfo {
-                input: NodeInput::Single(project_node),
-                personality: NodeType::Op(OpType::Reorder),
+                personality: NodeType::Leaf(io),
             },
-        ));
+        ))
+    }
 
-        // TODO: Nested subselects and joins as recursive nodes in execution graph
-        // Hook up the root to the reorder
-        let mut root = unsafe { Arc::get_mut_unchecked(&mut self.root) };
-        root.graph = Some(reorder_node);
+    fn add_scan(
+        &mut self,
+        io: IoType,
+        relation_columns: Vec<String>,
+        condition: Option<Expr>,
+    ) -> &mut Self {
+        let root = unsafe { Arc::get_mut_unchecked(&mut self.root) };
+        root.graph = Some(GraphBuilder::scan(io, relation_columns, condition));
 
         self
     }
 
-    async fn build(&mut self) -> Arc<RootNode> {
-        self.root.clone()
+    /*
+     * Start the graph from an input built elsewhere, e.g. a CTE or a derived table
+     */
+    fn add_input(&mut self, input: Arc<HyperNode>) -> &mut Self {
+        let root = unsafe { Arc::get_mut_unchecked(&mut self.root) };
+        root.graph = Some(input);
+
+        self
     }
-}
 
-pub struct GraphInflator {}
+    /*
+     * Join the graph built so far with another input, concatenating their columns
+     */
+    fn add_join(&mut self, right: Arc<HyperNode>, join: Join) -> &mut Self {
+        let root = unsafe { Arc::get_mut_unchecked(&mut self.root) };
+        let left = root
+            .graph
+            .take()
+            .expect("GraphBuilder::add_join() requires a left input node");
+        let columns = join.output_columns();
+        root.graph = Some(Arc::new(HyperNode::new(
+            String::from("join"),
+            Some(columns),
+            NodeInfo {
+                input: NodeInput::Double(left, right),
+                personality: NodeType::Op(OpType::Join(join)),
+            },
+        )));
 
-impl GraphInflator {
-    pub fn new() -> GraphInflator {
-        GraphInflator {}
+        self
     }
 
-    pub async fn add_select_stmt(
-        &self,
-        builder: &mut GraphBuilder,
-        select_stmt: SelectStatement,
-    ) -> Result<(), CustomError> {
-        let columns = None;
-        for f in select_stmt.fields.into_iter() {
-            match f {
-                nom_sql::FieldDefinitionExpression::All => {}
-                nom_sql::FieldDefinitionExpression::AllInTable(_) => {
-                    return Err(CustomError::from("Unsupported Statement"))
-                }
-                nom_sql::FieldDefinitionExpression::Value(_) => {
-                    return Err(CustomError::from("Unsupported Statement"))
-                }
-                nom_sql::FieldDefinitionExpression::Col(col) => match col.function {
-                    Some(func) => match *func {
-                        FunctionExpression::CountStar => {
-                            return Err(CustomError::from("Unsupported Statement"))
-                        }
-                        FunctionExpression::Avg(_, _) => {
-                            return Err(CustomError::from("Unsupported Statement"))
-                        }
-                        FunctionExpression::Count(_, _) => {
-                            return Err(CustomError::from("Unsupported Statement"))
-                        }
-                        FunctionExpression::Sum(_, _) => {
-                            return Err(CustomError::from("Unsupported Statement"))
-                        }
-                        FunctionExpression::Max(_) => {
-                            return Err(CustomError::from("Unsupported Statement"))
-                        }
-                        FunctionExpression::Min(_) => {
-                            return Err(CustomError::from("Unsupported Statement"))
-                        }
-                        FunctionExpression::GroupConcat(_, _) => {
-                            return Err(CustomError::from("Unsupported Statement"))
-                        }
-                    },
-                    None => return Err(CustomError::from("Unsupported Statement")),
-                },
-            }
-        }
-
-        let table_name = match select_stmt.tables.len() {
-            1 => select_stmt
-                .tables
-                .first()
-                .unwrap()
-                .to_string()
-                .to_lowercase(),
-            _ => return Err(CustomError::from("Unsupported number of tables")),
+    /*
+     * Combine the graph built so far with another input through a set op. Products append the
+     * columns of the right input, while unions and differences keep the left column names.
+     */
+    fn add_set(&mut self, right: Arc<HyperNode>, op: SetOpType, distinct: bool) -> &mut Self {
+        let root = unsafe { Arc::get_mut_unchecked(&mut self.root) };
+        let left = root
+            .graph
+            .take()
+            .expect("GraphBuilder::add_set() requires a left input node");
+        let (name, columns) = match op {
+            SetOpType::Union => ("union", left.columns.clone()),
+            SetOpType::Difference => ("except", left.columns.clone()),
+            SetOpType::Product => (
+                "product",
+                Some(
+                    left.columns
+                        .iter()
+                        .chain(right.columns.iter())
+                        .flatten()
+                        .cloned()
+                        .collect(),
+                ),
+            ),
         };
+        root.graph = Some(Arc::new(HyperNode::new(
+            String::from(name),
+            columns,
+            NodeInfo {
+                input: NodeInput::Double(left, right),
+                personality: NodeType::Op(OpType::Set(op, distinct)),
+            },
+        )));
 
-        builder.add_subselect(columns, table_name, select_stmt.where_clause);
+        self
+    }
 
-        Ok(())
+    /*
+     * Natural join the graph built so far with another input on the column names they share.
+     */
+    fn add_natural_join(&mut self, right: Arc<HyperNode>) -> &mut Self {
+        let left_columns = self.columns();
+        let right_columns = right.columns.clone().unwrap_or_default();
+        self.add_join(right, Join::natural(&left_columns, &right_columns))
     }
 
-    pub async fn inflate(
-        &self,
-        query_id: i64,
-        query: SqlQuery,
-    ) -> Result<Arc<RootNode>, CustomError> {
-        let mut builder = GraphBuilder::new(query_id);
+    fn add_projection(&mut self, projection: Vec<(usize, String)>) -> &mut Self {
+        let relation_columns = self.columns();
 
-        match query {
-            SqlQuery::Select(stmt) => {
-                log::trace!("Found SelectStatement: {:?}", &stmt);
-                self.add_select_stmt(&mut builder, stmt).await?;
-            }
-            _ => {
-                log::debug!("Unsupported, valid SqlQuery: {:#?}", query);
-                return Err(CustomError::from("Unsupported Statement"));
-            }
-        }
+        // Project keeps the referenced columns in relation order, and Reorder arranges them as selected
+        let mut project_indices: Vec<usize> = projection.iter().map(|(i, _)| *i).collect();
+        project_indices.sort_unstable();
+        project_indices.dedup();
+        let project_columns: Vec<String> = project_indices
+            .iter()
+            .map(|i| relation_columns[*i].clone())
+            .collect();
+        let reorder_indices: Vec<usize> = projection
+            .iter()
+            .map(|(i, _)| project_indices.binary_search(i).unwrap())
+            .collect();
+        let reorder_columns: Vec<String> = projection.into_iter().map(|(_, name)| name).collect();
 
-        let root = builder.build().await;
-        Ok(root)
+        // First: Project out the data that we want to use
+        self.add_op("project", project_columns, OpType::Project(project_indices));
+
+        // Second: Reorder the data that we want output by the subselect
+        self.add_op("reorder", reorder_columns, OpType::Reorder(reorder_indices))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use dotenv::dotenv;
-    use lazy_static::lazy_static;
-    use nom_sql::parser::parse_query;
+    /*
+     * Stack an op on top of the graph built so far, feeding it the current output
+     */
+    fn add_op(&mut self, name: &str, columns: Vec<String>, op: OpType) -> &mut Self {
+        let root = unsafe { Arc::get_mut_unchecked(&mut self.root) };
+        let input = root
+            .graph
+            .take()
+            .expect("GraphBuilder::add_op() requires an input node");
+        root.graph = Some(Arc::new(HyperNode::new(
+            String::from(name),
+            Some(columns),
+            NodeInfo {
+                input: NodeInput::Single(input),
+                personality: NodeType::Op(op),
+            },
+        )));
 
-    lazy_static! {
-        static ref FIXTURE: () = {
-            dotenv().ok();
-            let _ = simple_logger::SimpleLogger::new().init();
-        };
+        self
     }
 
-    pub fn setup() {
-        lazy_static::initialize(&FIXTURE);
+    /*
+     * Sort the output of the graph built so far, dropping the trailing columns past `visible`
+     * that were only carried along to sort on
+     */
+    fn add_sort(&mut self, keys: Vec<SortKey>, visible: usize) -> &mut Self {
+        let columns = self.columns();
+        if columns.len() > visible {
+            self.add_op("sort", columns.clone(), OpType::Sort(keys));
+            self.add_op(
+                "project",
+                columns[..visible].to_vec(),
+                OpType::Project((0..visible).collect()),
+            )
+        } else {
+            self.add_op("sort", columns, OpType::Sort(keys))
+        }
     }
 
-    #[actix_rt::test]
-    async fn test_can_inflate_select_star() {
-        setup();
+    fn add_distinct(&mut self) -> &mut Self {
+        let columns = self.columns();
+        self.add_op("distinct", columns, OpType::Distinct)
+    }
 
-        let query = "SELECT * FROM FOO";
-        let sql_query = parse_query(query).expect("Failed to parse test query");
-        log::trace!("Inflating executing graph for {:?}", sql_query);
+    /*
+     * Limit the output of the graph built so far. A limit over a sort becomes a top-n op,
+     * which keeps a bounded heap of rows rather than sorting all of them, as long as the heap
+     * fits the sort memory budget. Larger limits keep the sort, which spills to disk.
+     */
+    fn add_limit(&mut self, limit: u64, offset: u64) -> &mut Self {
+        let root = unsafe { Arc::get_mut_unchecked(&mut self.root) };
+        let graph = root
+            .graph
+            .take()
+            .expect("GraphBuilder::add_limit() requires an input node");
+        let memory_budget = SortConfig::from_env().memory_budget;
+        root.graph = Some(GraphBuilder::fuse_limit(
+            graph,
+            limit,
+            offset,
+            memory_budget,
+        ));
 
-        let root = GraphInflator::new().inflate(1, sql_query).await.unwrap();
-        log::trace!("Inflated execution graph: {:?}", root.as_ref());
+        self
     }
 
-    #[actix_rt::test]
-    async fn test_can_run_select_star() {
-        setup();
+    fn fuse_limit(
+        node: Arc<HyperNode>,
+        limit: u64,
+        offset: u64,
+        memory_budget: usize,
+    ) -> Arc<HyperNode> {
+        let width = node.columns.as_ref().map_or(0, |columns| columns.len());
+        let fits = TopN::fits(limit, offset, width, memory_budget);
+        let (personality, input) = match (&node.info.personality, &node.info.input) {
+            (NodeType::Op(OpType::Sort(keys)), NodeInput::Single(input)) if fits => (
+                NodeType::Op(OpType::TopN(keys.clone(), limit, offset)),
+                input.clone(),
+            ),
+            // Look through the projection that drops the hidden sort columns
+            (NodeType::Op(OpType::Project(_)), NodeInput::Single(input))
+                if fits && matches!(input.info.personality, NodeType::Op(OpType::Sort(_))) =>
+            {
+                (
+                    node.info.personality.clone(),
+                    GraphBuilder::fuse_limit(input.clone(), limit, offset, memory_budget),
+                )
+            }
+            _ => (NodeType::Op(OpType::Limit(limit, offset)), node.clone()),
+        };
+        let name = match personality {
+            NodeType::Op(OpType::TopN(..)) => String::from("top"),
+            NodeType::Op(OpType::Limit(..)) => String::from("limit"),
+            _ => node.name.clone(),
+        };
 
-        let query = "SELECT * FROM FOO";
-        let sql_query = parse_query(query).expect("Failed to parse test query");
-        log::trace!("Inflating executing graph for {:?}", sql_query);
+        Arc::new(HyperNode::new(
+            name,
+            node.columns.clone(),
+            NodeInfo {
+                input: NodeInput::Single(input),
+                personality,
+            },
+        ))
+    }
 
-        let root = GraphInflator::new().inflate(1, sql_query).await.unwrap();
-        log::trace!("Inflated execution graph: {:?}", root.as_ref());
+    fn columns(&self) -> Vec<String> {
+        self.root
+            .graph
+            .as_ref()
+            .and_then(|graph| graph.columns.clone())
+            .unwrap_or_default()
+    }
 
-        // TODO: we have end to end coverage of these tests, but it would e good to have chunked unit test coverage instead
+    async fn build(&mut self) -> Arc<RootNode> {
+        self.root.clone()
     }
+}
 
-    #[actix_rt::test]
-    async fn test_condition_predicate_comparison() {
-        setup();
+/*
+ * A column of the SELECT list, bound to a relation column or still an aggregate function
+ */
+enum Selected {
+    Column(usize, String),
+    Aggregate(String, Function),
+    Window(String, Function),
+    Expr(String, Expr),
+}
 
-        for query in [
-            "SELECT * FROM FOO WHERE c0 = 1",
-            "SELECT * FROM FOO WHERE c0 > c1",
-        ]
-        .iter()
-        {
-            let sql_query = parse_query(query).expect("Failed to parse test query");
-            log::trace!("Testing condition predicate from {:#?}", sql_query);
+/*
+ * Whether an expression folds rows, not counting subqueries or window functions
+ */
+fn contains_aggregate(expr: &Expr) -> bool {
+    let mut found = false;
+    let _ = expr.transform(&mut |expr| match expr {
+        Expr::Function(func) if func.is_aggregate() => {
+            found = true;
+            Ok(Some(expr.clone()))
+        }
+        _ => Ok(None),
+    });
+    found
+}
 
-            if let SqlQuery::Select(select_stmt) = sql_query {
-                let predicate = ConditionPredicate::try_from(&select_stmt.where_clause);
-                assert!(predicate.is_err());
+#[derive(Clone)]
+pub struct GraphInflator {
+    user_id: Option<i64>,
+    tables: HashMap<String, Vec<String>>,
+    ctes: Vec<Cte>,
+    cached_tables: HashSet<i64>,
+    cache_limit: Option<i64>,
+}
 
-                // TODO: we want is_ok and check correctness
-            } else {
-                assert!(false);
-            }
+impl GraphInflator {
+    pub fn new() -> GraphInflator {
+        GraphInflator {
+            user_id: None,
+            tables: HashMap::new(),
+            ctes: vec![],
+            cached_tables: HashSet::new(),
+            cache_limit: None,
         }
     }
-    #[actix_rt::test]
-    async fn test_condition_predicate_logical() {
-        setup();
-
-        for query in [
-            "SELECT * FROM FOO WHERE c0 != 1 AND c1 <= c0",
-            "SELECT * FROM FOO WHERE c3 OR c1 < c2",
-        ]
-        .iter()
-        {
-            let sql_query = parse_query(query).expect("Failed to parse test query");
-            log::trace!("Testing condition predicate from {:#?}", sql_query);
 
-            if let SqlQuery::Select(select_stmt) = sql_query {
-                let predicate = ConditionPredicate::try_from(&select_stmt.where_clause);
-                assert!(predicate.is_err());
-
-                // TODO: we want is_ok and check correctness
-            } else {
-                assert!(false);
-            }
+    /*
+     * Bind table references to the named columns of the user's table schemas
+     */
+    pub fn for_user(user_id: i64) -> GraphInflator {
+        GraphInflator {
+            user_id: Some(user_id),
+            ..GraphInflator::new()
         }
     }
 
-    #[actix_rt::test]
-    async fn test_condition_predicate_negation() {
-        setup();
+    pub fn with_table(mut self, name: &str, column_names: Vec<String>) -> GraphInflator {
+        self.tables.insert(name.to_lowercase(), column_names);
+        self
+    }
 
-        for query in [
-            "SELECT * FROM FOO WHERE NOT c0 < 1",
-            "SELECT * FROM FOO WHERE NOT c0 != 1 AND c1 >= c0",
-            "SELECT * FROM FOO WHERE c3 OR NOT c1 < c2",
-            "SELECT * FROM FOO WHERE  NOT (c1 < c2 AND c0)",
+    /*
+     * Read the tables that are not cached and larger than the cache limit from disk
+     */
+    pub fn with_table_cache(
+        mut self,
+        cached_tables: HashSet<i64>,
+        cache_limit: Option<i64>,
+    ) -> GraphInflator {
+        self.cached_tables = cached_tables;
+        self.cache_limit = cache_limit;
+        self
+    }
+
+    /*
+     * Tables are scanned from the cache, which loads them from their segments when they are not
+     * cached yet, unless they are too large to cache. Those are read from their segments directly.
+     */
+    fn table_io(&self, name: &str, table: &tables::TableRelation) -> IoType {
+        match self.cache_limit {
+            Some(limit) if table.size > limit && !self.cached_tables.contains(&table.id) => {
+                IoType::Disk(name.to_string())
+            }
+            _ => IoType::Ram(name.to_string()),
+        }
+    }
+
+    /*
+     * The column names of a table and how it is read
+     */
+    fn table_columns(&self, name: &str) -> Result<(Vec<String>, IoType), CustomError> {
+        if let Some(column_names) = self.tables.get(name) {
+            return Ok((column_names.clone(), IoType::Ram(name.to_string())));
+        }
+
+        match self.user_id {
+            Some(user_id) => {
+                let table = tables::TableRelation::find_by_name(user_id, name.to_string())?;
+                let table_schema = TableSchema::find_by_id(table.table_schema_id)?;
+                Ok((table_schema.column_names, self.table_io(name, &table)))
+            }
+            None => Err(CustomError::new(
+                404,
+                format!("No table schema found for {}", name),
+            )),
+        }
+    }
+
+    /*
+     * Qualify the columns of a relation with its alias or name, e.g. "t.c0". An alias that
+     * lists column names renames the columns in order.
+     */
+    fn qualify(
+        qualifier: &str,
+        column_names: Vec<String>,
+        renames: &[String],
+    ) -> Result<Vec<String>, CustomError> {
+        let qualifier = qualifier.to_lowercase();
+        if renames.is_empty() {
+            return Ok(column_names
+                .iter()
+                .map(|c| format!("{}.{}", qualifier, c))
+                .collect());
+        }
+        if renames.len() != column_names.len() {
+            return Err(CustomError::new(
+                400,
+                format!(
+                    "Bad request: {} has {} columns but {} column names were given",
+                    qualifier,
+                    column_names.len(),
+                    renames.len()
+                ),
+            ));
+        }
+        Ok(renames
+            .iter()
+            .map(|c| format!("{}.{}", qualifier, c.to_lowercase()))
+            .collect())
+    }
+
+    /*
+     * The common table expression a table name refers to, which shadows any table of the
+     * same name. Later CTEs shadow earlier ones and those of enclosing queries.
+     */
+    fn cte(&self, name: &str) -> Option<usize> {
+        let name = name.to_lowercase();
+        self.ctes
+            .iter()
+            .rposition(|cte| cte.alias.name.to_lowercase() == name)
+    }
+
+    /*
+     * Build the input of a relation in the FROM clause. Tables are scanned, while CTEs and
+     * derived tables are subqueries whose output columns are renamed for the relation.
+     */
+    fn relation_input(&self, factor: &TableFactor) -> Result<Arc<HyperNode>, CustomError> {
+        match factor {
+            TableFactor::Table { name, alias } => {
+                let qualifier = alias.as_ref().map(|a| &a.name).unwrap_or(name);
+                let renames = alias.as_ref().map(|a| a.columns.as_slice()).unwrap_or(&[]);
+                match self.cte(name) {
+                    Some(i) => {
+                        // A CTE only sees the ones defined before it, so it cannot recurse
+                        let cte = &self.ctes[i];
+                        let inflator = GraphInflator {
+                            ctes: self.ctes[..i].to_vec(),
+                            ..self.clone()
+                        };
+                        let subquery = inflator.query_graph(&cte.query)?;
+                        let renames = match renames.is_empty() {
+                            true => cte.alias.columns.as_slice(),
+                            false => renames,
+                        };
+                        GraphInflator::rename(subquery, qualifier, renames)
+                    }
+                    None => {
+                        let (column_names, io) = self.table_columns(&name.to_lowercase())?;
+                        let columns = GraphInflator::qualify(qualifier, column_names, renames)?;
+                        Ok(GraphBuilder::scan(io, columns, None))
+                    }
+                }
+            }
+            TableFactor::Derived {
+                subquery,
+                alias: Some(alias),
+            } => {
+                let subquery = self.query_graph(subquery)?;
+                GraphInflator::rename(subquery, &alias.name, &alias.columns)
+            }
+            TableFactor::Derived { alias: None, .. } => {
+                Err(CustomError::from("Subquery in FROM must have an alias"))
+            }
+        }
+    }
+
+    fn rename(
+        subquery: Arc<HyperNode>,
+        qualifier: &str,
+        renames: &[String],
+    ) -> Result<Arc<HyperNode>, CustomError> {
+        let column_names = subquery
+            .columns
+            .iter()
+            .flatten()
+            .map(|c| String::from(unqualified(c)))
+            .collect();
+        let columns = GraphInflator::qualify(qualifier, column_names, renames)?;
+        Ok(Arc::new(HyperNode::new(
+            String::from("rename"),
+            Some(columns),
+            NodeInfo {
+                input: NodeInput::Single(subquery),
+                personality: NodeType::Op(OpType::Rename),
+            },
+        )))
+    }
+
+    /*
+     * Build the relation a SELECT reads from, returning its qualified columns. A lone table is
+     * filtered by the WHERE clause as it is scanned. Joined tables are hash joined on the
+     * equalities between them, and the rest of the WHERE clause filters the joined records.
+     * Last, IN, NOT IN and EXISTS subqueries semi and anti join the relation with their results.
+     */
+    fn add_relation(
+        &self,
+        builder: &mut GraphBuilder,
+        select: &Select,
+    ) -> Result<Vec<String>, CustomError> {
+        let first = match select.from.first() {
+            Some(first) => first,
+            None => return Err(CustomError::from("Unsupported Statement")),
+        };
+        let mut conditions = match select.where_clause {
+            Some(ref condition) => conjuncts(condition),
+            None => vec![],
+        };
+        let subqueries = take_subqueries(&mut conditions);
+        let mut scalars: Vec<SelectQuery> = vec![];
+        let conditions = conditions
+            .iter()
+            .map(|condition| bind_scalars(condition, &mut scalars))
+            .collect::<Result<Vec<Expr>, _>>()?;
+
+        let input = self.relation_input(&first.relation)?;
+        let mut columns = input.columns.clone().unwrap_or_default();
+        let scanned = match input.info.personality {
+            NodeType::Leaf(ref io @ IoType::Ram(_)) | NodeType::Leaf(ref io @ IoType::Disk(_))
+                if select.from.len() == 1 && first.joins.is_empty() && scalars.is_empty() =>
+            {
+                Some(io.clone())
+            }
+            _ => None,
+        };
+        match scanned {
+            Some(io) => {
+                let condition = match subqueries.is_empty() {
+                    true => select.where_clause.clone(),
+                    false => conjoin(conditions),
+                };
+                if let Some(ref condition) = condition {
+                    ConditionPredicate::new(condition, &columns)?;
+                }
+                builder.add_scan(io, columns.clone(), condition);
+            }
+            None => {
+                let mut conditions = conditions;
+                builder.add_input(input);
+                self.add_joins(builder, select, &mut conditions)?;
+                columns = builder.columns();
+
+                self.add_scalars(builder, &scalars)?;
+
+                if let Some(condition) = conjoin(conditions) {
+                    // Compile once up front so that bad references fail the request rather than the run
+                    let filtered = builder.columns();
+                    ConditionPredicate::new(&condition, &filtered)?;
+                    builder.add_op("select", filtered, OpType::Select(condition));
+                }
+                if !scalars.is_empty() {
+                    builder.add_op(
+                        "project",
+                        columns.clone(),
+                        OpType::Project((0..columns.len()).collect()),
+                    );
+                }
+            }
+        }
+
+        for (column, subquery, op) in subqueries.into_iter() {
+            let right = self.query_graph(&subquery)?;
+            let right_columns = right.columns.clone().unwrap_or_default();
+            let (left_keys, right_keys) = match column {
+                Some(column) => {
+                    if right_columns.len() != 1 {
+                        return Err(CustomError::from("Subquery must return exactly one column"));
+                    }
+                    (vec![column_index(&columns, &column)?], vec![0])
+                }
+                None => (vec![], vec![]),
+            };
+            builder.add_join(
+                right,
+                Join::new(op, &columns, &right_columns, left_keys, right_keys, None),
+            );
+        }
+        Ok(columns)
+    }
+
+    /*
+     * Each scalar subquery runs once, and its single value is appended to every record as the
+     * column that bind_scalars points its uses to
+     */
+    fn add_scalars(
+        &self,
+        builder: &mut GraphBuilder,
+        scalars: &[SelectQuery],
+    ) -> Result<Vec<String>, CustomError> {
+        let mut names = vec![];
+        for (i, scalar) in scalars.iter().enumerate() {
+            let subquery = self.query_graph(scalar)?;
+            if subquery.columns.as_ref().map(|c| c.len()) != Some(1) {
+                return Err(CustomError::from("Subquery must return exactly one column"));
+            }
+            let name = format!("{}.value", scalar_name(i));
+            let scalar = Arc::new(HyperNode::new(
+                String::from("scalar"),
+                Some(vec![name.clone()]),
+                NodeInfo {
+                    input: NodeInput::Single(subquery),
+                    personality: NodeType::Op(OpType::Scalar),
+                },
+            ));
+            builder.add_set(scalar, SetOpType::Product, false);
+            names.push(name);
+        }
+        Ok(names)
+    }
+
+    /*
+     * Join the relations after the first onto the graph. Comma separated relations are keyed on
+     * the equalities the WHERE clause draws to the relations before them, while JOIN clauses are
+     * keyed on the equalities in their ON clause and test the rest of it as each pair is made.
+     */
+    fn add_joins(
+        &self,
+        builder: &mut GraphBuilder,
+        select: &Select,
+        conditions: &mut Vec<Expr>,
+    ) -> Result<(), CustomError> {
+        for (i, table) in select.from.iter().enumerate() {
+            if i > 0 {
+                let columns = builder.columns();
+                let right = self.relation_input(&table.relation)?;
+                let right_columns = right.columns.clone().unwrap_or_default();
+                let (left_keys, right_keys) = equi_keys(conditions, &columns, &right_columns);
+                if left_keys.is_empty() {
+                    builder.add_set(right, SetOpType::Product, false);
+                } else {
+                    builder.add_join(
+                        right,
+                        Join::new(
+                            JoinOpType::Equi,
+                            &columns,
+                            &right_columns,
+                            left_keys,
+                            right_keys,
+                            None,
+                        ),
+                    );
+                }
+            }
+
+            for join in table.joins.iter() {
+                let columns = builder.columns();
+                let right = self.relation_input(&join.relation)?;
+                let right_columns = right.columns.clone().unwrap_or_default();
+                let (left_keys, right_keys, condition) = match join.constraint {
+                    JoinConstraint::On(ref condition) => {
+                        let mut on = conjuncts(condition);
+                        let (left_keys, right_keys) = equi_keys(&mut on, &columns, &right_columns);
+                        (left_keys, right_keys, conjoin(on))
+                    }
+                    JoinConstraint::Using(ref using) => {
+                        let mut keys = (vec![], vec![], None);
+                        for name in using.iter() {
+                            let col = ColumnRef {
+                                table: None,
+                                name: name.clone(),
+                            };
+                            keys.0.push(column_index(&columns, &col)?);
+                            keys.1.push(column_index(&right_columns, &col)?);
+                        }
+                        keys
+                    }
+                    JoinConstraint::Natural if join.operator == JoinOperator::Inner => {
+                        builder.add_natural_join(right);
+                        continue;
+                    }
+                    JoinConstraint::Natural => {
+                        return Err(CustomError::from("Unsupported Statement"))
+                    }
+                    JoinConstraint::None => (vec![], vec![], None),
+                };
+                let op = match join.operator {
+                    JoinOperator::Cross => {
+                        builder.add_set(right, SetOpType::Product, false);
+                        continue;
+                    }
+                    JoinOperator::Inner => match left_keys.is_empty() {
+                        true => JoinOpType::Theta,
+                        false => JoinOpType::Equi,
+                    },
+                    JoinOperator::LeftOuter => JoinOpType::LeftOuter,
+                    JoinOperator::RightOuter => JoinOpType::RightOuter,
+                    JoinOperator::FullOuter => JoinOpType::FullOuter,
+                };
+                let join = match join.constraint {
+                    JoinConstraint::Using(_) => {
+                        Join::using(op, &columns, &right_columns, left_keys, right_keys)
+                    }
+                    _ => Join::new(
+                        op,
+                        &columns,
+                        &right_columns,
+                        left_keys,
+                        right_keys,
+                        condition,
+                    ),
+                };
+                if let Some(ref condition) = join.condition {
+                    ConditionPredicate::new(condition, &join.columns)?;
+                }
+                builder.add_join(right, join);
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * Inflate a query on its own, as the input to the relation that uses its results
+     */
+    fn query_graph(&self, query: &SelectQuery) -> Result<Arc<HyperNode>, CustomError> {
+        let mut builder = GraphBuilder::new(0);
+        self.add_query(&mut builder, query)?;
+        match builder.root.graph.clone() {
+            Some(graph) => Ok(graph),
+            None => Err(CustomError::from("Unsupported Statement")),
+        }
+    }
+
+    pub async fn add_select_query(
+        &self,
+        builder: &mut GraphBuilder,
+        query: SelectQuery,
+    ) -> Result<(), CustomError> {
+        self.add_query(builder, &query)
+    }
+
+    /*
+     * Inflate a query with its common table expressions in scope. The ORDER BY of a SELECT may
+     * reach past its output into the relation, while the ORDER BY of a set operation can only
+     * sort on the combined output.
+     */
+    fn add_query(
+        &self,
+        builder: &mut GraphBuilder,
+        query: &SelectQuery,
+    ) -> Result<(), CustomError> {
+        let scoped;
+        let inflator = match query.ctes.is_empty() {
+            true => self,
+            false => {
+                scoped = GraphInflator {
+                    ctes: self.ctes.iter().chain(query.ctes.iter()).cloned().collect(),
+                    ..self.clone()
+                };
+                &scoped
+            }
+        };
+
+        match query.body {
+            SetExpr::Select(ref select) => inflator.add_select(builder, select, &query.order)?,
+            ref body => {
+                inflator.add_set_expr(builder, body)?;
+                if !query.order.is_empty() {
+                    let columns = builder.columns();
+                    let mut outputs: Vec<(usize, String)> =
+                        columns.iter().cloned().enumerate().collect();
+                    let keys =
+                        inflator.bind_order(&query.order, &mut outputs, |expr| match expr {
+                            Expr::Column(col) => {
+                                let i = column_index(&columns, col)?;
+                                Ok((i, columns[i].clone()))
+                            }
+                            expr => Err(CustomError::new(
+                                400,
+                                format!("Bad request: Unknown ORDER BY column {}", expr),
+                            )),
+                        })?;
+                    builder.add_sort(keys, columns.len());
+                }
+            }
+        }
+
+        match (query.limit, query.offset) {
+            (Some(limit), offset) => {
+                builder.add_limit(limit, offset);
+            }
+            (None, 0) => (),
+            (None, offset) => {
+                let columns = builder.columns();
+                builder.add_op("limit", columns, OpType::Limit(u64::MAX, offset));
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * Chain the operands of a set operation through set ops, from left to right
+     */
+    fn add_set_expr(
+        &self,
+        builder: &mut GraphBuilder,
+        set_expr: &SetExpr,
+    ) -> Result<(), CustomError> {
+        let (op, distinct, left, right) = match set_expr {
+            SetExpr::Select(select) => return self.add_select(builder, select, &[]),
+            SetExpr::Query(query) => return self.add_query(builder, query),
+            SetExpr::SetOperation {
+                op,
+                distinct,
+                left,
+                right,
+            } => (op, *distinct, left, right),
+        };
+        let (op, name) = match op {
+            SetOperator::Union => (SetOpType::Union, "UNION"),
+            // Difference drops every copy of a record found on the right, which is not EXCEPT ALL
+            SetOperator::Except if distinct => (SetOpType::Difference, "EXCEPT"),
+            _ => return Err(CustomError::from("Unsupported Statement")),
+        };
+
+        self.add_set_expr(builder, left)?;
+        let right = self.query_graph(&SelectQuery {
+            ctes: vec![],
+            body: right.as_ref().clone(),
+            order: vec![],
+            limit: None,
+            offset: 0,
+        })?;
+        if right.columns.as_ref().map(|c| c.len()) != Some(builder.columns().len()) {
+            return Err(CustomError::new(
+                400,
+                format!(
+                    "Bad request: Each SELECT of a {} must have the same number of columns",
+                    name
+                ),
+            ));
+        }
+        builder.add_set(right, op, distinct);
+        Ok(())
+    }
+
+    fn add_select(
+        &self,
+        builder: &mut GraphBuilder,
+        select: &Select,
+        order: &[OrderBy],
+    ) -> Result<(), CustomError> {
+        let mut relation_columns = self.add_relation(builder, select)?;
+
+        // Scalar subqueries in the SELECT list and HAVING are bound like those in WHERE, whose
+        // columns are gone by now. They join the relation, or the aggregated rows.
+        let mut scalars: Vec<SelectQuery> = vec![];
+        let mut selected: Vec<Selected> = vec![];
+        for f in select.fields.iter() {
+            match f {
+                SelectItem::Wildcard => {
+                    selected.extend(
+                        relation_columns
+                            .iter()
+                            .cloned()
+                            .enumerate()
+                            .map(|(i, c)| Selected::Column(i, column_name(&c).to_string())),
+                    );
+                }
+                SelectItem::QualifiedWildcard(qualifier) => {
+                    let prefix = format!("{}.", qualifier.to_lowercase());
+                    let len = selected.len();
+                    selected.extend(
+                        relation_columns
+                            .iter()
+                            .cloned()
+                            .enumerate()
+                            .filter(|(_, c)| {
+                                c.split(COLUMN_ALIAS_SEPARATOR)
+                                    .any(|c| c.starts_with(prefix.as_str()))
+                            })
+                            .map(|(i, c)| Selected::Column(i, column_name(&c).to_string())),
+                    );
+                    if selected.len() == len {
+                        return Err(CustomError::new(
+                            400,
+                            format!("Bad request: Unknown table {}", qualifier),
+                        ));
+                    }
+                }
+                SelectItem::Expr {
+                    expr: Expr::Column(col),
+                    alias,
+                } => {
+                    let index = column_index(&relation_columns, col)?;
+                    let name = match alias {
+                        Some(alias) => alias.to_lowercase(),
+                        None => column_name(&relation_columns[index]).to_string(),
+                    };
+                    selected.push(Selected::Column(index, name));
+                }
+                SelectItem::Expr {
+                    expr: Expr::Function(func),
+                    alias,
+                } if func.is_aggregate() => {
+                    let name = match alias {
+                        Some(alias) => alias.to_lowercase(),
+                        None => func.to_string().to_lowercase(),
+                    };
+                    selected.push(Selected::Aggregate(name, func.clone()));
+                }
+                SelectItem::Expr {
+                    expr: Expr::Function(func),
+                    alias,
+                } if func.over.is_some() => {
+                    let name = match alias {
+                        Some(alias) => alias.to_lowercase(),
+                        None => func.to_string().to_lowercase(),
+                    };
+                    selected.push(Selected::Window(name, func.clone()));
+                }
+                SelectItem::Expr { expr, alias } => {
+                    let name = match alias {
+                        Some(alias) => alias.to_lowercase(),
+                        None => expr.to_string().to_lowercase(),
+                    };
+                    selected.push(Selected::Expr(name, bind_scalars(expr, &mut scalars)?));
+                }
+            }
+        }
+        let having = match select.having {
+            Some(ref having) => Some(bind_scalars(having, &mut scalars)?),
+            None => None,
+        };
+
+        let aggregated = !select.group_by.is_empty()
+            || selected.iter().any(|s| match s {
+                Selected::Aggregate(..) => true,
+                Selected::Expr(_, expr) => contains_aggregate(expr),
+                _ => false,
+            });
+        if !aggregated {
+            relation_columns.extend(self.add_scalars(builder, &scalars)?);
+        }
+        let (selected, relation_columns) =
+            match selected.iter().any(|s| matches!(s, Selected::Window(..))) {
+                false => (selected, relation_columns),
+                true if !aggregated => self.add_windows(builder, selected, relation_columns)?,
+                true => {
+                    return Err(CustomError::from(
+                        "Window functions cannot be combined with GROUP BY or aggregates",
+                    ))
+                }
+            };
+        let (selected, relation_columns) = match selected
+            .iter()
+            .any(|s| matches!(s, Selected::Expr(..)))
+        {
+            true if !aggregated => self.add_row_expressions(builder, selected, relation_columns)?,
+            _ => (selected, relation_columns),
+        };
+
+        match (select.group_by.is_empty(), &having) {
+            (true, None) => self.add_ungrouped(
+                builder,
+                selected,
+                &scalars,
+                select.distinct,
+                order,
+                relation_columns,
+            ),
+            (true, Some(_)) => Err(CustomError::from("HAVING requires a GROUP BY clause")),
+            (false, having) => self.add_group_by(
+                builder,
+                selected,
+                &scalars,
+                &select.group_by,
+                having,
+                select.distinct,
+                order,
+                relation_columns,
+            ),
+        }
+    }
+
+    /*
+     * Window functions are evaluated over the relation before it is projected. Each distinct
+     * PARTITION BY and ORDER BY sorts the relation on its keys for a window op, which appends
+     * the values of its functions to the relation as columns named after the functions.
+     */
+    fn add_windows(
+        &self,
+        builder: &mut GraphBuilder,
+        selected: Vec<Selected>,
+        mut relation_columns: Vec<String>,
+    ) -> Result<(Vec<Selected>, Vec<String>), CustomError> {
+        let mut specs: Vec<(&WindowSpec, Vec<&Function>)> = vec![];
+        let mut placements: Vec<(usize, usize)> = vec![];
+        for s in selected.iter() {
+            let (func, spec) = match s {
+                Selected::Window(_, func) => match func.over {
+                    Some(ref spec) => (func, spec.as_ref()),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            let shared = specs.iter().position(|(other, _)| {
+                other.partition_by == spec.partition_by && other.order == spec.order
+            });
+            match shared {
+                Some(i) => {
+                    specs[i].1.push(func);
+                    placements.push((i, specs[i].1.len() - 1));
+                }
+                None => {
+                    specs.push((spec, vec![func]));
+                    placements.push((specs.len() - 1, 0));
+                }
+            }
+        }
+
+        let base = relation_columns.clone();
+        let mut offsets = vec![];
+        for (spec, functions) in specs.iter() {
+            offsets.push(relation_columns.len());
+            let partition = spec
+                .partition_by
+                .iter()
+                .map(|expr| match expr {
+                    Expr::Column(col) => column_index(&base, col),
+                    _ => Err(CustomError::from("Unsupported Statement")),
+                })
+                .collect::<Result<Vec<usize>, _>>()?;
+            let order = spec
+                .order
+                .iter()
+                .map(|order_by| match order_by.expr {
+                    Expr::Column(ref col) => Ok(SortKey {
+                        column: column_index(&base, col)?,
+                        ascending: order_by.ascending,
+                    }),
+                    _ => Err(CustomError::from("Unsupported Statement")),
+                })
+                .collect::<Result<Vec<SortKey>, _>>()?;
+            let window = Window {
+                partition,
+                order,
+                functions: functions
+                    .iter()
+                    .map(|func| self.window_function(func, &base))
+                    .collect::<Result<_, _>>()?,
+            };
+
+            let keys = window.sort_keys();
+            if !keys.is_empty() {
+                builder.add_sort(keys, relation_columns.len());
+            }
+            relation_columns.extend(functions.iter().map(|func| func.to_string().to_lowercase()));
+            builder.add_op("window", relation_columns.clone(), OpType::Window(window));
+        }
+
+        let mut placements = placements.into_iter();
+        let selected = selected
+            .into_iter()
+            .map(|s| match s {
+                Selected::Window(name, _) => match placements.next() {
+                    Some((spec, i)) => Selected::Column(offsets[spec] + i, name),
+                    None => unreachable!(),
+                },
+                s => s,
+            })
+            .collect();
+        Ok((selected, relation_columns))
+    }
+
+    /*
+     * Without aggregates, expressions are computed for each row of the relation and appended
+     * to it before the projection, which then picks them like any other column
+     */
+    fn add_row_expressions(
+        &self,
+        builder: &mut GraphBuilder,
+        selected: Vec<Selected>,
+        mut relation_columns: Vec<String>,
+    ) -> Result<(Vec<Selected>, Vec<String>), CustomError> {
+        let base = relation_columns.len();
+        let mut exprs = vec![];
+        let mut mapped = vec![];
+        for s in selected.into_iter() {
+            match s {
+                Selected::Expr(name, expr) => {
+                    // Compile once up front so that bad references fail the request rather than the run
+                    ScalarEvaluator::new(&expr, &relation_columns[..base])?;
+                    mapped.push(Selected::Column(base + exprs.len(), name.clone()));
+                    relation_columns.push(name);
+                    exprs.push(expr);
+                }
+                s => mapped.push(s),
+            }
+        }
+        builder.add_op("compute", relation_columns.clone(), OpType::Compute(exprs));
+        Ok((mapped, relation_columns))
+    }
+
+    /*
+     * Ranking functions and LAG/LEAD ignore the frame, while aggregates without one frame the
+     * partition up to the peers of the current row, or all of it without an ORDER BY
+     */
+    fn window_function(
+        &self,
+        func: &Function,
+        relation_columns: &[String],
+    ) -> Result<WindowFunction, CustomError> {
+        let column = |arg: &Expr| match arg {
+            Expr::Column(col) => column_index(relation_columns, col),
+            _ => Err(CustomError::from("Unsupported Statement")),
+        };
+        match (func.name.as_str(), func.args.as_slice()) {
+            _ if func.distinct => Err(CustomError::new(
+                400,
+                format!("Bad request: Unsupported window function {}", func),
+            )),
+            ("row_number", []) => Ok(WindowFunction::RowNumber),
+            ("rank", []) => Ok(WindowFunction::Rank),
+            ("dense_rank", []) => Ok(WindowFunction::DenseRank),
+            ("lag", [arg, rest @ ..]) | ("lead", [arg, rest @ ..]) if rest.len() <= 2 => {
+                let offset = match rest.first() {
+                    None => 1,
+                    Some(Expr::Literal(Literal::Integer(offset))) if *offset >= 0 => {
+                        *offset as usize
+                    }
+                    Some(_) => {
+                        return Err(CustomError::new(
+                            400,
+                            format!(
+                                "Bad request: The offset of {} must be a non-negative integer",
+                                func
+                            ),
+                        ))
+                    }
+                };
+                let default = match rest.get(1) {
+                    None => Box::new(Null::default()),
+                    Some(Expr::Literal(literal)) => ScalarEvaluator::literal(literal)?,
+                    Some(_) => {
+                        return Err(CustomError::new(
+                            400,
+                            format!("Bad request: The default of {} must be a literal", func),
+                        ))
+                    }
+                };
+                match func.name.as_str() {
+                    "lag" => Ok(WindowFunction::Lag(column(arg)?, offset, default)),
+                    _ => Ok(WindowFunction::Lead(column(arg)?, offset, default)),
+                }
+            }
+            _ => {
+                let (op, argument) = match self.aggregate_op(func) {
+                    Ok(aggregate) => aggregate,
+                    Err(_) => {
+                        return Err(CustomError::new(
+                            400,
+                            format!("Bad request: Unsupported window function {}", func),
+                        ))
+                    }
+                };
+                let frame = match func.over.as_deref() {
+                    Some(WindowSpec {
+                        frame: Some(frame), ..
+                    }) => frame.clone(),
+                    Some(WindowSpec { order, .. }) if !order.is_empty() => WindowFrame {
+                        units: WindowFrameUnits::Range,
+                        start: WindowFrameBound::Preceding(None),
+                        end: WindowFrameBound::CurrentRow,
+                    },
+                    _ => WindowFrame {
+                        units: WindowFrameUnits::Range,
+                        start: WindowFrameBound::Preceding(None),
+                        end: WindowFrameBound::Following(None),
+                    },
+                };
+                check_frame(&frame)?;
+                let aggregate = Aggregate {
+                    op,
+                    column: argument.map(column).transpose()?,
+                    distinct: false,
+                };
+                Ok(WindowFunction::Aggregate(aggregate, frame))
+            }
+        }
+    }
+
+    /*
+     * Without GROUP BY, aggregates fold the whole relation into a single row
+     */
+    fn add_ungrouped(
+        &self,
+        builder: &mut GraphBuilder,
+        selected: Vec<Selected>,
+        scalars: &[SelectQuery],
+        distinct: bool,
+        order: &[OrderBy],
+        relation_columns: Vec<String>,
+    ) -> Result<(), CustomError> {
+        let mut projection: Vec<(usize, String)> = vec![];
+        let mut aggregates: Vec<Aggregate> = vec![];
+        let mut aggregate_columns: Vec<String> = vec![];
+        let mut outputs: Vec<(usize, String)> = vec![];
+        let mut computed: Vec<(usize, Expr)> = vec![];
+        if selected.iter().all(|s| matches!(s, Selected::Column(..))) {
+            for s in selected.into_iter() {
+                if let Selected::Column(index, name) = s {
+                    projection.push((index, name));
+                }
+            }
+        } else {
+            for s in selected.into_iter() {
+                match s {
+                    Selected::Aggregate(name, func) => {
+                        let aggregate =
+                            self.add_aggregate(&func, &relation_columns, &mut projection)?;
+                        aggregates.push(aggregate);
+                        aggregate_columns.push(func.to_string().to_lowercase());
+                        outputs.push((aggregates.len() - 1, name));
+                    }
+                    Selected::Expr(name, expr) => {
+                        let expr = self.bind_aggregates(
+                            &expr,
+                            0,
+                            &relation_columns,
+                            &mut projection,
+                            &mut aggregates,
+                            &mut aggregate_columns,
+                        )?;
+                        // Placed by add_computed once the aggregate op output is complete
+                        computed.push((outputs.len(), expr));
+                        outputs.push((usize::MAX, name));
+                    }
+                    Selected::Column(_, name) => {
+                        return Err(CustomError::new(
+                            400,
+                            format!(
+                                "Bad request: Column {} must appear in the GROUP BY clause or be used in an aggregate",
+                                name
+                            ),
+                        ))
+                    }
+                    Selected::Window(..) => unreachable!("Window functions are bound by add_windows"),
+                }
+            }
+        }
+
+        let visible = match aggregates.is_empty() {
+            true => projection.len(),
+            false => outputs.len(),
+        };
+        let order = match order {
+            [] => None,
+            order if aggregates.is_empty() => {
+                Some(self.bind_order(order, &mut projection, |expr| match expr {
+                    Expr::Column(col) => {
+                        let index = column_index(&relation_columns, col)?;
+                        Ok((index, relation_columns[index].clone()))
+                    }
+                    Expr::Function(func) if func.is_aggregate() => Err(CustomError::new(
+                        400,
+                        format!("Bad request: Cannot order by {} without GROUP BY", func),
+                    )),
+                    // Window functions are only evaluated for the SELECT list
+                    Expr::Function(func) if func.over.is_some() => {
+                        let name = func.to_string().to_lowercase();
+                        match relation_columns.iter().position(|c| *c == name) {
+                            Some(index) => Ok((index, name)),
+                            None => Err(CustomError::new(
+                                400,
+                                format!(
+                                    "Bad request: Window function {} must appear in the SELECT list to order by it",
+                                    func
+                                ),
+                            )),
+                        }
+                    }
+                    _ => Err(CustomError::from("Unsupported Statement")),
+                })?)
+            }
+            order => Some(self.bind_order(order, &mut outputs, |expr| {
+                Err(CustomError::new(
+                    400,
+                    format!("Bad request: Unknown ORDER BY column {}", expr),
+                ))
+            })?),
+        };
+
+        builder.add_projection(projection);
+        if !aggregates.is_empty() {
+            match computed.is_empty() {
+                // Each output is its own aggregate, so the aggregate op can carry the names
+                true => {
+                    let names = outputs.into_iter().map(|(_, name)| name).collect();
+                    builder.add_op("aggregate", names, OpType::Agg(aggregates));
+                }
+                false => {
+                    builder.add_op("aggregate", aggregate_columns, OpType::Agg(aggregates));
+                    self.add_scalars(builder, scalars)?;
+                    self.add_computed(builder, computed, &mut outputs)?;
+                    let (indices, names) = outputs.into_iter().unzip();
+                    builder.add_op("reorder", names, OpType::Reorder(indices));
+                }
+            }
+        }
+        self.add_distinct_and_sort(builder, distinct, order, visible)
+    }
+
+    /*
+     * Rewrite an expression over grouped rows to read the output of the group op. Aggregates
+     * are added to the op as needed, and columns must be among the first `keys` of the
+     * projection, which are the GROUP BY columns. Scalar subqueries are read after the op.
+     */
+    #[allow(clippy::too_many_arguments)]
+    fn bind_aggregates(
+        &self,
+        expr: &Expr,
+        keys: usize,
+        relation_columns: &[String],
+        projection: &mut Vec<(usize, String)>,
+        aggregates: &mut Vec<Aggregate>,
+        group_columns: &mut Vec<String>,
+    ) -> Result<Expr, CustomError> {
+        expr.transform(&mut |expr| match expr {
+            Expr::Function(func) if func.is_aggregate() => {
+                let aggregate = self.add_aggregate(func, relation_columns, projection)?;
+                let position = self.add_group_aggregate(aggregate, func, aggregates, group_columns);
+                Ok(Some(Expr::column(None, &group_columns[position])))
+            }
+            Expr::Column(col) if is_scalar(col) => Ok(None),
+            Expr::Column(col) => {
+                let index = column_index(relation_columns, col)?;
+                match projection[..keys].iter().position(|(i, _)| *i == index) {
+                    Some(key) => Ok(Some(Expr::column(None, &group_columns[key]))),
+                    None => Err(CustomError::new(
+                        400,
+                        format!(
+                            "Bad request: Column {} must appear in the GROUP BY clause or be used in an aggregate",
+                            relation_columns[index]
+                        ),
+                    )),
+                }
+            }
+            _ => Ok(None),
+        })
+    }
+
+    /*
+     * Append the computed outputs to the relation with a compute op, pointing each output
+     * at its slot to its new column
+     */
+    fn add_computed(
+        &self,
+        builder: &mut GraphBuilder,
+        computed: Vec<(usize, Expr)>,
+        outputs: &mut [(usize, String)],
+    ) -> Result<(), CustomError> {
+        let mut columns = builder.columns();
+        let base = columns.len();
+        let mut exprs = vec![];
+        for (slot, expr) in computed.into_iter() {
+            // Compile once up front so that bad references fail the request rather than the run
+            ScalarEvaluator::new(&expr, &columns[..base])?;
+            outputs[slot].0 = columns.len();
+            columns.push(outputs[slot].1.clone());
+            exprs.push(expr);
+        }
+        builder.add_op("compute", columns, OpType::Compute(exprs));
+        Ok(())
+    }
+
+    /*
+     * Resolve ORDER BY expressions to positions in the output, matching selected names first.
+     * Anything else is bound by `bind`, and when it is not already output it is appended
+     * as a hidden column to be projected away after sorting.
+     */
+    fn bind_order<F>(
+        &self,
+        order: &[OrderBy],
+        outputs: &mut Vec<(usize, String)>,
+        mut bind: F,
+    ) -> Result<Vec<SortKey>, CustomError>
+    where
+        F: FnMut(&Expr) -> Result<(usize, String), CustomError>,
+    {
+        let mut keys = vec![];
+        for order_by in order.iter() {
+            let named = match order_by.expr {
+                Expr::Column(ColumnRef {
+                    table: None,
+                    ref name,
+                }) => {
+                    let name = name.to_lowercase();
+                    outputs.iter().position(|(_, output)| *output == name)
+                }
+                _ => None,
+            };
+            let column = match named {
+                Some(column) => column,
+                None => {
+                    let output = bind(&order_by.expr)?;
+                    match outputs.iter().position(|(i, _)| *i == output.0) {
+                        Some(column) => column,
+                        None => {
+                            outputs.push(output);
+                            outputs.len() - 1
+                        }
+                    }
+                }
+            };
+            keys.push(SortKey {
+                column,
+                ascending: order_by.ascending,
+            });
+        }
+        Ok(keys)
+    }
+
+    /*
+     * Grouped rows are hashed on the GROUP BY columns, which lead the input of the group op.
+     * The group op emits the keys and then every aggregate of the SELECT list and HAVING,
+     * which a Select filters on before a Reorder arranges the columns as selected.
+     */
+    #[allow(clippy::too_many_arguments)]
+    fn add_group_by(
+        &self,
+        builder: &mut GraphBuilder,
+        selected: Vec<Selected>,
+        scalars: &[SelectQuery],
+        group_by: &[Expr],
+        having: &Option<Expr>,
+        distinct: bool,
+        order: &[OrderBy],
+        relation_columns: Vec<String>,
+    ) -> Result<(), CustomError> {
+        let mut projection: Vec<(usize, String)> = vec![];
+        for expr in group_by.iter() {
+            let index = match expr {
+                Expr::Column(col) => column_index(&relation_columns, col)?,
+                _ => return Err(CustomError::from("Unsupported Statement")),
+            };
+            if !projection.iter().any(|(i, _)| *i == index) {
+                projection.push((index, relation_columns[index].clone()));
+            }
+        }
+        let keys: Vec<usize> = (0..projection.len()).collect();
+
+        let mut aggregates: Vec<Aggregate> = vec![];
+        let mut group_columns: Vec<String> = projection.iter().map(|(_, c)| c.clone()).collect();
+        let mut reorder: Vec<(usize, String)> = vec![];
+        let mut computed: Vec<(usize, Expr)> = vec![];
+        for s in selected.into_iter() {
+            match s {
+                Selected::Column(index, name) => {
+                    match projection.iter().position(|(i, _)| *i == index) {
+                        Some(key) => reorder.push((key, name)),
+                        None => {
+                            return Err(CustomError::new(
+                                400,
+                                format!(
+                                    "Bad request: Column {} must appear in the GROUP BY clause or be used in an aggregate",
+                                    relation_columns[index]
+                                ),
+                            ))
+                        }
+                    }
+                }
+                Selected::Aggregate(name, func) => {
+                    let aggregate =
+                        self.add_aggregate(&func, &relation_columns, &mut projection)?;
+                    let position = self.add_group_aggregate(
+                        aggregate,
+                        &func,
+                        &mut aggregates,
+                        &mut group_columns,
+                    );
+                    reorder.push((position, name));
+                }
+                Selected::Expr(name, expr) => {
+                    let expr = self.bind_aggregates(
+                        &expr,
+                        keys.len(),
+                        &relation_columns,
+                        &mut projection,
+                        &mut aggregates,
+                        &mut group_columns,
+                    )?;
+                    // Placed by add_computed once the group op output is complete
+                    computed.push((reorder.len(), expr));
+                    reorder.push((usize::MAX, name));
+                }
+                Selected::Window(..) => unreachable!("Window functions are bound by add_windows"),
+            }
+        }
+
+        // Aggregates in HAVING become references to the group op output
+        let having = match having {
+            Some(having) => Some(self.bind_aggregates(
+                having,
+                keys.len(),
+                &relation_columns,
+                &mut projection,
+                &mut aggregates,
+                &mut group_columns,
+            )?),
+            None => None,
+        };
+        if let Some(ref having) = having {
+            // Compile once up front so that bad references fail the request rather than the run
+            let scalar_columns = (0..scalars.len()).map(|i| format!("{}.value", scalar_name(i)));
+            let having_columns: Vec<String> = group_columns
+                .iter()
+                .cloned()
+                .chain(scalar_columns)
+                .collect();
+            ConditionPredicate::new(having, &having_columns)?;
+        }
+
+        let visible = reorder.len();
+        let order = match order {
+            [] => None,
+            order => Some(self.bind_order(order, &mut reorder, |expr| {
+                let position = match expr {
+                    Expr::Function(func) if func.is_aggregate() => {
+                        let aggregate =
+                            self.add_aggregate(func, &relation_columns, &mut projection)?;
+                        self.add_group_aggregate(
+                            aggregate,
+                            func,
+                            &mut aggregates,
+                            &mut group_columns,
+                        )
+                    }
+                    Expr::Column(col) => {
+                        let index = column_index(&relation_columns, col)?;
+                        match projection[..keys.len()].iter().position(|(i, _)| *i == index) {
+                            Some(key) => key,
+                            None => {
+                                return Err(CustomError::new(
+                                    400,
+                                    format!(
+                                        "Bad request: Column {} must appear in the GROUP BY clause to order by it",
+                                        relation_columns[index]
+                                    ),
+                                ))
+                            }
+                        }
+                    }
+                    _ => return Err(CustomError::from("Unsupported Statement")),
+                };
+                Ok((position, group_columns[position].clone()))
+            })?),
+        };
+
+        builder.add_projection(projection);
+        builder.add_op(
+            "group",
+            group_columns.clone(),
+            OpType::GroupBy(keys, aggregates),
+        );
+        group_columns.extend(self.add_scalars(builder, scalars)?);
+        if let Some(having) = having {
+            builder.add_op("having", group_columns, OpType::Select(having));
+        }
+        if !computed.is_empty() {
+            self.add_computed(builder, computed, &mut reorder)?;
+        }
+        let (indices, names) = reorder.into_iter().unzip();
+        builder.add_op("reorder", names, OpType::Reorder(indices));
+        self.add_distinct_and_sort(builder, distinct, order, visible)
+    }
+
+    /*
+     * DISTINCT drops duplicate rows before they are sorted, so it can only compare the rows
+     * as selected, without any hidden columns that are carried along to sort on
+     */
+    fn add_distinct_and_sort(
+        &self,
+        builder: &mut GraphBuilder,
+        distinct: bool,
+        order: Option<Vec<SortKey>>,
+        visible: usize,
+    ) -> Result<(), CustomError> {
+        if distinct {
+            if builder.columns().len() > visible {
+                return Err(CustomError::from(
+                    "ORDER BY columns must appear in the SELECT DISTINCT list",
+                ));
+            }
+            builder.add_distinct();
+        }
+        if let Some(keys) = order {
+            builder.add_sort(keys, visible);
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Share an aggregate that is already computed, e.g. COUNT(*) in both SELECT and HAVING,
+     * returning its position in the output of the group op
+     */
+    fn add_group_aggregate(
+        &self,
+        aggregate: Aggregate,
+        func: &Function,
+        aggregates: &mut Vec<Aggregate>,
+        group_columns: &mut Vec<String>,
+    ) -> usize {
+        let keys = group_columns.len() - aggregates.len();
+        match aggregates.iter().position(|a| *a == aggregate) {
+            Some(position) => keys + position,
+            None => {
+                aggregates.push(aggregate);
+                group_columns.push(func.to_string().to_lowercase());
+                group_columns.len() - 1
+            }
+        }
+    }
+
+    /*
+     * The aggregate a function call names, along with the argument it reads
+     */
+    fn aggregate_op<'a>(
+        &self,
+        func: &'a Function,
+    ) -> Result<(AggOpType, Option<&'a Expr>), CustomError> {
+        Ok(match (func.name.as_str(), func.args.as_slice()) {
+            ("count", [Expr::Wildcard]) if !func.distinct => (AggOpType::CountStar, None),
+            ("count", [argument]) => (AggOpType::Count, Some(argument)),
+            ("sum", [argument]) => (AggOpType::Sum, Some(argument)),
+            ("avg", [argument]) => (AggOpType::Average, Some(argument)),
+            ("max", [argument]) => (AggOpType::Maximum, Some(argument)),
+            ("min", [argument]) => (AggOpType::Minimum, Some(argument)),
+            ("group_concat", [argument]) => {
+                (AggOpType::GroupConcat(String::from(",")), Some(argument))
+            }
+            ("group_concat", [argument, Expr::Literal(Literal::String(separator))]) => {
+                (AggOpType::GroupConcat(separator.clone()), Some(argument))
+            }
+            _ => {
+                return Err(CustomError::new(
+                    400,
+                    format!("Bad request: Unsupported aggregate {}", func),
+                ))
+            }
+        })
+    }
+
+    /*
+     * Aggregates read their argument from the projection, so the argument is appended to it
+     */
+    fn add_aggregate(
+        &self,
+        func: &Function,
+        relation_columns: &[String],
+        projection: &mut Vec<(usize, String)>,
+    ) -> Result<Aggregate, CustomError> {
+        let (op, argument) = self.aggregate_op(func)?;
+        let column = match argument {
+            None => None,
+            Some(Expr::Column(col)) => {
+                let index = column_index(relation_columns, col)?;
+                match projection.iter().position(|(i, _)| *i == index) {
+                    Some(position) => Some(position),
+                    None => {
+                        projection.push((index, relation_columns[index].clone()));
+                        Some(projection.len() - 1)
+                    }
+                }
+            }
+            Some(_) => return Err(CustomError::from("Unsupported Statement")),
+        };
+
+        Ok(Aggregate {
+            op,
+            column,
+            distinct: func.distinct,
+        })
+    }
+
+    pub async fn inflate(
+        &self,
+        query_id: i64,
+        statement: Statement,
+    ) -> Result<Arc<RootNode>, CustomError> {
+        let mut builder = GraphBuilder::new(query_id);
+
+        match statement {
+            Statement::Select(query) => {
+                log::trace!("Found SelectQuery: {:?}", &query);
+                self.add_select_query(&mut builder, query).await?;
+            }
+        }
+
+        let root = builder.build().await;
+        Ok(root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::parse_sql;
+    use crate::query::{as_f64, as_i64, as_str, is_null};
+    use dotenv::dotenv;
+    use lazy_static::lazy_static;
+
+    lazy_static! {
+        static ref FIXTURE: () = {
+            dotenv().ok();
+            let _ = simple_logger::SimpleLogger::new().init();
+        };
+    }
+
+    pub fn setup() {
+        lazy_static::initialize(&FIXTURE);
+    }
+
+    fn parse_query(query: &str) -> Result<Statement, CustomError> {
+        parse_sql(query)
+    }
+
+    fn where_clause(query: &str) -> Option<Expr> {
+        match parse_query(query).expect("Failed to parse test query") {
+            Statement::Select(SelectQuery {
+                body: SetExpr::Select(select),
+                ..
+            }) => select.where_clause,
+            statement => panic!("Expected a SELECT: {:?}", statement),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_can_inflate_select_star() {
+        setup();
+
+        let query = "SELECT * FROM FOO";
+        let sql_query = parse_query(query).expect("Failed to parse test query");
+        log::trace!("Inflating executing graph for {:?}", sql_query);
+
+        let root = GraphInflator::new()
+            .with_table("foo", vec![String::from("c0")])
+            .inflate(1, sql_query)
+            .await
+            .unwrap();
+        log::trace!("Inflated execution graph: {:?}", root.as_ref());
+    }
+
+    #[actix_rt::test]
+    async fn test_can_run_select_star() {
+        setup();
+
+        let query = "SELECT * FROM FOO";
+        let sql_query = parse_query(query).expect("Failed to parse test query");
+        log::trace!("Inflating executing graph for {:?}", sql_query);
+
+        let root = GraphInflator::new()
+            .with_table("foo", vec![String::from("c0")])
+            .inflate(1, sql_query)
+            .await
+            .unwrap();
+        log::trace!("Inflated execution graph: {:?}", root.as_ref());
+
+        // TODO: we have end to end coverage of these tests, but it would e good to have chunked unit test coverage instead
+    }
+
+    fn record(columns: Vec<Box<dyn SqlType>>) -> QueryRecord {
+        QueryRecord {
+            columns,
+            ..Default::default()
+        }
+    }
+
+    fn check_condition_predicates(cases: &[(&str, Vec<Box<dyn SqlType>>, bool)]) {
+        let columns: Vec<String> = (0..5).map(|i| format!("foo.c{}", i)).collect();
+        for (query, values, expected) in cases.iter() {
+            let condition = where_clause(query);
+            log::trace!("Testing condition predicate from {:#?}", condition);
+
+            let predicate = ConditionPredicate::try_from(&condition, &columns)
+                .expect("Failed to compile condition predicate")
+                .expect("Missing condition predicate");
+            assert_eq!(
+                predicate.test(&record(values.clone())),
+                *expected,
+                "{}",
+                query
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_condition_predicate_comparison() {
+        setup();
+
+        check_condition_predicates(&[
+            (
+                "SELECT * FROM FOO WHERE c0 = 1",
+                vec![Box::new(1_i64)],
+                true,
+            ),
+            (
+                "SELECT * FROM FOO WHERE c0 = 1",
+                vec![Box::new(2_i64)],
+                false,
+            ),
+            (
+                "SELECT * FROM FOO WHERE c0 = 1",
+                vec![Box::new(Null::default())],
+                false,
+            ),
+            (
+                "SELECT * FROM FOO WHERE c0 > c1",
+                vec![Box::new(2.5_f64), Box::new(2_i64)],
+                true,
+            ),
+            (
+                "SELECT * FROM FOO WHERE c0 > c1",
+                vec![Box::new(String::from("a")), Box::new(String::from("b"))],
+                false,
+            ),
+        ]);
+    }
+
+    #[actix_rt::test]
+    async fn test_condition_predicate_logical() {
+        setup();
+
+        check_condition_predicates(&[
+            (
+                "SELECT * FROM FOO WHERE c0 != 1 AND c1 <= c0",
+                vec![Box::new(2_i64), Box::new(2_i64)],
+                true,
+            ),
+            (
+                "SELECT * FROM FOO WHERE c0 != 1 AND c1 <= c0",
+                vec![Box::new(1_i64), Box::new(0_i64)],
+                false,
+            ),
+            (
+                "SELECT * FROM FOO WHERE c3 OR c1 < c2",
+                vec![
+                    Box::new(0_i64),
+                    Box::new(1_i64),
+                    Box::new(2_i64),
+                    Box::new(0_i64),
+                ],
+                true,
+            ),
+            (
+                "SELECT * FROM FOO WHERE c3 OR c1 < c2",
+                vec![
+                    Box::new(0_i64),
+                    Box::new(3_i64),
+                    Box::new(2_i64),
+                    Box::new(Null::default()),
+                ],
+                false,
+            ),
+        ]);
+    }
+
+    #[actix_rt::test]
+    async fn test_condition_predicate_negation() {
+        setup();
+
+        check_condition_predicates(&[
+            (
+                "SELECT * FROM FOO WHERE NOT c0 < 1",
+                vec![Box::new(1_i64)],
+                true,
+            ),
+            (
+                "SELECT * FROM FOO WHERE NOT c0 < 1",
+                vec![Box::new(0_i64)],
+                false,
+            ),
+            (
+                "SELECT * FROM FOO WHERE NOT c0 < 1",
+                vec![Box::new(Null::default())],
+                false,
+            ),
+            (
+                "SELECT * FROM FOO WHERE NOT c0 != 1 AND c1 >= c0",
+                vec![Box::new(1_i64), Box::new(1_i64)],
+                true,
+            ),
+            (
+                "SELECT * FROM FOO WHERE c3 OR NOT c1 < c2",
+                vec![
+                    Box::new(0_i64),
+                    Box::new(1_i64),
+                    Box::new(2_i64),
+                    Box::new(0_i64),
+                ],
+                false,
+            ),
+            (
+                "SELECT * FROM FOO WHERE  NOT (c1 < c2 AND c0)",
+                vec![Box::new(0_i64), Box::new(1_i64), Box::new(2_i64)],
+                true,
+            ),
+        ]);
+    }
+
+    #[actix_rt::test]
+    async fn test_condition_predicate_base() {
+        setup();
+
+        // Field, Literal, Literal List
+        check_condition_predicates(&[
+            ("SELECT * FROM FOO WHERE c0", vec![Box::new(3_i64)], true),
+            ("SELECT * FROM FOO WHERE c0", vec![Box::new(0.0_f64)], false),
+            ("SELECT * FROM FOO WHERE false OR true", vec![], true),
+            ("SELECT * FROM FOO WHERE 2 in (1, 2, 3)", vec![], true),
+        ]);
+
+        // NestedSelect
+        let condition = where_clause("SELECT * FROM FOO WHERE  (SELECT true from BAR)");
+        let predicate = ConditionPredicate::try_from(&condition, &[]);
+        assert!(predicate.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_condition_predicate_patterns() {
+        setup();
+
+        let row = || -> Vec<Box<dyn SqlType>> {
+            vec![
+                Box::new(String::from("sensor-12")),
+                Box::new(7_i64),
+                Box::new(Null::default()),
+                Box::new(2.5_f64),
+            ]
+        };
+        check_condition_predicates(&[
+            ("SELECT * FROM FOO WHERE c0 LIKE 'sensor-%'", row(), true),
+            ("SELECT * FROM FOO WHERE c0 LIKE 'sensor-_'", row(), false),
+            ("SELECT * FROM FOO WHERE c0 NOT LIKE '%-1_'", row(), false),
+            ("SELECT * FROM FOO WHERE c0 ILIKE 'SENSOR%'", row(), true),
+            ("SELECT * FROM FOO WHERE c2 LIKE '%'", row(), false),
+            ("SELECT * FROM FOO WHERE c2 NOT LIKE '%'", row(), false),
+            ("SELECT * FROM FOO WHERE c1 BETWEEN 7 AND 8", row(), true),
+            ("SELECT * FROM FOO WHERE c3 BETWEEN 1 AND c1", row(), true),
+            (
+                "SELECT * FROM FOO WHERE c1 NOT BETWEEN c3 AND 10",
+                row(),
+                false,
+            ),
+            ("SELECT * FROM FOO WHERE c1 BETWEEN c2 AND 10", row(), false),
+            (
+                "SELECT * FROM FOO WHERE NOT c1 BETWEEN c2 AND 10",
+                row(),
+                false,
+            ),
+            ("SELECT * FROM FOO WHERE c2 IS NULL", row(), true),
+            ("SELECT * FROM FOO WHERE c2 IS NOT NULL", row(), false),
+            ("SELECT * FROM FOO WHERE c1 IN (1, 7, 9)", row(), true),
+            ("SELECT * FROM FOO WHERE c1 NOT IN (1, c2)", row(), false),
+            (
+                "SELECT * FROM FOO WHERE c2 IS NULL OR c1 NOT IN (1, c2)",
+                row(),
+                true,
+            ),
+        ]);
+    }
+
+    #[actix_rt::test]
+    async fn test_condition_predicate_arithmetic() {
+        setup();
+
+        check_condition_predicates(&[
+            (
+                "SELECT * FROM FOO WHERE c0 - 1 = 0",
+                vec![Box::new(1_i64)],
+                true,
+            ),
+            (
+                "SELECT * FROM FOO WHERE c0 - 1 = 0",
+                vec![Box::new(2_i64)],
+                false,
+            ),
+            (
+                "SELECT * FROM FOO WHERE c2 * 3 > 9",
+                vec![Box::new(0_i64), Box::new(0_i64), Box::new(3.5_f64)],
+                true,
+            ),
+            (
+                "SELECT * FROM FOO WHERE 10 <= c4 / 11",
+                vec![
+                    Box::new(0_i64),
+                    Box::new(0_i64),
+                    Box::new(0_i64),
+                    Box::new(0_i64),
+                    Box::new(110_i64),
+                ],
+                true,
+            ),
+            (
+                "SELECT * FROM FOO WHERE 10 <= c4 / 11",
+                vec![
+                    Box::new(0_i64),
+                    Box::new(0_i64),
+                    Box::new(0_i64),
+                    Box::new(0_i64),
+                    Box::new(109_i64),
+                ],
+                false,
+            ),
+        ]);
+    }
+
+    #[actix_rt::test]
+    async fn test_condition_predicate_bracketed() {
+        setup();
+
+        check_condition_predicates(&[
+            (
+                "SELECT * FROM FOO WHERE (c0 - 1) = 0",
+                vec![Box::new(1_i64)],
+                true,
+            ),
+            (
+                "SELECT * FROM FOO WHERE 0 = (c0 - 1)",
+                vec![Box::new(1_i64)],
+                true,
+            ),
+            (
+                "SELECT * FROM FOO WHERE 0 = (c0 - 1)",
+                vec![Box::new(0_i64)],
+                false,
+            ),
+            // "SELECT * FROM FOO WHERE c0 - 1 = (0)",
+            // "SELECT * FROM FOO WHERE 9 < (c2 * 3 + 1)",
+            // "SELECT * FROM FOO WHERE 10 <= ((c4) / 11)",
+            // "SELECT * FROM FOO WHERE (10 <= ((c4) / 11))",
+        ]);
+    }
+
+    #[actix_rt::test]
+    async fn test_condition_predicate_named_columns() {
+        setup();
+
+        let columns: Vec<String> = ["readings.device", "readings.celsius"]
+            .iter()
+            .map(|s| String::from(*s))
+            .collect();
+        for (query, expected) in [
+            ("SELECT * FROM readings WHERE celsius > 3", true),
+            ("SELECT * FROM readings WHERE readings.celsius > 3", true),
+            ("SELECT * FROM readings WHERE device = 'b'", false),
+        ]
+        .iter()
+        {
+            let predicate = ConditionPredicate::try_from(&where_clause(query), &columns)
+                .expect("Failed to compile condition predicate")
+                .expect("Missing condition predicate");
+            let values: Vec<Box<dyn SqlType>> =
+                vec![Box::new(String::from("a")), Box::new(3.5_f64)];
+            assert_eq!(predicate.test(&record(values)), *expected, "{}", query);
+        }
+
+        for query in [
+            "SELECT * FROM readings WHERE humidity > 3",
+            "SELECT * FROM readings WHERE other.celsius > 3",
+        ]
+        .iter()
+        {
+            let predicate = ConditionPredicate::try_from(&where_clause(query), &columns);
+            assert!(predicate.is_err());
+        }
+    }
+
+    fn int_record(values: &[i64]) -> QueryRecord {
+        record(
+            values
+                .iter()
+                .map(|v| Box::new(*v) as Box<dyn SqlType>)
+                .collect(),
+        )
+    }
+
+    fn ints(records: &[QueryRecord]) -> Vec<Vec<Option<i64>>> {
+        records
+            .iter()
+            .map(|r| r.columns.iter().map(|c| as_i64(c.as_ref())).collect())
+            .collect()
+    }
+
+    fn inflator() -> GraphInflator {
+        GraphInflator::new().with_table(
+            "foo",
+            ["c0", "c1", "c2"]
+                .iter()
+                .map(|s| String::from(*s))
+                .collect(),
+        )
+    }
+
+    async fn inflate(inflator: GraphInflator, query: &str) -> Result<Arc<RootNode>, CustomError> {
+        let sql_query = parse_query(query).expect("Failed to parse test query");
+        inflator.inflate(1, sql_query).await
+    }
+
+    /*
+     * Run a single op WorkNode over the given records and gather what it emits
+     */
+    async fn run_op(op: OpType, records: Vec<QueryRecord>) -> Vec<QueryRecord> {
+        run_op_results(op, records)
+            .await
+            .into_iter()
+            .map(|r| r.expect("Unexpected error from op"))
+            .collect()
+    }
+
+    async fn run_op_results(
+        op: OpType,
+        records: Vec<QueryRecord>,
+    ) -> Vec<Result<QueryRecord, CustomError>> {
+        let ctx = Arc::new(ExecuteContext {
+            user_id: 1,
+            app_data: Arc::new(AppData {
+                table_cache: Mutex::new(HashMap::new()),
+            }),
+        });
+        let info = Arc::new(NodeInfo {
+            input: NodeInput::None,
+            personality: NodeType::Op(op),
+        });
+        let work_node = WorkNode::new(ctx, Placement::Server(Partition::Whole), None, info);
+
+        let (mut input_sender, input_receiver) =
+            futures::channel::mpsc::channel::<Result<QueryRecord, CustomError>>(1 << 10);
+        for r in records.into_iter() {
+            input_sender.send(Ok(r)).await.unwrap();
+        }
+        drop(input_sender);
+
+        let (output_sender, output_receiver) =
+            futures::channel::mpsc::channel::<Result<QueryRecord, CustomError>>(1 << 10);
+        work_node.collect(output_sender, Some(input_receiver)).await;
+        output_receiver.collect().await
+    }
+
+    #[actix_rt::test]
+    async fn test_project_and_reorder_columns() {
+        setup();
+
+        let records = vec![int_record(&[1, 2, 3]), int_record(&[4, 5, 6])];
+        let records = run_op(OpType::Project(vec![0, 2]), records).await;
+        assert_eq!(
+            ints(&records),
+            vec![vec![Some(1), Some(3)], vec![Some(4), Some(6)]]
+        );
+
+        let records = run_op(OpType::Reorder(vec![1, 0, 1]), records).await;
+        assert_eq!(
+            ints(&records),
+            vec![
+                vec![Some(3), Some(1), Some(3)],
+                vec![Some(6), Some(4), Some(6)]
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_inflate_projection() {
+        setup();
+
+        let root = inflate(inflator(), "SELECT c2, c0 AS first, foo.* FROM foo")
+            .await
+            .unwrap();
+        let reorder = root.graph.as_ref().unwrap();
+        assert_eq!(
+            reorder.columns.as_ref().unwrap(),
+            &vec!["foo.c2", "first", "foo.c0", "foo.c1", "foo.c2"]
+        );
+        match (&reorder.info.personality, &reorder.info.input) {
+            (NodeType::Op(OpType::Reorder(indices)), NodeInput::Single(project)) => {
+                assert_eq!(indices, &vec![2, 0, 0, 1, 2]);
+                match project.info.personality {
+                    NodeType::Op(OpType::Project(ref indices)) => {
+                        assert_eq!(indices, &vec![0, 1, 2])
+                    }
+                    _ => panic!("unexpected node {:?}", project.info.personality),
+                }
+            }
+            _ => panic!("unexpected node {:?}", reorder.info.personality),
+        }
+
+        let root = inflate(inflator(), "SELECT f.c1 FROM foo AS f")
+            .await
+            .unwrap();
+        let reorder = root.graph.as_ref().unwrap();
+        assert_eq!(reorder.columns.as_ref().unwrap(), &vec!["f.c1"]);
+
+        for query in [
+            "SELECT c3 FROM foo",
+            "SELECT bar.* FROM foo",
+            "SELECT bar.c0 FROM foo",
+        ]
+        .iter()
+        {
+            assert!(inflate(inflator(), query).await.is_err(), "{}", query);
+        }
+    }
+
+    fn aggregate(op: AggOpType, column: Option<usize>, distinct: bool) -> Aggregate {
+        Aggregate {
+            op,
+            column,
+            distinct,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_aggregate_columns() {
+        setup();
+
+        let records = vec![
+            record(vec![Box::new(3_i64), Box::new(String::from("b"))]),
+            record(vec![Box::new(Null::default()), Box::new(String::from("a"))]),
+            record(vec![Box::new(1_i64), Box::new(String::from("b"))]),
+            record(vec![Box::new(3_i64), Box::new(Null::default())]),
+        ];
+        let aggregates = vec![
+            aggregate(AggOpType::CountStar, None, false),
+            aggregate(AggOpType::Count, Some(0), false),
+            aggregate(AggOpType::Count, Some(1), true),
+            aggregate(AggOpType::Sum, Some(0), false),
+            aggregate(AggOpType::Sum, Some(0), true),
+            aggregate(AggOpType::Average, Some(0), false),
+            aggregate(AggOpType::Maximum, Some(0), false),
+            aggregate(AggOpType::Minimum, Some(1), false),
+            aggregate(AggOpType::GroupConcat(String::from(",")), Some(1), false),
+        ];
+        let records = run_op(OpType::Agg(aggregates), records).await;
+        assert_eq!(records.len(), 1);
+        let columns = &records[0].columns;
+        assert_eq!(
+            ints(&records)[0][..5],
+            [Some(4), Some(3), Some(2), Some(7), Some(4)]
+        );
+        assert_eq!(as_f64(columns[5].as_ref()), Some(7.0 / 3.0));
+        assert_eq!(as_i64(columns[6].as_ref()), Some(3));
+        assert_eq!(as_str(columns[7].as_ref()), Some("a"));
+        assert_eq!(as_str(columns[8].as_ref()), Some("b,a,b"));
+    }
+
+    #[actix_rt::test]
+    async fn test_aggregate_empty_input() {
+        setup();
+
+        let aggregates = vec![
+            aggregate(AggOpType::CountStar, None, false),
+            aggregate(AggOpType::Count, Some(0), false),
+            aggregate(AggOpType::Sum, Some(0), false),
+            aggregate(AggOpType::Average, Some(0), false),
+            aggregate(AggOpType::Maximum, Some(0), false),
+        ];
+        let records = run_op(OpType::Agg(aggregates), vec![]).await;
+        assert_eq!(records.len(), 1);
+        assert_eq!(ints(&records)[0][..2], [Some(0), Some(0)]);
+        assert!(records[0].columns[2..].iter().all(|c| is_null(c.as_ref())));
+    }
+
+    #[actix_rt::test]
+    async fn test_inflate_aggregates() {
+        setup();
+
+        let root = inflate(
+            inflator(),
+            "SELECT count(*), sum(c2) AS total, max(foo.c0) FROM foo",
+        )
+        .await
+        .unwrap();
+        let agg = root.graph.as_ref().unwrap();
+        assert_eq!(
+            agg.columns.as_ref().unwrap(),
+            &vec!["count(*)", "total", "max(foo.c0)"]
+        );
+        match (&agg.info.personality, &agg.info.input) {
+            (NodeType::Op(OpType::Agg(aggregates)), NodeInput::Single(reorder)) => {
+                let columns: Vec<Option<usize>> = aggregates.iter().map(|a| a.column).collect();
+                assert_eq!(columns, vec![None, Some(0), Some(1)]);
+                assert_eq!(reorder.columns.as_ref().unwrap(), &vec!["foo.c2", "foo.c0"]);
+            }
+            _ => panic!("unexpected node {:?}", agg.info.personality),
+        }
+
+        for query in ["SELECT c0, count(*) FROM foo", "SELECT sum(c3) FROM foo"].iter() {
+            assert!(inflate(inflator(), query).await.is_err(), "{}", query);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_group_by_columns() {
+        setup();
+
+        let records = vec![
+            int_record(&[1, 10, 5]),
+            int_record(&[2, 20, 5]),
+            int_record(&[1, 30, 6]),
+            record(vec![
+                Box::new(Null::default()),
+                Box::new(40_i64),
+                Box::new(5_i64),
+            ]),
+            int_record(&[1, 50, 5]),
+        ];
+        let aggregates = vec![
+            aggregate(AggOpType::CountStar, None, false),
+            aggregate(AggOpType::Sum, Some(1), false),
+        ];
+        let groups = run_op(OpType::GroupBy(vec![0, 2], aggregates), records).await;
+        assert_eq!(
+            ints(&groups),
+            vec![
+                vec![Some(1), Some(5), Some(2), Some(60)],
+                vec![Some(2), Some(5), Some(1), Some(20)],
+                vec![Some(1), Some(6), Some(1), Some(30)],
+                vec![None, Some(5), Some(1), Some(40)],
+            ]
+        );
+
+        let groups = run_op(
+            OpType::GroupBy(vec![0], vec![aggregate(AggOpType::CountStar, None, false)]),
+            vec![],
+        )
+        .await;
+        assert!(groups.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_inflate_group_by_having() {
+        setup();
+
+        let root = inflate(
+            inflator(),
+            "SELECT count(*) AS n, c1 FROM foo WHERE c0 > 0 GROUP BY foo.c1 HAVING count(*) > 1 AND max(c2) < 10",
+        )
+        .await
+        .unwrap();
+        let reorder = root.graph.as_ref().unwrap();
+        assert_eq!(reorder.columns.as_ref().unwrap(), &vec!["n", "foo.c1"]);
+        let having = match (&reorder.info.personality, &reorder.info.input) {
+            (NodeType::Op(OpType::Reorder(indices)), NodeInput::Single(having)) => {
+                assert_eq!(indices, &vec![1, 0]);
+                having
+            }
+            _ => panic!("Expected a reorder over the having filter"),
+        };
+        let group = match (&having.info.personality, &having.info.input) {
+            (NodeType::Op(OpType::Select(_)), NodeInput::Single(group)) => group,
+            _ => panic!("Expected a select over the grouped rows"),
+        };
+        assert_eq!(
+            group.columns.as_ref().unwrap(),
+            &vec!["foo.c1", "count(*)", "max(c2)"]
+        );
+        match group.info.personality {
+            NodeType::Op(OpType::GroupBy(ref keys, ref aggregates)) => {
+                assert_eq!(keys, &vec![0]);
+                assert_eq!(aggregates.len(), 2);
+                assert_eq!(aggregates[1].column, Some(1));
+            }
+            _ => panic!("unexpected node {:?}", group.info.personality),
+        }
+
+        for query in [
+            "SELECT c0, count(*) FROM foo GROUP BY c1",
+            "SELECT * FROM foo GROUP BY c1",
+            "SELECT c1 FROM foo GROUP BY c1 HAVING c0 > 1",
+            "SELECT c1 FROM foo GROUP BY c3",
+        ]
+        .iter()
+        {
+            assert!(inflate(inflator(), query).await.is_err(), "{}", query);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_inflate_order_by() {
+        setup();
+
+        // c1 is only carried along to sort on, then projected away
+        let root = inflate(
+            inflator(),
+            "SELECT c2 AS last, c0 FROM foo ORDER BY last DESC, foo.c1",
+        )
+        .await
+        .unwrap();
+        let project = root.graph.as_ref().unwrap();
+        assert_eq!(project.columns.as_ref().unwrap(), &vec!["last", "foo.c0"]);
+        let sort = match (&project.info.personality, &project.info.input) {
+            (NodeType::Op(OpType::Project(indices)), NodeInput::Single(sort)) => {
+                assert_eq!(indices, &vec![0, 1]);
+                sort
+            }
+            _ => panic!("Expected a project over the sort"),
+        };
+        assert_eq!(
+            sort.columns.as_ref().unwrap(),
+            &vec!["last", "foo.c0", "foo.c1"]
+        );
+        match sort.info.personality {
+            NodeType::Op(OpType::Sort(ref keys)) => assert_eq!(
+                keys,
+                &vec![
+                    SortKey {
+                        column: 0,
+                        ascending: false
+                    },
+                    SortKey {
+                        column: 2,
+                        ascending: true
+                    }
+                ]
+            ),
+            _ => panic!("unexpected node {:?}", sort.info.personality),
+        }
+
+        let root = inflate(
+            inflator(),
+            "SELECT c1, count(*) FROM foo GROUP BY c1 ORDER BY sum(c2) DESC",
+        )
+        .await
+        .unwrap();
+        let project = root.graph.as_ref().unwrap();
+        assert_eq!(
+            project.columns.as_ref().unwrap(),
+            &vec!["foo.c1", "count(*)"]
+        );
+
+        for query in [
+            "SELECT c0 FROM foo ORDER BY c3",
+            "SELECT c0 FROM foo ORDER BY count(*)",
+            "SELECT count(*) FROM foo ORDER BY c0",
+            "SELECT c1 FROM foo GROUP BY c1 ORDER BY c0",
+        ]
+        .iter()
+        {
+            assert!(inflate(inflator(), query).await.is_err(), "{}", query);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_limit_closes_upstream() {
+        setup();
+
+        let records: Vec<QueryRecord> = (0..10).map(|i| int_record(&[i])).collect();
+        let limited = run_op(OpType::Limit(3, 2), records.clone()).await;
+        assert_eq!(
+            ints(&limited),
+            vec![vec![Some(2)], vec![Some(3)], vec![Some(4)]]
+        );
+        assert_eq!(run_op(OpType::Limit(5, 8), records.clone()).await.len(), 2);
+        assert!(run_op(OpType::Limit(0, 0), records).await.is_empty());
+
+        // The upstream sender is still open, but finds the receiver closed once the limit is met
+        let ctx = Arc::new(ExecuteContext {
+            user_id: 1,
+            app_data: Arc::new(AppData {
+                table_cache: Mutex::new(HashMap::new()),
+            }),
+        });
+        let info = Arc::new(NodeInfo {
+            input: NodeInput::None,
+            personality: NodeType::Op(OpType::Limit(1, 0)),
+        });
+        let work_node = WorkNode::new(ctx, Placement::Server(Partition::Whole), None, info);
+        let (mut input_sender, input_receiver) =
+            futures::channel::mpsc::channel::<Result<QueryRecord, CustomError>>(1 << 10);
+        input_sender.send(Ok(int_record(&[1]))).await.unwrap();
+        let (output_sender, output_receiver) =
+            futures::channel::mpsc::channel::<Result<QueryRecord, CustomError>>(1 << 10);
+        work_node.collect(output_sender, Some(input_receiver)).await;
+
+        let err = input_sender.send(Ok(int_record(&[2]))).await.unwrap_err();
+        assert!(err.is_disconnected());
+        assert_eq!(output_receiver.collect::<Vec<_>>().await.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_inflate_limit() {
+        setup();
+
+        let root = inflate(inflator(), "SELECT c0 FROM foo LIMIT 10 OFFSET 5")
+            .await
+            .unwrap();
+        let limit = root.graph.as_ref().unwrap();
+        assert_eq!(limit.columns.as_ref().unwrap(), &vec!["foo.c0"]);
+        match (&limit.info.personality, &limit.info.input) {
+            (NodeType::Op(OpType::Limit(10, 5)), NodeInput::Single(reorder)) => {
+                match reorder.info.personality {
+                    NodeType::Op(OpType::Reorder(_)) => (),
+                    _ => panic!("unexpected node {:?}", reorder.info.personality),
+                }
+            }
+            _ => panic!("unexpected node {:?}", limit.info.personality),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_inflate_top_n() {
+        setup();
+
+        // The sort under the projection of the hidden c1 becomes a top-n
+        let root = inflate(
+            inflator(),
+            "SELECT c0 FROM foo ORDER BY c1 DESC LIMIT 10 OFFSET 5",
+        )
+        .await
+        .unwrap();
+        let project = root.graph.as_ref().unwrap();
+        assert_eq!(project.columns.as_ref().unwrap(), &vec!["foo.c0"]);
+        match (&project.info.personality, &project.info.input) {
+            (NodeType::Op(OpType::Project(_)), NodeInput::Single(top)) => {
+                assert_eq!(top.columns.as_ref().unwrap(), &vec!["foo.c0", "foo.c1"]);
+                match top.info.personality {
+                    NodeType::Op(OpType::TopN(ref keys, 10, 5)) => assert_eq!(
+                        keys,
+                        &vec![SortKey {
+                            column: 1,
+                            ascending: false
+                        }]
+                    ),
+                    _ => panic!("unexpected node {:?}", top.info.personality),
+                }
+            }
+            _ => panic!("unexpected node {:?}", project.info.personality),
+        }
+
+        let root = inflate(inflator(), "SELECT c0 FROM foo ORDER BY c0 LIMIT 3")
+            .await
+            .unwrap();
+        match root.graph.as_ref().unwrap().info.personality {
+            NodeType::Op(OpType::TopN(_, 3, 0)) => (),
+            _ => panic!(
+                "unexpected node {:?}",
+                root.graph.as_ref().unwrap().info.personality
+            ),
+        }
+
+        // A heap of rows beyond the sort memory budget is left to the sort, which can spill
+        let root = inflate(inflator(), "SELECT c0 FROM foo ORDER BY c0 LIMIT 100000000")
+            .await
+            .unwrap();
+        let limit = root.graph.as_ref().unwrap();
+        match (&limit.info.personality, &limit.info.input) {
+            (NodeType::Op(OpType::Limit(100_000_000, 0)), NodeInput::Single(sort)) => {
+                assert!(matches!(
+                    sort.info.personality,
+                    NodeType::Op(OpType::Sort(_))
+                ))
+            }
+            _ => panic!("Expected a limit over a sort"),
+        }
+        assert!(TopN::fits(10, 5, 2, 1 << 20));
+        assert!(!TopN::fits(10, 5, 2, 100));
+        assert!(!TopN::fits(u64::MAX, 1, 2, 1 << 40));
+
+        let records: Vec<QueryRecord> = [5, 1, 4, 1, 3, 9, 2]
+            .iter()
+            .enumerate()
+            .map(|(i, v)| int_record(&[*v, i as i64]))
+            .collect();
+        let keys = vec![SortKey {
+            column: 0,
+            ascending: true,
+        }];
+        let top = run_op(OpType::TopN(keys, 3, 1), records).await;
+        assert_eq!(
+            ints(&top),
+            vec![
+                vec![Some(1), Some(3)],
+                vec![Some(2), Some(6)],
+                vec![Some(3), Some(4)]
+            ]
+        );
+    }
+
+    fn join_inflator() -> GraphInflator {
+        inflator().with_table(
+            "bar",
+            ["c0", "c3"].iter().map(|s| String::from(*s)).collect(),
+        )
+    }
+
+    #[actix_rt::test]
+    async fn test_inflate_join() {
+        setup();
+
+        // The join concatenates both scans, and the rest of the WHERE clause filters the join
+        let root = inflate(
+            join_inflator(),
+            "SELECT foo.c1, bar.c3 FROM foo JOIN bar ON foo.c0 = bar.c0 WHERE bar.c3 > 1",
+        )
+        .await
+        .unwrap();
+        let reorder = root.graph.as_ref().unwrap();
+        assert_eq!(reorder.columns.as_ref().unwrap(), &vec!["foo.c1", "bar.c3"]);
+        let project = match reorder.info.input {
+            NodeInput::Single(ref project) => project,
+            _ => panic!("Expected a projection under the reorder"),
+        };
+        let select = match project.info.input {
+            NodeInput::Single(ref select) => select,
+            _ => panic!("Expected a select under the projection"),
+        };
+        let join = match (&select.info.personality, &select.info.input) {
+            (NodeType::Op(OpType::Select(_)), NodeInput::Single(join)) => join,
+            _ => panic!("Expected the WHERE clause to filter the join"),
+        };
+        assert_eq!(
+            join.columns.as_ref().unwrap(),
+            &vec!["foo.c0", "foo.c1", "foo.c2", "bar.c0", "bar.c3"]
+        );
+        match (&join.info.personality, &join.info.input) {
+            (NodeType::Op(OpType::Join(join)), NodeInput::Double(left, right)) => {
+                assert_eq!(join.op, JoinOpType::Equi);
+                assert_eq!(join.left_keys, vec![0]);
+                assert_eq!(join.right_keys, vec![0]);
+                assert_eq!(left.name, "select_foo");
+                assert_eq!(right.name, "select_bar");
+            }
+            _ => panic!("Expected a join of the two tables"),
+        }
+
+        // Comma joins find their keys in the WHERE clause, and USING names columns on both sides
+        for query in [
+            "SELECT c1, c3 FROM foo, bar WHERE bar.c0 = foo.c0",
+            "SELECT c1, c3 FROM foo JOIN bar USING (c0)",
         ]
         .iter()
         {
-            let sql_query = parse_query(query).expect("Failed to parse test query");
-            log::trace!("Testing condition predicate from {:#?}", sql_query);
+            let root = inflate(join_inflator(), query).await.unwrap();
+            let project = match root.graph.as_ref().unwrap().info.input {
+                NodeInput::Single(ref project) => project,
+                _ => panic!("Expected a projection under the reorder"),
+            };
+            match project.info.input {
+                NodeInput::Single(ref join) => match join.info.personality {
+                    NodeType::Op(OpType::Join(ref join)) => {
+                        assert_eq!((join.left_keys[0], join.right_keys[0]), (0, 0))
+                    }
+                    _ => panic!("Expected a join for {}", query),
+                },
+                _ => panic!("Expected a join for {}", query),
+            }
+        }
+
+        // Without an equality the tables make a product, which the WHERE clause filters
+        let root = inflate(join_inflator(), "SELECT c1 FROM foo, bar WHERE c3 > 1")
+            .await
+            .unwrap();
+        let project = match root.graph.as_ref().unwrap().info.input {
+            NodeInput::Single(ref project) => project,
+            _ => panic!("Expected a projection under the reorder"),
+        };
+        match project.info.input {
+            NodeInput::Single(ref select) => match select.info.input {
+                NodeInput::Single(ref product) => {
+                    assert_eq!(product.columns.as_ref().unwrap().len(), 5);
+                    match product.info.personality {
+                        NodeType::Op(OpType::Set(SetOpType::Product, false)) => (),
+                        _ => panic!("Expected a product of the two tables"),
+                    }
+                }
+                _ => panic!("Expected a product under the select"),
+            },
+            _ => panic!("Expected a select under the projection"),
+        }
+        let err = inflate(
+            join_inflator(),
+            "SELECT c1 FROM foo JOIN bar ON foo.c1 > bar.c4",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.error_status_code, 400);
+    }
+
+    /*
+     * Run a join WorkNode over the given left and right records and gather what it emits
+     */
+    async fn run_join(join: Join, left: &[&[i64]], right: &[&[i64]]) -> Vec<QueryRecord> {
+        run_double(OpType::Join(join), left, right).await
+    }
 
-            if let SqlQuery::Select(select_stmt) = sql_query {
-                let predicate = ConditionPredicate::try_from(&select_stmt.where_clause);
-                assert!(predicate.is_err());
+    /*
+     * Run a double input WorkNode over the given left and right records and gather what it emits
+     */
+    async fn run_double(op: OpType, left: &[&[i64]], right: &[&[i64]]) -> Vec<QueryRecord> {
+        let ctx = Arc::new(ExecuteContext {
+            user_id: 1,
+            app_data: Arc::new(AppData {
+                table_cache: Mutex::new(HashMap::new()),
+            }),
+        });
+        let info = Arc::new(NodeInfo {
+            input: NodeInput::None,
+            personality: NodeType::Op(op),
+        });
+        let work_node = WorkNode::new(ctx, Placement::Server(Partition::Whole), None, info);
 
-                // TODO: we want is_ok and check correctness
-            } else {
-                assert!(false);
-            }
+        let (mut left_sender, left_receiver) =
+            futures::channel::mpsc::channel::<Result<QueryRecord, CustomError>>(1 << 10);
+        for r in left.iter() {
+            left_sender.send(Ok(int_record(r))).await.unwrap();
+        }
+        drop(left_sender);
+        let (mut right_sender, right_receiver) =
+            futures::channel::mpsc::channel::<Result<QueryRecord, CustomError>>(1 << 10);
+        for r in right.iter() {
+            right_sender.send(Ok(int_record(r))).await.unwrap();
         }
+        drop(right_sender);
+
+        let (output_sender, output_receiver) =
+            futures::channel::mpsc::channel::<Result<QueryRecord, CustomError>>(1 << 10);
+        work_node
+            .collect_double(output_sender, left_receiver, right_receiver)
+            .await;
+        output_receiver
+            .map(|r| r.expect("Unexpected error from double input op"))
+            .collect()
+            .await
+    }
+
+    fn join_columns(prefix: &str, width: usize) -> Vec<String> {
+        (0..width).map(|i| format!("{}.c{}", prefix, i)).collect()
+    }
+
+    fn key_join(op: JoinOpType) -> Join {
+        Join::new(
+            op,
+            &join_columns("l", 2),
+            &join_columns("r", 2),
+            vec![1],
+            vec![0],
+            None,
+        )
+    }
+
+    const LEFT: &[&[i64]] = &[&[10, 1], &[11, 2], &[12, 1], &[13, 4]];
+    const RIGHT: &[&[i64]] = &[&[1, 100], &[2, 200], &[1, 101], &[3, 300]];
+
+    #[actix_rt::test]
+    async fn test_join_records() {
+        setup();
+
+        let joined = run_join(key_join(JoinOpType::Equi), LEFT, RIGHT).await;
+        assert_eq!(
+            ints(&joined),
+            vec![
+                vec![Some(10), Some(1), Some(1), Some(100)],
+                vec![Some(10), Some(1), Some(1), Some(101)],
+                vec![Some(11), Some(2), Some(2), Some(200)],
+                vec![Some(12), Some(1), Some(1), Some(100)],
+                vec![Some(12), Some(1), Some(1), Some(101)],
+            ]
+        );
     }
+
     #[actix_rt::test]
-    async fn test_condition_predicate_base() {
+    async fn test_outer_joins() {
+        setup();
+
+        let joined = run_join(key_join(JoinOpType::LeftOuter), LEFT, RIGHT).await;
+        assert_eq!(joined.len(), 6);
+        assert_eq!(
+            ints(&joined[5..]),
+            vec![vec![Some(13), Some(4), None, None]]
+        );
+
+        let joined = run_join(key_join(JoinOpType::RightOuter), LEFT, RIGHT).await;
+        assert_eq!(joined.len(), 6);
+        assert_eq!(
+            ints(&joined[5..]),
+            vec![vec![None, None, Some(3), Some(300)]]
+        );
+
+        let joined = run_join(key_join(JoinOpType::FullOuter), LEFT, RIGHT).await;
+        assert_eq!(joined.len(), 7);
+        assert_eq!(
+            ints(&joined[5..]),
+            vec![
+                vec![Some(13), Some(4), None, None],
+                vec![None, None, Some(3), Some(300)]
+            ]
+        );
+
+        // A USING key takes the right value where only the right side has a match
+        let using = |op| {
+            Join::using(
+                op,
+                &join_columns("l", 2),
+                &join_columns("r", 2),
+                vec![1],
+                vec![0],
+            )
+        };
+        let joined = run_join(using(JoinOpType::Equi), LEFT, RIGHT).await;
+        assert_eq!(ints(&joined[..1]), vec![vec![Some(10), Some(1), Some(100)]]);
+        let joined = run_join(using(JoinOpType::FullOuter), LEFT, RIGHT).await;
+        assert_eq!(
+            ints(&joined[5..]),
+            vec![
+                vec![Some(13), Some(4), None],
+                vec![None, Some(3), Some(300)]
+            ]
+        );
+
+        // An ON condition beyond the keys decides the matches, so a failing pair is padded
+        let condition = where_clause("SELECT * FROM l WHERE l.c0 > 10");
+        let join = Join::new(
+            JoinOpType::LeftOuter,
+            &join_columns("l", 2),
+            &join_columns("r", 2),
+            vec![1],
+            vec![0],
+            condition,
+        );
+        let joined = run_join(join, LEFT, RIGHT).await;
+        assert_eq!(
+            ints(&joined[..1]),
+            vec![vec![Some(10), Some(1), None, None]]
+        );
+        assert_eq!(joined.len(), 5);
+    }
+
+    #[actix_rt::test]
+    async fn test_natural_and_theta_joins() {
+        setup();
+
+        // l.c1 and r.c1 share a name, so a natural join keys on them and keeps one copy
+        let join = Join::natural(&join_columns("l", 2), &join_columns("r", 2)[1..]);
+        let joined = run_join(join, LEFT, &[&[1], &[4], &[5]]).await;
+        assert_eq!(
+            ints(&joined),
+            vec![
+                vec![Some(10), Some(1)],
+                vec![Some(12), Some(1)],
+                vec![Some(13), Some(4)]
+            ]
+        );
+
+        let mut builder = GraphBuilder::new(1);
+        builder.add_scan(
+            IoType::Ram(String::from("foo")),
+            join_columns("foo", 3),
+            None,
+        );
+        builder.add_natural_join(GraphBuilder::scan(
+            IoType::Ram(String::from("bar")),
+            join_columns("bar", 2),
+            None,
+        ));
+        assert_eq!(
+            builder.columns(),
+            vec!["foo.c0|bar.c0", "foo.c1|bar.c1", "foo.c2"]
+        );
+
+        // A theta join pairs every record of both sides that passes the condition
+        let condition = where_clause("SELECT * FROM l WHERE l.c1 < r.c0");
+        let join = Join::new(
+            JoinOpType::Theta,
+            &join_columns("l", 2),
+            &join_columns("r", 2),
+            vec![],
+            vec![],
+            condition,
+        );
+        let joined = run_join(join, LEFT, RIGHT).await;
+        assert_eq!(
+            ints(&joined),
+            vec![
+                vec![Some(10), Some(1), Some(2), Some(200)],
+                vec![Some(10), Some(1), Some(3), Some(300)],
+                vec![Some(11), Some(2), Some(3), Some(300)],
+                vec![Some(12), Some(1), Some(2), Some(200)],
+                vec![Some(12), Some(1), Some(3), Some(300)],
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_semi_and_anti_joins() {
+        setup();
+
+        let joined = run_join(key_join(JoinOpType::Semi), LEFT, RIGHT).await;
+        assert_eq!(
+            ints(&joined),
+            vec![
+                vec![Some(10), Some(1)],
+                vec![Some(11), Some(2)],
+                vec![Some(12), Some(1)]
+            ]
+        );
+
+        let joined = run_join(key_join(JoinOpType::Anti), LEFT, RIGHT).await;
+        assert_eq!(ints(&joined), vec![vec![Some(13), Some(4)]]);
+
+        // Like NOT IN, a NULL among the subquery results leaves nothing to keep
+        let mut null_record = int_record(&[0]);
+        null_record.columns[0] = Box::new(Null::default());
+        let ctx = Arc::new(ExecuteContext {
+            user_id: 1,
+            app_data: Arc::new(AppData {
+                table_cache: Mutex::new(HashMap::new()),
+            }),
+        });
+        let info = Arc::new(NodeInfo {
+            input: NodeInput::None,
+            personality: NodeType::Op(OpType::Join(key_join(JoinOpType::Anti))),
+        });
+        let work_node = WorkNode::new(ctx, Placement::Server(Partition::Whole), None, info);
+        let (mut left_sender, left_receiver) =
+            futures::channel::mpsc::channel::<Result<QueryRecord, CustomError>>(1 << 10);
+        left_sender.send(Ok(int_record(&[13, 4]))).await.unwrap();
+        drop(left_sender);
+        let (mut right_sender, right_receiver) =
+            futures::channel::mpsc::channel::<Result<QueryRecord, CustomError>>(1 << 10);
+        right_sender.send(Ok(int_record(&[1]))).await.unwrap();
+        right_sender.send(Ok(null_record)).await.unwrap();
+        drop(right_sender);
+        let (output_sender, output_receiver) =
+            futures::channel::mpsc::channel::<Result<QueryRecord, CustomError>>(1 << 10);
+        work_node
+            .collect_join(output_sender, left_receiver, right_receiver)
+            .await;
+        assert_eq!(output_receiver.collect::<Vec<_>>().await.len(), 0);
+
+        // Every record is kept when the subquery is empty
+        let joined = run_join(key_join(JoinOpType::Anti), LEFT, &[]).await;
+        assert_eq!(joined.len(), 4);
+    }
+
+    #[actix_rt::test]
+    async fn test_inflate_join_variants() {
         setup();
 
-        // Field, Literal, Literal List, NestedSelect
+        let join_of = |root: &Arc<RootNode>| -> Join {
+            let mut node = root.graph.clone().unwrap();
+            loop {
+                node = match (&node.info.personality, &node.info.input) {
+                    (NodeType::Op(OpType::Join(join)), _) => return join.clone(),
+                    (_, NodeInput::Single(input)) => input.clone(),
+                    _ => panic!("Expected a join in the graph"),
+                }
+            }
+        };
+
+        let root = inflate(
+            join_inflator(),
+            "SELECT c1, c3 FROM foo LEFT JOIN bar ON foo.c0 = bar.c0 AND bar.c3 > 1",
+        )
+        .await
+        .unwrap();
+        let join = join_of(&root);
+        assert_eq!(join.op, JoinOpType::LeftOuter);
+        assert_eq!((join.left_keys, join.right_keys), (vec![0], vec![0]));
+        assert!(join.condition.is_some());
+
+        let root = inflate(
+            join_inflator(),
+            "SELECT c1, c3 FROM foo JOIN bar ON foo.c1 < bar.c3",
+        )
+        .await
+        .unwrap();
+        let join = join_of(&root);
+        assert_eq!(join.op, JoinOpType::Theta);
+        assert!(join.left_keys.is_empty());
+
+        let root = inflate(
+            join_inflator(),
+            "SELECT c1 FROM foo WHERE c2 > 1 AND c0 IN (SELECT c3 FROM bar)",
+        )
+        .await
+        .unwrap();
+        let join = join_of(&root);
+        assert_eq!(join.op, JoinOpType::Semi);
+        assert_eq!((&join.left_keys, &join.right_keys), (&vec![0], &vec![0]));
+        assert_eq!(join.output_columns(), vec!["foo.c0", "foo.c1", "foo.c2"]);
+
+        let root = inflate(
+            join_inflator(),
+            "SELECT c1 FROM foo WHERE NOT c0 IN (SELECT c3 FROM bar)",
+        )
+        .await
+        .unwrap();
+        assert_eq!(join_of(&root).op, JoinOpType::Anti);
 
+        let root = inflate(
+            join_inflator(),
+            "SELECT c1 FROM foo WHERE c0 NOT IN (SELECT c3 FROM bar)",
+        )
+        .await
+        .unwrap();
+        assert_eq!(join_of(&root).op, JoinOpType::Anti);
+
+        // EXISTS and NOT EXISTS join without keys
+        let root = inflate(
+            join_inflator(),
+            "SELECT c1 FROM foo WHERE NOT EXISTS (SELECT c3 FROM bar WHERE c3 > 1)",
+        )
+        .await
+        .unwrap();
+        let join = join_of(&root);
+        assert_eq!(join.op, JoinOpType::Anti);
+        assert!(join.left_keys.is_empty());
+
+        let root = inflate(
+            join_inflator(),
+            "SELECT c1, c3 FROM foo RIGHT JOIN bar USING (c0)",
+        )
+        .await
+        .unwrap();
+        let join = join_of(&root);
+        assert_eq!(join.op, JoinOpType::RightOuter);
+        assert_eq!(
+            join.output_columns(),
+            vec!["foo.c0|bar.c0", "foo.c1", "foo.c2", "bar.c3"]
+        );
+        assert_eq!((join.left_keys, join.right_keys), (vec![0], vec![0]));
+
+        // USING keeps one copy of its columns, so they can be selected without a qualifier
+        let root = inflate(
+            join_inflator(),
+            "SELECT c0, c3 FROM foo JOIN bar USING (c0)",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            root.graph.as_ref().unwrap().columns.as_ref().unwrap(),
+            &vec!["foo.c0", "bar.c3"]
+        );
+
+        // and with the qualifier of either side, as the merged column of both
         for query in [
-            "SELECT * FROM FOO WHERE c0",
-            "SELECT * FROM FOO WHERE false OR true",
-            "SELECT * FROM FOO WHERE 2 in (1, 2, 3)",
-            "SELECT * FROM FOO WHERE  (SELECT true from BAR)",
+            "SELECT bar.c0, c3 FROM foo JOIN bar USING (c0)",
+            "SELECT bar.* FROM foo JOIN bar USING (c0)",
         ]
         .iter()
         {
-            let sql_query = parse_query(query).expect("Failed to parse test query");
-            log::trace!("Testing condition predicate from {:#?}", sql_query);
+            let root = inflate(join_inflator(), query).await.unwrap();
+            assert_eq!(
+                root.graph.as_ref().unwrap().columns.as_ref().unwrap(),
+                &vec!["foo.c0", "bar.c3"],
+                "{}",
+                query
+            );
+        }
+
+        let root = inflate(
+            join_inflator(),
+            "SELECT c1, c3 FROM foo FULL OUTER JOIN bar ON foo.c0 = bar.c0",
+        )
+        .await
+        .unwrap();
+        assert_eq!(join_of(&root).op, JoinOpType::FullOuter);
+
+        let root = inflate(join_inflator(), "SELECT c1, c3 FROM foo NATURAL JOIN bar")
+            .await
+            .unwrap();
+        let join = join_of(&root);
+        assert_eq!((join.left_keys, join.right_keys), (vec![0], vec![0]));
+
+        let root = inflate(join_inflator(), "SELECT c1, c3 FROM foo CROSS JOIN bar")
+            .await
+            .unwrap();
+        let project = match root.graph.as_ref().unwrap().info.input {
+            NodeInput::Single(ref project) => project,
+            _ => panic!("Expected a projection under the reorder"),
+        };
+        match project.info.input {
+            NodeInput::Single(ref product) => assert!(matches!(
+                product.info.personality,
+                NodeType::Op(OpType::Set(SetOpType::Product, false))
+            )),
+            _ => panic!("Expected a product under the projection"),
+        }
+
+        let err = inflate(
+            join_inflator(),
+            "SELECT c1 FROM foo WHERE c0 IN (SELECT c0, c3 FROM bar)",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.error_status_code, 400);
+    }
+
+    #[actix_rt::test]
+    async fn test_set_records() {
+        setup();
+
+        let left: &[&[i64]] = &[&[1, 1], &[2, 2], &[1, 1], &[3, 3]];
+        let right: &[&[i64]] = &[&[2, 2], &[4, 4], &[4, 4]];
+
+        let union = run_double(OpType::Set(SetOpType::Union, false), left, right).await;
+        assert_eq!(union.len(), 7);
+        let union = run_double(OpType::Set(SetOpType::Union, true), left, right).await;
+        assert_eq!(
+            ints(&union),
+            vec![
+                vec![Some(1), Some(1)],
+                vec![Some(2), Some(2)],
+                vec![Some(3), Some(3)],
+                vec![Some(4), Some(4)]
+            ]
+        );
+
+        let difference = run_double(OpType::Set(SetOpType::Difference, true), left, right).await;
+        assert_eq!(
+            ints(&difference),
+            vec![vec![Some(1), Some(1)], vec![Some(3), Some(3)]]
+        );
+
+        let product =
+            run_double(OpType::Set(SetOpType::Product, false), &[&[1], &[2]], right).await;
+        assert_eq!(product.len(), 6);
+        assert_eq!(
+            ints(&product[..3]),
+            vec![
+                vec![Some(1), Some(2), Some(2)],
+                vec![Some(1), Some(4), Some(4)],
+                vec![Some(1), Some(4), Some(4)]
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_inflate_compound_select() {
+        setup();
+
+        // The trailing ORDER BY and LIMIT sort the whole union rather than its last SELECT
+        let root = inflate(
+            join_inflator(),
+            "SELECT c0 FROM foo UNION ALL SELECT c3 FROM bar ORDER BY c0 DESC LIMIT 5",
+        )
+        .await
+        .unwrap();
+        let top = root.graph.as_ref().unwrap();
+        let union = match (&top.info.personality, &top.info.input) {
+            (NodeType::Op(OpType::TopN(keys, 5, 0)), NodeInput::Single(union)) => {
+                assert_eq!(
+                    keys,
+                    &vec![SortKey {
+                        column: 0,
+                        ascending: false
+                    }]
+                );
+                union
+            }
+            _ => panic!("Expected a top-n over the union"),
+        };
+        assert_eq!(union.columns.as_ref().unwrap(), &vec!["foo.c0"]);
+        match (&union.info.personality, &union.info.input) {
+            (NodeType::Op(OpType::Set(SetOpType::Union, false)), NodeInput::Double(_, right)) => {
+                assert_eq!(right.columns.as_ref().unwrap(), &vec!["bar.c3"])
+            }
+            _ => panic!("Expected a union of the two selects"),
+        }
+
+        let root = inflate(
+            join_inflator(),
+            "SELECT c0 FROM foo UNION SELECT c0 FROM bar EXCEPT SELECT c3 FROM bar",
+        )
+        .await
+        .unwrap();
+        let except = root.graph.as_ref().unwrap();
+        match (&except.info.personality, &except.info.input) {
+            (
+                NodeType::Op(OpType::Set(SetOpType::Difference, true)),
+                NodeInput::Double(left, _),
+            ) => match left.info.personality {
+                NodeType::Op(OpType::Set(SetOpType::Union, true)) => (),
+                _ => panic!("Expected the union to be the left input"),
+            },
+            _ => panic!("Expected a difference over the union"),
+        }
 
-            if let SqlQuery::Select(select_stmt) = sql_query {
-                let predicate = ConditionPredicate::try_from(&select_stmt.where_clause);
-                assert!(predicate.is_err());
+        let err = inflate(
+            join_inflator(),
+            "SELECT c0, c1 FROM foo UNION SELECT c3 FROM bar",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.error_status_code, 400);
+    }
+
+    #[actix_rt::test]
+    async fn test_inflate_distinct() {
+        setup();
 
-                // TODO: we want is_ok and check correctness
-            } else {
-                assert!(false);
+        // Duplicates are dropped before the sort, over the selected columns only
+        let root = inflate(
+            inflator(),
+            "SELECT DISTINCT c1, c0 FROM foo ORDER BY c0 DESC",
+        )
+        .await
+        .unwrap();
+        let sort = root.graph.as_ref().unwrap();
+        match (&sort.info.personality, &sort.info.input) {
+            (NodeType::Op(OpType::Sort(_)), NodeInput::Single(distinct)) => {
+                assert_eq!(
+                    distinct.columns.as_ref().unwrap(),
+                    &vec!["foo.c1", "foo.c0"]
+                );
+                match distinct.info.personality {
+                    NodeType::Op(OpType::Distinct) => (),
+                    _ => panic!("Expected a distinct under the sort"),
+                }
             }
+            _ => panic!("Expected a sort over the distinct"),
         }
+
+        let err = inflate(inflator(), "SELECT DISTINCT c1 FROM foo ORDER BY c0")
+            .await
+            .unwrap_err();
+        assert_eq!(err.error_status_code, 400);
+
+        // Floats equal to integers, and both zeros, are the same value
+        let records = vec![
+            record(vec![Box::new(1_i64), Box::new(String::from("a"))]),
+            record(vec![Box::new(1.0_f64), Box::new(String::from("a"))]),
+            record(vec![Box::new(0.0_f64), Box::new(String::from("a"))]),
+            record(vec![Box::new(-0.0_f64), Box::new(String::from("a"))]),
+            record(vec![Box::new(1_i64), Box::new(String::from("b"))]),
+            record(vec![Box::new(Null::default()), Box::new(String::from("b"))]),
+            record(vec![Box::new(Null::default()), Box::new(String::from("b"))]),
+        ];
+        let distinct = run_op(OpType::Distinct, records).await;
+        assert_eq!(distinct.len(), 4);
     }
 
     #[actix_rt::test]
-    async fn test_condition_predicate_arithmetic() {
+    async fn test_inflate_subqueries() {
+        setup();
+
+        // A scalar subquery is appended to the relation, filtered on, and projected away
+        let root = inflate(
+            join_inflator(),
+            "SELECT c1 FROM foo WHERE c0 > (SELECT max(c3) FROM bar) OR c1 = (SELECT max(c3) FROM bar)",
+        )
+        .await
+        .unwrap();
+        let project = match root.graph.as_ref().unwrap().info.input {
+            NodeInput::Single(ref project) => project,
+            _ => panic!("Expected a projection under the reorder"),
+        };
+        let drop = match project.info.input {
+            NodeInput::Single(ref drop) => drop,
+            _ => panic!("Expected the scalar to be projected away"),
+        };
+        assert_eq!(
+            drop.columns.as_ref().unwrap(),
+            &vec!["foo.c0", "foo.c1", "foo.c2"]
+        );
+        let select = match drop.info.input {
+            NodeInput::Single(ref select) => select,
+            _ => panic!("Expected a select under the projection"),
+        };
+        let product = match (&select.info.personality, &select.info.input) {
+            (NodeType::Op(OpType::Select(_)), NodeInput::Single(product)) => product,
+            _ => panic!("Expected the WHERE clause to filter the product"),
+        };
+        // The repeated subquery shares one column
+        assert_eq!(
+            product.columns.as_ref().unwrap(),
+            &vec!["foo.c0", "foo.c1", "foo.c2", "$subquery0.value"]
+        );
+        match (&product.info.personality, &product.info.input) {
+            (
+                NodeType::Op(OpType::Set(SetOpType::Product, false)),
+                NodeInput::Double(_, scalar),
+            ) => match scalar.info.personality {
+                NodeType::Op(OpType::Scalar) => (),
+                _ => panic!("Expected a scalar subquery"),
+            },
+            _ => panic!("Expected a product with the scalar subquery"),
+        }
+
+        // A bare subquery is tested for truth like a column
+        inflate(
+            join_inflator(),
+            "SELECT c1 FROM foo WHERE (SELECT c3 FROM bar)",
+        )
+        .await
+        .unwrap();
+
+        // Derived tables are renamed for their alias
+        let root = inflate(
+            join_inflator(),
+            "SELECT foo.c1, t.c3 FROM foo JOIN (SELECT c0, c3 FROM bar WHERE c3 > 1) AS t ON foo.c0 = t.c0",
+        )
+        .await
+        .unwrap();
+        let project = match root.graph.as_ref().unwrap().info.input {
+            NodeInput::Single(ref project) => project,
+            _ => panic!("Expected a projection under the reorder"),
+        };
+        match project.info.input {
+            NodeInput::Single(ref join) => match join.info.input {
+                NodeInput::Double(_, ref rename) => {
+                    assert_eq!(rename.columns.as_ref().unwrap(), &vec!["t.c0", "t.c3"]);
+                    assert!(matches!(
+                        rename.info.personality,
+                        NodeType::Op(OpType::Rename)
+                    ));
+                }
+                _ => panic!("Expected a join with the derived table"),
+            },
+            _ => panic!("Expected a join under the projection"),
+        }
+
+        // Correlated subqueries cannot be run on their own
+        let err = inflate(
+            join_inflator(),
+            "SELECT c1 FROM foo WHERE c0 > (SELECT max(c3) FROM bar WHERE bar.c0 = foo.c0)",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.error_status_code, 400);
+    }
+
+    #[actix_rt::test]
+    async fn test_inflate_common_table_expressions() {
         setup();
 
-        // Field, Literal, Literal List, NestedSelect
+        // A CTE is inflated where it is read, renamed for its name and column list
+        let root = inflate(
+            join_inflator(),
+            "WITH big (k, v) AS (SELECT c0, c1 FROM foo WHERE c1 > 1), \
+             bigger AS (SELECT k FROM big WHERE v > 2) \
+             SELECT bigger.k, bar.c3 FROM bigger JOIN bar ON bigger.k = bar.c0",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            root.graph.as_ref().unwrap().columns.as_ref().unwrap(),
+            &vec!["bigger.k", "bar.c3"]
+        );
+        let project = match root.graph.as_ref().unwrap().info.input {
+            NodeInput::Single(ref project) => project,
+            _ => panic!("Expected a projection under the reorder"),
+        };
+        let rename = match project.info.input {
+            NodeInput::Single(ref join) => match join.info.input {
+                NodeInput::Double(ref rename, _) => rename,
+                _ => panic!("Expected a join with the CTE"),
+            },
+            _ => panic!("Expected a join under the projection"),
+        };
+        assert!(matches!(
+            rename.info.personality,
+            NodeType::Op(OpType::Rename)
+        ));
+        assert_eq!(rename.columns.as_ref().unwrap(), &vec!["bigger.k"]);
+
+        // The CTE shadows the table of the same name, and a derived table reads like one
+        inflate(
+            join_inflator(),
+            "WITH bar AS (SELECT c0 FROM foo) SELECT t.c0 FROM (SELECT c0 FROM bar) AS t",
+        )
+        .await
+        .unwrap();
+        inflate(
+            join_inflator(),
+            "WITH t AS (SELECT c0 FROM foo) SELECT c0 FROM t UNION SELECT c3 FROM bar ORDER BY c0",
+        )
+        .await
+        .unwrap();
 
         for query in [
-            "SELECT * FROM FOO WHERE c0 - 1 = 0",
-            "SELECT * FROM FOO WHERE c2 * 3 > 9",
-            "SELECT * FROM FOO WHERE 10 <= c4 / 11",
+            // A CTE cannot read itself or those defined after it
+            "WITH t AS (SELECT c0 FROM t) SELECT c0 FROM t",
+            "WITH a AS (SELECT c0 FROM b), b AS (SELECT c0 FROM foo) SELECT c0 FROM a",
+            "WITH t (x, y) AS (SELECT c0 FROM foo) SELECT x FROM t",
+            "SELECT c0 FROM (SELECT c0 FROM foo)",
         ]
         .iter()
         {
-            let sql_query = parse_query(query).expect("Failed to parse test query");
-            log::trace!("Testing condition predicate from {:#?}", sql_query);
+            assert!(inflate(join_inflator(), query).await.is_err(), "{}", query);
+        }
+    }
 
-            if let SqlQuery::Select(select_stmt) = sql_query {
-                let predicate = ConditionPredicate::try_from(&select_stmt.where_clause);
-                assert!(predicate.is_err());
+    #[actix_rt::test]
+    async fn test_inflate_expressions() {
+        setup();
+
+        // Row expressions are computed before the projection picks them
+        let root = inflate(
+            inflator(),
+            "SELECT c0 * 2 + 1 AS odd, upper(c1), c2 FROM foo ORDER BY odd",
+        )
+        .await
+        .unwrap();
+        let mut node = root.graph.clone().unwrap();
+        assert_eq!(
+            node.columns.as_ref().unwrap(),
+            &vec!["odd", "upper(c1)", "foo.c2"]
+        );
+        let exprs = loop {
+            node = match (&node.info.personality, &node.info.input) {
+                (NodeType::Op(OpType::Compute(exprs)), _) => break exprs.clone(),
+                (_, NodeInput::Single(input)) => input.clone(),
+                _ => panic!("Expected a compute op in the graph"),
+            }
+        };
+        assert_eq!(exprs.len(), 2);
+        assert_eq!(node.columns.as_ref().unwrap().len(), 5);
 
-                // TODO: we want is_ok and check correctness
-            } else {
-                assert!(false);
+        // Expressions over aggregates are computed after the group op
+        let root = inflate(
+            inflator(),
+            "SELECT c1, sum(c0) / count(*) AS mean, coalesce(max(c2), 0) FROM foo GROUP BY c1",
+        )
+        .await
+        .unwrap();
+        let reorder = root.graph.as_ref().unwrap();
+        assert_eq!(
+            reorder.columns.as_ref().unwrap(),
+            &vec!["foo.c1", "mean", "coalesce(max(c2), 0)"]
+        );
+        match (&reorder.info.personality, &reorder.info.input) {
+            (NodeType::Op(OpType::Reorder(indices)), NodeInput::Single(compute)) => {
+                assert_eq!(indices, &vec![0, 4, 5]);
+                assert_eq!(
+                    compute.columns.as_ref().unwrap()[..4].to_vec(),
+                    vec!["foo.c1", "sum(c0)", "count(*)", "max(c2)"]
+                );
             }
+            _ => panic!("Expected a reorder of the computed columns"),
+        }
+
+        for query in [
+            "SELECT 1 + 1 FROM foo",
+            "SELECT CASE WHEN c0 > 1 THEN 'big' ELSE 'small' END FROM foo",
+            "SELECT count(*) * 2, max(c0) FROM foo",
+            "SELECT c1, count(*) FROM foo GROUP BY c1 HAVING count(*) + 1 > 2",
+        ]
+        .iter()
+        {
+            inflate(inflator(), query).await.unwrap();
+        }
+
+        for query in [
+            "SELECT c0 + c9 FROM foo",
+            "SELECT nosuch(c0) FROM foo",
+            "SELECT c0 + count(*) FROM foo",
+            "SELECT c1, c0 + 1 FROM foo GROUP BY c1",
+            "SELECT c1 FROM foo ORDER BY c0 + 1",
+        ]
+        .iter()
+        {
+            let err = inflate(inflator(), query).await.unwrap_err();
+            assert_eq!(err.error_status_code, 400, "{}", query);
         }
     }
 
     #[actix_rt::test]
-    async fn test_condition_predicate_bracketed() {
+    async fn test_inflate_window_functions() {
         setup();
 
+        // Functions sharing a window are evaluated by one window op over the sorted relation
+        let root = inflate(
+            inflator(),
+            "SELECT c0, row_number() OVER (PARTITION BY c1 ORDER BY c2 DESC) AS n, \
+             sum(c0) OVER (PARTITION BY c1 ORDER BY c2 DESC ROWS BETWEEN 1 PRECEDING AND CURRENT ROW) \
+             FROM foo ORDER BY n",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            root.graph.as_ref().unwrap().columns.as_ref().unwrap(),
+            &vec![
+                "foo.c0",
+                "n",
+                "sum(c0) over (partition by c1 order by c2 desc rows between 1 preceding and current row)"
+            ]
+        );
+        let mut node = root.graph.clone().unwrap();
+        let window = loop {
+            node = match (&node.info.personality, &node.info.input) {
+                (NodeType::Op(OpType::Window(window)), NodeInput::Single(sort)) => {
+                    match sort.info.personality {
+                        NodeType::Op(OpType::Sort(ref keys)) => {
+                            assert_eq!(keys, &window.sort_keys())
+                        }
+                        _ => panic!("Expected the window input to be sorted"),
+                    }
+                    break window.clone();
+                }
+                (_, NodeInput::Single(input)) => input.clone(),
+                _ => panic!("Expected a window in the graph"),
+            }
+        };
+        assert_eq!(window.partition, vec![1]);
+        assert_eq!(
+            window.order,
+            vec![SortKey {
+                column: 2,
+                ascending: false
+            }]
+        );
+        assert_eq!(window.functions.len(), 2);
+
         for query in [
-            "SELECT * FROM FOO WHERE (c0 - 1) = 0",
-            "SELECT * FROM FOO WHERE 0 = (c0 - 1)",
-            // "SELECT * FROM FOO WHERE c0 - 1 = (0)",
-            // "SELECT * FROM FOO WHERE 9 < (c2 * 3 + 1)",
-            // "SELECT * FROM FOO WHERE 10 <= ((c4) / 11)",
-            // "SELECT * FROM FOO WHERE (10 <= ((c4) / 11))",
+            "SELECT c0, lag(c0, 2, 0) OVER (ORDER BY c1), lead(c0) OVER (ORDER BY c2) FROM foo",
+            "SELECT rank() OVER (ORDER BY c1), dense_rank() OVER (ORDER BY c1) FROM foo",
+            "SELECT max(c2) OVER () FROM foo",
         ]
         .iter()
         {
-            let sql_query = parse_query(query).expect("Failed to parse test query");
-            log::trace!("Testing condition predicate from {:#?}", sql_query);
-
-            if let SqlQuery::Select(select_stmt) = sql_query {
-                let predicate = ConditionPredicate::try_from(&select_stmt.where_clause);
-                assert!(predicate.is_err());
+            inflate(inflator(), query).await.unwrap();
+        }
 
-                // TODO: we want is_ok and check correctness
-            } else {
-                assert!(false);
-            }
+        for query in [
+            "SELECT c1, count(*), row_number() OVER (ORDER BY c1) FROM foo GROUP BY c1",
+            "SELECT ntile(4) OVER (ORDER BY c1) FROM foo",
+            "SELECT lag(c0, -1) OVER (ORDER BY c1) FROM foo",
+            "SELECT sum(c0) OVER (ORDER BY c1 RANGE BETWEEN 1 PRECEDING AND CURRENT ROW) FROM foo",
+            "SELECT sum(c0) OVER (ORDER BY c1 ROWS BETWEEN UNBOUNDED FOLLOWING AND CURRENT ROW) FROM foo",
+            "SELECT c0 FROM foo ORDER BY row_number() OVER (ORDER BY c1)",
+        ]
+        .iter()
+        {
+            let err = inflate(inflator(), query).await.unwrap_err();
+            assert_eq!(err.error_status_code, 400, "{}", query);
         }
     }
+
+    #[actix_rt::test]
+    async fn test_scalar_and_exists() {
+        setup();
+
+        let scalar = run_op(OpType::Scalar, vec![int_record(&[7])]).await;
+        assert_eq!(ints(&scalar), vec![vec![Some(7)]]);
+        let scalar = run_op(OpType::Scalar, vec![]).await;
+        assert_eq!(ints(&scalar), vec![vec![None]]);
+        let scalar = run_op_results(OpType::Scalar, vec![int_record(&[7]), int_record(&[8])]).await;
+        assert_eq!(scalar.len(), 1);
+        assert_eq!(scalar[0].as_ref().unwrap_err().error_status_code, 400);
+
+        // EXISTS is a semi join without keys, which keeps everything once anything matches
+        let exists = |op| {
+            Join::new(
+                op,
+                &join_columns("l", 2),
+                &join_columns("r", 2),
+                vec![],
+                vec![],
+                None,
+            )
+        };
+        assert_eq!(
+            run_join(exists(JoinOpType::Semi), LEFT, RIGHT).await.len(),
+            4
+        );
+        assert_eq!(run_join(exists(JoinOpType::Semi), LEFT, &[]).await.len(), 0);
+        assert_eq!(
+            run_join(exists(JoinOpType::Anti), LEFT, RIGHT).await.len(),
+            0
+        );
+        assert_eq!(run_join(exists(JoinOpType::Anti), LEFT, &[]).await.len(), 4);
+    }
+
+    #[test]
+    fn test_large_uncached_tables_are_read_from_disk() {
+        setup();
+        let table = |id, size| tables::TableRelation {
+            id,
+            user_id: 1,
+            table_schema_id: 1,
+            name: String::from("foo"),
+            size,
+            created_at: chrono::NaiveDateTime::from_timestamp(0, 0),
+            updated_at: chrono::NaiveDateTime::from_timestamp(0, 0),
+        };
+        let inflator =
+            GraphInflator::new().with_table_cache(vec![2].into_iter().collect(), Some(10));
+        assert!(matches!(
+            inflator.table_io("foo", &table(1, 10)),
+            IoType::Ram(_)
+        ));
+        assert!(
+            matches!(inflator.table_io("foo", &table(1, 11)), IoType::Disk(name) if name == "foo")
+        );
+        assert!(matches!(
+            inflator.table_io("foo", &table(2, 11)),
+            IoType::Ram(_)
+        ));
+        let inflator = GraphInflator::new().with_table_cache(HashSet::new(), None);
+        assert!(matches!(
+            inflator.table_io("foo", &table(1, i64::MAX)),
+            IoType::Ram(_)
+        ));
+    }
 }
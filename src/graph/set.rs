@@ -0,0 +1,63 @@
+use crate::query::{QueryRecord, SqlKey};
+use std::collections::HashSet;
+
+// Set ops compare whole records, so every column takes part in the key. Unlike join keys,
+// NULLs compare equal to each other here, the way DISTINCT treats them.
+
+pub fn record_key(record: &QueryRecord) -> Vec<SqlKey> {
+    record
+        .columns
+        .iter()
+        .map(|value| SqlKey::from(value.as_ref()))
+        .collect()
+}
+
+#[derive(Default)]
+pub struct RecordSet {
+    keys: HashSet<Vec<SqlKey>>,
+}
+
+impl RecordSet {
+    pub fn new() -> RecordSet {
+        RecordSet {
+            keys: HashSet::new(),
+        }
+    }
+
+    /*
+     * Add a record to the set, returning false when an equal record was already there
+     */
+    pub fn insert(&mut self, record: &QueryRecord) -> bool {
+        self.keys.insert(record_key(record))
+    }
+
+    pub fn contains(&self, record: &QueryRecord) -> bool {
+        self.keys.contains(&record_key(record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::{Null, SqlType};
+
+    fn record(columns: Vec<Box<dyn SqlType>>) -> QueryRecord {
+        QueryRecord {
+            columns,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_record_set() {
+        let mut set = RecordSet::new();
+        assert!(set.insert(&record(vec![Box::new(1i64), Box::new(String::from("a"))])));
+        assert!(set.insert(&record(vec![Box::new(1i64), Box::new(String::from("b"))])));
+        assert!(!set.insert(&record(vec![Box::new(1i64), Box::new(String::from("a"))])));
+
+        // NULLs are not distinct from each other
+        assert!(set.insert(&record(vec![Box::new(Null::default()), Box::new(2i64)])));
+        assert!(set.contains(&record(vec![Box::new(Null::default()), Box::new(2i64)])));
+        assert!(!set.contains(&record(vec![Box::new(2i64), Box::new(2i64)])));
+    }
+}
@@ -0,0 +1,559 @@
+use crate::{
+    error_handler::CustomError,
+    query::{
+        as_bool, as_decimal, as_f64, as_i64, as_str, as_timestamp, is_null, QueryRecord, SqlType,
+    },
+};
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    env,
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Lines, Write},
+    mem,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+// Sorting buffers records until the memory budget is spent, then spills the buffer as a sorted
+// run to disk. The runs are merged back together when the input is exhausted.
+
+const DEFAULT_SORT_MEMORY_BUDGET: usize = 256 << 20;
+
+// Before any records are seen, string values are assumed to be about this many bytes
+const ESTIMATED_STRING_LEN: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortKey {
+    pub column: usize,
+    pub ascending: bool,
+}
+
+/*
+ * A total order over values of any type: NULL, then bools, numbers, times and strings
+ */
+pub fn order_values(left: &dyn SqlType, right: &dyn SqlType) -> Ordering {
+    fn rank(value: &dyn SqlType) -> u8 {
+        if is_null(value) {
+            0
+        } else if as_bool(value).is_some() {
+            1
+        } else if as_f64(value).is_some() {
+            2
+        } else if as_timestamp(value).is_some() {
+            3
+        } else if as_str(value).is_some() {
+            4
+        } else {
+            5
+        }
+    }
+
+    if let (Some(l), Some(r)) = (as_i64(left), as_i64(right)) {
+        return l.cmp(&r);
+    }
+    if let (Some(l), Some(r)) = (as_decimal(left), as_decimal(right)) {
+        return l.cmp(&r);
+    }
+    if let (Some(l), Some(r)) = (as_f64(left), as_f64(right)) {
+        return l.partial_cmp(&r).unwrap_or_else(|| l.total_cmp(&r));
+    }
+    if let (Some(l), Some(r)) = (as_str(left), as_str(right)) {
+        return l.cmp(r);
+    }
+    if let (Some(l), Some(r)) = (as_bool(left), as_bool(right)) {
+        return l.cmp(&r);
+    }
+    if let (Some(l), Some(r)) = (as_timestamp(left), as_timestamp(right)) {
+        return l.cmp(&r);
+    }
+    rank(left).cmp(&rank(right))
+}
+
+pub fn order_records(keys: &[SortKey], left: &QueryRecord, right: &QueryRecord) -> Ordering {
+    for key in keys.iter() {
+        let ordering = match (left.columns.get(key.column), right.columns.get(key.column)) {
+            (Some(l), Some(r)) => order_values(l.as_ref(), r.as_ref()),
+            (l, r) => l.is_some().cmp(&r.is_some()),
+        };
+        let ordering = match key.ascending {
+            true => ordering,
+            false => ordering.reverse(),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+/*
+ * A rough count of the bytes held by a record, used to enforce the memory budget
+ */
+pub fn approximate_size(record: &QueryRecord) -> usize {
+    mem::size_of::<QueryRecord>()
+        + record
+            .columns
+            .iter()
+            .map(|c| {
+                mem::size_of::<Box<dyn SqlType>>()
+                    + match as_str(c.as_ref()) {
+                        Some(v) => mem::size_of::<String>() + v.len(),
+                        None => mem::size_of::<f64>(),
+                    }
+            })
+            .sum::<usize>()
+}
+
+/*
+ * A rough count of the bytes held by a record of the given width, before its values are known
+ */
+pub fn estimated_size(width: usize) -> usize {
+    mem::size_of::<QueryRecord>()
+        + width
+            * (mem::size_of::<Box<dyn SqlType>>() + mem::size_of::<String>() + ESTIMATED_STRING_LEN)
+}
+
+#[derive(Debug, Clone)]
+pub struct SortConfig {
+    pub memory_budget: usize,
+    pub spill_dir: PathBuf,
+}
+
+impl SortConfig {
+    /*
+     * Read SORT_MEMORY_BUDGET (bytes) and SORT_SPILL_DIR, defaulting to 256 MiB and the temp dir
+     */
+    pub fn from_env() -> SortConfig {
+        let memory_budget = match env::var("SORT_MEMORY_BUDGET") {
+            Ok(budget) => budget.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid SORT_MEMORY_BUDGET {}", budget);
+                DEFAULT_SORT_MEMORY_BUDGET
+            }),
+            Err(_) => DEFAULT_SORT_MEMORY_BUDGET,
+        };
+        let spill_dir = match env::var("SORT_SPILL_DIR") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => env::temp_dir(),
+        };
+        SortConfig {
+            memory_budget,
+            spill_dir,
+        }
+    }
+}
+
+/*
+ * A file of NDJSON records spilled to disk, which is removed once it is dropped
+ */
+pub struct SpillFile {
+    path: PathBuf,
+}
+
+impl SpillFile {
+    pub fn new(config: &SortConfig, kind: &str) -> SpillFile {
+        SpillFile {
+            path: config.spill_dir.join(format!(
+                "hetnetdb-{}-{}.ndjson",
+                kind,
+                uuid::Uuid::new_v4()
+            )),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            log::warn!("Failed to remove spill file {:?}: {:?}", self.path, err);
+        }
+    }
+}
+
+pub struct ExternalSorter {
+    keys: Vec<SortKey>,
+    config: SortConfig,
+    buffer: Vec<QueryRecord>,
+    buffer_size: usize,
+    runs: Vec<SpillFile>,
+}
+
+impl ExternalSorter {
+    pub fn new(keys: &[SortKey], config: SortConfig) -> ExternalSorter {
+        ExternalSorter {
+            keys: keys.to_vec(),
+            config,
+            buffer: vec![],
+            buffer_size: 0,
+            runs: vec![],
+        }
+    }
+
+    pub fn push(&mut self, record: QueryRecord) -> Result<(), CustomError> {
+        self.buffer_size += approximate_size(&record);
+        self.buffer.push(record);
+        if self.buffer_size > self.config.memory_budget {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    pub fn spilled_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    fn sort_buffer(&mut self) {
+        let keys = &self.keys;
+        self.buffer.sort_by(|l, r| order_records(keys, l, r));
+    }
+
+    fn spill(&mut self) -> Result<(), CustomError> {
+        self.sort_buffer();
+        let run = SpillFile::new(&self.config, "sort");
+        log::debug!(
+            "Spilling {} sorted records ({} bytes) to {:?}",
+            self.buffer.len(),
+            self.buffer_size,
+            run.path
+        );
+
+        let mut writer = BufWriter::new(File::create(&run.path)?);
+        for record in self.buffer.drain(..) {
+            serde_json::to_writer(&mut writer, &record)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+
+        self.buffer_size = 0;
+        self.runs.push(run);
+        Ok(())
+    }
+
+    /*
+     * Yield every record in order, merging the spilled runs with what is still buffered
+     */
+    pub fn finish(mut self) -> Result<SortedRecords, CustomError> {
+        self.sort_buffer();
+        if self.runs.is_empty() {
+            return Ok(SortedRecords {
+                merge: Merge::Memory(self.buffer.into_iter()),
+            });
+        }
+
+        let mut sources = vec![];
+        for run in self.runs.into_iter() {
+            let lines = BufReader::new(File::open(&run.path)?).lines();
+            sources.push(RunSource::Disk { lines, _file: run });
+        }
+        sources.push(RunSource::Memory(self.buffer.into_iter()));
+
+        let keys = Rc::new(self.keys);
+        let mut heap = BinaryHeap::new();
+        for (run, source) in sources.iter_mut().enumerate() {
+            if let Some(record) = source.next().transpose()? {
+                heap.push(Reverse(RankedRecord {
+                    keys: keys.clone(),
+                    record,
+                    rank: run,
+                }));
+            }
+        }
+        Ok(SortedRecords {
+            merge: Merge::Runs(heap, sources),
+        })
+    }
+}
+
+enum RunSource {
+    // The spill file is held so that it is only removed once the run is read
+    Disk {
+        lines: Lines<BufReader<File>>,
+        _file: SpillFile,
+    },
+    Memory(std::vec::IntoIter<QueryRecord>),
+}
+
+impl Iterator for RunSource {
+    type Item = Result<QueryRecord, CustomError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            RunSource::Disk { lines, .. } => lines.next().map(|line| {
+                let line = line?;
+                Ok(serde_json::from_str(&line)?)
+            }),
+            RunSource::Memory(records) => records.next().map(Ok),
+        }
+    }
+}
+
+/*
+ * A record in a heap, ordered by the sort keys and then by rank, i.e. the run it was read
+ * from or the order it arrived in, which keeps sorting stable
+ */
+struct RankedRecord {
+    keys: Rc<Vec<SortKey>>,
+    record: QueryRecord,
+    rank: usize,
+}
+
+impl Ord for RankedRecord {
+    fn cmp(&self, other: &Self) -> Ordering {
+        order_records(&self.keys, &self.record, &other.record).then(self.rank.cmp(&other.rank))
+    }
+}
+
+impl PartialOrd for RankedRecord {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for RankedRecord {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RankedRecord {}
+
+pub struct SortedRecords {
+    merge: Merge,
+}
+
+enum Merge {
+    Memory(std::vec::IntoIter<QueryRecord>),
+    Runs(BinaryHeap<Reverse<RankedRecord>>, Vec<RunSource>),
+}
+
+impl Iterator for SortedRecords {
+    type Item = Result<QueryRecord, CustomError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.merge {
+            Merge::Memory(records) => records.next().map(Ok),
+            Merge::Runs(heap, sources) => {
+                let Reverse(entry) = heap.pop()?;
+                match sources[entry.rank].next() {
+                    Some(Ok(record)) => heap.push(Reverse(RankedRecord {
+                        keys: entry.keys.clone(),
+                        record,
+                        rank: entry.rank,
+                    })),
+                    Some(Err(err)) => return Some(Err(err)),
+                    None => (),
+                }
+                Some(Ok(entry.record))
+            }
+        }
+    }
+}
+
+/*
+ * Keep only the first `limit` records past `offset` in a bounded max-heap, where each record
+ * either displaces the current last of the top records or is dropped
+ */
+pub struct TopN {
+    keys: Rc<Vec<SortKey>>,
+    limit: usize,
+    offset: usize,
+    heap: BinaryHeap<RankedRecord>,
+    pushed: usize,
+}
+
+impl TopN {
+    /*
+     * Whether the heap of top records of the given width is expected to fit the memory budget
+     */
+    pub fn fits(limit: u64, offset: u64, width: usize, memory_budget: usize) -> bool {
+        (limit as usize)
+            .saturating_add(offset as usize)
+            .saturating_mul(estimated_size(width))
+            <= memory_budget
+    }
+
+    pub fn new(keys: &[SortKey], limit: u64, offset: u64) -> TopN {
+        TopN {
+            keys: Rc::new(keys.to_vec()),
+            limit: limit as usize,
+            offset: offset as usize,
+            heap: BinaryHeap::new(),
+            pushed: 0,
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.limit.saturating_add(self.offset)
+    }
+
+    pub fn push(&mut self, record: QueryRecord) {
+        let entry = RankedRecord {
+            keys: self.keys.clone(),
+            record,
+            rank: self.pushed,
+        };
+        self.pushed += 1;
+
+        if self.heap.len() < self.capacity() {
+            self.heap.push(entry);
+        } else if let Some(mut last) = self.heap.peek_mut() {
+            if entry < *last {
+                *last = entry;
+            }
+        }
+    }
+
+    pub fn finish(self) -> Vec<QueryRecord> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .skip(self.offset)
+            .map(|entry| entry.record)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::Null;
+
+    fn record(columns: Vec<Box<dyn SqlType>>) -> QueryRecord {
+        QueryRecord {
+            columns,
+            ..Default::default()
+        }
+    }
+
+    fn sort(keys: &[SortKey], config: SortConfig, records: &[QueryRecord]) -> Vec<QueryRecord> {
+        let mut sorter = ExternalSorter::new(keys, config);
+        for r in records.iter() {
+            sorter.push(r.clone()).unwrap();
+        }
+        sorter.finish().unwrap().map(|r| r.unwrap()).collect()
+    }
+
+    #[actix_rt::test]
+    async fn test_order_values() {
+        let values: Vec<Box<dyn SqlType>> = vec![
+            Box::new(String::from("b")),
+            Box::new(2.5_f64),
+            Box::new(Null::default()),
+            Box::new(String::from("a")),
+            Box::new(-1_i64),
+            Box::new(3_i64),
+        ];
+        let mut sorted = values.clone();
+        sorted.sort_by(|l, r| order_values(l.as_ref(), r.as_ref()));
+        assert_eq!(
+            format!("{:?}", sorted),
+            format!(
+                "{:?}",
+                vec![&values[2], &values[4], &values[1], &values[5], &values[3], &values[0]]
+            )
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_external_sort_spills_and_merges() {
+        let spill_dir =
+            env::temp_dir().join(format!("hetnetdb-sort-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&spill_dir).unwrap();
+
+        // Sort on the first column descending, then the second ascending, over sequence numbers
+        let records: Vec<QueryRecord> = (0..500_i64)
+            .map(|i| record(vec![Box::new(i % 7), Box::new((i * 31) % 11), Box::new(i)]))
+            .collect();
+        let keys = vec![
+            SortKey {
+                column: 0,
+                ascending: false,
+            },
+            SortKey {
+                column: 1,
+                ascending: true,
+            },
+        ];
+
+        let mut sorter = ExternalSorter::new(
+            &keys,
+            SortConfig {
+                memory_budget: 2048,
+                spill_dir: spill_dir.clone(),
+            },
+        );
+        for r in records.iter() {
+            sorter.push(r.clone()).unwrap();
+        }
+        assert!(sorter.spilled_runs() > 1);
+        assert!(fs::read_dir(&spill_dir).unwrap().count() > 1);
+        let spilled: Vec<QueryRecord> = sorter.finish().unwrap().map(|r| r.unwrap()).collect();
+
+        let in_memory = sort(
+            &keys,
+            SortConfig {
+                memory_budget: usize::MAX,
+                spill_dir: spill_dir.clone(),
+            },
+            &records,
+        );
+        let sequence = |records: &[QueryRecord]| -> Vec<i64> {
+            records
+                .iter()
+                .map(|r| as_i64(r.columns[2].as_ref()).unwrap())
+                .collect()
+        };
+        assert_eq!(spilled.len(), 500);
+        assert_eq!(sequence(&spilled), sequence(&in_memory));
+        assert!(spilled.windows(2).all(|w| {
+            order_records(&keys, &w[0], &w[1]) != Ordering::Greater
+                && (order_records(&keys, &w[0], &w[1]) == Ordering::Less
+                    || as_i64(w[0].columns[2].as_ref()) < as_i64(w[1].columns[2].as_ref()))
+        }));
+
+        assert_eq!(fs::read_dir(&spill_dir).unwrap().count(), 0);
+        fs::remove_dir(&spill_dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_top_n_matches_sort() {
+        let records: Vec<QueryRecord> = (0..200_i64)
+            .map(|i| record(vec![Box::new((i * 37) % 13), Box::new(i)]))
+            .collect();
+        let keys = vec![SortKey {
+            column: 0,
+            ascending: false,
+        }];
+        let sorted = sort(
+            &keys,
+            SortConfig {
+                memory_budget: usize::MAX,
+                spill_dir: env::temp_dir(),
+            },
+            &records,
+        );
+
+        for (limit, offset) in [(10, 0), (10, 25), (0, 5), (300, 0), (5, 198)].iter() {
+            let mut top = TopN::new(&keys, *limit, *offset);
+            for r in records.iter() {
+                top.push(r.clone());
+            }
+            let expected: Vec<String> = sorted
+                .iter()
+                .skip(*offset as usize)
+                .take(*limit as usize)
+                .map(|r| format!("{:?}", r.columns))
+                .collect();
+            let actual: Vec<String> = top
+                .finish()
+                .iter()
+                .map(|r| format!("{:?}", r.columns))
+                .collect();
+            assert_eq!(actual, expected, "LIMIT {} OFFSET {}", limit, offset);
+        }
+    }
+}
@@ -0,0 +1,479 @@
+use super::{order_records, Accumulator, Aggregate, SortKey};
+use crate::{
+    error_handler::CustomError,
+    query::{Null, QueryRecord, SqlType, WindowFrame, WindowFrameBound, WindowFrameUnits},
+};
+use std::{cmp::Ordering, mem, vec};
+
+// Window functions compute a value for every row from the other rows of its partition. The rows
+// arrive sorted on the partition and then the window order, so one partition is held at a time.
+
+#[derive(Debug, Clone)]
+pub enum WindowFunction {
+    RowNumber,
+    Rank,
+    DenseRank,
+    Lag(usize, usize, Box<dyn SqlType>),
+    Lead(usize, usize, Box<dyn SqlType>),
+    Aggregate(Aggregate, WindowFrame),
+}
+
+/*
+ * Window functions that share a PARTITION BY and ORDER BY, appended to the record in order
+ */
+#[derive(Debug, Clone)]
+pub struct Window {
+    pub partition: Vec<usize>,
+    pub order: Vec<SortKey>,
+    pub functions: Vec<WindowFunction>,
+}
+
+impl Window {
+    /*
+     * The order the input must arrive in, which groups the rows of each partition together
+     */
+    pub fn sort_keys(&self) -> Vec<SortKey> {
+        self.partition
+            .iter()
+            .map(|column| SortKey {
+                column: *column,
+                ascending: true,
+            })
+            .chain(self.order.iter().cloned())
+            .collect()
+    }
+
+    /*
+     * Append the value of every window function to each row of a partition
+     */
+    fn evaluate(&self, mut rows: Vec<QueryRecord>) -> Result<Vec<QueryRecord>, CustomError> {
+        let peers = Peers::new(&self.order, &rows);
+        let mut values: Vec<vec::IntoIter<Box<dyn SqlType>>> = vec![];
+        for function in self.functions.iter() {
+            let function_values: Vec<Box<dyn SqlType>> = match function {
+                WindowFunction::RowNumber => (1..=rows.len() as i64)
+                    .map(|n| Box::new(n) as Box<dyn SqlType>)
+                    .collect(),
+                WindowFunction::Rank => (0..rows.len())
+                    .map(|i| Box::new(peers.start[i] as i64 + 1) as Box<dyn SqlType>)
+                    .collect(),
+                WindowFunction::DenseRank => (0..rows.len())
+                    .map(|i| Box::new(peers.group[i] as i64 + 1) as Box<dyn SqlType>)
+                    .collect(),
+                WindowFunction::Lag(column, offset, default) => (0..rows.len())
+                    .map(|i| match i.checked_sub(*offset) {
+                        Some(j) => shifted(&rows[j], *column),
+                        None => default.clone(),
+                    })
+                    .collect(),
+                WindowFunction::Lead(column, offset, default) => (0..rows.len())
+                    .map(
+                        |i| match i.checked_add(*offset).filter(|j| *j < rows.len()) {
+                            Some(j) => shifted(&rows[j], *column),
+                            None => default.clone(),
+                        },
+                    )
+                    .collect(),
+                WindowFunction::Aggregate(aggregate, frame) => {
+                    aggregate_frames(aggregate, frame, &rows, &peers)?
+                }
+            };
+            values.push(function_values.into_iter());
+        }
+
+        for row in rows.iter_mut() {
+            row.columns.extend(
+                values
+                    .iter_mut()
+                    .filter_map(|function_values| function_values.next()),
+            );
+        }
+        Ok(rows)
+    }
+}
+
+fn shifted(row: &QueryRecord, column: usize) -> Box<dyn SqlType> {
+    match row.columns.get(column) {
+        Some(value) => value.clone(),
+        None => Box::new(Null::default()),
+    }
+}
+
+/*
+ * Rows that tie on the window order are peers, which share a rank and a RANGE frame
+ */
+struct Peers {
+    start: Vec<usize>,
+    end: Vec<usize>,
+    group: Vec<usize>,
+}
+
+impl Peers {
+    fn new(order: &[SortKey], rows: &[QueryRecord]) -> Peers {
+        let mut peers = Peers {
+            start: Vec::with_capacity(rows.len()),
+            end: vec![0; rows.len()],
+            group: Vec::with_capacity(rows.len()),
+        };
+        for i in 0..rows.len() {
+            match i {
+                0 => {
+                    peers.start.push(0);
+                    peers.group.push(0);
+                }
+                i if order_records(order, &rows[i - 1], &rows[i]) == Ordering::Equal => {
+                    peers.start.push(peers.start[i - 1]);
+                    peers.group.push(peers.group[i - 1]);
+                }
+                i => {
+                    peers.start.push(i);
+                    peers.group.push(peers.group[i - 1] + 1);
+                }
+            }
+        }
+        for i in (0..rows.len()).rev() {
+            peers.end[i] = match i + 1 < rows.len() && peers.start[i + 1] == peers.start[i] {
+                true => peers.end[i + 1],
+                false => i + 1,
+            };
+        }
+        peers
+    }
+}
+
+/*
+ * The rows of the frame around row `i`, as a range of the partition that may be empty
+ */
+fn frame_bounds(
+    frame: &WindowFrame,
+    i: usize,
+    len: usize,
+    peers: &Peers,
+) -> Result<(usize, usize), CustomError> {
+    let rows = frame.units == WindowFrameUnits::Rows;
+    let start = match frame.start {
+        WindowFrameBound::Preceding(None) => 0,
+        WindowFrameBound::Preceding(Some(n)) if rows => i.saturating_sub(n as usize),
+        WindowFrameBound::CurrentRow if rows => i,
+        WindowFrameBound::CurrentRow => peers.start[i],
+        WindowFrameBound::Following(Some(n)) if rows => i.saturating_add(n as usize),
+        WindowFrameBound::Following(None) => len,
+        _ => return Err(unsupported_frame(frame)),
+    };
+    let end = match frame.end {
+        WindowFrameBound::Preceding(None) => 0,
+        WindowFrameBound::Preceding(Some(n)) if rows => (i + 1).saturating_sub(n as usize),
+        WindowFrameBound::CurrentRow if rows => i + 1,
+        WindowFrameBound::CurrentRow => peers.end[i],
+        WindowFrameBound::Following(Some(n)) if rows => i.saturating_add(n as usize + 1),
+        WindowFrameBound::Following(None) => len,
+        _ => return Err(unsupported_frame(frame)),
+    };
+    Ok((start.min(len), end.min(len)))
+}
+
+/*
+ * Frames are checked as the query is inflated, so that a bad frame fails the request up front
+ */
+pub fn check_frame(frame: &WindowFrame) -> Result<(), CustomError> {
+    match (&frame.start, &frame.end) {
+        (WindowFrameBound::Following(None), _) | (_, WindowFrameBound::Preceding(None)) => Err(
+            CustomError::new(400, format!("Bad request: Invalid window frame {}", frame)),
+        ),
+        (WindowFrameBound::Preceding(Some(_)), _)
+        | (WindowFrameBound::Following(Some(_)), _)
+        | (_, WindowFrameBound::Preceding(Some(_)))
+        | (_, WindowFrameBound::Following(Some(_)))
+            if frame.units == WindowFrameUnits::Range =>
+        {
+            Err(unsupported_frame(frame))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn unsupported_frame(frame: &WindowFrame) -> CustomError {
+    CustomError::new(
+        400,
+        format!(
+            "Bad request: Unsupported window frame {}, RANGE frames only support UNBOUNDED and CURRENT ROW",
+            frame
+        ),
+    )
+}
+
+/*
+ * Frames that start at the first row of the partition only grow, so one accumulator runs along
+ * the partition. Sliding frames are aggregated from scratch for each row.
+ */
+fn aggregate_frames(
+    aggregate: &Aggregate,
+    frame: &WindowFrame,
+    rows: &[QueryRecord],
+    peers: &Peers,
+) -> Result<Vec<Box<dyn SqlType>>, CustomError> {
+    let mut values = Vec::with_capacity(rows.len());
+    let mut running = Accumulator::new(aggregate);
+    let mut accumulated = 0;
+    for i in 0..rows.len() {
+        let (start, end) = frame_bounds(frame, i, rows.len(), peers)?;
+        if start == 0 && end >= accumulated {
+            for row in rows[accumulated..end].iter() {
+                running.update(row)?;
+            }
+            accumulated = end;
+            values.push(running.value());
+        } else {
+            let mut accumulator = Accumulator::new(aggregate);
+            for row in rows[start..end.max(start)].iter() {
+                accumulator.update(row)?;
+            }
+            values.push(accumulator.finish());
+        }
+    }
+    Ok(values)
+}
+
+/*
+ * Buffers the rows of the current partition, evaluating it once the next one begins
+ */
+pub struct WindowPartitions {
+    window: Window,
+    keys: Vec<SortKey>,
+    partition: Vec<QueryRecord>,
+}
+
+impl WindowPartitions {
+    pub fn new(window: &Window) -> WindowPartitions {
+        let mut keys = window.sort_keys();
+        keys.truncate(window.partition.len());
+        WindowPartitions {
+            window: window.clone(),
+            keys,
+            partition: vec![],
+        }
+    }
+
+    pub fn push(&mut self, record: QueryRecord) -> Result<Vec<QueryRecord>, CustomError> {
+        let finished = match self.partition.last() {
+            Some(last) if order_records(&self.keys, last, &record) != Ordering::Equal => {
+                let rows = mem::take(&mut self.partition);
+                self.window.evaluate(rows)?
+            }
+            _ => vec![],
+        };
+        self.partition.push(record);
+        Ok(finished)
+    }
+
+    pub fn finish(mut self) -> Result<Vec<QueryRecord>, CustomError> {
+        let rows = mem::take(&mut self.partition);
+        self.window.evaluate(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::AggOpType;
+    use crate::query::{as_f64, as_i64};
+
+    fn int_record(values: &[i64]) -> QueryRecord {
+        QueryRecord {
+            columns: values
+                .iter()
+                .map(|v| Box::new(*v) as Box<dyn SqlType>)
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn run(window: &Window, rows: &[&[i64]]) -> Vec<QueryRecord> {
+        let mut partitions = WindowPartitions::new(window);
+        let mut output = vec![];
+        for row in rows.iter() {
+            output.extend(partitions.push(int_record(row)).unwrap());
+        }
+        output.extend(partitions.finish().unwrap());
+        output
+    }
+
+    fn column(records: &[QueryRecord], i: usize) -> Vec<Option<f64>> {
+        records
+            .iter()
+            .map(|r| as_f64(r.columns[i].as_ref()))
+            .collect()
+    }
+
+    fn window(functions: Vec<WindowFunction>) -> Window {
+        Window {
+            partition: vec![0],
+            order: vec![SortKey {
+                column: 1,
+                ascending: true,
+            }],
+            functions,
+        }
+    }
+
+    // Sorted on (device, time) with a reading, and a tie on time 2 in the first partition
+    const ROWS: &[&[i64]] = &[
+        &[1, 1, 10],
+        &[1, 2, 20],
+        &[1, 2, 30],
+        &[1, 4, 40],
+        &[2, 1, 5],
+        &[2, 3, 7],
+    ];
+
+    #[test]
+    fn test_ranking_functions() {
+        let output = run(
+            &window(vec![
+                WindowFunction::RowNumber,
+                WindowFunction::Rank,
+                WindowFunction::DenseRank,
+            ]),
+            ROWS,
+        );
+        let ints = |i: usize| -> Vec<i64> {
+            output
+                .iter()
+                .map(|r| as_i64(r.columns[i].as_ref()).unwrap())
+                .collect()
+        };
+        assert_eq!(ints(3), vec![1, 2, 3, 4, 1, 2]);
+        assert_eq!(ints(4), vec![1, 2, 2, 4, 1, 2]);
+        assert_eq!(ints(5), vec![1, 2, 2, 3, 1, 2]);
+    }
+
+    #[test]
+    fn test_lag_and_lead() {
+        let output = run(
+            &window(vec![
+                WindowFunction::Lag(2, 1, Box::new(Null::default())),
+                WindowFunction::Lead(2, 2, Box::new(-1_i64)),
+            ]),
+            ROWS,
+        );
+        assert_eq!(
+            column(&output, 3),
+            vec![None, Some(10.0), Some(20.0), Some(30.0), None, Some(5.0)]
+        );
+        assert_eq!(
+            column(&output, 4),
+            vec![
+                Some(30.0),
+                Some(40.0),
+                Some(-1.0),
+                Some(-1.0),
+                Some(-1.0),
+                Some(-1.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_moving_aggregates() {
+        let aggregate = |op| Aggregate {
+            op,
+            column: Some(2),
+            distinct: false,
+        };
+        let rows = |start, end| WindowFrame {
+            units: WindowFrameUnits::Rows,
+            start,
+            end,
+        };
+        let output = run(
+            &window(vec![
+                // The default frame with an ORDER BY includes the peers of the current row
+                WindowFunction::Aggregate(
+                    aggregate(AggOpType::Sum),
+                    WindowFrame {
+                        units: WindowFrameUnits::Range,
+                        start: WindowFrameBound::Preceding(None),
+                        end: WindowFrameBound::CurrentRow,
+                    },
+                ),
+                WindowFunction::Aggregate(
+                    aggregate(AggOpType::Average),
+                    rows(
+                        WindowFrameBound::Preceding(Some(1)),
+                        WindowFrameBound::CurrentRow,
+                    ),
+                ),
+                WindowFunction::Aggregate(
+                    aggregate(AggOpType::Maximum),
+                    rows(
+                        WindowFrameBound::CurrentRow,
+                        WindowFrameBound::Following(Some(1)),
+                    ),
+                ),
+                WindowFunction::Aggregate(
+                    aggregate(AggOpType::Minimum),
+                    rows(
+                        WindowFrameBound::Preceding(Some(3)),
+                        WindowFrameBound::Preceding(Some(2)),
+                    ),
+                ),
+            ]),
+            ROWS,
+        );
+        assert_eq!(
+            column(&output, 3),
+            vec![
+                Some(10.0),
+                Some(60.0),
+                Some(60.0),
+                Some(100.0),
+                Some(5.0),
+                Some(12.0)
+            ]
+        );
+        assert_eq!(
+            column(&output, 4),
+            vec![
+                Some(10.0),
+                Some(15.0),
+                Some(25.0),
+                Some(35.0),
+                Some(5.0),
+                Some(6.0)
+            ]
+        );
+        assert_eq!(
+            column(&output, 5),
+            vec![
+                Some(20.0),
+                Some(30.0),
+                Some(40.0),
+                Some(40.0),
+                Some(7.0),
+                Some(7.0)
+            ]
+        );
+        assert_eq!(
+            column(&output, 6),
+            vec![None, None, Some(10.0), Some(10.0), None, None]
+        );
+    }
+
+    #[test]
+    fn test_range_frames_with_offsets() {
+        let frame = WindowFrame {
+            units: WindowFrameUnits::Range,
+            start: WindowFrameBound::Preceding(Some(1)),
+            end: WindowFrameBound::CurrentRow,
+        };
+        let aggregate = Aggregate {
+            op: AggOpType::Sum,
+            column: Some(2),
+            distinct: false,
+        };
+        let mut partitions =
+            WindowPartitions::new(&window(vec![WindowFunction::Aggregate(aggregate, frame)]));
+        partitions.push(int_record(ROWS[0])).unwrap();
+        assert_eq!(partitions.finish().unwrap_err().error_status_code, 400);
+    }
+}
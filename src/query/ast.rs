@@ -0,0 +1,754 @@
+use crate::error_handler::CustomError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// The logical plan AST that parsed queries are lowered into. The execution graph is inflated
+// from these types alone, so the SQL parser behind them can change without touching the graph.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Statement {
+    Select(SelectQuery),
+}
+
+/*
+ * A full query: its common table expressions, the body, and the ORDER BY and LIMIT that
+ * apply to the body's records
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SelectQuery {
+    pub ctes: Vec<Cte>,
+    pub body: SetExpr,
+    pub order: Vec<OrderBy>,
+    pub limit: Option<u64>,
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cte {
+    pub alias: TableAlias,
+    pub query: SelectQuery,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SetExpr {
+    Select(Box<Select>),
+    Query(Box<SelectQuery>),
+    SetOperation {
+        op: SetOperator,
+        distinct: bool,
+        left: Box<SetExpr>,
+        right: Box<SetExpr>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SetOperator {
+    Union,
+    Except,
+    Intersect,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Select {
+    pub distinct: bool,
+    pub fields: Vec<SelectItem>,
+    pub from: Vec<TableWithJoins>,
+    pub where_clause: Option<Expr>,
+    pub group_by: Vec<Expr>,
+    pub having: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SelectItem {
+    Wildcard,
+    QualifiedWildcard(String),
+    Expr { expr: Expr, alias: Option<String> },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TableWithJoins {
+    pub relation: TableFactor,
+    pub joins: Vec<JoinClause>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TableFactor {
+    Table {
+        name: String,
+        alias: Option<TableAlias>,
+    },
+    Derived {
+        subquery: Box<SelectQuery>,
+        alias: Option<TableAlias>,
+    },
+}
+
+/*
+ * Renames a relation and, when columns are listed, its columns in order
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TableAlias {
+    pub name: String,
+    pub columns: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JoinClause {
+    pub operator: JoinOperator,
+    pub relation: TableFactor,
+    pub constraint: JoinConstraint,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum JoinOperator {
+    Inner,
+    LeftOuter,
+    RightOuter,
+    FullOuter,
+    Cross,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum JoinConstraint {
+    On(Expr),
+    Using(Vec<String>),
+    Natural,
+    None,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderBy {
+    pub expr: Expr,
+    pub ascending: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColumnRef {
+    pub table: Option<String>,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Literal {
+    Null,
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum UnaryOperator {
+    Not,
+    Minus,
+    Plus,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BinaryOperator {
+    And,
+    Or,
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Concat,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Expr {
+    Column(ColumnRef),
+    Literal(Literal),
+    // Only as a function argument, as in COUNT(*)
+    Wildcard,
+    Unary {
+        op: UnaryOperator,
+        expr: Box<Expr>,
+    },
+    Binary {
+        left: Box<Expr>,
+        op: BinaryOperator,
+        right: Box<Expr>,
+    },
+    Nested(Box<Expr>),
+    IsNull {
+        expr: Box<Expr>,
+        negated: bool,
+    },
+    InList {
+        expr: Box<Expr>,
+        list: Vec<Expr>,
+        negated: bool,
+    },
+    InSubquery {
+        expr: Box<Expr>,
+        subquery: Box<SelectQuery>,
+        negated: bool,
+    },
+    Between {
+        expr: Box<Expr>,
+        low: Box<Expr>,
+        high: Box<Expr>,
+        negated: bool,
+    },
+    Like {
+        expr: Box<Expr>,
+        pattern: Box<Expr>,
+        negated: bool,
+        case_insensitive: bool,
+    },
+    Exists {
+        subquery: Box<SelectQuery>,
+        negated: bool,
+    },
+    Subquery(Box<SelectQuery>),
+    Function(Function),
+    Case {
+        operand: Option<Box<Expr>>,
+        branches: Vec<(Expr, Expr)>,
+        else_result: Option<Box<Expr>>,
+    },
+    Cast {
+        expr: Box<Expr>,
+        data_type: String,
+    },
+}
+
+/*
+ * A function call, which is a window function when it has an OVER clause
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Function {
+    pub name: String,
+    pub args: Vec<Expr>,
+    pub distinct: bool,
+    pub over: Option<Box<WindowSpec>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WindowSpec {
+    pub partition_by: Vec<Expr>,
+    pub order: Vec<OrderBy>,
+    pub frame: Option<WindowFrame>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WindowFrame {
+    pub units: WindowFrameUnits,
+    pub start: WindowFrameBound,
+    pub end: WindowFrameBound,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WindowFrameUnits {
+    Rows,
+    Range,
+}
+
+/*
+ * Bounds without an offset are UNBOUNDED PRECEDING and UNBOUNDED FOLLOWING
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WindowFrameBound {
+    Preceding(Option<u64>),
+    CurrentRow,
+    Following(Option<u64>),
+}
+
+impl Expr {
+    pub fn column(table: Option<&str>, name: &str) -> Expr {
+        Expr::Column(ColumnRef {
+            table: table.map(String::from),
+            name: String::from(name),
+        })
+    }
+
+    /*
+     * Rebuild the expression top-down, replacing each node that `replace` returns Some for.
+     * Replacements are not descended into, and neither are subqueries.
+     */
+    pub fn transform<F>(&self, replace: &mut F) -> Result<Expr, CustomError>
+    where
+        F: FnMut(&Expr) -> Result<Option<Expr>, CustomError>,
+    {
+        if let Some(expr) = replace(self)? {
+            return Ok(expr);
+        }
+
+        let mut boxed = |expr: &Expr| expr.transform(replace).map(Box::new);
+        Ok(match self {
+            Expr::Unary { op, expr } => Expr::Unary {
+                op: op.clone(),
+                expr: boxed(expr)?,
+            },
+            Expr::Binary { left, op, right } => Expr::Binary {
+                left: boxed(left)?,
+                op: op.clone(),
+                right: boxed(right)?,
+            },
+            Expr::Nested(expr) => Expr::Nested(boxed(expr)?),
+            Expr::IsNull { expr, negated } => Expr::IsNull {
+                expr: boxed(expr)?,
+                negated: *negated,
+            },
+            Expr::InList {
+                expr,
+                list,
+                negated,
+            } => Expr::InList {
+                expr: boxed(expr)?,
+                list: list
+                    .iter()
+                    .map(|item| item.transform(replace))
+                    .collect::<Result<_, _>>()?,
+                negated: *negated,
+            },
+            Expr::InSubquery {
+                expr,
+                subquery,
+                negated,
+            } => Expr::InSubquery {
+                expr: boxed(expr)?,
+                subquery: subquery.clone(),
+                negated: *negated,
+            },
+            Expr::Between {
+                expr,
+                low,
+                high,
+                negated,
+            } => Expr::Between {
+                expr: boxed(expr)?,
+                low: boxed(low)?,
+                high: boxed(high)?,
+                negated: *negated,
+            },
+            Expr::Like {
+                expr,
+                pattern,
+                negated,
+                case_insensitive,
+            } => Expr::Like {
+                expr: boxed(expr)?,
+                pattern: boxed(pattern)?,
+                negated: *negated,
+                case_insensitive: *case_insensitive,
+            },
+            Expr::Function(func) => Expr::Function(Function {
+                args: func
+                    .args
+                    .iter()
+                    .map(|arg| arg.transform(replace))
+                    .collect::<Result<_, _>>()?,
+                ..func.clone()
+            }),
+            Expr::Case {
+                operand,
+                branches,
+                else_result,
+            } => {
+                let mut transform = |expr: &Expr| expr.transform(replace);
+                Expr::Case {
+                    operand: match operand {
+                        Some(operand) => Some(Box::new(transform(operand)?)),
+                        None => None,
+                    },
+                    branches: branches
+                        .iter()
+                        .map(|(when, then)| Ok((transform(when)?, transform(then)?)))
+                        .collect::<Result<_, CustomError>>()?,
+                    else_result: match else_result {
+                        Some(else_result) => Some(Box::new(transform(else_result)?)),
+                        None => None,
+                    },
+                }
+            }
+            Expr::Cast { expr, data_type } => Expr::Cast {
+                expr: boxed(expr)?,
+                data_type: data_type.clone(),
+            },
+            expr => expr.clone(),
+        })
+    }
+}
+
+impl Function {
+    /*
+     * Aggregates fold many records into one value, unless they are windowed
+     */
+    pub fn is_aggregate(&self) -> bool {
+        self.over.is_none()
+            && matches!(
+                self.name.as_str(),
+                "count" | "sum" | "avg" | "min" | "max" | "group_concat"
+            )
+    }
+}
+
+/*
+ * Write items separated by commas
+ */
+fn write_list<T: fmt::Display>(f: &mut fmt::Formatter, items: &[T]) -> fmt::Result {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", item)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Statement::Select(query) => write!(f, "{}", query),
+        }
+    }
+}
+
+impl fmt::Display for SelectQuery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.ctes.is_empty() {
+            write!(f, "WITH ")?;
+            write_list(f, &self.ctes)?;
+            write!(f, " ")?;
+        }
+        write!(f, "{}", self.body)?;
+        if !self.order.is_empty() {
+            write!(f, " ORDER BY ")?;
+            write_list(f, &self.order)?;
+        }
+        if let Some(limit) = self.limit {
+            write!(f, " LIMIT {}", limit)?;
+        }
+        if self.offset > 0 {
+            write!(f, " OFFSET {}", self.offset)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Cte {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} AS ({})", self.alias, self.query)
+    }
+}
+
+impl fmt::Display for SetExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SetExpr::Select(select) => write!(f, "{}", select),
+            SetExpr::Query(query) => write!(f, "({})", query),
+            SetExpr::SetOperation {
+                op,
+                distinct,
+                left,
+                right,
+            } => {
+                let op = match op {
+                    SetOperator::Union => "UNION",
+                    SetOperator::Except => "EXCEPT",
+                    SetOperator::Intersect => "INTERSECT",
+                };
+                let all = if *distinct { "" } else { " ALL" };
+                write!(f, "{} {}{} {}", left, op, all, right)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Select {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SELECT ")?;
+        if self.distinct {
+            write!(f, "DISTINCT ")?;
+        }
+        write_list(f, &self.fields)?;
+        if !self.from.is_empty() {
+            write!(f, " FROM ")?;
+            write_list(f, &self.from)?;
+        }
+        if let Some(ref condition) = self.where_clause {
+            write!(f, " WHERE {}", condition)?;
+        }
+        if !self.group_by.is_empty() {
+            write!(f, " GROUP BY ")?;
+            write_list(f, &self.group_by)?;
+        }
+        if let Some(ref having) = self.having {
+            write!(f, " HAVING {}", having)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SelectItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SelectItem::Wildcard => write!(f, "*"),
+            SelectItem::QualifiedWildcard(table) => write!(f, "{}.*", table),
+            SelectItem::Expr { expr, alias: None } => write!(f, "{}", expr),
+            SelectItem::Expr {
+                expr,
+                alias: Some(alias),
+            } => write!(f, "{} AS {}", expr, alias),
+        }
+    }
+}
+
+impl fmt::Display for TableWithJoins {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.relation)?;
+        for join in self.joins.iter() {
+            write!(f, " {}", join)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TableFactor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let alias = match self {
+            TableFactor::Table { name, alias } => {
+                write!(f, "{}", name)?;
+                alias
+            }
+            TableFactor::Derived { subquery, alias } => {
+                write!(f, "({})", subquery)?;
+                alias
+            }
+        };
+        match alias {
+            Some(alias) => write!(f, " AS {}", alias),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for TableAlias {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if !self.columns.is_empty() {
+            write!(f, " (")?;
+            write_list(f, &self.columns)?;
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for JoinClause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let natural = match self.constraint {
+            JoinConstraint::Natural => "NATURAL ",
+            _ => "",
+        };
+        let operator = match self.operator {
+            JoinOperator::Inner => "JOIN",
+            JoinOperator::LeftOuter => "LEFT JOIN",
+            JoinOperator::RightOuter => "RIGHT JOIN",
+            JoinOperator::FullOuter => "FULL JOIN",
+            JoinOperator::Cross => "CROSS JOIN",
+        };
+        write!(f, "{}{} {}", natural, operator, self.relation)?;
+        match self.constraint {
+            JoinConstraint::On(ref condition) => write!(f, " ON {}", condition),
+            JoinConstraint::Using(ref columns) => {
+                write!(f, " USING (")?;
+                write_list(f, columns)?;
+                write!(f, ")")
+            }
+            JoinConstraint::Natural | JoinConstraint::None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for OrderBy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.ascending {
+            true => write!(f, "{}", self.expr),
+            false => write!(f, "{} DESC", self.expr),
+        }
+    }
+}
+
+impl fmt::Display for ColumnRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.table {
+            Some(ref table) => write!(f, "{}.{}", table, self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Literal::Null => write!(f, "NULL"),
+            Literal::Boolean(b) => write!(f, "{}", b),
+            Literal::Integer(i) => write!(f, "{}", i),
+            Literal::Float(v) => write!(f, "{:?}", v),
+            Literal::String(s) => write!(f, "'{}'", s.replace('\'', "''")),
+        }
+    }
+}
+
+impl fmt::Display for UnaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            UnaryOperator::Not => "NOT ",
+            UnaryOperator::Minus => "-",
+            UnaryOperator::Plus => "+",
+        })
+    }
+}
+
+impl fmt::Display for BinaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BinaryOperator::And => "AND",
+            BinaryOperator::Or => "OR",
+            BinaryOperator::Equal => "=",
+            BinaryOperator::NotEqual => "!=",
+            BinaryOperator::Less => "<",
+            BinaryOperator::LessOrEqual => "<=",
+            BinaryOperator::Greater => ">",
+            BinaryOperator::GreaterOrEqual => ">=",
+            BinaryOperator::Add => "+",
+            BinaryOperator::Subtract => "-",
+            BinaryOperator::Multiply => "*",
+            BinaryOperator::Divide => "/",
+            BinaryOperator::Modulo => "%",
+            BinaryOperator::Concat => "||",
+        })
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let not = |negated: &bool| if *negated { "NOT " } else { "" };
+        match self {
+            Expr::Column(column) => write!(f, "{}", column),
+            Expr::Literal(literal) => write!(f, "{}", literal),
+            Expr::Wildcard => write!(f, "*"),
+            Expr::Unary { op, expr } => write!(f, "{}{}", op, expr),
+            Expr::Binary { left, op, right } => write!(f, "{} {} {}", left, op, right),
+            Expr::Nested(expr) => write!(f, "({})", expr),
+            Expr::IsNull { expr, negated } => write!(f, "{} IS {}NULL", expr, not(negated)),
+            Expr::InList {
+                expr,
+                list,
+                negated,
+            } => {
+                write!(f, "{} {}IN (", expr, not(negated))?;
+                write_list(f, list)?;
+                write!(f, ")")
+            }
+            Expr::InSubquery {
+                expr,
+                subquery,
+                negated,
+            } => write!(f, "{} {}IN ({})", expr, not(negated), subquery),
+            Expr::Between {
+                expr,
+                low,
+                high,
+                negated,
+            } => write!(f, "{} {}BETWEEN {} AND {}", expr, not(negated), low, high),
+            Expr::Like {
+                expr,
+                pattern,
+                negated,
+                case_insensitive,
+            } => {
+                let like = if *case_insensitive { "ILIKE" } else { "LIKE" };
+                write!(f, "{} {}{} {}", expr, not(negated), like, pattern)
+            }
+            Expr::Exists { subquery, negated } => {
+                write!(f, "{}EXISTS ({})", not(negated), subquery)
+            }
+            Expr::Subquery(subquery) => write!(f, "({})", subquery),
+            Expr::Function(func) => write!(f, "{}", func),
+            Expr::Case {
+                operand,
+                branches,
+                else_result,
+            } => {
+                write!(f, "CASE")?;
+                if let Some(operand) = operand {
+                    write!(f, " {}", operand)?;
+                }
+                for (when, then) in branches.iter() {
+                    write!(f, " WHEN {} THEN {}", when, then)?;
+                }
+                if let Some(else_result) = else_result {
+                    write!(f, " ELSE {}", else_result)?;
+                }
+                write!(f, " END")
+            }
+            Expr::Cast { expr, data_type } => write!(f, "CAST({} AS {})", expr, data_type),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}(", self.name)?;
+        if self.distinct {
+            write!(f, "DISTINCT ")?;
+        }
+        write_list(f, &self.args)?;
+        write!(f, ")")?;
+        match self.over {
+            Some(ref over) => write!(f, " OVER ({})", over),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for WindowSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut clauses = vec![];
+        if !self.partition_by.is_empty() {
+            let exprs: Vec<String> = self.partition_by.iter().map(|e| e.to_string()).collect();
+            clauses.push(format!("PARTITION BY {}", exprs.join(", ")));
+        }
+        if !self.order.is_empty() {
+            let keys: Vec<String> = self.order.iter().map(|o| o.to_string()).collect();
+            clauses.push(format!("ORDER BY {}", keys.join(", ")));
+        }
+        if let Some(ref frame) = self.frame {
+            clauses.push(frame.to_string());
+        }
+        write!(f, "{}", clauses.join(" "))
+    }
+}
+
+impl fmt::Display for WindowFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let units = match self.units {
+            WindowFrameUnits::Rows => "ROWS",
+            WindowFrameUnits::Range => "RANGE",
+        };
+        write!(f, "{} BETWEEN {} AND {}", units, self.start, self.end)
+    }
+}
+
+impl fmt::Display for WindowFrameBound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WindowFrameBound::Preceding(None) => write!(f, "UNBOUNDED PRECEDING"),
+            WindowFrameBound::Preceding(Some(n)) => write!(f, "{} PRECEDING", n),
+            WindowFrameBound::CurrentRow => write!(f, "CURRENT ROW"),
+            WindowFrameBound::Following(None) => write!(f, "UNBOUNDED FOLLOWING"),
+            WindowFrameBound::Following(Some(n)) => write!(f, "{} FOLLOWING", n),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use super::sql_types::*;
+use crate::error_handler::CustomError;
+use std::{fmt, str::FromStr};
+
+// The registry of column types that a table schema may declare. Each type names the
+// SqlType its fields are read into, so unknown types are rejected rather than read as NULL.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    I64,
+    F64,
+    String,
+    Bool,
+    Timestamp,
+    Date,
+    // Precision and scale, as in SQL's DECIMAL(p, s)
+    Decimal(Option<u32>, Option<u32>),
+}
+
+pub const COLUMN_TYPES: &[&str] = &[
+    "i64",
+    "f64",
+    "string",
+    "bool",
+    "timestamp",
+    "date",
+    "decimal",
+    "decimal(p)",
+    "decimal(p,s)",
+];
+
+impl ColumnType {
+    /*
+     * Read a CSV field as a value of this type, explaining what was wrong with it on failure
+     */
+    pub fn read(&self, field: String) -> Result<Box<dyn SqlType>, CustomError> {
+        let invalid = |field: &str| {
+            CustomError::new(400, format!("Bad request: Invalid {} '{}'", self, field))
+        };
+        Ok(match self {
+            ColumnType::I64 => Box::new(field.trim().parse::<i64>().map_err(|_| invalid(&field))?),
+            ColumnType::F64 => Box::new(field.trim().parse::<f64>().map_err(|_| invalid(&field))?),
+            ColumnType::String => Box::new(field),
+            ColumnType::Bool => Box::new(parse_bool(&field)?),
+            ColumnType::Timestamp => Box::new(Timestamp::parse(&field)?),
+            ColumnType::Date => Box::new(Date::parse(&field)?),
+            ColumnType::Decimal(precision, scale) => {
+                Box::new(parse_decimal(&field, *precision, *scale)?)
+            }
+        })
+    }
+
+    /*
+     * The precision and scale of "decimal", "decimal(p)" or "decimal(p,s)"
+     */
+    fn decimal(type_string: &str) -> Option<ColumnType> {
+        let spec = type_string.strip_prefix("decimal")?.trim();
+        if spec.is_empty() {
+            return Some(ColumnType::Decimal(None, None));
+        }
+        let args: Vec<u32> = spec
+            .strip_prefix('(')?
+            .strip_suffix(')')?
+            .split(',')
+            .map(|arg| arg.trim().parse::<u32>().ok())
+            .collect::<Option<_>>()?;
+        match args.as_slice() {
+            [precision] if *precision > 0 && *precision <= 28 => {
+                Some(ColumnType::Decimal(Some(*precision), Some(0)))
+            }
+            [precision, scale] if *precision > 0 && *precision <= 28 && scale <= precision => {
+                Some(ColumnType::Decimal(Some(*precision), Some(*scale)))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for ColumnType {
+    type Err = CustomError;
+
+    fn from_str(type_string: &str) -> Result<ColumnType, CustomError> {
+        let type_string = type_string.trim().to_lowercase();
+        let column_type = match type_string.as_str() {
+            "i64" => Some(ColumnType::I64),
+            "f64" => Some(ColumnType::F64),
+            "string" => Some(ColumnType::String),
+            "bool" => Some(ColumnType::Bool),
+            "timestamp" => Some(ColumnType::Timestamp),
+            "date" => Some(ColumnType::Date),
+            other => ColumnType::decimal(other),
+        };
+        column_type.ok_or_else(|| {
+            CustomError::new(
+                400,
+                format!(
+                    "Bad request: Unknown column type '{}', expected one of {}",
+                    type_string,
+                    COLUMN_TYPES.join(", ")
+                ),
+            )
+        })
+    }
+}
+
+// Types display as the canonical name that schemas store
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ColumnType::I64 => write!(f, "i64"),
+            ColumnType::F64 => write!(f, "f64"),
+            ColumnType::String => write!(f, "string"),
+            ColumnType::Bool => write!(f, "bool"),
+            ColumnType::Timestamp => write!(f, "timestamp"),
+            ColumnType::Date => write!(f, "date"),
+            ColumnType::Decimal(None, _) => write!(f, "decimal"),
+            ColumnType::Decimal(Some(precision), None)
+            | ColumnType::Decimal(Some(precision), Some(0)) => {
+                write!(f, "decimal({})", precision)
+            }
+            ColumnType::Decimal(Some(precision), Some(scale)) => {
+                write!(f, "decimal({},{})", precision, scale)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn can_parse_registered_types() {
+        for (type_string, canonical) in [
+            ("i64", "i64"),
+            (" F64 ", "f64"),
+            ("string", "string"),
+            ("bool", "bool"),
+            ("Timestamp", "timestamp"),
+            ("date", "date"),
+            ("decimal", "decimal"),
+            ("decimal(10)", "decimal(10)"),
+            ("DECIMAL(8, 2)", "decimal(8,2)"),
+        ]
+        .iter()
+        {
+            let column_type = type_string.parse::<ColumnType>().unwrap();
+            assert_eq!(column_type.to_string(), *canonical);
+            assert_eq!(canonical.parse::<ColumnType>().unwrap(), column_type);
+        }
+
+        for type_string in [
+            "i46",
+            "",
+            "int",
+            "decimal(2,3)",
+            "decimal(0)",
+            "decimal(8,2",
+        ]
+        .iter()
+        {
+            let err = type_string.parse::<ColumnType>().unwrap_err();
+            assert_eq!(err.error_status_code, 400);
+            assert!(err.error_message.contains("Unknown column type"), "{}", err);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn read_errors_explain_the_field() {
+        let err = ColumnType::I64.read(String::from("4.5")).unwrap_err();
+        assert_eq!(err.error_message, "Bad request: Invalid i64 '4.5'");
+        let err = ColumnType::F64.read(String::from("")).unwrap_err();
+        assert_eq!(err.error_message, "Bad request: Invalid f64 ''");
+        assert!(ColumnType::Decimal(Some(4), Some(2))
+            .read(String::from("123.4"))
+            .is_err());
+        assert_eq!(
+            as_i64(ColumnType::I64.read(String::from(" 42 ")).unwrap().as_ref()),
+            Some(42)
+        );
+    }
+}
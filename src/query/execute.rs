@@ -1,11 +1,9 @@
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 
 use super::query::*;
-use crate::{error_handler::*, graph, users::User, AppData};
+use crate::{error_handler::*, graph, tables, users::User, AppData};
 // use futures::channel::mpsc::{ channel};
-use futures_util::future;
-use futures_util::task::Poll;
-use futures_util::StreamExt;
+use futures_util::TryStreamExt;
 use graph::ExecuteContext;
 
 use serde::Deserialize;
@@ -35,9 +33,11 @@ impl Execution {
             None => return Err(CustomError::from("Bad Request. Incomplete query.")),
         };
 
-        // Initialize an execution graph
+        // Initialize an execution graph, which reads tables too large to cache from disk
         let query_id = query.id.unwrap_or_default();
-        let root: Arc<dyn graph::Node> = graph::GraphInflator::new()
+        let cached_tables = app_data.table_cache.lock().await.keys().cloned().collect();
+        let root: Arc<dyn graph::Node> = graph::GraphInflator::for_user(user.id)
+            .with_table_cache(cached_tables, tables::SegmentStore::from_env().cache_limit)
             .inflate(query_id, sql_query)
             .await?;
 
@@ -51,48 +51,20 @@ impl Execution {
         });
         root.curse(ctx, sender).await?;
 
-        // Collect all of the records emitted to the channel
-        let error = RwLock::new(None);
-        let records = receiver
-            .map(|r| {
-                match r {
-                    Ok(_) => (),
-                    Err(ref err) => {
-                        let mut error = error.write().unwrap();
-                        *error = Some(err.clone());
-                    }
-                }
-                r
-            })
-            .take_until(future::poll_fn(|_ctx| {
-                let error = error.read().unwrap();
-                match &*error {
-                    Some(_) => Poll::Ready(()),
-                    None => Poll::Pending,
-                }
-            }))
-            .map(Result::ok)
-            .map(Option::unwrap)
-            .collect::<Vec<QueryRecord>>()
-            .await;
+        // Collect all of the records emitted to the channel, stopping at the first error, which
+        // drops the receiver and so stops the graph too
+        let records = receiver.try_collect::<Vec<QueryRecord>>().await?;
 
-        // Report errors
-        match error.into_inner().unwrap() {
-            // We die
-            Some(err) => Err(err),
-            None => {
-                // Package up the results and return
-                let query_result = QueryResult {
-                    records,
-                    ..Default::default()
-                };
-                log::info!(
-                    "Query {} produced {} records",
-                    query_id,
-                    query_result.records.len()
-                );
-                Ok(query_result)
-            }
-        }
+        // Package up the results and return
+        let query_result = QueryResult {
+            records,
+            ..Default::default()
+        };
+        log::info!(
+            "Query {} produced {} records",
+            query_id,
+            query_result.records.len()
+        );
+        Ok(query_result)
     }
 }
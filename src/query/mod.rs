@@ -1,8 +1,14 @@
+mod ast;
+mod column_types;
 mod execute;
+mod parser;
 mod query;
 mod routes;
 mod sql_types;
 
+pub use ast::*;
+pub use column_types::*;
+pub use parser::parse_sql;
 pub use query::{QueryRecord, QueryRecordBuilder, QueryResult};
 pub use routes::init_routes;
 pub use sql_types::*;
@@ -0,0 +1,742 @@
+use super::ast::*;
+use crate::error_handler::CustomError;
+use sqlparser::ast as sql;
+use sqlparser::dialect::GenericDialect;
+use sqlparser::parser::Parser;
+use std::fmt::Display;
+
+// Lower the syntax tree of sqlparser into the hetnetdb AST, refusing whatever has no meaning
+// in hetnetdb yet so that the graph only ever sees what it can plan
+
+pub fn parse_sql(text: &str) -> Result<Statement, CustomError> {
+    let mut statements = Parser::parse_sql(&GenericDialect {}, text)?;
+    if statements.len() != 1 {
+        return Err(CustomError::from("Expected exactly one statement"));
+    }
+    match statements.remove(0) {
+        sql::Statement::Query(query) => Ok(Statement::Select(lower_query(*query)?)),
+        statement => Err(unsupported(&statement)),
+    }
+}
+
+fn unsupported<T: Display>(node: &T) -> CustomError {
+    CustomError::new(400, format!("Bad request: Unsupported SQL: {}", node))
+}
+
+fn ident(ident: sql::Ident) -> String {
+    ident.value
+}
+
+fn object_name(name: sql::ObjectName) -> String {
+    let parts: Vec<String> = name.0.into_iter().map(ident).collect();
+    parts.join(".")
+}
+
+fn lower_query(query: sql::Query) -> Result<SelectQuery, CustomError> {
+    if !query.limit_by.is_empty()
+        || query.fetch.is_some()
+        || !query.locks.is_empty()
+        || query.for_clause.is_some()
+    {
+        return Err(unsupported(&query));
+    }
+
+    let ctes = match query.with {
+        Some(ref with) if with.recursive => return Err(unsupported(with)),
+        Some(with) => with
+            .cte_tables
+            .into_iter()
+            .map(|cte| {
+                Ok(Cte {
+                    alias: lower_alias(cte.alias),
+                    query: lower_query(*cte.query)?,
+                })
+            })
+            .collect::<Result<_, CustomError>>()?,
+        None => vec![],
+    };
+    let order = query
+        .order_by
+        .into_iter()
+        .map(lower_order_by)
+        .collect::<Result<_, _>>()?;
+    let limit = match query.limit {
+        Some(limit) => Some(lower_count(limit)?),
+        None => None,
+    };
+    let offset = match query.offset {
+        Some(offset) => lower_count(offset.value)?,
+        None => 0,
+    };
+
+    Ok(SelectQuery {
+        ctes,
+        body: lower_set_expr(*query.body)?,
+        order,
+        limit,
+        offset,
+    })
+}
+
+/*
+ * LIMIT, OFFSET and window frame offsets are counts of rows
+ */
+fn lower_count(expr: sql::Expr) -> Result<u64, CustomError> {
+    match expr {
+        sql::Expr::Value(sql::Value::Number(ref n, _)) => n.parse::<u64>().map_err(|_| {
+            CustomError::new(400, format!("Bad request: Expected a row count, not {}", n))
+        }),
+        expr => Err(CustomError::new(
+            400,
+            format!("Bad request: Expected a row count, not {}", expr),
+        )),
+    }
+}
+
+fn lower_order_by(order_by: sql::OrderByExpr) -> Result<OrderBy, CustomError> {
+    if order_by.nulls_first.is_some() {
+        return Err(unsupported(&order_by));
+    }
+    Ok(OrderBy {
+        expr: lower_expr(order_by.expr)?,
+        ascending: order_by.asc.unwrap_or(true),
+    })
+}
+
+fn lower_set_expr(set_expr: sql::SetExpr) -> Result<SetExpr, CustomError> {
+    match set_expr {
+        sql::SetExpr::Select(select) => Ok(SetExpr::Select(Box::new(lower_select(*select)?))),
+        sql::SetExpr::Query(query) => Ok(SetExpr::Query(Box::new(lower_query(*query)?))),
+        sql::SetExpr::SetOperation {
+            op,
+            set_quantifier,
+            left,
+            right,
+        } => {
+            let distinct = match set_quantifier {
+                sql::SetQuantifier::All => false,
+                sql::SetQuantifier::Distinct | sql::SetQuantifier::None => true,
+                quantifier => return Err(unsupported(&quantifier)),
+            };
+            let op = match op {
+                sql::SetOperator::Union => SetOperator::Union,
+                sql::SetOperator::Except => SetOperator::Except,
+                sql::SetOperator::Intersect => SetOperator::Intersect,
+            };
+            Ok(SetExpr::SetOperation {
+                op,
+                distinct,
+                left: Box::new(lower_set_expr(*left)?),
+                right: Box::new(lower_set_expr(*right)?),
+            })
+        }
+        set_expr => Err(unsupported(&set_expr)),
+    }
+}
+
+fn lower_select(select: sql::Select) -> Result<Select, CustomError> {
+    if select.top.is_some()
+        || select.into.is_some()
+        || !select.lateral_views.is_empty()
+        || !select.cluster_by.is_empty()
+        || !select.distribute_by.is_empty()
+        || !select.sort_by.is_empty()
+        || !select.named_window.is_empty()
+        || select.qualify.is_some()
+    {
+        return Err(unsupported(&select));
+    }
+
+    let distinct = match select.distinct {
+        None => false,
+        Some(sql::Distinct::Distinct) => true,
+        Some(distinct) => return Err(unsupported(&distinct)),
+    };
+    let fields = select
+        .projection
+        .into_iter()
+        .map(lower_select_item)
+        .collect::<Result<_, _>>()?;
+    let from = select
+        .from
+        .into_iter()
+        .map(|table| {
+            Ok(TableWithJoins {
+                relation: lower_table_factor(table.relation)?,
+                joins: table
+                    .joins
+                    .into_iter()
+                    .map(lower_join)
+                    .collect::<Result<_, CustomError>>()?,
+            })
+        })
+        .collect::<Result<_, CustomError>>()?;
+    let group_by = match select.group_by {
+        sql::GroupByExpr::Expressions(exprs) => exprs
+            .into_iter()
+            .map(lower_expr)
+            .collect::<Result<_, _>>()?,
+        group_by => return Err(unsupported(&group_by)),
+    };
+
+    Ok(Select {
+        distinct,
+        fields,
+        from,
+        where_clause: lower_optional(select.selection)?,
+        group_by,
+        having: lower_optional(select.having)?,
+    })
+}
+
+fn lower_select_item(item: sql::SelectItem) -> Result<SelectItem, CustomError> {
+    let plain = |options: &sql::WildcardAdditionalOptions| {
+        options.opt_exclude.is_none()
+            && options.opt_except.is_none()
+            && options.opt_rename.is_none()
+            && options.opt_replace.is_none()
+    };
+    match item {
+        sql::SelectItem::UnnamedExpr(expr) => Ok(SelectItem::Expr {
+            expr: lower_expr(expr)?,
+            alias: None,
+        }),
+        sql::SelectItem::ExprWithAlias { expr, alias } => Ok(SelectItem::Expr {
+            expr: lower_expr(expr)?,
+            alias: Some(ident(alias)),
+        }),
+        sql::SelectItem::Wildcard(ref options) if plain(options) => Ok(SelectItem::Wildcard),
+        sql::SelectItem::QualifiedWildcard(name, ref options) if plain(options) => {
+            Ok(SelectItem::QualifiedWildcard(object_name(name)))
+        }
+        item => Err(unsupported(&item)),
+    }
+}
+
+fn lower_alias(alias: sql::TableAlias) -> TableAlias {
+    TableAlias {
+        name: ident(alias.name),
+        columns: alias.columns.into_iter().map(ident).collect(),
+    }
+}
+
+fn lower_table_factor(factor: sql::TableFactor) -> Result<TableFactor, CustomError> {
+    match factor {
+        sql::TableFactor::Table {
+            name,
+            alias,
+            args: None,
+            ref with_hints,
+            version: None,
+            ref partitions,
+        } if with_hints.is_empty() && partitions.is_empty() => Ok(TableFactor::Table {
+            name: object_name(name),
+            alias: alias.map(lower_alias),
+        }),
+        sql::TableFactor::Derived {
+            lateral: false,
+            subquery,
+            alias,
+        } => Ok(TableFactor::Derived {
+            subquery: Box::new(lower_query(*subquery)?),
+            alias: alias.map(lower_alias),
+        }),
+        factor => Err(unsupported(&factor)),
+    }
+}
+
+fn lower_join(join: sql::Join) -> Result<JoinClause, CustomError> {
+    let (operator, constraint) = match join.join_operator {
+        sql::JoinOperator::Inner(constraint) => (JoinOperator::Inner, constraint),
+        sql::JoinOperator::LeftOuter(constraint) => (JoinOperator::LeftOuter, constraint),
+        sql::JoinOperator::RightOuter(constraint) => (JoinOperator::RightOuter, constraint),
+        sql::JoinOperator::FullOuter(constraint) => (JoinOperator::FullOuter, constraint),
+        sql::JoinOperator::CrossJoin => (JoinOperator::Cross, sql::JoinConstraint::None),
+        _ => return Err(unsupported(&join)),
+    };
+    let constraint = match constraint {
+        sql::JoinConstraint::On(condition) => JoinConstraint::On(lower_expr(condition)?),
+        sql::JoinConstraint::Using(columns) => {
+            JoinConstraint::Using(columns.into_iter().map(ident).collect())
+        }
+        sql::JoinConstraint::Natural => JoinConstraint::Natural,
+        sql::JoinConstraint::None => JoinConstraint::None,
+    };
+
+    Ok(JoinClause {
+        operator,
+        relation: lower_table_factor(join.relation)?,
+        constraint,
+    })
+}
+
+fn lower_optional(expr: Option<sql::Expr>) -> Result<Option<Expr>, CustomError> {
+    match expr {
+        Some(expr) => Ok(Some(lower_expr(expr)?)),
+        None => Ok(None),
+    }
+}
+
+fn lower_boxed(expr: sql::Expr) -> Result<Box<Expr>, CustomError> {
+    lower_expr(expr).map(Box::new)
+}
+
+fn lower_expr(expr: sql::Expr) -> Result<Expr, CustomError> {
+    Ok(match expr {
+        sql::Expr::Identifier(name) => Expr::Column(ColumnRef {
+            table: None,
+            name: ident(name),
+        }),
+        sql::Expr::CompoundIdentifier(mut names) if names.len() == 2 => {
+            let name = ident(names.pop().unwrap());
+            Expr::Column(ColumnRef {
+                table: Some(ident(names.pop().unwrap())),
+                name,
+            })
+        }
+        sql::Expr::Value(value) => Expr::Literal(lower_value(value)?),
+        sql::Expr::UnaryOp { op, expr } => {
+            let op = match op {
+                sql::UnaryOperator::Not => UnaryOperator::Not,
+                sql::UnaryOperator::Minus => UnaryOperator::Minus,
+                sql::UnaryOperator::Plus => UnaryOperator::Plus,
+                op => return Err(unsupported(&op)),
+            };
+            match (op, lower_expr(*expr)?) {
+                // Negative numbers are literals rather than arithmetic
+                (UnaryOperator::Minus, Expr::Literal(Literal::Integer(i))) => {
+                    Expr::Literal(Literal::Integer(-i))
+                }
+                (UnaryOperator::Minus, Expr::Literal(Literal::Float(v))) => {
+                    Expr::Literal(Literal::Float(-v))
+                }
+                (op, expr) => Expr::Unary {
+                    op,
+                    expr: Box::new(expr),
+                },
+            }
+        }
+        sql::Expr::BinaryOp { left, op, right } => {
+            let op = match op {
+                sql::BinaryOperator::And => BinaryOperator::And,
+                sql::BinaryOperator::Or => BinaryOperator::Or,
+                sql::BinaryOperator::Eq => BinaryOperator::Equal,
+                sql::BinaryOperator::NotEq => BinaryOperator::NotEqual,
+                sql::BinaryOperator::Lt => BinaryOperator::Less,
+                sql::BinaryOperator::LtEq => BinaryOperator::LessOrEqual,
+                sql::BinaryOperator::Gt => BinaryOperator::Greater,
+                sql::BinaryOperator::GtEq => BinaryOperator::GreaterOrEqual,
+                sql::BinaryOperator::Plus => BinaryOperator::Add,
+                sql::BinaryOperator::Minus => BinaryOperator::Subtract,
+                sql::BinaryOperator::Multiply => BinaryOperator::Multiply,
+                sql::BinaryOperator::Divide => BinaryOperator::Divide,
+                sql::BinaryOperator::Modulo => BinaryOperator::Modulo,
+                sql::BinaryOperator::StringConcat => BinaryOperator::Concat,
+                op => return Err(unsupported(&op)),
+            };
+            Expr::Binary {
+                left: lower_boxed(*left)?,
+                op,
+                right: lower_boxed(*right)?,
+            }
+        }
+        sql::Expr::Nested(expr) => Expr::Nested(lower_boxed(*expr)?),
+        sql::Expr::IsNull(expr) => Expr::IsNull {
+            expr: lower_boxed(*expr)?,
+            negated: false,
+        },
+        sql::Expr::IsNotNull(expr) => Expr::IsNull {
+            expr: lower_boxed(*expr)?,
+            negated: true,
+        },
+        sql::Expr::InList {
+            expr,
+            list,
+            negated,
+        } => Expr::InList {
+            expr: lower_boxed(*expr)?,
+            list: list.into_iter().map(lower_expr).collect::<Result<_, _>>()?,
+            negated,
+        },
+        sql::Expr::InSubquery {
+            expr,
+            subquery,
+            negated,
+        } => Expr::InSubquery {
+            expr: lower_boxed(*expr)?,
+            subquery: Box::new(lower_query(*subquery)?),
+            negated,
+        },
+        sql::Expr::Between {
+            expr,
+            negated,
+            low,
+            high,
+        } => Expr::Between {
+            expr: lower_boxed(*expr)?,
+            low: lower_boxed(*low)?,
+            high: lower_boxed(*high)?,
+            negated,
+        },
+        sql::Expr::Like {
+            negated,
+            expr,
+            pattern,
+            escape_char: None,
+        } => Expr::Like {
+            expr: lower_boxed(*expr)?,
+            pattern: lower_boxed(*pattern)?,
+            negated,
+            case_insensitive: false,
+        },
+        sql::Expr::ILike {
+            negated,
+            expr,
+            pattern,
+            escape_char: None,
+        } => Expr::Like {
+            expr: lower_boxed(*expr)?,
+            pattern: lower_boxed(*pattern)?,
+            negated,
+            case_insensitive: true,
+        },
+        sql::Expr::Exists { subquery, negated } => Expr::Exists {
+            subquery: Box::new(lower_query(*subquery)?),
+            negated,
+        },
+        sql::Expr::Subquery(subquery) => Expr::Subquery(Box::new(lower_query(*subquery)?)),
+        sql::Expr::Function(func) => Expr::Function(lower_function(func)?),
+        // A few functions have syntax of their own, but are plain calls once parsed
+        sql::Expr::Substring {
+            expr,
+            substring_from,
+            substring_for,
+            ..
+        } => {
+            let mut args = vec![lower_expr(*expr)?];
+            match (substring_from, substring_for) {
+                (Some(from), Some(length)) => {
+                    args.push(lower_expr(*from)?);
+                    args.push(lower_expr(*length)?);
+                }
+                (Some(from), None) => args.push(lower_expr(*from)?),
+                (None, Some(length)) => {
+                    args.push(Expr::Literal(Literal::Integer(1)));
+                    args.push(lower_expr(*length)?);
+                }
+                (None, None) => (),
+            }
+            call("substr", args)
+        }
+        sql::Expr::Trim {
+            expr,
+            trim_where: None,
+            trim_what: None,
+            trim_characters: None,
+        } => call("trim", vec![lower_expr(*expr)?]),
+        sql::Expr::Ceil {
+            expr,
+            field: sql::DateTimeField::NoDateTime,
+        } => call("ceil", vec![lower_expr(*expr)?]),
+        sql::Expr::Floor {
+            expr,
+            field: sql::DateTimeField::NoDateTime,
+        } => call("floor", vec![lower_expr(*expr)?]),
+        sql::Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => Expr::Case {
+            operand: match operand {
+                Some(operand) => Some(lower_boxed(*operand)?),
+                None => None,
+            },
+            branches: conditions
+                .into_iter()
+                .zip(results)
+                .map(|(when, then)| Ok((lower_expr(when)?, lower_expr(then)?)))
+                .collect::<Result<_, CustomError>>()?,
+            else_result: match else_result {
+                Some(else_result) => Some(lower_boxed(*else_result)?),
+                None => None,
+            },
+        },
+        sql::Expr::Cast {
+            expr,
+            data_type,
+            format: None,
+        } => Expr::Cast {
+            expr: lower_boxed(*expr)?,
+            data_type: data_type.to_string().to_lowercase(),
+        },
+        expr => return Err(unsupported(&expr)),
+    })
+}
+
+fn call(name: &str, args: Vec<Expr>) -> Expr {
+    Expr::Function(Function {
+        name: String::from(name),
+        args,
+        distinct: false,
+        over: None,
+    })
+}
+
+fn lower_value(value: sql::Value) -> Result<Literal, CustomError> {
+    match value {
+        sql::Value::Null => Ok(Literal::Null),
+        sql::Value::Boolean(b) => Ok(Literal::Boolean(b)),
+        sql::Value::Number(n, _) => match n.parse::<i64>() {
+            Ok(i) => Ok(Literal::Integer(i)),
+            Err(_) => Ok(Literal::Float(n.parse::<f64>()?)),
+        },
+        sql::Value::SingleQuotedString(s)
+        | sql::Value::EscapedStringLiteral(s)
+        | sql::Value::NationalStringLiteral(s) => Ok(Literal::String(s)),
+        value => Err(unsupported(&value)),
+    }
+}
+
+fn lower_function(func: sql::Function) -> Result<Function, CustomError> {
+    if func.filter.is_some() || func.null_treatment.is_some() || !func.order_by.is_empty() {
+        return Err(unsupported(&func));
+    }
+
+    let mut args = vec![];
+    for arg in func.args.iter() {
+        args.push(match arg {
+            sql::FunctionArg::Unnamed(sql::FunctionArgExpr::Expr(expr)) => {
+                lower_expr(expr.clone())?
+            }
+            sql::FunctionArg::Unnamed(sql::FunctionArgExpr::Wildcard) => Expr::Wildcard,
+            arg => return Err(unsupported(arg)),
+        });
+    }
+    let over = match func.over {
+        Some(sql::WindowType::WindowSpec(spec)) => Some(Box::new(lower_window_spec(spec)?)),
+        Some(over) => return Err(unsupported(&over)),
+        None => None,
+    };
+
+    Ok(Function {
+        name: object_name(func.name).to_lowercase(),
+        args,
+        distinct: func.distinct,
+        over,
+    })
+}
+
+fn lower_window_spec(spec: sql::WindowSpec) -> Result<WindowSpec, CustomError> {
+    let frame = match spec.window_frame {
+        Some(frame) => {
+            let units = match frame.units {
+                sql::WindowFrameUnits::Rows => WindowFrameUnits::Rows,
+                sql::WindowFrameUnits::Range => WindowFrameUnits::Range,
+                units => return Err(unsupported(&units)),
+            };
+            // A frame given by its start alone ends at the current row
+            let end = match frame.end_bound {
+                Some(bound) => lower_frame_bound(bound)?,
+                None => WindowFrameBound::CurrentRow,
+            };
+            Some(WindowFrame {
+                units,
+                start: lower_frame_bound(frame.start_bound)?,
+                end,
+            })
+        }
+        None => None,
+    };
+
+    Ok(WindowSpec {
+        partition_by: spec
+            .partition_by
+            .into_iter()
+            .map(lower_expr)
+            .collect::<Result<_, _>>()?,
+        order: spec
+            .order_by
+            .into_iter()
+            .map(lower_order_by)
+            .collect::<Result<_, _>>()?,
+        frame,
+    })
+}
+
+fn lower_frame_bound(bound: sql::WindowFrameBound) -> Result<WindowFrameBound, CustomError> {
+    Ok(match bound {
+        sql::WindowFrameBound::CurrentRow => WindowFrameBound::CurrentRow,
+        sql::WindowFrameBound::Preceding(None) => WindowFrameBound::Preceding(None),
+        sql::WindowFrameBound::Preceding(Some(n)) => {
+            WindowFrameBound::Preceding(Some(lower_count(*n)?))
+        }
+        sql::WindowFrameBound::Following(None) => WindowFrameBound::Following(None),
+        sql::WindowFrameBound::Following(Some(n)) => {
+            WindowFrameBound::Following(Some(lower_count(*n)?))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn select(text: &str) -> SelectQuery {
+        match parse_sql(text).expect("Failed to parse test query") {
+            Statement::Select(query) => query,
+        }
+    }
+
+    fn body(query: &SelectQuery) -> &Select {
+        match query.body {
+            SetExpr::Select(ref select) => select,
+            _ => panic!("Expected a plain SELECT"),
+        }
+    }
+
+    #[test]
+    fn test_parse_select() {
+        let query = select(
+            "SELECT DISTINCT f.a, count(*) AS n FROM foo f WHERE a >= -2 AND b <> 'x' \
+             GROUP BY f.a HAVING count(*) > 1 ORDER BY n DESC LIMIT 10 OFFSET 5",
+        );
+        assert_eq!((query.limit, query.offset), (Some(10), 5));
+        assert_eq!(query.order.len(), 1);
+        assert!(!query.order[0].ascending);
+
+        let select = body(&query);
+        assert!(select.distinct);
+        assert_eq!(
+            select.fields[0],
+            SelectItem::Expr {
+                expr: Expr::column(Some("f"), "a"),
+                alias: None
+            }
+        );
+        assert_eq!(
+            select.from[0].relation,
+            TableFactor::Table {
+                name: String::from("foo"),
+                alias: Some(TableAlias {
+                    name: String::from("f"),
+                    columns: vec![]
+                })
+            }
+        );
+        assert_eq!(
+            select.where_clause.as_ref().unwrap().to_string(),
+            "a >= -2 AND b != 'x'"
+        );
+        assert_eq!(select.having.as_ref().unwrap().to_string(), "count(*) > 1");
+    }
+
+    #[test]
+    fn test_parse_common_table_expressions() {
+        let query =
+            select("WITH a (x) AS (SELECT c0 FROM foo), b AS (SELECT * FROM a) SELECT x FROM b");
+        let names: Vec<&str> = query.ctes.iter().map(|c| c.alias.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(query.ctes[0].alias.columns, vec![String::from("x")]);
+
+        assert!(parse_sql("WITH RECURSIVE a AS (SELECT 1) SELECT * FROM a").is_err());
+    }
+
+    #[test]
+    fn test_parse_window_functions() {
+        let query = select(
+            "SELECT sum(reading) OVER (PARTITION BY device ORDER BY ts ROWS BETWEEN 2 PRECEDING AND CURRENT ROW), \
+             row_number() OVER (ORDER BY ts ROWS UNBOUNDED PRECEDING) FROM readings",
+        );
+        let windows: Vec<String> = body(&query)
+            .fields
+            .iter()
+            .map(|f| match f {
+                SelectItem::Expr {
+                    expr: Expr::Function(func),
+                    ..
+                } => {
+                    assert!(!func.is_aggregate());
+                    func.to_string()
+                }
+                _ => panic!("Expected a window function"),
+            })
+            .collect();
+        assert_eq!(
+            windows,
+            vec![
+                "sum(reading) OVER (PARTITION BY device ORDER BY ts ROWS BETWEEN 2 PRECEDING AND CURRENT ROW)",
+                "row_number() OVER (ORDER BY ts ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_richer_expressions() {
+        let query = select(
+            "SELECT CASE WHEN a IS NULL THEN 0 ELSE a * 2 END, substring(b FROM 2 FOR 3), CAST(c AS BIGINT) \
+             FROM foo WHERE b LIKE 'x%' AND a BETWEEN 1 AND 5 AND c NOT IN (1, 2) \
+             AND NOT EXISTS (SELECT * FROM bar) AND a > (SELECT avg(a) FROM foo)",
+        );
+        let select = body(&query);
+        let fields: Vec<String> = select.fields.iter().map(|f| f.to_string()).collect();
+        assert_eq!(
+            fields,
+            vec![
+                "CASE WHEN a IS NULL THEN 0 ELSE a * 2 END",
+                "substr(b, 2, 3)",
+                "CAST(c AS bigint)",
+            ]
+        );
+        assert_eq!(
+            select.where_clause.as_ref().unwrap().to_string(),
+            "b LIKE 'x%' AND a BETWEEN 1 AND 5 AND c NOT IN (1, 2) \
+             AND NOT EXISTS (SELECT * FROM bar) AND a > (SELECT avg(a) FROM foo)"
+        );
+    }
+
+    #[test]
+    fn test_parse_joins_and_set_operations() {
+        let query = select(
+            "SELECT * FROM a NATURAL JOIN b CROSS JOIN c RIGHT JOIN (SELECT * FROM d) AS e USING (x) \
+             UNION ALL SELECT * FROM f EXCEPT SELECT * FROM g",
+        );
+        match query.body {
+            SetExpr::SetOperation {
+                op: SetOperator::Except,
+                distinct: true,
+                ref left,
+                ..
+            } => assert!(matches!(
+                left.as_ref(),
+                SetExpr::SetOperation {
+                    op: SetOperator::Union,
+                    distinct: false,
+                    ..
+                }
+            )),
+            _ => panic!("Expected EXCEPT over UNION ALL"),
+        }
+        assert_eq!(
+            query.to_string(),
+            "SELECT * FROM a NATURAL JOIN b CROSS JOIN c RIGHT JOIN (SELECT * FROM d) AS e USING (x) \
+             UNION ALL SELECT * FROM f EXCEPT SELECT * FROM g"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for text in [
+            "SELECT * FROM",
+            "INSERT INTO foo VALUES (1)",
+            "SELECT 1; SELECT 2",
+            "SELECT * FROM foo LIMIT -1",
+            "SELECT a FROM foo ORDER BY a NULLS FIRST",
+        ]
+        .iter()
+        {
+            let err = parse_sql(text).unwrap_err();
+            assert_eq!(err.error_status_code, 400, "{}", text);
+        }
+    }
+}
@@ -1,8 +1,6 @@
-use super::sql_types::*;
+use super::{parse_sql, sql_types::*, ColumnType, Statement};
 use crate::{error_handler::CustomError, table_schemas::TableSchema};
 use chrono::{DateTime, Utc};
-use nom_sql::parser::parse_query;
-use nom_sql::SqlQuery;
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 
@@ -10,8 +8,8 @@ use std::fmt::Debug;
 pub struct Query {
     pub id: Option<i64>,
     pub text: String,
-    pub parse: Option<SqlQuery>,
-    pub optimal_parse: Option<SqlQuery>,
+    pub parse: Option<Statement>,
+    pub optimal_parse: Option<Statement>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,7 +23,7 @@ impl Default for RecordTime {
     }
 }
 
-#[derive(Debug, Clone, Default, Serialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct QueryRecord {
     pub ready: RecordTime,
     pub columns: Vec<Box<dyn SqlType>>,
@@ -40,7 +38,7 @@ impl Query {
     pub fn parse(input_query: &Query) -> Result<Query, CustomError> {
         let mut query = input_query.clone();
         if input_query.parse.is_none() {
-            query.parse = Some(parse_query(&input_query.text)?);
+            query.parse = Some(parse_sql(&input_query.text)?);
             log::info!("Parse: {:#?}", query.parse.as_ref().unwrap());
         } else {
             log::info!("Pre-populated Parse: {:#?}", query.parse.as_ref().unwrap());
@@ -61,41 +59,140 @@ impl Query {
 }
 
 pub struct QueryRecordBuilder {
-    into_types: Vec<Box<dyn Fn(String) -> Result<Box<dyn SqlType>, CustomError>>>,
+    column_names: Vec<String>,
+    column_types: Vec<ColumnType>,
+    column_nullable: Vec<bool>,
+    null_values: Vec<String>,
 }
 
 impl QueryRecordBuilder {
-    pub fn new(table_schema: &TableSchema) -> QueryRecordBuilder {
-        let into_types = (&table_schema.column_types)
+    pub fn new(table_schema: &TableSchema) -> Result<QueryRecordBuilder, CustomError> {
+        let column_types = table_schema
+            .column_types
             .iter()
-            .map(|type_string| {
-                let into_type: Box<dyn Fn(String) -> Result<Box<dyn SqlType>, CustomError>> =
-                    match type_string.as_str() {
-                        "i64" => Box::new(|s: String| Ok(Box::new(s.parse::<i64>()?))),
-                        "f64" => Box::new(|s: String| Ok(Box::new(s.parse::<f64>()?))),
-                        "string" => Box::new(|s: String| Ok(Box::new(s))),
-                        _ => Box::new(|_s| Ok(Box::new(Null::default()))),
-                    };
-                into_type
+            .enumerate()
+            .map(|(i, type_string)| {
+                type_string
+                    .parse::<ColumnType>()
+                    .map_err(|err| in_column(err, None, table_schema.column_names.get(i)))
             })
-            .collect();
+            .collect::<Result<Vec<ColumnType>, CustomError>>()?;
+
+        Ok(QueryRecordBuilder {
+            column_names: table_schema.column_names.clone(),
+            column_types,
+            column_nullable: table_schema.column_nullable.clone(),
+            null_values: table_schema.null_values.clone(),
+        })
+    }
 
-        QueryRecordBuilder { into_types }
+    /*
+     * Read the fields of a row, numbered from 1, into a record. Empty fields and null values
+     * are NULL in nullable columns, and missing in the others
+     */
+    pub fn from_vec(&self, row: usize, columns: Vec<String>) -> Result<QueryRecord, CustomError> {
+        self.read_fields(row, columns.into_iter().map(Some).collect())
     }
 
-    pub fn from_vec(&self, columns: Vec<String>) -> Result<QueryRecord, CustomError> {
+    /*
+     * Coerce the JSON values of a row, in column order, to the column types. Scalars are read
+     * from their text, so 42 and "42" are the same i64, and JSON null is always missing
+     */
+    pub fn read_json(
+        &self,
+        row: usize,
+        columns: Vec<serde_json::Value>,
+    ) -> Result<QueryRecord, CustomError> {
+        let fields = columns
+            .into_iter()
+            .map(|value| match value {
+                serde_json::Value::Null => None,
+                serde_json::Value::String(field) => Some(field),
+                value => Some(value.to_string()),
+            })
+            .collect();
+        self.read_fields(row, fields)
+    }
+
+    fn read_fields(
+        &self,
+        row: usize,
+        columns: Vec<Option<String>>,
+    ) -> Result<QueryRecord, CustomError> {
+        if columns.len() != self.column_types.len() {
+            return Err(in_column(
+                CustomError::new(
+                    400,
+                    format!(
+                        "Bad request: Expected {} fields but found {}",
+                        self.column_types.len(),
+                        columns.len()
+                    ),
+                ),
+                Some(row),
+                None,
+            ));
+        }
         let mut record = QueryRecord {
             ..Default::default()
         };
-        let columns: Result<Vec<Box<dyn SqlType>>, _> = (&self.into_types)
+        let columns: Result<Vec<Box<dyn SqlType>>, _> = (&self.column_types)
             .iter()
             .zip(columns.into_iter())
-            .map(|item| item.0(item.1))
+            .enumerate()
+            .map(|(i, (column_type, field))| {
+                let nullable = self.column_nullable.get(i).copied().unwrap_or(false);
+                let is_null = |field: &str| {
+                    let value = field.trim();
+                    value.is_empty() || self.is_null_value(value)
+                };
+                let is_missing =
+                    |field: &str| field.trim().is_empty() && *column_type != ColumnType::String;
+                match field {
+                    Some(field) if nullable && is_null(&field) => {
+                        Ok(Box::new(Null {}) as Box<dyn SqlType>)
+                    }
+                    Some(field) if !is_missing(&field) => column_type
+                        .read(field)
+                        .map_err(|err| in_column(err, Some(row), self.column_names.get(i))),
+                    None if nullable => Ok(Box::new(Null {}) as Box<dyn SqlType>),
+                    _ => Err(in_column(
+                        CustomError::new(
+                            400,
+                            format!(
+                                "Bad request: Missing {} in non-nullable column",
+                                column_type
+                            ),
+                        ),
+                        Some(row),
+                        self.column_names.get(i),
+                    )),
+                }
+            })
             .collect();
         record.columns = columns?;
         record.ready = RecordTime::default();
         Ok(record)
     }
+
+    fn is_null_value(&self, value: &str) -> bool {
+        self.null_values
+            .iter()
+            .any(|null_value| null_value.eq_ignore_ascii_case(value))
+    }
+}
+
+fn in_column(err: CustomError, row: Option<usize>, name: Option<&String>) -> CustomError {
+    let location = match (row, name) {
+        (Some(row), Some(name)) => format!("row {}, column '{}'", row, name),
+        (Some(row), None) => format!("row {}", row),
+        (None, Some(name)) => format!("column '{}'", name),
+        (None, None) => return err,
+    };
+    CustomError::new(
+        err.error_status_code,
+        format!("{} ({})", err.error_message, location),
+    )
 }
 
 #[cfg(test)]
@@ -129,7 +226,128 @@ mod tests {
             text,
             ..Default::default()
         });
-        assert!(query.is_err());
+        query.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn parse_serializes_the_ast() {
+        let text: String = "SELECT a, row_number() OVER (ORDER BY a) FROM BAR".into();
+        let query = Query::parse(&Query {
+            text,
+            ..Default::default()
+        })
+        .unwrap();
+        let json = serde_json::to_string(&query).unwrap();
+        let roundtrip: Query = serde_json::from_str(&json).unwrap();
+        assert_eq!(roundtrip.parse, query.parse);
+    }
+
+    #[actix_rt::test]
+    async fn build_records_of_each_type() {
+        let table_schema = TableSchema {
+            id: 0,
+            column_types: ["bool", "timestamp", "date", "decimal(6, 2)", "decimal"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            column_names: (0..5).map(|i| format!("c{}", i)).collect(),
+            column_nullable: vec![false; 5],
+            null_values: Vec::new(),
+            created_at: chrono::Utc::now().naive_utc(),
+            updated_at: chrono::Utc::now().naive_utc(),
+        };
+        let builder = QueryRecordBuilder::new(&table_schema).unwrap();
+        let fields = |fields: &[&str]| fields.iter().map(|s| s.to_string()).collect();
+
+        let record = builder
+            .from_vec(
+                1,
+                fields(&["true", "1614601800", "2021-03-01", "12.345", "0.125"]),
+            )
+            .unwrap();
+        let texts: Vec<String> = record
+            .columns
+            .iter()
+            .map(|c| to_text(c.as_ref()).unwrap())
+            .collect();
+        assert_eq!(
+            texts,
+            vec![
+                "true",
+                "2021-03-01T12:30:00Z",
+                "2021-03-01",
+                "12.35",
+                "0.125"
+            ]
+        );
+
+        let err = builder
+            .from_vec(
+                2,
+                fields(&["true", "1614601800", "2021-03-01", "12345.6", "1"]),
+            )
+            .unwrap_err();
+        assert_eq!(err.error_status_code, 400);
+        assert!(err.error_message.contains("column 'c3'"), "{}", err);
+
+        let err = builder
+            .from_vec(3, fields(&["true", "1614601800", "2021-03-01"]))
+            .unwrap_err();
+        assert_eq!(
+            err.error_message,
+            "Bad request: Expected 5 fields but found 3 (row 3)"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn build_records_with_nulls() {
+        let table_schema = TableSchema {
+            id: 0,
+            column_types: ["string", "i64", "f64", "string"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            column_names: ["device", "reading", "celsius", "note"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            column_nullable: vec![false, true, false, true],
+            null_values: vec![String::from("NA"), String::from("null")],
+            created_at: chrono::Utc::now().naive_utc(),
+            updated_at: chrono::Utc::now().naive_utc(),
+        };
+        let builder = QueryRecordBuilder::new(&table_schema).unwrap();
+        let fields = |fields: &[&str]| fields.iter().map(|s| s.to_string()).collect();
+
+        let record = builder
+            .from_vec(1, fields(&["", " NA ", "1.5", "NULL"]))
+            .unwrap();
+        assert_eq!(as_str(record.columns[0].as_ref()), Some(""));
+        assert!(is_null(record.columns[1].as_ref()));
+        assert!(is_null(record.columns[3].as_ref()));
+
+        let record = builder
+            .from_vec(2, fields(&["a", "", "2.5", "na"]))
+            .unwrap();
+        assert!(is_null(record.columns[1].as_ref()));
+        assert!(is_null(record.columns[3].as_ref()));
+
+        let err = builder
+            .from_vec(3, fields(&["a", "3", "", ""]))
+            .unwrap_err();
+        assert_eq!(err.error_status_code, 400);
+        assert_eq!(
+            err.error_message,
+            "Bad request: Missing f64 in non-nullable column (row 3, column 'celsius')"
+        );
+
+        let err = builder
+            .from_vec(4, fields(&["a", "3", "NA", ""]))
+            .unwrap_err();
+        assert_eq!(
+            err.error_message,
+            "Bad request: Invalid f64 'NA' (row 4, column 'celsius')"
+        );
     }
 
     #[actix_rt::test]
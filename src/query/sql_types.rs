@@ -1,11 +1,15 @@
+use crate::error_handler::CustomError;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, TimeZone, Utc};
 use dyn_clone::DynClone;
+use rust_decimal::{prelude::ToPrimitive, Decimal, RoundingStrategy};
 use serde::{Deserialize, Serialize};
-use std::{any::Any, fmt::Debug};
+use std::{any::Any, cmp::Ordering, fmt::Debug, str::FromStr};
 
 #[typetag::serde]
-pub trait SqlType: DynClone + Debug + Send {
+pub trait SqlType: DynClone + Debug + Send + Sync {
     fn name(self) -> String;
     fn value(&mut self) -> Box<dyn Any>;
+    fn as_any(&self) -> &dyn Any;
 }
 dyn_clone::clone_trait_object!(SqlType);
 
@@ -17,6 +21,9 @@ impl SqlType for String {
     fn value(&mut self) -> Box<dyn Any> {
         Box::new(self.clone())
     }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 #[typetag::serde]
@@ -27,6 +34,9 @@ impl SqlType for i64 {
     fn value(&mut self) -> Box<dyn Any> {
         Box::new(*self)
     }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 #[typetag::serde]
@@ -37,6 +47,174 @@ impl SqlType for f64 {
     fn value(&mut self) -> Box<dyn Any> {
         Box::new(*self)
     }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[typetag::serde]
+impl SqlType for bool {
+    fn name(self) -> String {
+        "BOOL".into()
+    }
+    fn value(&mut self) -> Box<dyn Any> {
+        Box::new(*self)
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/*
+ * An instant in UTC, serialized in RFC3339
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Timestamp(pub DateTime<Utc>);
+
+#[typetag::serde]
+impl SqlType for Timestamp {
+    fn name(self) -> String {
+        "TIMESTAMP".into()
+    }
+    fn value(&mut self) -> Box<dyn Any> {
+        Box::new(*self)
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Timestamp {
+    /*
+     * Accepts RFC3339, "YYYY-MM-DD HH:MM:SS[.f]" and "YYYY-MM-DD" in UTC, or seconds since
+     * the Unix epoch with an optional fraction
+     */
+    pub fn parse(s: &str) -> Result<Timestamp, CustomError> {
+        let s = s.trim();
+        if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+            return Ok(Timestamp(dt.with_timezone(&Utc)));
+        }
+        for format in ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"].iter() {
+            if let Ok(dt) = NaiveDateTime::parse_from_str(s, format) {
+                return Ok(Timestamp(DateTime::from_utc(dt, Utc)));
+            }
+        }
+        if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+            return Ok(Date(date).into());
+        }
+        let epoch = match s.parse::<i64>() {
+            Ok(seconds) => Utc.timestamp_opt(seconds, 0).single(),
+            Err(_) => match s.parse::<f64>() {
+                Ok(seconds) if seconds.is_finite() => {
+                    let nanos = ((seconds - seconds.floor()) * 1e9) as u32;
+                    Utc.timestamp_opt(seconds.floor() as i64, nanos).single()
+                }
+                _ => None,
+            },
+        };
+        match epoch {
+            Some(dt) => Ok(Timestamp(dt)),
+            None => Err(CustomError::new(
+                400,
+                format!("Bad request: Invalid timestamp '{}'", s),
+            )),
+        }
+    }
+}
+
+/*
+ * A calendar date without a time zone, serialized as YYYY-MM-DD
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Date(pub NaiveDate);
+
+#[typetag::serde]
+impl SqlType for Date {
+    fn name(self) -> String {
+        "DATE".into()
+    }
+    fn value(&mut self) -> Box<dyn Any> {
+        Box::new(*self)
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Date {
+    pub fn parse(s: &str) -> Result<Date, CustomError> {
+        match NaiveDate::parse_from_str(s.trim(), "%Y-%m-%d") {
+            Ok(date) => Ok(Date(date)),
+            Err(_) => Err(CustomError::new(
+                400,
+                format!("Bad request: Invalid date '{}'", s),
+            )),
+        }
+    }
+}
+
+// Dates compare with timestamps as midnight UTC
+impl From<Date> for Timestamp {
+    fn from(date: Date) -> Timestamp {
+        Timestamp(DateTime::from_utc(date.0.and_hms(0, 0, 0), Utc))
+    }
+}
+
+// Decimals serialize as strings so that no digits are lost
+#[typetag::serde]
+impl SqlType for Decimal {
+    fn name(self) -> String {
+        "DECIMAL".into()
+    }
+    fn value(&mut self) -> Box<dyn Any> {
+        Box::new(*self)
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub fn parse_bool(s: &str) -> Result<bool, CustomError> {
+    match s.trim().to_lowercase().as_str() {
+        "true" | "t" | "yes" | "y" | "1" => Ok(true),
+        "false" | "f" | "no" | "n" | "0" => Ok(false),
+        _ => Err(CustomError::new(
+            400,
+            format!("Bad request: Invalid bool '{}'", s),
+        )),
+    }
+}
+
+/*
+ * Parse a decimal, rounding half away from zero to `scale` digits after the point when given.
+ * With a precision, values with more than that many digits in all are rejected.
+ */
+pub fn parse_decimal(
+    s: &str,
+    precision: Option<u32>,
+    scale: Option<u32>,
+) -> Result<Decimal, CustomError> {
+    let invalid = || CustomError::new(400, format!("Bad request: Invalid decimal '{}'", s));
+    let s = s.trim();
+    let mut value = Decimal::from_str(s)
+        .or_else(|_| Decimal::from_scientific(s))
+        .map_err(|_| invalid())?;
+    if let Some(scale) = scale {
+        value = value.round_dp_with_strategy(scale, RoundingStrategy::MidpointAwayFromZero);
+        value.rescale(scale);
+    }
+    if let Some(precision) = precision {
+        if value.mantissa().unsigned_abs().to_string().len() > precision as usize {
+            return Err(CustomError::new(
+                400,
+                format!(
+                    "Bad request: Decimal '{}' does not fit {} digits of precision",
+                    s, precision
+                ),
+            ));
+        }
+    }
+    Ok(value)
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
@@ -50,6 +228,194 @@ impl SqlType for Null {
     fn value(&mut self) -> Box<dyn Any> {
         Box::new(Null {})
     }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub fn is_null(value: &dyn SqlType) -> bool {
+    value.as_any().is::<Null>()
+}
+
+pub fn as_i64(value: &dyn SqlType) -> Option<i64> {
+    value.as_any().downcast_ref::<i64>().copied()
+}
+
+pub fn as_f64(value: &dyn SqlType) -> Option<f64> {
+    let value = value.as_any();
+    if let Some(v) = value.downcast_ref::<f64>() {
+        Some(*v)
+    } else if let Some(v) = value.downcast_ref::<i64>() {
+        Some(*v as f64)
+    } else {
+        value.downcast_ref::<Decimal>().and_then(|v| v.to_f64())
+    }
+}
+
+/*
+ * Decimals and integers, which mix without losing precision
+ */
+pub fn as_decimal(value: &dyn SqlType) -> Option<Decimal> {
+    let value = value.as_any();
+    if let Some(v) = value.downcast_ref::<Decimal>() {
+        Some(*v)
+    } else {
+        value.downcast_ref::<i64>().map(|v| Decimal::from(*v))
+    }
+}
+
+/*
+ * Decimals and integers, and floats as the decimal they are written as, e.g. 0.1 rather than
+ * the binary fraction nearest it
+ */
+pub fn as_exact_decimal(value: &dyn SqlType) -> Option<Decimal> {
+    as_decimal(value).or_else(|| {
+        let v = value.as_any().downcast_ref::<f64>()?;
+        Decimal::from_str_exact(&v.to_string()).ok()
+    })
+}
+
+pub fn is_decimal(value: &dyn SqlType) -> bool {
+    value.as_any().is::<Decimal>()
+}
+
+pub fn as_str(value: &dyn SqlType) -> Option<&str> {
+    value.as_any().downcast_ref::<String>().map(|v| v.as_str())
+}
+
+pub fn as_bool(value: &dyn SqlType) -> Option<bool> {
+    value.as_any().downcast_ref::<bool>().copied()
+}
+
+/*
+ * Timestamps, and dates as midnight UTC
+ */
+pub fn as_timestamp(value: &dyn SqlType) -> Option<Timestamp> {
+    let value = value.as_any();
+    if let Some(v) = value.downcast_ref::<Timestamp>() {
+        Some(*v)
+    } else {
+        value.downcast_ref::<Date>().map(|v| (*v).into())
+    }
+}
+
+/*
+ * Interpret a value as a SQL boolean, where None is the unknown truth value of NULL
+ */
+pub fn truthy(value: &dyn SqlType) -> Option<bool> {
+    if is_null(value) {
+        None
+    } else if let Some(v) = as_bool(value) {
+        Some(v)
+    } else if let Some(v) = as_f64(value) {
+        Some(v != 0.0)
+    } else if let Some(v) = as_str(value) {
+        Some(v.trim().parse::<f64>().map(|v| v != 0.0).unwrap_or(false))
+    } else {
+        None
+    }
+}
+
+/*
+ * Order two values of comparable types, where None means NULL or incomparable.
+ * Strings compare with timestamps and dates as the instant they spell.
+ */
+pub fn compare(left: &dyn SqlType, right: &dyn SqlType) -> Option<Ordering> {
+    if let (Some(l), Some(r)) = (as_i64(left), as_i64(right)) {
+        return Some(l.cmp(&r));
+    }
+    if let (Some(l), Some(r)) = (as_decimal(left), as_decimal(right)) {
+        return Some(l.cmp(&r));
+    }
+    if let (Some(l), Some(r)) = (as_f64(left), as_f64(right)) {
+        return l.partial_cmp(&r);
+    }
+    if let (Some(l), Some(r)) = (as_str(left), as_str(right)) {
+        return Some(l.cmp(r));
+    }
+    if let (Some(l), Some(r)) = (as_bool(left), as_bool(right)) {
+        return Some(l.cmp(&r));
+    }
+    match (as_timestamp(left), as_timestamp(right)) {
+        (Some(l), Some(r)) => Some(l.cmp(&r)),
+        (Some(l), None) => as_str(right)
+            .and_then(|r| Timestamp::parse(r).ok())
+            .map(|r| l.cmp(&r)),
+        (None, Some(r)) => as_str(left)
+            .and_then(|l| Timestamp::parse(l).ok())
+            .map(|l| l.cmp(&r)),
+        (None, None) => None,
+    }
+}
+
+/*
+ * Render a non-null value as text, e.g. for GROUP_CONCAT
+ */
+pub fn to_text(value: &dyn SqlType) -> Option<String> {
+    let any = value.as_any();
+    if let Some(v) = as_str(value) {
+        Some(v.to_string())
+    } else if let Some(v) = as_decimal(value) {
+        Some(v.to_string())
+    } else if let Some(v) = any.downcast_ref::<Timestamp>() {
+        Some(v.0.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+    } else if let Some(v) = any.downcast_ref::<Date>() {
+        Some(v.0.format("%Y-%m-%d").to_string())
+    } else if let Some(v) = as_bool(value) {
+        Some(v.to_string())
+    } else {
+        as_f64(value).map(|v| v.to_string())
+    }
+}
+
+/*
+ * A hashable stand-in for a value, where values that compare equal share a key.
+ * Integral floats and decimals hash as integers, -0.0 as 0, and every NaN alike. Other
+ * decimals hash exactly, so that decimals rounding to the same float stay apart, and
+ * floats hash as the decimal they are written as. Dates hash as the timestamp of their
+ * midnight.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SqlKey {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(u64),
+    Decimal(Decimal),
+    Text(String),
+    Time(Timestamp),
+}
+
+impl From<&dyn SqlType> for SqlKey {
+    fn from(value: &dyn SqlType) -> SqlKey {
+        if let Some(v) = as_i64(value) {
+            SqlKey::Int(v)
+        } else if let Some(v) = as_bool(value) {
+            SqlKey::Bool(v)
+        } else if let Some(v) = as_timestamp(value) {
+            SqlKey::Time(v)
+        } else if let Some(v) = as_decimal(value) {
+            match v.fract().is_zero().then(|| v.to_i64()).flatten() {
+                Some(v) => SqlKey::Int(v),
+                None => SqlKey::Decimal(v.normalize()),
+            }
+        } else if let Some(v) = as_f64(value) {
+            if v.is_nan() {
+                SqlKey::Float(f64::NAN.to_bits())
+            } else if v.fract() == 0.0 && v >= i64::MIN as f64 && v < i64::MAX as f64 {
+                SqlKey::Int(v as i64)
+            } else {
+                match as_exact_decimal(value) {
+                    Some(d) => SqlKey::Decimal(d.normalize()),
+                    None => SqlKey::Float(v.to_bits()),
+                }
+            }
+        } else if let Some(v) = as_str(value) {
+            SqlKey::Text(v.to_string())
+        } else {
+            SqlKey::Null
+        }
+    }
 }
 
 #[cfg(test)]
@@ -108,4 +474,132 @@ mod tests {
             };
         }
     }
+
+    #[actix_rt::test]
+    async fn can_parse_temporal_types() {
+        let expected = Timestamp(Utc.ymd(2021, 3, 1).and_hms(12, 30, 0));
+        for s in [
+            "2021-03-01T12:30:00Z",
+            "2021-03-01T14:30:00+02:00",
+            "2021-03-01 12:30:00",
+            "2021-03-01T12:30:00.000",
+            "1614601800",
+            " 1614601800.0 ",
+        ]
+        .iter()
+        {
+            assert_eq!(Timestamp::parse(s).unwrap(), expected, "{}", s);
+        }
+        assert_eq!(
+            Timestamp::parse("1614601800.25")
+                .unwrap()
+                .0
+                .timestamp_millis(),
+            1614601800250
+        );
+        assert_eq!(
+            Timestamp::parse("2021-03-01").unwrap(),
+            Date::parse("2021-03-01").unwrap().into()
+        );
+        for s in ["2021-02-30", "yesterday", ""].iter() {
+            assert!(Timestamp::parse(s).is_err(), "{}", s);
+            assert!(Date::parse(s).is_err(), "{}", s);
+        }
+        assert_eq!(
+            to_text(&expected).unwrap(),
+            String::from("2021-03-01T12:30:00Z")
+        );
+        let date: Box<dyn SqlType> = Box::new(Date::parse("2021-03-01").unwrap());
+        assert_eq!(
+            serde_json::to_value(&date).unwrap(),
+            serde_json::json!({ "Date": "2021-03-01" })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn can_parse_bools_and_decimals() {
+        assert!(parse_bool("TRUE").unwrap());
+        assert!(!parse_bool(" f").unwrap());
+        assert!(parse_bool("maybe").is_err());
+
+        let decimal = |s: &str, precision, scale| parse_decimal(s, precision, scale);
+        assert_eq!(decimal("1.005", None, Some(2)).unwrap().to_string(), "1.01");
+        assert_eq!(
+            decimal("-1.005", None, Some(2)).unwrap().to_string(),
+            "-1.01"
+        );
+        assert_eq!(decimal("7", None, Some(2)).unwrap().to_string(), "7.00");
+        assert_eq!(decimal("1.5e2", None, None).unwrap().to_string(), "150");
+        assert_eq!(
+            decimal("999.99", Some(5), Some(2)).unwrap().to_string(),
+            "999.99"
+        );
+        assert!(decimal("1000.00", Some(5), Some(2)).is_err());
+        assert!(decimal("12abc", None, None).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn can_compare_mixed_types() {
+        let day = Date::parse("2021-03-01").unwrap();
+        let noon = Timestamp::parse("2021-03-01T12:00:00Z").unwrap();
+        assert_eq!(compare(&day, &noon), Some(Ordering::Less));
+        assert_eq!(
+            compare(&noon, &String::from("2021-03-01 12:00:00")),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(
+            compare(&String::from("2021-03-02"), &noon),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(compare(&noon, &String::from("noon")), None);
+
+        let cents = parse_decimal("0.10", None, None).unwrap();
+        assert_eq!(
+            compare(&cents, &parse_decimal("0.1", None, None).unwrap()),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(compare(&cents, &1_i64), Some(Ordering::Less));
+        assert_eq!(compare(&cents, &0.1_f64), Some(Ordering::Equal));
+        assert_eq!(compare(&true, &false), Some(Ordering::Greater));
+        assert_eq!(compare(&true, &1_i64), None);
+        assert_eq!(truthy(&false), Some(false));
+    }
+
+    #[actix_rt::test]
+    async fn equal_values_share_keys() {
+        let key = |value: &dyn SqlType| SqlKey::from(value);
+        assert_eq!(key(&3_i64), key(&3.0_f64));
+        assert_eq!(key(&0.0_f64), key(&-0.0_f64));
+        assert_eq!(key(&f64::NAN), key(&-f64::NAN));
+        assert_ne!(key(&3_i64), key(&3.5_f64));
+        assert_ne!(key(&3_i64), key(&String::from("3")));
+        assert_eq!(key(&Null::default()), SqlKey::Null);
+        assert_eq!(
+            key(&parse_decimal("2.50", None, None).unwrap()),
+            key(&2.5_f64)
+        );
+        assert_eq!(
+            key(&parse_decimal("3.00", None, None).unwrap()),
+            key(&3_i64)
+        );
+        assert_eq!(
+            key(&parse_decimal("0.10", None, None).unwrap()),
+            key(&0.1_f64)
+        );
+        assert_ne!(
+            key(&parse_decimal("1.0000000000000000001", None, None).unwrap()),
+            key(&parse_decimal("1.0000000000000000002", None, None).unwrap())
+        );
+        assert_ne!(
+            key(&parse_decimal("1.0000000000000000001", None, None).unwrap()),
+            key(&1_i64)
+        );
+        assert_eq!(key(&1e300_f64), SqlKey::Float(1e300_f64.to_bits()));
+        assert_eq!(key(&1e-30_f64), SqlKey::Float(1e-30_f64.to_bits()));
+        assert_eq!(
+            key(&Date::parse("2021-03-01").unwrap()),
+            key(&Timestamp::parse("2021-03-01T00:00:00Z").unwrap())
+        );
+        assert_ne!(key(&true), key(&1_i64));
+    }
 }
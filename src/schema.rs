@@ -2,6 +2,9 @@ table! {
     table_schemas (id) {
         id -> Int8,
         column_types -> Array<Text>,
+        column_names -> Array<Text>,
+        column_nullable -> Array<Bool>,
+        null_values -> Array<Text>,
         created_at -> Timestamp,
         updated_at -> Timestamp,
     }
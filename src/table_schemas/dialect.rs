@@ -0,0 +1,289 @@
+use crate::error_handler::CustomError;
+use csv_core::ReadRecordResult;
+use serde::{Deserialize, Serialize};
+
+// How the CSV of an upload or sample is written. Options are given as query parameters,
+// e.g. ?header=true&delimiter=tab&comment=%23, and default to headerless comma separated
+// values with double quotes.
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct CsvDialect {
+    pub header: Option<bool>,
+    pub delimiter: Option<String>,
+    pub quote: Option<String>,
+    pub escape: Option<String>,
+    pub comment: Option<String>,
+    #[serde(default)]
+    pub trim: bool,
+}
+
+impl CsvDialect {
+    /*
+     * An incremental parser of rows in this dialect, leaving header rows to the caller
+     */
+    pub fn parser(&self) -> Result<CsvParser, CustomError> {
+        let mut builder = csv_core::ReaderBuilder::new();
+        if let Some(delimiter) = &self.delimiter {
+            builder.delimiter(delimiter_byte(delimiter)?);
+        }
+        if let Some(quote) = &self.quote {
+            match quote.as_str() {
+                "none" => builder.quoting(false),
+                quote => builder.quote(byte("quote", quote)?),
+            };
+        }
+        if let Some(escape) = &self.escape {
+            builder
+                .escape(Some(byte("escape", escape)?))
+                .double_quote(false);
+        }
+        if let Some(comment) = &self.comment {
+            builder.comment(Some(byte("comment", comment)?));
+        }
+        Ok(CsvParser {
+            reader: builder.build(),
+            trim: self.trim,
+            output: vec![0; 1024],
+            output_len: 0,
+            ends: vec![0; 16],
+            ends_len: 0,
+        })
+    }
+
+    /*
+     * All the rows of some CSV, which must have the same number of fields
+     */
+    pub fn rows(&self, data: &[u8]) -> Result<Vec<Vec<String>>, CustomError> {
+        let mut parser = self.parser()?;
+        let mut rows: Vec<Vec<String>> = Vec::new();
+        let mut on_row = |row: Vec<String>| match rows.first() {
+            Some(first) if first.len() != row.len() => Err(CustomError::new(
+                400,
+                format!(
+                    "Bad request: Expected {} fields but found {} (row {})",
+                    first.len(),
+                    row.len(),
+                    rows.len() + 1
+                ),
+            )),
+            _ => {
+                rows.push(row);
+                Ok(())
+            }
+        };
+        parser.push(data, &mut on_row)?;
+        parser.finish(&mut on_row)?;
+        Ok(rows)
+    }
+}
+
+// Parses CSV as it arrives, keeping the fields of a row that spans chunks until it ends
+pub struct CsvParser {
+    reader: csv_core::Reader,
+    trim: bool,
+    output: Vec<u8>,
+    output_len: usize,
+    ends: Vec<usize>,
+    ends_len: usize,
+}
+
+impl CsvParser {
+    /*
+     * Parse a chunk of CSV, handing each row it completes to on_row
+     */
+    pub fn push<F>(&mut self, chunk: &[u8], on_row: F) -> Result<(), CustomError>
+    where
+        F: FnMut(Vec<String>) -> Result<(), CustomError>,
+    {
+        self.parse(chunk, false, on_row)
+    }
+
+    /*
+     * End the CSV, completing a last row that has no line terminator
+     */
+    pub fn finish<F>(&mut self, on_row: F) -> Result<(), CustomError>
+    where
+        F: FnMut(Vec<String>) -> Result<(), CustomError>,
+    {
+        self.parse(&[], true, on_row)
+    }
+
+    fn parse<F>(&mut self, mut input: &[u8], end: bool, mut on_row: F) -> Result<(), CustomError>
+    where
+        F: FnMut(Vec<String>) -> Result<(), CustomError>,
+    {
+        // The reader takes empty input as the end of the CSV
+        while end || !input.is_empty() {
+            let (result, nin, nout, nend) = self.reader.read_record(
+                input,
+                &mut self.output[self.output_len..],
+                &mut self.ends[self.ends_len..],
+            );
+            input = &input[nin..];
+            self.output_len += nout;
+            self.ends_len += nend;
+            match result {
+                ReadRecordResult::InputEmpty | ReadRecordResult::End => break,
+                ReadRecordResult::OutputFull => self.output.resize(self.output.len() * 2, 0),
+                ReadRecordResult::OutputEndsFull => self.ends.resize(self.ends.len() * 2, 0),
+                ReadRecordResult::Record => {
+                    let row = self.row()?;
+                    self.output_len = 0;
+                    self.ends_len = 0;
+                    on_row(row)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn row(&self) -> Result<Vec<String>, CustomError> {
+        let mut start = 0;
+        self.ends[..self.ends_len]
+            .iter()
+            .map(|end| {
+                let field = std::str::from_utf8(&self.output[start..*end]).map_err(|_| {
+                    CustomError::new(400, String::from("Bad request: Invalid UTF-8 in CSV"))
+                })?;
+                start = *end;
+                Ok(String::from(match self.trim {
+                    true => field.trim(),
+                    false => field,
+                }))
+            })
+            .collect()
+    }
+}
+
+fn delimiter_byte(delimiter: &str) -> Result<u8, CustomError> {
+    match delimiter.to_lowercase().as_str() {
+        "comma" => Ok(b','),
+        "tab" | "\\t" => Ok(b'\t'),
+        "semicolon" => Ok(b';'),
+        "pipe" => Ok(b'|'),
+        "space" => Ok(b' '),
+        _ => byte("delimiter", delimiter),
+    }
+}
+
+/*
+ * Dialect characters must be a single ASCII character
+ */
+fn byte(option: &str, value: &str) -> Result<u8, CustomError> {
+    match value.as_bytes() {
+        [byte] if byte.is_ascii() && *byte != b'\n' && *byte != b'\r' => Ok(*byte),
+        _ => Err(CustomError::new(
+            400,
+            format!("Bad request: Invalid {} '{}'", option, value),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(dialect: &CsvDialect, data: &str) -> Vec<Vec<String>> {
+        dialect.rows(data.as_bytes()).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn can_read_dialects() {
+        let tsv = CsvDialect {
+            delimiter: Some(String::from("tab")),
+            ..Default::default()
+        };
+        assert_eq!(read(&tsv, "a\t1,5\nb\t2\n"), [["a", "1,5"], ["b", "2"]]);
+
+        let semicolons = CsvDialect {
+            delimiter: Some(String::from(";")),
+            comment: Some(String::from("#")),
+            trim: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            read(&semicolons, "# exported\na ; 1\n b;2 \n"),
+            [["a", "1"], ["b", "2"]]
+        );
+
+        let escaped = CsvDialect {
+            delimiter: Some(String::from("pipe")),
+            quote: Some(String::from("'")),
+            escape: Some(String::from("\\")),
+            ..Default::default()
+        };
+        assert_eq!(read(&escaped, "'a|b'|'it\\'s'\n"), [["a|b", "it's"]]);
+
+        let unquoted = CsvDialect {
+            quote: Some(String::from("none")),
+            ..Default::default()
+        };
+        assert_eq!(read(&unquoted, "\"a,b\n"), [["\"a", "b"]]);
+    }
+
+    #[actix_rt::test]
+    async fn can_parse_rows_across_chunks() {
+        let data = "a,\"multi\nline, quoted\"\n\nb,\"two\"\nc,\u{e9}t\u{e9}";
+        let dialect = CsvDialect::default();
+        let expected = dialect.rows(data.as_bytes()).unwrap();
+        assert_eq!(
+            expected,
+            [
+                ["a", "multi\nline, quoted"],
+                ["b", "two"],
+                ["c", "\u{e9}t\u{e9}"]
+            ]
+        );
+
+        // Every split of the data parses the same rows, even inside a UTF-8 character
+        for chunk_size in 1..data.len() {
+            let mut parser = dialect.parser().unwrap();
+            let mut rows = Vec::new();
+            for chunk in data.as_bytes().chunks(chunk_size) {
+                parser
+                    .push(chunk, |row| {
+                        rows.push(row);
+                        Ok(())
+                    })
+                    .unwrap();
+            }
+            parser
+                .finish(|row| {
+                    rows.push(row);
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(rows, expected);
+        }
+
+        let err = dialect.rows(b"a,1\nb\n").unwrap_err();
+        assert_eq!(
+            err.error_message,
+            "Bad request: Expected 2 fields but found 1 (row 2)"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn invalid_dialects_are_bad_requests() {
+        for dialect in [
+            CsvDialect {
+                delimiter: Some(String::from("::")),
+                ..Default::default()
+            },
+            CsvDialect {
+                quote: Some(String::new()),
+                ..Default::default()
+            },
+            CsvDialect {
+                comment: Some(String::from("\n")),
+                ..Default::default()
+            },
+        ]
+        .iter()
+        {
+            let err = dialect.parser().err().unwrap();
+            assert_eq!(err.error_status_code, 400);
+            assert!(err.error_message.starts_with("Bad request: Invalid"));
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use super::{CsvDialect, MaybeTableSchema};
+use crate::error_handler::CustomError;
+use crate::query::ColumnType;
+
+// Schema inference for CSV samples. Each column takes the narrowest type that reads every
+// value in the sample, and is nullable when any of its values is empty or a null value.
+
+const CANDIDATE_TYPES: &[ColumnType] = &[
+    ColumnType::I64,
+    ColumnType::F64,
+    ColumnType::Bool,
+    ColumnType::Timestamp,
+];
+
+impl MaybeTableSchema {
+    /*
+     * Infer the names, types and nullability of the columns of a CSV sample. The first row
+     * names the columns when the dialect has a header, or when that is left unset and the
+     * row holds names above typed columns
+     */
+    pub fn infer(sample: &[u8], dialect: &CsvDialect) -> Result<MaybeTableSchema, CustomError> {
+        let rows = dialect.rows(sample)?;
+        if rows.is_empty() {
+            return Err(CustomError::new(
+                400,
+                String::from("Bad request: Cannot infer a schema from an empty sample"),
+            ));
+        }
+
+        let defaults = MaybeTableSchema::default();
+        let width = rows[0].len();
+        let infer_columns = |rows: &[Vec<String>]| -> Vec<(ColumnType, bool)> {
+            (0..width)
+                .map(|i| infer_column(rows.iter().map(|row| row[i].as_str()), &defaults))
+                .collect()
+        };
+        let columns = infer_columns(&rows[1..]);
+        let header = dialect
+            .header
+            .unwrap_or_else(|| rows.len() > 1 && is_header(&rows[0], &columns, &defaults));
+        let columns = match header {
+            true => columns,
+            false => infer_columns(&rows),
+        };
+
+        let table_schema = MaybeTableSchema {
+            column_types: columns.iter().map(|(t, _)| t.to_string()).collect(),
+            column_names: match header {
+                true => rows[0]
+                    .iter()
+                    .enumerate()
+                    .map(|(i, name)| column_name(i, name))
+                    .collect(),
+                false => Vec::new(),
+            },
+            column_nullable: columns.iter().map(|(_, nullable)| *nullable).collect(),
+            ..defaults
+        };
+        log::debug!("Inferred {:?} from {} rows", table_schema, rows.len());
+        Ok(table_schema)
+    }
+
+    fn is_null_value(&self, value: &str) -> bool {
+        let value = value.trim();
+        value.is_empty()
+            || self
+                .null_values
+                .iter()
+                .any(|null_value| null_value.eq_ignore_ascii_case(value))
+    }
+}
+
+/*
+ * The first type that reads every non-null value, or string, and whether there were nulls
+ */
+fn infer_column<'a>(
+    values: impl Iterator<Item = &'a str>,
+    table_schema: &MaybeTableSchema,
+) -> (ColumnType, bool) {
+    let mut candidates = CANDIDATE_TYPES.to_vec();
+    let mut nullable = false;
+    let mut empty = true;
+    for value in values {
+        if table_schema.is_null_value(value) {
+            nullable = true;
+            continue;
+        }
+        empty = false;
+        candidates.retain(|column_type| column_type.read(String::from(value)).is_ok());
+    }
+    match candidates.first() {
+        Some(column_type) if !empty => (*column_type, nullable),
+        _ => (ColumnType::String, nullable),
+    }
+}
+
+/*
+ * A row is a header when its values are all names rather than typed values, and some
+ * column of the rest of the sample is typed
+ */
+fn is_header(
+    row: &[String],
+    columns: &[(ColumnType, bool)],
+    table_schema: &MaybeTableSchema,
+) -> bool {
+    let is_name = |value: &String| {
+        !table_schema.is_null_value(value)
+            && CANDIDATE_TYPES
+                .iter()
+                .all(|column_type| column_type.read(value.clone()).is_err())
+    };
+    row.iter().all(is_name)
+        && columns
+            .iter()
+            .any(|(column_type, _)| *column_type != ColumnType::String)
+}
+
+/*
+ * Header names are lowercase words joined by underscores, falling back to the position
+ */
+pub(super) fn column_name(i: usize, name: &str) -> String {
+    let name = name
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("_");
+    match name.is_empty() {
+        true => format!("c{}", i),
+        false => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn can_infer_types_and_nullability() {
+        let sample = b"a,1,1.5,true,2021-03-01 12:30:00,x\n\
+                       b,2,2,false,2021-03-02,\n\
+                       NA,3,,yes,1614601800,null\n";
+        let table_schema = MaybeTableSchema::infer(sample, &CsvDialect::default()).unwrap();
+        assert!(table_schema.column_names.is_empty());
+        assert_eq!(
+            table_schema.column_types,
+            ["string", "i64", "f64", "bool", "timestamp", "string"]
+        );
+        assert_eq!(
+            table_schema.column_nullable,
+            [true, false, true, false, false, true]
+        );
+        let table_schema = table_schema.normalize().unwrap();
+        assert_eq!(
+            table_schema.column_names,
+            ["c0", "c1", "c2", "c3", "c4", "c5"]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn can_detect_header_rows() {
+        let sample = b"Device,Reading,Temp (C)\na,1,1.5\nb,2,\n";
+        let table_schema = MaybeTableSchema::infer(sample, &CsvDialect::default()).unwrap();
+        assert_eq!(table_schema.column_names, ["device", "reading", "temp_c"]);
+        assert_eq!(table_schema.column_types, ["string", "i64", "f64"]);
+        assert_eq!(table_schema.column_nullable, [false, false, true]);
+
+        // Without typed columns, the first row is only a header when asked
+        let sample = b"device,site\na,north\n";
+        let table_schema = MaybeTableSchema::infer(sample, &CsvDialect::default()).unwrap();
+        assert!(table_schema.column_names.is_empty());
+        let table_schema = MaybeTableSchema::infer(
+            sample,
+            &CsvDialect {
+                header: Some(true),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(table_schema.column_names, ["device", "site"]);
+        assert_eq!(table_schema.column_types, ["string", "string"]);
+
+        let sample = b"1,2\n3,4\n";
+        let table_schema = MaybeTableSchema::infer(
+            sample,
+            &CsvDialect {
+                header: Some(false),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(table_schema.column_types, ["i64", "i64"]);
+    }
+
+    #[actix_rt::test]
+    async fn infer_errors_on_bad_samples() {
+        let err = MaybeTableSchema::infer(b"", &CsvDialect::default()).unwrap_err();
+        assert_eq!(err.error_status_code, 400);
+        let err = MaybeTableSchema::infer(b"a,1\nb\n", &CsvDialect::default()).unwrap_err();
+        assert_eq!(err.error_status_code, 400);
+    }
+}
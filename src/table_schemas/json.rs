@@ -0,0 +1,244 @@
+use super::{infer::column_name, TableSchema};
+use crate::error_handler::CustomError;
+use crate::query::{QueryRecord, QueryRecordBuilder};
+use serde_json::{Map, Value};
+
+// JSON uploads hold either an array of objects or one object per line (NDJSON). Keys name
+// the columns as header names do, with nested objects flattening into dotted names, so
+// {"location": {"lat": 1}} fills the column location.lat, which queries quote as "location.lat".
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UploadFormat {
+    Csv,
+    Json,
+    Ndjson,
+}
+
+impl UploadFormat {
+    /*
+     * The format of a multipart field by its content type, or else its name
+     */
+    pub fn detect(content_type: &str, field_name: Option<&str>) -> UploadFormat {
+        match content_type {
+            "application/json" => UploadFormat::Json,
+            "application/x-ndjson" | "application/ndjson" | "application/jsonl" => {
+                UploadFormat::Ndjson
+            }
+            _ => match field_name {
+                Some("json") => UploadFormat::Json,
+                Some("ndjson") | Some("jsonl") => UploadFormat::Ndjson,
+                _ => UploadFormat::Csv,
+            },
+        }
+    }
+}
+
+impl TableSchema {
+    /*
+     * Read a JSON or NDJSON upload into records of this schema. Missing keys are NULL
+     */
+    pub fn verify_json(
+        &self,
+        raw_data: Vec<u8>,
+        format: UploadFormat,
+    ) -> Result<Vec<QueryRecord>, CustomError> {
+        let objects = match format {
+            UploadFormat::Json => {
+                match serde_json::from_slice(&raw_data).map_err(|err| invalid_json(err, None))? {
+                    Value::Array(values) => values,
+                    _ => {
+                        return Err(CustomError::new(
+                            400,
+                            String::from("Bad request: Expected a JSON array of objects"),
+                        ))
+                    }
+                }
+            }
+            _ => raw_data
+                .split(|byte| *byte == b'\n')
+                .enumerate()
+                .filter(|(_, line)| !line.iter().all(u8::is_ascii_whitespace))
+                .map(|(i, line)| {
+                    serde_json::from_slice(line).map_err(|err| invalid_json(err, Some(i + 1)))
+                })
+                .collect::<Result<Vec<Value>, _>>()?,
+        };
+
+        let query_record_builder = QueryRecordBuilder::new(self)?;
+        let records = objects
+            .into_iter()
+            .enumerate()
+            .map(|(i, object)| {
+                let columns = self.json_columns(i + 1, object)?;
+                query_record_builder.read_json(i + 1, columns)
+            })
+            .collect::<Result<Vec<_>, CustomError>>()?;
+        log::debug!("Verified {} JSON rows for {:?}", records.len(), &self);
+        Ok(records)
+    }
+
+    /*
+     * The values of an object in column order
+     */
+    fn json_columns(&self, row: usize, object: Value) -> Result<Vec<Value>, CustomError> {
+        let object = match object {
+            Value::Object(object) => object,
+            _ => {
+                return Err(CustomError::new(
+                    400,
+                    format!("Bad request: Expected a JSON object (row {})", row),
+                ))
+            }
+        };
+        let mut columns = vec![Value::Null; self.column_names.len()];
+        let mut fields = Vec::new();
+        flatten("", object, &mut fields);
+        for (name, value) in fields {
+            match self.column_names.iter().position(|c| *c == name) {
+                Some(position) => columns[position] = value,
+                None => {
+                    return Err(CustomError::new(
+                        400,
+                        format!("Bad request: Unknown column '{}' (row {})", name, row),
+                    ))
+                }
+            }
+        }
+        Ok(columns)
+    }
+}
+
+fn invalid_json(err: serde_json::Error, line: Option<usize>) -> CustomError {
+    let message = match line {
+        Some(line) => format!("Bad request: Invalid JSON: {} (line {})", err, line),
+        None => format!("Bad request: Invalid JSON: {}", err),
+    };
+    CustomError::new(400, message)
+}
+
+fn flatten(prefix: &str, object: Map<String, Value>, fields: &mut Vec<(String, Value)>) {
+    for (key, value) in object {
+        let key = match prefix {
+            "" => column_name(0, &key),
+            prefix => format!("{}.{}", prefix, column_name(0, &key)),
+        };
+        match value {
+            Value::Object(object) => flatten(&key, object, fields),
+            value => fields.push((key, value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::{as_f64, as_i64, as_str, is_null, to_text};
+
+    fn table_schema() -> TableSchema {
+        TableSchema {
+            id: 0,
+            column_types: ["string", "i64", "f64", "bool", "timestamp"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            column_names: ["device", "reading", "location.lat", "online", "seen_at"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            column_nullable: vec![false, false, true, true, true],
+            null_values: Vec::new(),
+            created_at: chrono::Utc::now().naive_utc(),
+            updated_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn can_detect_upload_formats() {
+        assert_eq!(
+            UploadFormat::detect("text/csv", Some("csv")),
+            UploadFormat::Csv
+        );
+        assert_eq!(
+            UploadFormat::detect("application/json", Some("csv")),
+            UploadFormat::Json
+        );
+        assert_eq!(
+            UploadFormat::detect("application/x-ndjson", None),
+            UploadFormat::Ndjson
+        );
+        assert_eq!(
+            UploadFormat::detect("application/octet-stream", Some("ndjson")),
+            UploadFormat::Ndjson
+        );
+    }
+
+    #[actix_rt::test]
+    async fn can_read_json_arrays_and_lines() {
+        let json = br#"[
+            {"device": "a", "reading": 1, "location": {"lat": 45.5}, "online": true,
+             "seen_at": "2021-03-01T12:30:00Z"},
+            {"reading": "2", "device": 7, "online": null}
+        ]"#;
+        let records = table_schema()
+            .verify_json(json.to_vec(), UploadFormat::Json)
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(as_str(records[0].columns[0].as_ref()), Some("a"));
+        assert_eq!(as_i64(records[0].columns[1].as_ref()), Some(1));
+        assert_eq!(as_f64(records[0].columns[2].as_ref()), Some(45.5));
+        assert_eq!(
+            to_text(records[0].columns[4].as_ref()).unwrap(),
+            "2021-03-01T12:30:00Z"
+        );
+        assert_eq!(as_str(records[1].columns[0].as_ref()), Some("7"));
+        assert_eq!(as_i64(records[1].columns[1].as_ref()), Some(2));
+        assert!(is_null(records[1].columns[2].as_ref()));
+        assert!(is_null(records[1].columns[3].as_ref()));
+
+        let ndjson =
+            b"{\"device\": \"a\", \"reading\": 1}\n\n{\"device\": \"b\", \"reading\": 2}\n";
+        let records = table_schema()
+            .verify_json(ndjson.to_vec(), UploadFormat::Ndjson)
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(as_i64(records[1].columns[1].as_ref()), Some(2));
+    }
+
+    #[actix_rt::test]
+    async fn json_errors_name_the_row() {
+        let verify = |json: &str, format| {
+            table_schema()
+                .verify_json(json.as_bytes().to_vec(), format)
+                .unwrap_err()
+                .error_message
+        };
+        assert!(verify("[{", UploadFormat::Json).starts_with("Bad request: Invalid JSON"));
+        assert!(verify("{}\n\n{", UploadFormat::Ndjson).ends_with("(line 3)"));
+        assert_eq!(
+            verify(r#"{"device": "a"}"#, UploadFormat::Json),
+            "Bad request: Expected a JSON array of objects"
+        );
+        assert_eq!(
+            verify(
+                "{\"device\": \"a\", \"reading\": 1}\n[1]",
+                UploadFormat::Ndjson
+            ),
+            "Bad request: Expected a JSON object (row 2)"
+        );
+        assert_eq!(
+            verify(
+                r#"[{"device": "a", "reading": 1, "site": "x"}]"#,
+                UploadFormat::Json
+            ),
+            "Bad request: Unknown column 'site' (row 1)"
+        );
+        assert_eq!(
+            verify(r#"[{"device": "a"}]"#, UploadFormat::Json),
+            "Bad request: Missing i64 in non-nullable column (row 1, column 'reading')"
+        );
+        assert_eq!(
+            verify(r#"[{"device": "a", "reading": 1.5}]"#, UploadFormat::Json),
+            "Bad request: Invalid i64 '1.5' (row 1, column 'reading')"
+        );
+    }
+}
@@ -1,5 +1,12 @@
+mod dialect;
+mod infer;
+mod json;
 mod model;
 mod routes;
+mod stream;
 
+pub use dialect::*;
+pub use json::*;
 pub use model::*;
 pub use routes::init_routes;
+pub use stream::*;
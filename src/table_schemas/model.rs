@@ -1,6 +1,7 @@
+use super::CsvDialect;
 use crate::db;
 use crate::error_handler::*;
-use crate::query::{QueryRecord, QueryRecordBuilder};
+use crate::query::{ColumnType, QueryRecord};
 use crate::schema::table_schemas;
 use chrono::NaiveDateTime;
 use diesel::prelude::*;
@@ -11,14 +12,39 @@ use serde::{Deserialize, Serialize};
 pub struct TableSchema {
     pub id: i64,
     pub column_types: Vec<String>,
+    pub column_names: Vec<String>,
+    pub column_nullable: Vec<bool>,
+    pub null_values: Vec<String>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
 }
 
-#[derive(Debug, Serialize, Deserialize, Insertable, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, Insertable, PartialEq)]
 #[table_name = "table_schemas"]
 pub struct MaybeTableSchema {
     pub column_types: Vec<String>,
+    #[serde(default)]
+    pub column_names: Vec<String>,
+    #[serde(default)]
+    pub column_nullable: Vec<bool>,
+    #[serde(default = "default_null_values")]
+    pub null_values: Vec<String>,
+}
+
+// Besides empty fields, these values are read as NULL in nullable columns
+fn default_null_values() -> Vec<String> {
+    vec![String::from("NA"), String::from("null")]
+}
+
+impl Default for MaybeTableSchema {
+    fn default() -> Self {
+        MaybeTableSchema {
+            column_types: Vec::new(),
+            column_names: Vec::new(),
+            column_nullable: Vec::new(),
+            null_values: default_null_values(),
+        }
+    }
 }
 
 impl TableSchema {
@@ -32,9 +58,18 @@ impl TableSchema {
 
     pub fn find_by_types(maybe_table_schema: MaybeTableSchema) -> Result<TableSchema, CustomError> {
         let conn = db::connection()?;
-        let table_schema = table_schemas::table
+        let mut query = table_schemas::table
             .filter(table_schemas::column_types.eq(maybe_table_schema.column_types))
-            .first(&conn)?;
+            .into_boxed();
+        if !maybe_table_schema.column_names.is_empty() {
+            query = query.filter(table_schemas::column_names.eq(maybe_table_schema.column_names));
+        }
+        if !maybe_table_schema.column_nullable.is_empty() {
+            query = query
+                .filter(table_schemas::column_nullable.eq(maybe_table_schema.column_nullable))
+                .filter(table_schemas::null_values.eq(maybe_table_schema.null_values));
+        }
+        let table_schema = query.first(&conn)?;
         Ok(table_schema)
     }
 
@@ -54,25 +89,103 @@ impl TableSchema {
         Ok(table_schema)
     }
 
-    pub fn verify(&self, raw_data: Vec<u8>) -> Result<Vec<QueryRecord>, CustomError> {
-        let mut reader = csv::ReaderBuilder::new()
-            .has_headers(false)
-            .from_reader(raw_data.as_slice());
-        let query_record_builder = QueryRecordBuilder::new(self);
-        let records: Result<Vec<_>, _> = reader
-            .deserialize()
-            .map(|raw_record| query_record_builder.from_vec(raw_record?))
-            .collect();
-        let records = records?;
+    /*
+     * Read an upload in the given dialect into records of this schema
+     */
+    pub fn verify(
+        &self,
+        raw_data: Vec<u8>,
+        dialect: &CsvDialect,
+    ) -> Result<Vec<QueryRecord>, CustomError> {
+        let mut record_stream = self.record_stream(dialect, usize::MAX)?;
+        record_stream.push(&raw_data)?;
+        let records = record_stream.finish()?;
         log::debug!("Verified {} rows for {:?}", records.len(), &self);
         Ok(records)
     }
 }
 
+impl MaybeTableSchema {
+    /*
+     * Store types by their canonical names, rejecting any that are not registered, and
+     * lowercase names, naming columns by position when no names are given. Columns are
+     * not nullable unless flagged
+     */
+    pub fn normalize(mut self) -> Result<MaybeTableSchema, CustomError> {
+        if self.column_names.is_empty() {
+            self.column_names = (0..self.column_types.len())
+                .map(|i| format!("c{}", i))
+                .collect();
+        }
+        if self.column_nullable.is_empty() {
+            self.column_nullable = vec![false; self.column_types.len()];
+        }
+        self.column_names = self
+            .column_names
+            .into_iter()
+            .map(|s| s.trim().to_lowercase())
+            .collect();
+        self.column_types = self
+            .column_types
+            .iter()
+            .enumerate()
+            .map(|(i, s)| match s.parse::<ColumnType>() {
+                Ok(column_type) => Ok(column_type.to_string()),
+                Err(err) => Err(match self.column_names.get(i) {
+                    Some(name) => CustomError::new(
+                        err.error_status_code,
+                        format!("{} (column '{}')", err.error_message, name),
+                    ),
+                    None => err,
+                }),
+            })
+            .collect::<Result<_, _>>()?;
+
+        if self.column_names.len() != self.column_types.len() {
+            return Err(CustomError::new(
+                400,
+                format!(
+                    "Bad request: {} column names for {} column types",
+                    self.column_names.len(),
+                    self.column_types.len()
+                ),
+            ));
+        }
+        if self.column_nullable.len() != self.column_types.len() {
+            return Err(CustomError::new(
+                400,
+                format!(
+                    "Bad request: {} nullable flags for {} column types",
+                    self.column_nullable.len(),
+                    self.column_types.len()
+                ),
+            ));
+        }
+        for (i, name) in self.column_names.iter().enumerate() {
+            if name.split('.').any(str::is_empty) {
+                return Err(CustomError::new(
+                    400,
+                    format!("Bad request: Invalid column name '{}'", name),
+                ));
+            }
+            if self.column_names[..i].contains(name) {
+                return Err(CustomError::new(
+                    400,
+                    format!("Bad request: Duplicate column name '{}'", name),
+                ));
+            }
+        }
+        Ok(self)
+    }
+}
+
 impl From<TableSchema> for MaybeTableSchema {
     fn from(table_schema: TableSchema) -> Self {
         MaybeTableSchema {
             column_types: table_schema.column_types,
+            column_names: table_schema.column_names,
+            column_nullable: table_schema.column_nullable,
+            null_values: table_schema.null_values,
         }
     }
 }
@@ -1,6 +1,8 @@
-use super::{MaybeTableSchema, TableSchema};
+use super::{CsvDialect, MaybeTableSchema, TableSchema};
 use crate::error_handler::CustomError;
+use actix_multipart::Multipart;
 use actix_web::{delete, get, post, web, HttpResponse};
+use futures::{StreamExt, TryStreamExt};
 
 #[get("/table_schemas/types")]
 async fn find_by_types(
@@ -8,14 +10,18 @@ async fn find_by_types(
 ) -> Result<HttpResponse, CustomError> {
     let maybe_table_schema = maybe_table_schema.into_inner();
     log::debug!("GET /table_schemas/types {:?}", maybe_table_schema);
-    let types: Vec<String> = maybe_table_schema
-        .column_types
-        .into_iter()
-        .map(|s| s.to_lowercase())
-        .collect();
-    let table_schema = TableSchema::find_by_types(MaybeTableSchema {
-        column_types: types,
-    })?;
+    // Types and names are looked up as they are stored, but names and nullability only narrow
+    // the lookup when given
+    let any_names = !maybe_table_schema.column_names.is_empty();
+    let any_nullable = !maybe_table_schema.column_nullable.is_empty();
+    let mut maybe_table_schema = maybe_table_schema.normalize()?;
+    if !any_names {
+        maybe_table_schema.column_names.clear();
+    }
+    if !any_nullable {
+        maybe_table_schema.column_nullable.clear();
+    }
+    let table_schema = TableSchema::find_by_types(maybe_table_schema)?;
     Ok(HttpResponse::Ok().json(table_schema))
 }
 
@@ -31,17 +37,40 @@ async fn find_by_id(id: web::Path<i64>) -> Result<HttpResponse, CustomError> {
 async fn create(
     maybe_table_schema: web::Json<MaybeTableSchema>,
 ) -> Result<HttpResponse, CustomError> {
-    let mut maybe_table_schema = maybe_table_schema.into_inner();
+    let maybe_table_schema = maybe_table_schema.into_inner();
     log::debug!("POST /table_schemas {:?}", maybe_table_schema);
-    maybe_table_schema.column_types = maybe_table_schema
-        .column_types
-        .into_iter()
-        .map(|s| s.to_lowercase())
-        .collect();
+    let maybe_table_schema = maybe_table_schema.normalize()?;
     let table_schema = TableSchema::create(maybe_table_schema)?;
     Ok(HttpResponse::Ok().json(table_schema))
 }
 
+/*
+ * Infer a schema from an uploaded CSV sample, reusing a matching schema when there is one
+ */
+#[post("/table_schemas/infer")]
+async fn infer(
+    dialect: web::Query<CsvDialect>,
+    mut sample: Multipart,
+) -> Result<HttpResponse, CustomError> {
+    let dialect = dialect.into_inner();
+    log::debug!("POST /table_schemas/infer {:?}", dialect);
+
+    let mut sample_data = Vec::with_capacity(1024);
+    while let Ok(Some(mut field)) = sample.try_next().await {
+        while let Some(chunk) = field.next().await {
+            sample_data.extend_from_slice(chunk?.as_ref());
+        }
+    }
+
+    let maybe_table_schema = MaybeTableSchema::infer(&sample_data, &dialect)?.normalize()?;
+    let table_schema = match TableSchema::find_by_types(maybe_table_schema.clone()) {
+        Ok(table_schema) => table_schema,
+        Err(err) if err.error_status_code == 404 => TableSchema::create(maybe_table_schema)?,
+        Err(err) => return Err(err),
+    };
+    Ok(HttpResponse::Ok().json(table_schema))
+}
+
 #[delete("/table_schemas/{id}")]
 async fn delete(id: web::Path<i64>) -> Result<HttpResponse, CustomError> {
     let id = id.into_inner();
@@ -54,5 +83,6 @@ pub fn init_routes(config: &mut web::ServiceConfig) {
     config.service(find_by_id);
     config.service(find_by_types);
     config.service(create);
+    config.service(infer);
     config.service(delete);
 }
@@ -0,0 +1,184 @@
+use super::{infer::column_name, CsvDialect, CsvParser, TableSchema};
+use crate::error_handler::CustomError;
+use crate::query::{QueryRecord, QueryRecordBuilder};
+
+// CSV uploads are read into records as their chunks arrive, in batches of a bounded number
+// of rows, so an upload never has to fit in memory and its first batches can be cached
+// before it ends.
+
+pub const UPLOAD_BATCH_ROWS: usize = 4096;
+
+pub struct RecordStream {
+    parser: CsvParser,
+    batcher: RecordBatcher,
+}
+
+struct RecordBatcher {
+    builder: QueryRecordBuilder,
+    column_names: Vec<String>,
+    header: bool,
+    header_len: usize,
+    positions: Option<Vec<usize>>,
+    rows: usize,
+    batch_rows: usize,
+    batch: Vec<QueryRecord>,
+    batches: Vec<Vec<QueryRecord>>,
+}
+
+impl TableSchema {
+    /*
+     * Read CSV in the given dialect into batches of records of this schema. A header row may
+     * order the columns differently, but must name each of them
+     */
+    pub fn record_stream(
+        &self,
+        dialect: &CsvDialect,
+        batch_rows: usize,
+    ) -> Result<RecordStream, CustomError> {
+        Ok(RecordStream {
+            parser: dialect.parser()?,
+            batcher: RecordBatcher {
+                builder: QueryRecordBuilder::new(self)?,
+                column_names: self.column_names.clone(),
+                header: dialect.header.unwrap_or(false),
+                header_len: 0,
+                positions: None,
+                rows: 0,
+                batch_rows,
+                batch: Vec::new(),
+                batches: Vec::new(),
+            },
+        })
+    }
+}
+
+impl RecordStream {
+    /*
+     * Read a chunk of CSV, returning the batches of records that it fills
+     */
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Vec<Vec<QueryRecord>>, CustomError> {
+        let batcher = &mut self.batcher;
+        self.parser.push(chunk, |fields| batcher.read(fields))?;
+        Ok(std::mem::take(&mut self.batcher.batches))
+    }
+
+    /*
+     * End the CSV, returning the records of the last batch
+     */
+    pub fn finish(mut self) -> Result<Vec<QueryRecord>, CustomError> {
+        let batcher = &mut self.batcher;
+        self.parser.finish(|fields| batcher.read(fields))?;
+        let mut records: Vec<QueryRecord> = self.batcher.batches.drain(..).flatten().collect();
+        records.append(&mut self.batcher.batch);
+        log::debug!("Read {} rows", self.batcher.rows);
+        Ok(records)
+    }
+}
+
+impl RecordBatcher {
+    fn read(&mut self, fields: Vec<String>) -> Result<(), CustomError> {
+        if self.header {
+            self.header = false;
+            self.header_len = fields.len();
+            self.positions = Some(header_positions(&self.column_names, &fields)?);
+            return Ok(());
+        }
+        self.rows += 1;
+        let fields = match &self.positions {
+            Some(_) if fields.len() != self.header_len => {
+                return Err(CustomError::new(
+                    400,
+                    format!(
+                        "Bad request: Expected {} fields but found {} (row {})",
+                        self.header_len,
+                        fields.len(),
+                        self.rows
+                    ),
+                ))
+            }
+            Some(positions) => positions.iter().map(|p| fields[*p].clone()).collect(),
+            None => fields,
+        };
+        self.batch.push(self.builder.from_vec(self.rows, fields)?);
+        if self.batch.len() >= self.batch_rows {
+            self.batches.push(std::mem::take(&mut self.batch));
+        }
+        Ok(())
+    }
+}
+
+/*
+ * The position in the header row of each column, matching names as inference does
+ */
+fn header_positions(column_names: &[String], header: &[String]) -> Result<Vec<usize>, CustomError> {
+    let names: Vec<String> = header
+        .iter()
+        .enumerate()
+        .map(|(i, name)| column_name(i, name))
+        .collect();
+    if let Some(name) = names.iter().find(|name| !column_names.contains(name)) {
+        return Err(CustomError::new(
+            400,
+            format!("Bad request: Unknown column '{}' in header", name),
+        ));
+    }
+    column_names
+        .iter()
+        .map(|column_name| {
+            names
+                .iter()
+                .position(|name| name == column_name)
+                .ok_or_else(|| {
+                    CustomError::new(
+                        400,
+                        format!("Bad request: Missing column '{}' in header", column_name),
+                    )
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::{as_i64, as_str};
+
+    fn table_schema() -> TableSchema {
+        TableSchema {
+            id: 0,
+            column_types: vec![String::from("string"), String::from("i64")],
+            column_names: vec![String::from("device"), String::from("reading")],
+            column_nullable: vec![false, false],
+            null_values: Vec::new(),
+            created_at: chrono::Utc::now().naive_utc(),
+            updated_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn can_stream_batches_of_records() {
+        let dialect = CsvDialect {
+            header: Some(true),
+            ..Default::default()
+        };
+        let mut stream = table_schema().record_stream(&dialect, 2).unwrap();
+        assert!(stream.push(b"Reading,Device\n1,a\n2,").unwrap().is_empty());
+        let batches = stream.push(b"b\n3,c\n4,d\n5").unwrap();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(as_str(batches[0][1].columns[0].as_ref()), Some("b"));
+        assert_eq!(as_i64(batches[1][1].columns[1].as_ref()), Some(4));
+        assert!(stream.push(b",").unwrap().is_empty());
+        let last = stream.finish().unwrap();
+        assert_eq!(last.len(), 1);
+        assert_eq!(as_str(last[0].columns[0].as_ref()), Some(""));
+
+        let mut stream = table_schema()
+            .record_stream(&CsvDialect::default(), 2)
+            .unwrap();
+        let err = stream.push(b"a,1\nb,2\nc,x\n").unwrap_err();
+        assert_eq!(
+            err.error_message,
+            "Bad request: Invalid i64 'x' (row 3, column 'reading')"
+        );
+    }
+}
@@ -1,5 +1,7 @@
 mod model;
 mod routes;
+mod segments;
 
 pub use model::*;
 pub use routes::init_routes;
+pub use segments::*;
@@ -1,11 +1,13 @@
-use super::{InsertableTable, MaybeTable, TableRelation};
-use crate::table_schemas::TableSchema;
+use super::{
+    blocking_error, cached_partitions, InsertableTable, MaybeTable, SegmentStore, TableRelation,
+};
+use crate::table_schemas::{CsvDialect, TableSchema, UploadFormat, UPLOAD_BATCH_ROWS};
 use crate::users::User;
 use crate::{error_handler::CustomError, query::QueryRecord, AppData};
 use actix_multipart::Multipart;
 use actix_web::{delete, get, post, put, web, HttpResponse};
-use futures::{io::Cursor, StreamExt, TryStreamExt};
-use futures_util::AsyncWriteExt;
+use futures::{StreamExt, TryStreamExt};
+use std::{path::PathBuf, sync::Arc};
 
 #[get("/tables/id/{id}")]
 async fn find_by_id(user: User, id: web::Path<i64>) -> Result<HttpResponse, CustomError> {
@@ -28,10 +30,17 @@ async fn upload(
     app_data: web::Data<AppData>,
     user: User,
     id: web::Path<i64>,
+    dialect: web::Query<CsvDialect>,
     mut uploaded_data: Multipart,
 ) -> Result<HttpResponse, CustomError> {
     let id = id.into_inner();
-    log::debug!("POST /tables/upload/{} (user = {})", id, user.id);
+    let dialect = dialect.into_inner();
+    log::debug!(
+        "POST /tables/upload/{} (user = {}) {:?}",
+        id,
+        user.id,
+        dialect
+    );
 
     let user_id = user.id;
     let table = web::block(move || TableRelation::find_by_id(user_id, id)).await?;
@@ -47,54 +56,126 @@ async fn upload(
     })
     .await?;
 
+    let table_schema_id = table.table_schema_id;
+    let table_schema = web::block(move || TableSchema::find_by_id(table_schema_id)).await?;
+
+    // CSV is verified, stored and cached in batches as it arrives, while JSON is verified once
+    // whole. The partitions of an upload that fails are taken back out of the cache and store.
+    // Earlier segments of the table are cached first, so that the upload adds to them, unless
+    // the table is too large to cache and only has segments.
+    let cache = app_data.table_cache.lock().await.contains_key(&table.id)
+        || !SegmentStore::from_env().exceeds_cache_limit(table.size);
+    if cache {
+        cached_partitions(&app_data, table.id).await?;
+    }
     let mut file_size: i64 = 0;
-    let mut data_buffer = Cursor::new(Vec::with_capacity(1024));
-    while let Ok(Some(mut field)) = uploaded_data.try_next().await {
-        let content_disposition = field.content_disposition().unwrap();
-        let filename = content_disposition.get_filename().unwrap();
-        let filepath = format!("{}/{}", &file_dir, sanitize_filename::sanitize(&filename));
-        log::trace!("Buffering /tables/upload/{} at {}", id, filepath);
-
-        // Field in turn is stream of *Bytes* object
-        while let Some(chunk) = field.next().await {
-            let chunk = chunk?;
-            file_size += chunk.len() as i64;
-            data_buffer.write_all(chunk.as_ref()).await?;
+    let mut cached = Vec::new();
+    let uploaded = async {
+        let mut format = None;
+        let mut record_stream = None;
+        let mut json_data = Vec::new();
+        while let Ok(Some(mut field)) = uploaded_data.try_next().await {
+            let content_disposition = field.content_disposition().unwrap();
+            let format = *format.get_or_insert_with(|| {
+                UploadFormat::detect(
+                    field.content_type().essence_str(),
+                    content_disposition.get_name(),
+                )
+            });
+            if format == UploadFormat::Csv && record_stream.is_none() {
+                record_stream = Some(table_schema.record_stream(&dialect, UPLOAD_BATCH_ROWS)?);
+            }
+            let filename = content_disposition.get_filename().unwrap();
+            let filepath = format!("{}/{}", &file_dir, sanitize_filename::sanitize(&filename));
+            log::trace!("Reading /tables/upload/{} at {}", id, filepath);
+
+            // Field in turn is stream of *Bytes* object
+            while let Some(chunk) = field.next().await {
+                let chunk = chunk?;
+                file_size += chunk.len() as i64;
+                match record_stream.as_mut() {
+                    Some(record_stream) => {
+                        for batch in record_stream.push(chunk.as_ref())? {
+                            cached.push(cache_records(&app_data, table.id, batch, cache).await?);
+                        }
+                    }
+                    None => json_data.extend_from_slice(chunk.as_ref()),
+                }
+            }
         }
+        let records = match (record_stream, format) {
+            (Some(record_stream), _) => record_stream.finish()?,
+            (None, Some(format)) => table_schema.verify_json(json_data, format)?,
+            (None, None) => Vec::new(),
+        };
+        cached.push(cache_records(&app_data, table.id, records, cache).await?);
+        Ok::<(), CustomError>(())
     }
-    log::debug!("Uploaded {} to {}", file_size, file_dir);
-
-    // Verify the data is valid for the selected schema
-    let uploaded_data = data_buffer.into_inner();
-    let table_schema_id = table.table_schema_id;
-    let table_schema = web::block(move || TableSchema::find_by_id(table_schema_id)).await?;
-    let uploaded_records: Vec<QueryRecord> = table_schema.verify(uploaded_data)?;
-
-    // Extend the Data Cache
-    {
-        let mut table_cache_map = app_data.table_cache.lock().await;
-        if table_cache_map.contains_key(&table.id) {
-            table_cache_map
-                .get_mut(&table.id)
-                .unwrap()
-                .push(uploaded_records);
-        } else {
-            table_cache_map.insert(table.id, vec![uploaded_records]);
+    .await;
+    if let Err(err) = uploaded {
+        if let Some(partitions) = app_data.table_cache.lock().await.get_mut(&table.id) {
+            partitions.retain(|partition| !cached.iter().any(|(_, c)| Arc::ptr_eq(partition, c)));
         }
+        let segments: Vec<PathBuf> = cached.into_iter().map(|(path, _)| path).collect();
+        let removed = web::block(move || {
+            let store = SegmentStore::from_env();
+            segments.iter().try_for_each(|path| store.remove(path))
+        })
+        .await;
+        if let Err(removed_err) = removed.map_err(blocking_error) {
+            log::error!(
+                "Failed to remove segments of a failed upload: {:?}",
+                removed_err
+            );
+        }
+        return Err(err);
     }
+    log::debug!(
+        "Uploaded {} in {} partitions to {}",
+        file_size,
+        cached.len(),
+        file_dir
+    );
 
-    // Update the table info
+    // Update the table info, whose size counts every upload its segments hold
     let insertable_table = InsertableTable {
         user_id: user.id,
         table_schema_id: table.table_schema_id,
         name: table.name,
-        size: file_size,
+        size: table.size + file_size,
     };
     let table = web::block(move || TableRelation::update(user.id, id, insertable_table)).await?;
 
     Ok(HttpResponse::Ok().json(table))
 }
 
+/*
+ * Write records to a new segment of a table, where they can be queried, and append them to its
+ * cached partitions when the table is cached
+ */
+async fn cache_records(
+    app_data: &AppData,
+    table_id: i64,
+    records: Vec<QueryRecord>,
+    cache: bool,
+) -> Result<(PathBuf, Arc<Vec<QueryRecord>>), CustomError> {
+    let records = Arc::new(records);
+    let segment_records = records.clone();
+    let path = web::block(move || SegmentStore::from_env().write(table_id, &segment_records))
+        .await
+        .map_err(blocking_error)?;
+    if cache {
+        app_data
+            .table_cache
+            .lock()
+            .await
+            .entry(table_id)
+            .or_insert_with(Vec::new)
+            .push(records.clone());
+    }
+    Ok((path, records))
+}
+
 #[put("/tables/{id}")]
 async fn update(
     user: User,
@@ -139,10 +220,18 @@ async fn create(
 }
 
 #[delete("/tables/{id}")]
-async fn delete(user: User, id: web::Path<i64>) -> Result<HttpResponse, CustomError> {
+async fn delete(
+    app_data: web::Data<AppData>,
+    user: User,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, CustomError> {
     let id = id.into_inner();
     log::debug!("DELETE /tables/{}", id);
     let table = TableRelation::delete(user.id, id)?;
+    app_data.table_cache.lock().await.remove(&table.id);
+    web::block(move || SegmentStore::from_env().remove_table(id))
+        .await
+        .map_err(blocking_error)?;
     Ok(HttpResponse::Ok().json(table))
 }
 
@@ -0,0 +1,475 @@
+use crate::query::{Date, Null, QueryRecord, SqlType, Timestamp};
+use crate::{error_handler::CustomError, AppData};
+use actix_web::{error::BlockingError, web};
+use chrono::{Datelike, NaiveDate, TimeZone, Utc};
+use rust_decimal::Decimal;
+use std::{
+    env,
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+// Each partition of an upload is kept in a binary segment file under the table's directory in
+// TABLE_DATA_DIR, so that the rows of a table outlive the process. Segments are named by the
+// time they were written and read back in that order. A segment starts with a magic number,
+// its column and record counts, followed by the values of each record as a type tag and the
+// value's bytes in little endian.
+//
+// Tables are cached once they are scanned or uploaded, unless they are larger than
+// TABLE_CACHE_LIMIT bytes and not cached already. Scans read those from their segments.
+
+const DEFAULT_TABLE_DATA_DIR: &str = "data/tables";
+const SEGMENT_MAGIC: &[u8; 6] = b"HNSEG1";
+const SEGMENT_EXTENSION: &str = "seg";
+
+const NULL_TAG: u8 = 0;
+const BOOL_TAG: u8 = 1;
+const I64_TAG: u8 = 2;
+const F64_TAG: u8 = 3;
+const DECIMAL_TAG: u8 = 4;
+const STRING_TAG: u8 = 5;
+const TIMESTAMP_TAG: u8 = 6;
+const DATE_TAG: u8 = 7;
+
+#[derive(Debug, Clone)]
+pub struct SegmentStore {
+    pub data_dir: PathBuf,
+    pub cache_limit: Option<i64>,
+}
+
+impl SegmentStore {
+    /*
+     * Read TABLE_DATA_DIR, defaulting to data/tables under the working directory, and
+     * TABLE_CACHE_LIMIT, defaulting to caching every table
+     */
+    pub fn from_env() -> SegmentStore {
+        let data_dir = match env::var("TABLE_DATA_DIR") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => PathBuf::from(DEFAULT_TABLE_DATA_DIR),
+        };
+        let cache_limit = match env::var("TABLE_CACHE_LIMIT") {
+            Ok(limit) => limit.parse().map(Some).unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid TABLE_CACHE_LIMIT {}", limit);
+                None
+            }),
+            Err(_) => None,
+        };
+        SegmentStore {
+            data_dir,
+            cache_limit,
+        }
+    }
+
+    /*
+     * Whether a table of the given size is too large to cache
+     */
+    pub fn exceeds_cache_limit(&self, size: i64) -> bool {
+        self.cache_limit.is_some_and(|limit| size > limit)
+    }
+
+    fn table_dir(&self, table_id: i64) -> PathBuf {
+        self.data_dir.join(table_id.to_string())
+    }
+
+    /*
+     * Write records to a new segment of a table. The segment is written aside and renamed into
+     * place, so a crash never leaves a partial segment to be read
+     */
+    pub fn write(&self, table_id: i64, records: &[QueryRecord]) -> Result<PathBuf, CustomError> {
+        let table_dir = self.table_dir(table_id);
+        fs::create_dir_all(&table_dir)?;
+        let name = format!(
+            "{:020}-{}",
+            Utc::now().timestamp_nanos(),
+            uuid::Uuid::new_v4()
+        );
+        let path = table_dir.join(format!("{}.{}", name, SEGMENT_EXTENSION));
+        let partial = table_dir.join(format!("{}.partial", name));
+
+        if let Err(err) = write_records(&partial, records) {
+            let _ = fs::remove_file(&partial);
+            return Err(err);
+        }
+        fs::rename(&partial, &path)?;
+        log::debug!("Wrote {} records to segment {:?}", records.len(), path);
+        Ok(path)
+    }
+
+    /*
+     * The segments of a table in the order they were written
+     */
+    pub fn paths(&self, table_id: i64) -> Result<Vec<PathBuf>, CustomError> {
+        let entries = match fs::read_dir(self.table_dir(table_id)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        let mut paths = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension() == Some(OsStr::new(SEGMENT_EXTENSION)) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    pub fn read(&self, path: &Path) -> Result<Vec<QueryRecord>, CustomError> {
+        let corrupt =
+            |reason: &str| CustomError::new(500, format!("Corrupt segment {:?}: {}", path, reason));
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 6];
+        reader
+            .read_exact(&mut magic)
+            .map_err(|_| corrupt("missing header"))?;
+        if &magic != SEGMENT_MAGIC {
+            return Err(corrupt("unknown format"));
+        }
+        let width =
+            u32::from_le_bytes(read_bytes(&mut reader).map_err(|_| corrupt("missing header"))?);
+        let len =
+            u64::from_le_bytes(read_bytes(&mut reader).map_err(|_| corrupt("missing header"))?);
+
+        // The counts are not trusted to size allocations, since a corrupt one could be huge, and
+        // records without columns would be read without consuming any bytes
+        if width == 0 && len > 0 {
+            return Err(corrupt("records without columns"));
+        }
+        let mut records = Vec::new();
+        for _ in 0..len {
+            let mut columns = Vec::new();
+            for _ in 0..width {
+                columns.push(read_value(&mut reader).map_err(|err| corrupt(&err.to_string()))?);
+            }
+            records.push(QueryRecord {
+                columns,
+                ..Default::default()
+            });
+        }
+        if reader.read(&mut [0])? != 0 {
+            return Err(corrupt("trailing bytes"));
+        }
+        log::trace!("Read {} records from segment {:?}", records.len(), path);
+        Ok(records)
+    }
+
+    pub fn remove(&self, path: &Path) -> Result<(), CustomError> {
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    /*
+     * Remove every segment of a table
+     */
+    pub fn remove_table(&self, table_id: i64) -> Result<(), CustomError> {
+        match fs::remove_dir_all(self.table_dir(table_id)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/*
+ * The cached partitions of a table, first reading them from its segments when the table is not
+ * yet cached, e.g. after a restart. The cache is not locked while the segments are read, so a
+ * load or upload that caches the table first wins.
+ */
+pub async fn cached_partitions(
+    app_data: &AppData,
+    table_id: i64,
+) -> Result<Vec<Arc<Vec<QueryRecord>>>, CustomError> {
+    if let Some(partitions) = app_data.table_cache.lock().await.get(&table_id) {
+        return Ok(partitions.clone());
+    }
+
+    let partitions = web::block(move || {
+        let store = SegmentStore::from_env();
+        store
+            .paths(table_id)?
+            .iter()
+            .map(|path| store.read(path).map(Arc::new))
+            .collect::<Result<Vec<_>, CustomError>>()
+    })
+    .await
+    .map_err(blocking_error)?;
+    log::debug!(
+        "Loaded {} partitions of table {} from segments",
+        partitions.len(),
+        table_id
+    );
+    Ok(app_data
+        .table_cache
+        .lock()
+        .await
+        .entry(table_id)
+        .or_insert(partitions)
+        .clone())
+}
+
+/*
+ * Keep the error of a blocking segment operation rather than a generic one
+ */
+pub fn blocking_error(err: BlockingError<CustomError>) -> CustomError {
+    match err {
+        BlockingError::Error(err) => err,
+        BlockingError::Canceled => {
+            CustomError::new(500, String::from("Segment operation was canceled"))
+        }
+    }
+}
+
+fn write_records(path: &Path, records: &[QueryRecord]) -> Result<(), CustomError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(SEGMENT_MAGIC)?;
+    let width = records.first().map_or(0, |r| r.columns.len());
+    writer.write_all(&(width as u32).to_le_bytes())?;
+    writer.write_all(&(records.len() as u64).to_le_bytes())?;
+    for record in records.iter() {
+        if record.columns.len() != width {
+            return Err(CustomError::new(
+                500,
+                format!(
+                    "Expected {} columns but found {} in segment record",
+                    width,
+                    record.columns.len()
+                ),
+            ));
+        }
+        for value in record.columns.iter() {
+            write_value(&mut writer, value.as_ref())?;
+        }
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    Ok(())
+}
+
+fn write_value<W: Write>(writer: &mut W, value: &dyn SqlType) -> Result<(), CustomError> {
+    let value = value.as_any();
+    if value.is::<Null>() {
+        writer.write_all(&[NULL_TAG])?;
+    } else if let Some(v) = value.downcast_ref::<bool>() {
+        writer.write_all(&[BOOL_TAG, *v as u8])?;
+    } else if let Some(v) = value.downcast_ref::<i64>() {
+        writer.write_all(&[I64_TAG])?;
+        writer.write_all(&v.to_le_bytes())?;
+    } else if let Some(v) = value.downcast_ref::<f64>() {
+        writer.write_all(&[F64_TAG])?;
+        writer.write_all(&v.to_le_bytes())?;
+    } else if let Some(v) = value.downcast_ref::<Decimal>() {
+        writer.write_all(&[DECIMAL_TAG])?;
+        writer.write_all(&v.serialize())?;
+    } else if let Some(v) = value.downcast_ref::<String>() {
+        writer.write_all(&[STRING_TAG])?;
+        writer.write_all(&(v.len() as u32).to_le_bytes())?;
+        writer.write_all(v.as_bytes())?;
+    } else if let Some(Timestamp(v)) = value.downcast_ref::<Timestamp>() {
+        writer.write_all(&[TIMESTAMP_TAG])?;
+        writer.write_all(&v.timestamp().to_le_bytes())?;
+        writer.write_all(&v.timestamp_subsec_nanos().to_le_bytes())?;
+    } else if let Some(Date(v)) = value.downcast_ref::<Date>() {
+        writer.write_all(&[DATE_TAG])?;
+        writer.write_all(&v.num_days_from_ce().to_le_bytes())?;
+    } else {
+        return Err(CustomError::new(
+            500,
+            String::from("Cannot write a value of an unknown type to a segment"),
+        ));
+    }
+    Ok(())
+}
+
+fn read_value<R: Read>(reader: &mut R) -> io::Result<Box<dyn SqlType>> {
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_string());
+    let [tag] = read_bytes::<R, 1>(reader)?;
+    Ok(match tag {
+        NULL_TAG => Box::new(Null {}),
+        BOOL_TAG => Box::new(read_bytes::<R, 1>(reader)?[0] != 0),
+        I64_TAG => Box::new(i64::from_le_bytes(read_bytes(reader)?)),
+        F64_TAG => Box::new(f64::from_le_bytes(read_bytes(reader)?)),
+        DECIMAL_TAG => Box::new(Decimal::deserialize(read_bytes(reader)?)),
+        STRING_TAG => {
+            let len = u32::from_le_bytes(read_bytes(reader)?);
+            let mut bytes = Vec::new();
+            reader.by_ref().take(len as u64).read_to_end(&mut bytes)?;
+            if bytes.len() != len as usize {
+                return Err(invalid("truncated string"));
+            }
+            Box::new(String::from_utf8(bytes).map_err(|_| invalid("invalid UTF-8"))?)
+        }
+        TIMESTAMP_TAG => {
+            let seconds = i64::from_le_bytes(read_bytes(reader)?);
+            let nanos = u32::from_le_bytes(read_bytes(reader)?);
+            match Utc.timestamp_opt(seconds, nanos).single() {
+                Some(dt) => Box::new(Timestamp(dt)),
+                None => return Err(invalid("invalid timestamp")),
+            }
+        }
+        DATE_TAG => {
+            let days = i32::from_le_bytes(read_bytes(reader)?);
+            match NaiveDate::from_num_days_from_ce_opt(days) {
+                Some(date) => Box::new(Date(date)),
+                None => return Err(invalid("invalid date")),
+            }
+        }
+        tag => return Err(invalid(&format!("unknown type tag {}", tag))),
+    })
+}
+
+fn read_bytes<R: Read, const N: usize>(reader: &mut R) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::{as_bool, as_decimal, as_f64, as_i64, as_str, as_timestamp, is_null};
+    use std::str::FromStr;
+
+    fn store() -> SegmentStore {
+        SegmentStore {
+            cache_limit: None,
+            data_dir: env::temp_dir()
+                .join(format!("hetnetdb-segment-test-{}", uuid::Uuid::new_v4())),
+        }
+    }
+
+    fn record(columns: Vec<Box<dyn SqlType>>) -> QueryRecord {
+        QueryRecord {
+            columns,
+            ..Default::default()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn can_round_trip_segments() {
+        let store = store();
+        let timestamp = Timestamp::parse("2021-03-01T12:30:00.25Z").unwrap();
+        let date = Date::parse("2021-03-02").unwrap();
+        let records = vec![
+            record(vec![
+                Box::new(String::from("a\u{e9}")),
+                Box::new(-7_i64),
+                Box::new(1.5_f64),
+                Box::new(true),
+                Box::new(Decimal::from_str("12.340").unwrap()),
+                Box::new(timestamp),
+                Box::new(date),
+            ]),
+            record(vec![
+                Box::new(String::new()),
+                Box::new(Null {}),
+                Box::new(Null {}),
+                Box::new(false),
+                Box::new(Null {}),
+                Box::new(Null {}),
+                Box::new(Null {}),
+            ]),
+        ];
+        let first = store.write(1, &records).unwrap();
+        let second = store.write(1, &records[1..]).unwrap();
+        store.write(2, &[]).unwrap();
+        assert_eq!(store.paths(1).unwrap(), vec![first.clone(), second]);
+        assert!(store.paths(3).unwrap().is_empty());
+
+        let read = store.read(&first).unwrap();
+        assert_eq!(read.len(), 2);
+        let columns = &read[0].columns;
+        assert_eq!(as_str(columns[0].as_ref()), Some("a\u{e9}"));
+        assert_eq!(as_i64(columns[1].as_ref()), Some(-7));
+        assert_eq!(as_f64(columns[2].as_ref()), Some(1.5));
+        assert_eq!(as_bool(columns[3].as_ref()), Some(true));
+        assert_eq!(
+            as_decimal(columns[4].as_ref()).unwrap().to_string(),
+            "12.340"
+        );
+        assert_eq!(as_timestamp(columns[5].as_ref()), Some(timestamp));
+        assert_eq!(columns[6].as_any().downcast_ref::<Date>(), Some(&date));
+        assert_eq!(as_str(read[1].columns[0].as_ref()), Some(""));
+        assert!(is_null(read[1].columns[1].as_ref()));
+        assert!(store.read(&store.paths(2).unwrap()[0]).unwrap().is_empty());
+
+        store.remove(&first).unwrap();
+        assert_eq!(store.paths(1).unwrap().len(), 1);
+        store.remove_table(1).unwrap();
+        store.remove_table(1).unwrap();
+        assert!(store.paths(1).unwrap().is_empty());
+        fs::remove_dir_all(&store.data_dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn corrupt_segments_are_errors() {
+        let store = store();
+        let path = store
+            .write(
+                1,
+                &[record(vec![Box::new(1_i64), Box::new(String::from("a"))])],
+            )
+            .unwrap();
+        let bytes = fs::read(&path).unwrap();
+
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let err = store.read(&path).unwrap_err();
+        assert_eq!(err.error_status_code, 500);
+        assert!(err.error_message.starts_with("Corrupt segment"));
+
+        fs::write(&path, [&bytes[..], b"x"].concat()).unwrap();
+        assert!(store
+            .read(&path)
+            .unwrap_err()
+            .error_message
+            .ends_with("trailing bytes"));
+
+        // Counts and lengths past the end of the segment are errors rather than allocations
+        let huge_count = [
+            &SEGMENT_MAGIC[..],
+            &u32::MAX.to_le_bytes(),
+            &u64::MAX.to_le_bytes(),
+        ];
+        fs::write(&path, huge_count.concat()).unwrap();
+        assert!(store
+            .read(&path)
+            .unwrap_err()
+            .error_message
+            .starts_with("Corrupt segment"));
+        let huge_string = [
+            &SEGMENT_MAGIC[..],
+            &1_u32.to_le_bytes(),
+            &1_u64.to_le_bytes(),
+            &[STRING_TAG],
+            &u32::MAX.to_le_bytes(),
+            b"a",
+        ];
+        fs::write(&path, huge_string.concat()).unwrap();
+        assert!(store
+            .read(&path)
+            .unwrap_err()
+            .error_message
+            .ends_with("truncated string"));
+        let no_columns = [
+            &SEGMENT_MAGIC[..],
+            &0_u32.to_le_bytes(),
+            &u64::MAX.to_le_bytes(),
+        ];
+        fs::write(&path, no_columns.concat()).unwrap();
+        assert!(store
+            .read(&path)
+            .unwrap_err()
+            .error_message
+            .ends_with("records without columns"));
+
+        fs::write(&path, b"{\"i64\":1}").unwrap();
+        assert!(store
+            .read(&path)
+            .unwrap_err()
+            .error_message
+            .ends_with("unknown format"));
+        fs::remove_dir_all(&store.data_dir).unwrap();
+    }
+}